@@ -0,0 +1,90 @@
+//! Hashing throughput benchmarks.
+//!
+//! Run with `cargo bench --bench hashing`.  The `hash_file` group compares each
+//! algorithm alone with the default set hashed serially and pipelined (which
+//! should run at about the speed of the slowest algorithm); the `hash_many` group
+//! hashes a folder of small files with different worker counts.
+use std::fs::{create_dir_all, write, File};
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion, BenchmarkId, Throughput};
+use futures::TryStreamExt;
+use tokio::runtime::Runtime;
+
+use astral_filing_cabinet::filehash::{hash_file_with, hash_many, MultiDigest, HashAlgo, ALL_ALGORITHMS, DEFAULT_ALGORITHMS};
+use astral_filing_cabinet::filehash::pipeline::hash_serial;
+
+const LARGE_SIZE: usize = 64 * 1024 * 1024;
+const SMALL_SIZE: usize = 16 * 1024;
+const SMALL_COUNT: usize = 1000;
+
+/// Generate incompressible-looking data without a random number generator.
+fn data(size: usize, seed: u64) -> Vec<u8> {
+  let mut x = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+  (0..size).map(|_| {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x as u8
+  }).collect()
+}
+
+fn bench_hash_file(c: &mut Criterion, rt: &Runtime, dir: &Path) {
+  let path = dir.join("large.bin");
+  write(&path, data(LARGE_SIZE, 1)).expect("write failed");
+
+  let mut group = c.benchmark_group("hash_file");
+  group.sample_size(10);
+  group.throughput(Throughput::Bytes(LARGE_SIZE as u64));
+  for algo in ALL_ALGORITHMS {
+    group.bench_with_input(BenchmarkId::new("single", algo), algo, |b, algo| {
+      b.iter(|| rt.block_on(hash_file_with(&path, MultiDigest::with_algorithms(&[*algo]))).expect("hash failed"))
+    });
+  }
+  group.bench_function("default-serial", |b| {
+    b.iter(|| {
+      let mut file = File::open(&path).expect("open failed");
+      hash_serial(&mut file, MultiDigest::with_algorithms(DEFAULT_ALGORITHMS)).expect("hash failed")
+    })
+  });
+  group.bench_function("default-pipelined", |b| {
+    b.iter(|| rt.block_on(hash_file_with(&path, MultiDigest::with_algorithms(DEFAULT_ALGORITHMS))).expect("hash failed"))
+  });
+  group.bench_function("all-pipelined", |b| {
+    b.iter(|| rt.block_on(hash_file_with(&path, MultiDigest::with_algorithms(ALL_ALGORITHMS))).expect("hash failed"))
+  });
+  group.finish();
+}
+
+fn bench_hash_many(c: &mut Criterion, rt: &Runtime, dir: &Path) {
+  let dir = dir.join("small");
+  create_dir_all(&dir).expect("mkdir failed");
+  let paths: Vec<PathBuf> = (0..SMALL_COUNT).map(|i| {
+    let path = dir.join(format!("{:04}.bin", i));
+    write(&path, data(SMALL_SIZE, i as u64 + 2)).expect("write failed");
+    path
+  }).collect();
+
+  let mut group = c.benchmark_group("hash_many");
+  group.sample_size(10);
+  group.throughput(Throughput::Bytes((SMALL_SIZE * SMALL_COUNT) as u64));
+  for jobs in [1, 4, 8] {
+    group.bench_with_input(BenchmarkId::new("jobs", jobs), &jobs, |b, jobs| {
+      b.iter(|| {
+        let hashes: Vec<_> = rt.block_on(hash_many(&paths, &[HashAlgo::Sha256], *jobs).try_collect()).expect("hash failed");
+        assert_eq!(hashes.len(), SMALL_COUNT);
+      })
+    });
+  }
+  group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+  let rt = Runtime::new().expect("runtime failed");
+  let scratch = tempfile::tempdir().expect("tempdir failed");
+  bench_hash_file(c, &rt, scratch.path());
+  bench_hash_many(c, &rt, scratch.path());
+}
+
+criterion_group!(hashing, benches);
+criterion_main!(hashing);
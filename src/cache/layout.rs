@@ -0,0 +1,159 @@
+//! Object layouts for caches and directory remotes.
+//!
+//! AFC stores objects keyed by their SHA-256 digest and sharded by its first two hex
+//! digits (`ab/cdef…`).  DVC shards the same way, but keys objects by MD5: DVC 3
+//! stores them under `files/md5/`, while DVC 2 stored them at the root of the cache
+//! (DVC 3 still looks there for outputs recorded by DVC 2).  DVC also stores a
+//! manifest for each directory, under the manifest's MD5 with a `.dir` suffix.
+//!
+//! The layout only decides where an object lives.  Objects are still verified against
+//! every hash we have for them, so a DVC cache works without SHA-256 hashes.
+use std::fmt;
+
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Serialize, Deserialize};
+
+use crate::filehash::{MultiHash, HashAlgo, DigestValue};
+
+use super::CacheError;
+use super::manifest::DIR_SUFFIX;
+
+/// The directory under which DVC 3 stores MD5-keyed objects.
+const DVC3_PREFIX: &str = "files/md5";
+
+/// The layout of objects in a cache or directory remote.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all="lowercase")]
+pub enum CacheLayout {
+  /// AFC's layout, keyed by SHA-256.
+  #[default]
+  Afc,
+  /// DVC 3's layout, keyed by MD5 under `files/md5`.
+  Dvc,
+  /// DVC 2's layout, keyed by MD5 at the root.
+  Dvc2,
+}
+
+impl fmt::Display for CacheLayout {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let s = match self {
+      CacheLayout::Afc => "afc",
+      CacheLayout::Dvc => "dvc",
+      CacheLayout::Dvc2 => "dvc2",
+    };
+    f.write_str(s)
+  }
+}
+
+/// Split a key into its sharded relative path.
+fn shard(key: &str) -> RelativePathBuf {
+  let (shard, rest) = key.split_at(2);
+  RelativePathBuf::from(shard).join(rest)
+}
+
+/// Reassemble a key from a sharded relative path.
+fn unshard(path: &RelativePath) -> Option<String> {
+  let mut parts = path.components();
+  let shard = parts.next()?.as_str();
+  let rest = parts.next()?.as_str();
+  if parts.next().is_some() || shard.len() != 2 {
+    return None;
+  }
+  Some(format!("{}{}", shard, rest))
+}
+
+impl CacheLayout {
+  /// Get the hash algorithm that keys objects in this layout.
+  pub fn key_algorithm(&self) -> HashAlgo {
+    match self {
+      CacheLayout::Afc => HashAlgo::Sha256,
+      CacheLayout::Dvc | CacheLayout::Dvc2 => HashAlgo::Md5,
+    }
+  }
+
+  /// Get the key (as a hex string) of an object.
+  pub fn object_key(&self, hash: &MultiHash) -> Result<String, CacheError> {
+    let key = match self {
+      CacheLayout::Afc => hash.sha256.as_ref().map(|h| h.to_string()),
+      CacheLayout::Dvc | CacheLayout::Dvc2 => hash.md5.as_ref().map(|h| h.to_string()),
+    };
+    key.ok_or(CacheError::NoKey(self.key_algorithm()))
+  }
+
+  /// Get the relative path of a key.
+  fn key_relpath(&self, key: &str) -> RelativePathBuf {
+    match self {
+      CacheLayout::Dvc => RelativePathBuf::from(DVC3_PREFIX).join(shard(key)),
+      _ => shard(key),
+    }
+  }
+
+  /// Get the relative path at which new objects are stored.
+  pub fn object_relpath(&self, hash: &MultiHash) -> Result<RelativePathBuf, CacheError> {
+    Ok(self.key_relpath(&self.object_key(hash)?))
+  }
+
+  /// Get the relative paths at which an existing object may be stored, in order.
+  ///
+  /// This is the object's path, followed by the DVC 2 path for DVC 3 caches.
+  pub fn object_relpaths(&self, hash: &MultiHash) -> Result<Vec<RelativePathBuf>, CacheError> {
+    let mut paths = vec![self.object_relpath(hash)?];
+    if *self == CacheLayout::Dvc {
+      paths.push(CacheLayout::Dvc2.object_relpath(hash)?);
+    }
+    Ok(paths)
+  }
+
+  /// Get the relative path of a directory manifest.
+  ///
+  /// Returns `None` for layouts that do not store manifests.
+  pub fn manifest_relpath(&self, md5: &DigestValue<16>) -> Option<RelativePathBuf> {
+    match self {
+      CacheLayout::Afc => None,
+      _ => Some(self.key_relpath(&format!("{}{}", md5, DIR_SUFFIX))),
+    }
+  }
+
+  /// Parse the relative path of an object back to its key.
+  ///
+  /// Returns `None` if the path is not an object path (e.g. a temporary file or
+  /// a directory manifest).
+  pub fn parse_object_relpath(&self, path: &RelativePath) -> Option<MultiHash> {
+    match self {
+      CacheLayout::Afc => {
+        let key = unshard(path)?.parse().ok()?;
+        Some(MultiHash { sha256: Some(key), ..MultiHash::default() })
+      },
+      CacheLayout::Dvc => {
+        let rel = path.strip_prefix(DVC3_PREFIX).unwrap_or(path);
+        CacheLayout::Dvc2.parse_object_relpath(rel)
+      },
+      CacheLayout::Dvc2 => {
+        let key = unshard(path)?.parse().ok()?;
+        Some(MultiHash { md5: Some(key), ..MultiHash::default() })
+      },
+    }
+  }
+}
+
+#[test]
+fn test_dvc_relpaths() {
+  let md5 = "5d41402abc4b2a76b9719d911017c592";
+  let hash = MultiHash { md5: Some(md5.parse().unwrap()), ..MultiHash::default() };
+  assert!(matches!(CacheLayout::Afc.object_relpath(&hash), Err(CacheError::NoKey(HashAlgo::Sha256))));
+
+  let paths = CacheLayout::Dvc.object_relpaths(&hash).expect("no key");
+  assert_eq!(paths[0].as_str(), "files/md5/5d/41402abc4b2a76b9719d911017c592");
+  assert_eq!(paths[1].as_str(), "5d/41402abc4b2a76b9719d911017c592");
+  assert_eq!(CacheLayout::Dvc2.object_relpaths(&hash).expect("no key").len(), 1);
+
+  for path in &paths {
+    let parsed = CacheLayout::Dvc.parse_object_relpath(path).expect("parse failed");
+    assert_eq!(parsed.md5, hash.md5);
+  }
+
+  let man = CacheLayout::Dvc.manifest_relpath(hash.md5.as_ref().unwrap()).expect("no manifest path");
+  assert_eq!(man.as_str(), "files/md5/5d/41402abc4b2a76b9719d911017c592.dir");
+  assert!(CacheLayout::Dvc.parse_object_relpath(&man).is_none());
+  assert!(CacheLayout::Afc.manifest_relpath(hash.md5.as_ref().unwrap()).is_none());
+}
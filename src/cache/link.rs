@@ -0,0 +1,167 @@
+//! Strategies for placing cached objects in the work tree.
+//!
+//! Copying objects out of the cache doubles disk usage, so AFC can instead link
+//! work tree files to the cache.  Strategies are tried in order until one works;
+//! e.g. reflinks are only supported on some file systems, and hard links do not
+//! work across file systems.
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use log::*;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::fs::{hard_link, metadata, set_permissions, rename, remove_file};
+use tokio::task::spawn_blocking;
+
+use crate::filehash::MultiHash;
+use crate::util::io::{path_exists, sibling_temp_path};
+
+use super::{Cache, CacheError};
+
+/// The default link strategies: reflink if possible, falling back to copying.
+pub const DEFAULT_LINK_STRATEGIES: &[LinkStrategy] = &[LinkStrategy::Reflink, LinkStrategy::Copy];
+
+/// A strategy for placing a cached object in the work tree.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all="lowercase")]
+pub enum LinkStrategy {
+  /// Copy-on-write clone of the object (only on supporting file systems).
+  Reflink,
+  /// Hard link to the object; the file is made read-only.
+  Hardlink,
+  /// Symbolic link to the object; the object is made read-only.
+  Symlink,
+  /// Copy the object.
+  Copy,
+}
+
+/// Error parsing a link strategy.
+#[derive(Error, Debug, Clone)]
+#[error("invalid link strategy {0:?}")]
+pub struct LinkParseError(String);
+
+impl fmt::Display for LinkStrategy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let s = match self {
+      LinkStrategy::Reflink => "reflink",
+      LinkStrategy::Hardlink => "hardlink",
+      LinkStrategy::Symlink => "symlink",
+      LinkStrategy::Copy => "copy",
+    };
+    f.write_str(s)
+  }
+}
+
+impl FromStr for LinkStrategy {
+  type Err = LinkParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "reflink" => Ok(LinkStrategy::Reflink),
+      "hardlink" => Ok(LinkStrategy::Hardlink),
+      "symlink" => Ok(LinkStrategy::Symlink),
+      "copy" => Ok(LinkStrategy::Copy),
+      _ => Err(LinkParseError(s.to_owned())),
+    }
+  }
+}
+
+/// Make a file read-only.
+async fn make_readonly(path: &Path) -> io::Result<()> {
+  let mut perms = metadata(path).await?.permissions();
+  if !perms.readonly() {
+    perms.set_readonly(true);
+    set_permissions(path, perms).await?;
+  }
+  Ok(())
+}
+
+/// Create a link to `src` at `dst` with a particular (non-copy) strategy.
+async fn make_link(strategy: LinkStrategy, src: &Path, dst: &Path) -> io::Result<()> {
+  match strategy {
+    LinkStrategy::Reflink => {
+      let src = src.to_owned();
+      let dst = dst.to_owned();
+      spawn_blocking(move || reflink_copy::reflink(src, dst)).await?
+    },
+    LinkStrategy::Hardlink => {
+      hard_link(src, dst).await?;
+      make_readonly(dst).await
+    },
+    #[cfg(unix)]
+    LinkStrategy::Symlink => {
+      let src = src.canonicalize()?;
+      make_readonly(&src).await?;
+      tokio::fs::symlink(src, dst).await
+    },
+    #[cfg(not(unix))]
+    LinkStrategy::Symlink => {
+      Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks not supported on this platform"))
+    },
+    LinkStrategy::Copy => unreachable!("copy is not a link"),
+  }
+}
+
+impl Cache {
+  /// Place a cached object at a destination path, trying link strategies in order.
+  ///
+  /// The object is verified against its hashes before it is linked (copies are
+  /// verified as they are made).  Returns the strategy that succeeded; if none of
+  /// the strategies work, the error from the last one is returned.
+  pub async fn link<P: AsRef<Path>>(&self, hash: &MultiHash, dest: P, strategies: &[LinkStrategy]) -> Result<LinkStrategy, CacheError> {
+    let dest = dest.as_ref();
+    let opath = self.locate(hash).await?;
+    let mut verified = false;
+    let mut last_err = None;
+
+    for strategy in strategies {
+      if *strategy == LinkStrategy::Copy {
+        self.get(hash, dest).await?;
+        return Ok(LinkStrategy::Copy);
+      }
+
+      if !verified {
+        if !self.verify(hash).await? {
+          error!("cache object {:?} is corrupt", opath);
+          return Err(CacheError::Corrupt(self.layout().object_key(hash)?));
+        }
+        verified = true;
+      }
+
+      if let Some(dir) = dest.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+      }
+      let tmp = sibling_temp_path(dest);
+      match make_link(*strategy, &opath, &tmp).await {
+        Ok(()) => {
+          debug!("{:?}: placed with {}", dest, strategy);
+          rename(&tmp, dest).await?;
+          // renaming onto another link to the same file does nothing, leaving tmp
+          if path_exists(&tmp).await? {
+            remove_file(&tmp).await?;
+          }
+          return Ok(*strategy);
+        },
+        Err(e) => {
+          debug!("{:?}: {} failed: {}", dest, strategy, e);
+          let _ = remove_file(&tmp).await;
+          last_err = Some(e);
+        }
+      }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no link strategies")).into())
+  }
+}
+
+#[test]
+fn test_parse_strategy() {
+  assert_eq!("reflink".parse::<LinkStrategy>().unwrap(), LinkStrategy::Reflink);
+  assert_eq!(" hardlink".parse::<LinkStrategy>().unwrap(), LinkStrategy::Hardlink);
+  assert!("teleport".parse::<LinkStrategy>().is_err());
+  for s in [LinkStrategy::Reflink, LinkStrategy::Hardlink, LinkStrategy::Symlink, LinkStrategy::Copy] {
+    assert_eq!(s.to_string().parse::<LinkStrategy>().unwrap(), s);
+  }
+}
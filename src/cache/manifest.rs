@@ -0,0 +1,135 @@
+//! DVC directory manifests.
+//!
+//! DVC records a directory as a JSON list of `{"md5": …, "relpath": …}` entries
+//! sorted by path, and identifies the directory by the MD5 of that list as written
+//! by Python's `json.dumps` with sorted keys.  [manifest_json] reproduces that
+//! encoding exactly, so manifests we write have the hashes DVC expects.
+//!
+//! [listing_json] generalizes the encoding to other hash algorithms; AFC uses it to
+//! compute the tree hashes of folders.
+use std::fmt::Write;
+
+use digest::Digest;
+use md5::Md5;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Serialize, Deserialize};
+
+use crate::filehash::{DigestValue, HashAlgo};
+
+/// The suffix DVC adds to the keys of directory manifests.
+pub const DIR_SUFFIX: &str = ".dir";
+
+/// An entry in a directory manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+  /// The MD5 hash of the file.
+  pub md5: DigestValue<16>,
+  /// The file's path relative to the directory (with `/` separators).
+  pub relpath: RelativePathBuf,
+}
+
+/// Parse a directory manifest.
+pub fn parse_manifest(data: &[u8]) -> serde_json::Result<Vec<ManifestEntry>> {
+  serde_json::from_slice(data)
+}
+
+/// Write a string as a JSON literal the way Python's `json.dumps` does (ASCII only).
+fn write_py_string(out: &mut String, s: &str) {
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      '\u{8}' => out.push_str("\\b"),
+      '\u{c}' => out.push_str("\\f"),
+      ' '..='~' => out.push(c),
+      _ => {
+        let mut buf = [0u16; 2];
+        for unit in c.encode_utf16(&mut buf) {
+          write!(out, "\\u{:04x}", unit).expect("string write failed");
+        }
+      },
+    }
+  }
+  out.push('"');
+}
+
+/// Encode a listing of `(hash, path)` pairs as DVC encodes manifests, with `algo`
+/// naming the hash field.
+///
+/// The entries are sorted by path; keys are sorted as Python sorts them, so e.g.
+/// `sha256` comes after `relpath`.
+pub fn listing_json<S: AsRef<str>, P: AsRef<RelativePath>>(algo: HashAlgo, entries: &[(S, P)]) -> String {
+  let mut sorted: Vec<(&str, &str)> = entries.iter().map(|(h, p)| (h.as_ref(), p.as_ref().as_str())).collect();
+  sorted.sort_by(|a, b| a.1.cmp(b.1));
+  let key = algo.to_string();
+
+  let mut out = String::from("[");
+  for (i, (hash, path)) in sorted.into_iter().enumerate() {
+    if i > 0 {
+      out.push_str(", ");
+    }
+    out.push('{');
+    if key.as_str() < "relpath" {
+      write!(out, "\"{}\": \"{}\", \"relpath\": ", key, hash).expect("string write failed");
+      write_py_string(&mut out, path);
+    } else {
+      out.push_str("\"relpath\": ");
+      write_py_string(&mut out, path);
+      write!(out, ", \"{}\": \"{}\"", key, hash).expect("string write failed");
+    }
+    out.push('}');
+  }
+  out.push(']');
+  out
+}
+
+/// Encode a directory manifest as DVC does, sorting the entries by path.
+pub fn manifest_json(entries: &[ManifestEntry]) -> String {
+  let pairs: Vec<_> = entries.iter().map(|e| (e.md5.to_string(), &e.relpath)).collect();
+  listing_json(HashAlgo::Md5, &pairs)
+}
+
+/// Compute the MD5 of an encoded manifest (its key, without the `.dir` suffix).
+pub fn manifest_md5(json: &[u8]) -> DigestValue<16> {
+  Md5::digest(json).into()
+}
+
+#[test]
+fn test_manifest_json() {
+  let entries = vec![
+    ManifestEntry { md5: "0c8d5dc1d3b4a7bc5c5b41e8ab0f1b10".parse().unwrap(), relpath: "b/c.txt".into() },
+    ManifestEntry { md5: "5d41402abc4b2a76b9719d911017c592".parse().unwrap(), relpath: "a \"é\".txt".into() },
+  ];
+  let json = manifest_json(&entries);
+  assert_eq!(json, concat!(
+    r#"[{"md5": "5d41402abc4b2a76b9719d911017c592", "relpath": "a \"\u00e9\".txt"}, "#,
+    r#"{"md5": "0c8d5dc1d3b4a7bc5c5b41e8ab0f1b10", "relpath": "b/c.txt"}]"#,
+  ));
+
+  let parsed = parse_manifest(json.as_bytes()).expect("parse failed");
+  assert_eq!(parsed[0], entries[1]);
+  assert_eq!(parsed[1], entries[0]);
+}
+
+#[test]
+fn test_listing_json() {
+  let json = listing_json(HashAlgo::Sha1, &[("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d", "hello.txt")]);
+  assert_eq!(json, r#"[{"relpath": "hello.txt", "sha1": "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"}]"#);
+  assert_eq!(listing_json::<&str, &str>(HashAlgo::Sha256, &[]), "[]");
+}
+
+#[test]
+fn test_manifest_md5() {
+  // the manifest for a directory containing `hello.txt` with contents `hello`,
+  // hashed with `hashlib.md5(json.dumps(...).encode()).hexdigest()`
+  let entries = vec![
+    ManifestEntry { md5: "5d41402abc4b2a76b9719d911017c592".parse().unwrap(), relpath: "hello.txt".into() },
+  ];
+  let json = manifest_json(&entries);
+  assert_eq!(json, r#"[{"md5": "5d41402abc4b2a76b9719d911017c592", "relpath": "hello.txt"}]"#);
+  assert_eq!(manifest_md5(json.as_bytes()).to_string(), "0273ec1121a1a261f4da03e0dc056f27");
+}
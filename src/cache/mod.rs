@@ -1 +1,400 @@
 //! Operations for the file cache.
+//!
+//! The cache stores file contents keyed by their SHA-256 digest.  Objects are sharded
+//! into subdirectories by the first two hex digits of the digest, so an object with
+//! hash `abcdef…` is stored at `ab/cdef…` under the cache root.  Objects are inserted
+//! by writing to a temporary file and renaming it into place, so a partially-written
+//! object is never visible under its final name.
+//!
+//! A cache can also use DVC's MD5-keyed layout (see [layout]), so AFC can read and
+//! populate an existing DVC cache.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use futures::TryStreamExt;
+use log::*;
+use thiserror::Error;
+use tokio::fs::{File, create_dir_all, remove_file, rename};
+use friendly::bytes;
+use relative_path::{RelativePath, RelativePathBuf};
+
+use crate::filehash::{MultiHash, MultiDigest, HashAlgo, DigestValue, copy_hashed, copy_file_hashed, hash_file_with, DEFAULT_ALGORITHMS};
+use crate::util::walk::walk_directory;
+use crate::util::io::{path_exists, temp_path, sibling_temp_path, write_file_atomic};
+
+pub mod link;
+pub mod layout;
+pub mod manifest;
+
+pub use layout::CacheLayout;
+use manifest::{ManifestEntry, manifest_json, manifest_md5, parse_manifest};
+
+/// The directory (under the cache root) where corrupt objects are moved aside.
+pub const QUARANTINE_DIR: &str = "quarantine";
+/// The directory (under the cache root) where remotes keep partial downloads.
+pub const PARTIAL_DIR: &str = "tmp";
+
+/// An error that occurred in a cache operation.
+#[derive(Error, Debug)]
+pub enum CacheError {
+  #[error("IO error occurred: {0}")]
+  IOError(#[from] io::Error),
+  #[error("object has no {0} hash")]
+  NoKey(HashAlgo),
+  #[error("object {0} is not in the cache")]
+  NotFound(String),
+  #[error("object {0} does not match its recorded hashes")]
+  Corrupt(String),
+  #[error("{0} caches do not store directory manifests")]
+  NoManifests(CacheLayout),
+}
+
+/// A content-addressed local file cache.
+#[derive(Debug, Clone)]
+pub struct Cache {
+  root: PathBuf,
+  algorithms: Vec<HashAlgo>,
+  layout: CacheLayout,
+}
+
+/// Get the key (SHA-256 digest) for an object in the AFC layout.
+pub fn object_key(hash: &MultiHash) -> Result<&DigestValue<32>, CacheError> {
+  hash.sha256.as_ref().ok_or(CacheError::NoKey(HashAlgo::Sha256))
+}
+
+/// Get the relative path at which an object is stored in a sharded object store.
+///
+/// This is the AFC layout, shared by the default cache and by remotes that store
+/// plain files.
+pub fn object_relpath(hash: &MultiHash) -> Result<RelativePathBuf, CacheError> {
+  CacheLayout::Afc.object_relpath(hash)
+}
+
+/// Parse the relative path of an object in a sharded object store back to its key.
+///
+/// Returns `None` if the path is not an object path (e.g. a temporary file).
+pub fn parse_object_relpath(path: &RelativePath) -> Option<MultiHash> {
+  CacheLayout::Afc.parse_object_relpath(path)
+}
+
+impl Cache {
+  /// Open a cache rooted at the specified directory.
+  ///
+  /// The directory does not need to exist; it is created when objects are inserted.
+  pub fn open<P: AsRef<Path>>(root: P) -> Cache {
+    let root = root.as_ref().to_owned();
+    Cache {
+      root,
+      algorithms: DEFAULT_ALGORITHMS.to_vec(),
+      layout: CacheLayout::Afc,
+    }
+  }
+
+  /// Set the hash algorithms to compute when inserting files.
+  ///
+  /// The layout's key algorithm (SHA-256 by default) is always computed.
+  pub fn with_algorithms(mut self, algos: &[HashAlgo]) -> Cache {
+    self.algorithms = algos.to_vec();
+    self.add_key_algorithm();
+    self
+  }
+
+  /// Get the hash algorithms computed when inserting files.
+  pub fn algorithms(&self) -> &[HashAlgo] {
+    &self.algorithms
+  }
+
+  /// Set the layout of objects in this cache.
+  pub fn with_layout(mut self, layout: CacheLayout) -> Cache {
+    self.layout = layout;
+    self.add_key_algorithm();
+    self
+  }
+
+  fn add_key_algorithm(&mut self) {
+    let key = self.layout.key_algorithm();
+    if !self.algorithms.contains(&key) {
+      self.algorithms.push(key);
+    }
+  }
+
+  /// Get the root path of this cache.
+  pub fn root_path(&self) -> &Path {
+    self.root.as_path()
+  }
+
+  /// Get the layout of objects in this cache.
+  pub fn layout(&self) -> CacheLayout {
+    self.layout
+  }
+
+  /// Get the path at which a new object is stored.
+  pub fn object_path(&self, hash: &MultiHash) -> Result<PathBuf, CacheError> {
+    Ok(self.layout.object_relpath(hash)?.to_path(&self.root))
+  }
+
+  /// Find the path of an existing object.
+  ///
+  /// This differs from [Cache::object_path] for DVC 3 caches, which also hold
+  /// objects at their DVC 2 paths.  Returns the object path if it is not found.
+  pub async fn locate(&self, hash: &MultiHash) -> Result<PathBuf, CacheError> {
+    let paths = self.layout.object_relpaths(hash)?;
+    for rel in &paths {
+      let path = rel.to_path(&self.root);
+      if path_exists(&path).await? {
+        return Ok(path);
+      }
+    }
+    Ok(paths[0].to_path(&self.root))
+  }
+
+  /// Check whether the cache contains an object.
+  pub async fn contains(&self, hash: &MultiHash) -> Result<bool, CacheError> {
+    let path = self.locate(hash).await?;
+    Ok(path_exists(&path).await?)
+  }
+
+  /// Open an object in the cache for reading.
+  pub async fn open_object(&self, hash: &MultiHash) -> Result<File, CacheError> {
+    let opath = self.locate(hash).await?;
+    match File::open(&opath).await {
+      Ok(f) => Ok(f),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        Err(CacheError::NotFound(self.layout.object_key(hash)?))
+      },
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Insert a file into the cache, returning its size and hashes.
+  ///
+  /// The file is hashed while it is copied into the cache (through the hashing
+  /// pipeline), so it is only read once, and the size is that of the data hashed.
+  /// If the cache already contains an object with the same contents, the existing
+  /// object is kept.
+  pub async fn insert_file<P: AsRef<Path>>(&self, path: P) -> Result<(u64, MultiHash), CacheError> {
+    let path = path.as_ref();
+    create_dir_all(&self.root).await?;
+    let tmp = temp_path(&self.root, "insert");
+    debug!("copying {:?} to {:?}", path, tmp);
+
+    let digest = MultiDigest::with_algorithms(&self.algorithms);
+    let (size, hash) = match copy_file_hashed(path, &tmp, digest).await {
+      Ok(res) => res,
+      Err(e) => {
+        if path_exists(&tmp).await? {
+          remove_file(&tmp).await?;
+        }
+        return Err(e.into());
+      }
+    };
+
+    let opath = self.object_path(&hash)?;
+    if self.contains(&hash).await? {
+      debug!("{:?}: object already in cache", path);
+      remove_file(&tmp).await?;
+    } else {
+      debug!("{:?}: caching {} as {}", path, bytes(size), self.layout.object_key(&hash)?);
+      if let Some(dir) = opath.parent() {
+        create_dir_all(dir).await?;
+      }
+      rename(&tmp, &opath).await?;
+    }
+
+    Ok((size, hash))
+  }
+
+  /// Get a temporary path in the cache for staging a new object.
+  ///
+  /// Files written here can be moved into place with [Cache::install].
+  pub async fn temp_path(&self) -> Result<PathBuf, CacheError> {
+    create_dir_all(&self.root).await?;
+    Ok(temp_path(&self.root, "incoming"))
+  }
+
+  /// Move an already-verified file into the cache as the object for `hash`.
+  ///
+  /// The file should be on the same file system as the cache (e.g. from
+  /// [Cache::temp_path]).  If the object already exists, the file is removed.
+  pub async fn install<P: AsRef<Path>>(&self, hash: &MultiHash, path: P) -> Result<(), CacheError> {
+    let path = path.as_ref();
+    let opath = self.object_path(hash)?;
+    if self.contains(hash).await? {
+      remove_file(path).await?;
+    } else {
+      if let Some(dir) = opath.parent() {
+        create_dir_all(dir).await?;
+      }
+      rename(path, &opath).await?;
+    }
+    Ok(())
+  }
+
+  /// Copy an object from the cache to a destination file, verifying its contents.
+  ///
+  /// The object is written to a temporary file next to the destination, checked
+  /// against every hash in `hash`, and only then renamed into place.  An existing
+  /// file at the destination is replaced.
+  pub async fn get<P: AsRef<Path>>(&self, hash: &MultiHash, dest: P) -> Result<(), CacheError> {
+    let dest = dest.as_ref();
+    let key = self.layout.object_key(hash)?;
+    let mut src = self.open_object(hash).await?;
+
+    if let Some(dir) = dest.parent() {
+      create_dir_all(dir).await?;
+    }
+    let tmp = sibling_temp_path(dest);
+    debug!("copying {} to {:?}", key, dest);
+    let mut dst = File::create(&tmp).await?;
+    let res = copy_hashed(&mut src, &mut dst, MultiDigest::for_hash(hash)).await;
+    drop(dst);
+    let actual = match res {
+      Ok((_, h)) => h,
+      Err(e) => {
+        remove_file(&tmp).await?;
+        return Err(e.into());
+      }
+    };
+
+    if !hash.matches(&actual) {
+      error!("cache object {} is corrupt", key);
+      remove_file(&tmp).await?;
+      return Err(CacheError::Corrupt(key));
+    }
+
+    rename(&tmp, dest).await?;
+    Ok(())
+  }
+
+  /// Verify that a cached object matches its hashes.
+  ///
+  /// Returns `false` if the object's contents do not match.
+  pub async fn verify(&self, hash: &MultiHash) -> Result<bool, CacheError> {
+    let opath = self.locate(hash).await?;
+    if !path_exists(&opath).await? {
+      return Err(CacheError::NotFound(self.layout.object_key(hash)?));
+    }
+    let actual = hash_file_with(&opath, MultiDigest::for_hash(hash)).await?;
+    Ok(hash.matches(&actual))
+  }
+
+  /// List the objects in the cache.
+  ///
+  /// The hashes only include the layout's key algorithm.  Quarantined objects,
+  /// partial downloads, temporary files and manifests are not objects.
+  pub async fn list_objects(&self) -> Result<Vec<MultiHash>, CacheError> {
+    let mut objects = Vec::new();
+    if !path_exists(&self.root).await? {
+      return Ok(objects);
+    }
+    let mut stream = walk_directory(&self.root);
+    while let Some(de) = stream.try_next().await? {
+      if !de.file_type()?.is_file() {
+        continue;
+      }
+      let path = de.path();
+      let rel = match path.strip_prefix(&self.root).ok().and_then(|p| RelativePathBuf::from_path(p).ok()) {
+        Some(p) => p,
+        None => continue,
+      };
+      if let Some(QUARANTINE_DIR | PARTIAL_DIR) = rel.components().next().map(|c| c.as_str()) {
+        continue;
+      }
+      match self.layout.parse_object_relpath(&rel) {
+        Some(h) => objects.push(h),
+        None => trace!("{}: not an object, skipping", rel),
+      }
+    }
+    Ok(objects)
+  }
+
+  /// Move a (corrupt) object out of the cache into its quarantine directory.
+  ///
+  /// The object is kept for inspection as `quarantine/<key>`, and can be restored
+  /// by pulling or adding it again.  Returns the object's new path.
+  pub async fn quarantine(&self, hash: &MultiHash) -> Result<PathBuf, CacheError> {
+    let key = self.layout.object_key(hash)?;
+    let opath = self.locate(hash).await?;
+    if !path_exists(&opath).await? {
+      return Err(CacheError::NotFound(key));
+    }
+    let dir = self.root.join(QUARANTINE_DIR);
+    create_dir_all(&dir).await?;
+    let qpath = dir.join(&key);
+    debug!("quarantining object {} to {:?}", key, qpath);
+    rename(&opath, &qpath).await?;
+    Ok(qpath)
+  }
+
+  /// Get the path of a directory manifest.
+  pub fn manifest_path(&self, md5: &DigestValue<16>) -> Result<PathBuf, CacheError> {
+    let rel = self.layout.manifest_relpath(md5).ok_or(CacheError::NoManifests(self.layout))?;
+    Ok(rel.to_path(&self.root))
+  }
+
+  /// Read a directory manifest, verifying its hash.
+  pub async fn read_manifest(&self, md5: &DigestValue<16>) -> Result<Vec<ManifestEntry>, CacheError> {
+    let path = self.manifest_path(md5)?;
+    let key = format!("{}{}", md5, manifest::DIR_SUFFIX);
+    let data = match tokio::fs::read(&path).await {
+      Ok(d) => d,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(CacheError::NotFound(key)),
+      Err(e) => return Err(e.into()),
+    };
+    if manifest_md5(&data) != *md5 {
+      error!("manifest {} is corrupt", key);
+      return Err(CacheError::Corrupt(key));
+    }
+    parse_manifest(&data).map_err(|e| {
+      error!("manifest {} is invalid: {}", key, e);
+      CacheError::Corrupt(key)
+    })
+  }
+
+  /// Move an already-verified file into the cache as the manifest `md5`.
+  ///
+  /// Like [Cache::install], the file is removed if the manifest already exists.
+  pub async fn install_manifest<P: AsRef<Path>>(&self, md5: &DigestValue<16>, path: P) -> Result<(), CacheError> {
+    let path = path.as_ref();
+    let mpath = self.manifest_path(md5)?;
+    if path_exists(&mpath).await? {
+      remove_file(path).await?;
+    } else {
+      if let Some(dir) = mpath.parent() {
+        create_dir_all(dir).await?;
+      }
+      rename(path, &mpath).await?;
+    }
+    Ok(())
+  }
+
+  /// Write a directory manifest, returning its MD5.
+  pub async fn write_manifest(&self, entries: &[ManifestEntry]) -> Result<DigestValue<16>, CacheError> {
+    let json = manifest_json(entries);
+    let md5 = manifest_md5(json.as_bytes());
+    let path = self.manifest_path(&md5)?;
+    if !path_exists(&path).await? {
+      debug!("writing manifest {} with {} entries", md5, entries.len());
+      if let Some(dir) = path.parent() {
+        create_dir_all(dir).await?;
+      }
+      write_file_atomic(&path, json).await?;
+    }
+    Ok(md5)
+  }
+}
+
+#[test]
+fn test_object_relpath_roundtrip() {
+  let mut digest = MultiDigest::new();
+  digest.update(b"hello");
+  let hash = digest.finish();
+  let path = object_relpath(&hash).expect("no key");
+  let key = hash.sha256.as_ref().unwrap().to_string();
+  assert_eq!(path.as_str(), format!("{}/{}", &key[..2], &key[2..]));
+
+  let parsed = parse_object_relpath(&path).expect("parse failed");
+  assert_eq!(parsed.sha256, hash.sha256);
+  assert!(parse_object_relpath(RelativePath::new("ab/.cdef.tmp")).is_none());
+  assert!(parse_object_relpath(RelativePath::new("abc")).is_none());
+}
@@ -0,0 +1,230 @@
+//! The `add` command.
+use std::fs::{DirEntry, Metadata};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use friendly::bytes;
+use futures::{StreamExt, TryStreamExt, stream};
+use log::*;
+use relative_path::{RelativePath, RelativePathBuf};
+use tokio::fs::{metadata, read_dir};
+
+use super::open_tree;
+use crate::cache::Cache;
+use crate::tree::{WorkTree, AFC_DIR, GIT_DIR};
+use crate::tree::artifact::{Artifact, ArtifactMeta, FileMeta, FolderMeta, FolderEntry};
+use crate::tree::ignore::{ensure_ignored, GITIGNORE};
+use crate::tree::pointer::{AFCPointer, AFCPointerFile};
+use crate::util::io::path_exists;
+use crate::util::walk::walk_directory;
+
+/// Add files or directories to AFC, storing them in the cache and writing pointer files.
+///
+/// A directory is tracked as a single folder artifact listing all the files in it.
+#[derive(Args, Debug, Clone)]
+#[command(name="add")]
+pub struct AddCmd {
+  /// Transfer the files with this remote instead of the default.
+  #[arg(short='r', long="remote")]
+  remote: Option<String>,
+
+  /// Track all the files with this one pointer file (created, or added to if it
+  /// exists); they must be in the same directory as the pointer.
+  #[arg(short='p', long="pointer", value_name="FILE")]
+  pointer: Option<PathBuf>,
+
+  /// The files or directories to add.
+  #[arg(name="PATH", required=true)]
+  paths: Vec<PathBuf>,
+}
+
+/// Check whether a path is somewhere we must not track files.
+fn is_forbidden(path: &RelativePath) -> bool {
+  path.components().any(|c| c.as_str() == GIT_DIR || c.as_str() == AFC_DIR)
+}
+
+/// Add the files in a directory to the cache, returning the folder's metadata.
+///
+/// Up to `jobs` files are copied and hashed at once.
+async fn cache_folder(cache: &Cache, tpath: &RelativePath, dir: &Path, jobs: usize) -> Result<FolderMeta> {
+  // read the whole listing first, so the walk is not cut short by an error
+  let listing: Vec<DirEntry> = walk_directory(dir).try_collect().await?;
+  let mut files = Vec::new();
+  for de in listing {
+    let ftype = de.file_type()?;
+    if ftype.is_dir() {
+      continue;
+    }
+    let path = de.path();
+    let relpath = RelativePathBuf::from_path(path.strip_prefix(dir)?)?;
+    if is_forbidden(&relpath) {
+      warn!("{}: skipping {}", tpath, relpath);
+      continue;
+    }
+    if relpath.extension() == Some("afc") {
+      bail!("{}: contains pointer file {}", tpath, relpath);
+    }
+    if !ftype.is_file() {
+      warn!("{}: {} is not a regular file, skipping", tpath, relpath);
+      continue;
+    }
+
+    files.push((relpath, path));
+  }
+
+  let files: Vec<FolderEntry> = stream::iter(files)
+    .map(|(relpath, path)| async move {
+      debug!("{}: adding {} to cache", tpath, relpath);
+      let (size, hashes) = cache.insert_file(&path).await?;
+      Ok::<_, anyhow::Error>(FolderEntry {
+        relpath,
+        meta: FileMeta { size: Some(size as usize), hashes },
+      })
+    })
+    .buffered(jobs.max(1))
+    .try_collect().await?;
+  Ok(FolderMeta::from_entries(files))
+}
+
+/// A file or directory to add, checked before anything is cached.
+struct AddTarget {
+  tpath: RelativePathBuf,
+  name: String,
+  fpath: PathBuf,
+  meta: Metadata,
+  ppath: RelativePathBuf,
+}
+
+/// Find the pointer file already tracking a path, if there is one.
+///
+/// A path is tracked by a pointer in its own directory, or by a folder artifact
+/// containing it, whose pointer is in one of the path's ancestor directories.
+async fn find_pointer(tree: &WorkTree, tpath: &RelativePath) -> Result<Option<RelativePathBuf>> {
+  let tpath = tpath.normalize();
+  let mut dir = tpath.parent().map(RelativePath::to_owned);
+  while let Some(d) = dir {
+    let mut list = read_dir(d.to_path(tree.root_path())).await?;
+    while let Some(de) = list.next_entry().await? {
+      let ppath = d.join(de.file_name().to_string_lossy().as_ref());
+      if ppath.extension() != Some("afc") || !de.file_type().await?.is_file() {
+        continue;
+      }
+      let arts = Artifact::load_afc_pointer(tree, &ppath).await
+        .with_context(|| format!("{}: cannot load pointer", ppath))?;
+      if arts.iter().any(|a| tpath.starts_with(a.path().normalize())) {
+        return Ok(Some(ppath));
+      }
+    }
+    dir = d.parent().map(RelativePath::to_owned);
+  }
+  Ok(None)
+}
+
+impl AddCmd {
+  /// Check a path to add, before caching anything.
+  async fn check_path(&self, tree: &WorkTree, path: &Path, pointer: Option<&RelativePath>) -> Result<AddTarget> {
+    let tpath = tree.tree_path(path)?;
+    if is_forbidden(&tpath) {
+      bail!("{}: cannot add files inside .git or .afc", tpath);
+    }
+    if tpath.extension() == Some("afc") {
+      bail!("{}: cannot add a pointer file", tpath);
+    }
+    let name = match tpath.file_name() {
+      Some(n) => n.to_owned(),
+      None => bail!("{:?}: not a file", path),
+    };
+
+    let fpath = tpath.to_path(tree.root_path());
+    let meta = metadata(&fpath).await?;
+    if !meta.is_file() && !meta.is_dir() {
+      bail!("{}: not a regular file or directory", tpath);
+    }
+
+    if let Some(tracker) = find_pointer(tree, &tpath).await? {
+      bail!("{}: already tracked by {}", tpath, tracker);
+    }
+    let ppath = match pointer {
+      Some(mp) if mp.parent() != tpath.parent() => {
+        bail!("{}: not in the same directory as {}", tpath, mp);
+      },
+      Some(mp) => mp.to_owned(),
+      None => tpath.with_file_name(format!("{}.afc", name)),
+    };
+    Ok(AddTarget { tpath, name, fpath, meta, ppath })
+  }
+
+  pub async fn run(&self) -> Result<()> {
+    let (tree, settings) = open_tree().await?;
+    let cache = settings.open_cache(tree.root_path());
+    if let Some(name) = &self.remote {
+      // make sure the remote exists, so pointers do not name a typo
+      settings.remote_settings(Some(name))?;
+    }
+
+    let mut multi = match &self.pointer {
+      Some(p) => {
+        let ppath = tree.tree_path(p)?;
+        if ppath.extension() != Some("afc") {
+          bail!("{}: pointer files must have the .afc extension", ppath);
+        }
+        let pfpath = ppath.to_path(tree.root_path());
+        let file = if path_exists(&pfpath).await? {
+          AFCPointerFile::load(&pfpath).await?
+        } else {
+          AFCPointerFile::default()
+        };
+        Some((ppath, file))
+      },
+      None => None,
+    };
+
+    // check every path first, so a bad one does not leave the others half-added
+    let mut targets: Vec<AddTarget> = Vec::with_capacity(self.paths.len());
+    for path in &self.paths {
+      let target = self.check_path(&tree, path, multi.as_ref().map(|(mp, _)| mp.as_relative_path())).await?;
+      if targets.iter().any(|t| t.tpath.normalize() == target.tpath.normalize()) {
+        bail!("{}: listed more than once", target.tpath);
+      }
+      targets.push(target);
+    }
+
+    for AddTarget { tpath, name, fpath, meta, ppath } in targets {
+      let (ameta, size) = if meta.is_dir() {
+        let fm = cache_folder(&cache, &tpath, &fpath, settings.core.jobs).await?;
+        let size: usize = fm.files.iter().filter_map(|e| e.meta.size).sum();
+        (ArtifactMeta::Folder(fm), size as u64)
+      } else {
+        debug!("{}: adding to cache", tpath);
+        let (size, hashes) = cache.insert_file(&fpath).await?;
+        (ArtifactMeta::File(FileMeta { size: Some(size as usize), hashes }), size)
+      };
+      let nfiles = ameta.file_metas().len();
+      let ptr = AFCPointer {
+        path: name.as_str().into(),
+        remote: self.remote.clone(),
+        meta: ameta,
+      };
+      // save the shared pointer as we go, so each cached file is recorded
+      match &mut multi {
+        Some((_, file)) => {
+          file.push(ptr)?;
+          file.save(ppath.to_path(tree.root_path())).await?;
+        },
+        None => AFCPointerFile::from(ptr).save(ppath.to_path(tree.root_path())).await?,
+      }
+
+      let ignore = tpath.with_file_name(GITIGNORE).to_path(tree.root_path());
+      ensure_ignored(&ignore, &name).await?;
+
+      if meta.is_dir() {
+        println!("added {} ({} files, {}) -> {}", tpath, nfiles, bytes(size), ppath);
+      } else {
+        println!("added {} ({}) -> {}", tpath, bytes(size), ppath);
+      }
+    }
+
+    Ok(())
+  }
+}
@@ -0,0 +1,72 @@
+//! The `checkout` command.
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use clap::Args;
+
+use super::{open_tree, scan_selected};
+use crate::cache::Cache;
+use crate::cache::link::LinkStrategy;
+use crate::tree::WorkTree;
+use crate::tree::artifact::Artifact;
+use crate::tree::checkout::{checkout_artifact, CheckoutOptions, CheckoutOutcome};
+
+/// Restore artifacts from the cache into the work tree.
+#[derive(Args, Debug, Clone)]
+#[command(name="checkout")]
+pub struct CheckoutCmd {
+  /// Overwrite locally-modified files.
+  #[arg(short='f', long="force")]
+  force: bool,
+
+  /// Link strategies to try, in order (e.g. `reflink,hardlink,copy`).
+  #[arg(long="link", value_delimiter=',')]
+  link: Vec<LinkStrategy>,
+
+  /// Re-hash every file instead of using the hash cache in `.afc/state`.
+  #[arg(long="no-hash-cache")]
+  no_hash_cache: bool,
+
+  /// The artifacts (or directories containing artifacts) to check out [default: all].
+  #[arg(name="PATH")]
+  paths: Vec<PathBuf>,
+}
+
+impl CheckoutCmd {
+  pub async fn run(&self) -> Result<()> {
+    let (tree, settings) = open_tree().await?;
+    let tree = if self.no_hash_cache { tree } else { tree.load_hash_cache().await? };
+    let cache = settings.open_cache(tree.root_path());
+    let opts = CheckoutOptions {
+      force: self.force,
+      links: if self.link.is_empty() { settings.cache.link } else { self.link.clone() },
+    };
+    let arts = scan_selected(&tree, &self.paths).await?;
+    let res = checkout_all(&tree, &cache, &arts, &opts).await;
+    tree.save_state().await?;
+    res
+  }
+}
+
+/// Check out a list of artifacts, reporting what was done.
+///
+/// Fails if any artifacts had local modifications that were not overwritten.
+pub(super) async fn checkout_all(tree: &WorkTree, cache: &Cache, arts: &[Artifact], opts: &CheckoutOptions) -> Result<()> {
+  let mut modified = 0;
+  for art in arts {
+    match checkout_artifact(tree, cache, art, opts).await? {
+      CheckoutOutcome::Restored => println!("restored {}", art.path()),
+      CheckoutOutcome::Modified => {
+        println!("skipped {}: locally modified", art.path());
+        modified += 1;
+      },
+      CheckoutOutcome::Unchanged => (),
+    }
+  }
+
+  if modified > 0 {
+    bail!("{} artifacts have local modifications (use --force to overwrite)", modified);
+  }
+
+  Ok(())
+}
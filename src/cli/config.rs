@@ -0,0 +1,231 @@
+//! The `config` commands.
+use anyhow::{Result, anyhow, bail};
+use clap::{Args, Subcommand};
+use toml::Value;
+use toml::value::Table;
+
+use super::find_tree;
+use crate::settings::{ConfigFile, ConfigScope, Settings, load_merged, lookup, merge_tables};
+use crate::tree::WorkTree;
+
+/// Get and set AFC configuration.
+#[derive(Subcommand, Debug, Clone)]
+#[command(name="config")]
+pub enum ConfigCommands {
+  /// Print a configuration value.
+  Get {
+    /// The configuration key (e.g. `cache.link`).
+    #[arg(name="KEY")]
+    key: String,
+  },
+  /// Set a configuration value.
+  Set {
+    #[command(flatten)]
+    scope: ScopeOpts,
+    /// The configuration key (e.g. `cache.link`).
+    #[arg(name="KEY")]
+    key: String,
+    /// The value: a boolean, number, `[array, of, values]` or string.
+    #[arg(name="VALUE")]
+    value: String,
+  },
+  /// Remove a configuration value.
+  Unset {
+    #[command(flatten)]
+    scope: ScopeOpts,
+    /// The configuration key (e.g. `cache.link`).
+    #[arg(name="KEY")]
+    key: String,
+  },
+  /// List all configuration values.
+  List,
+}
+
+/// Options selecting the configuration file to modify.
+#[derive(Args, Debug, Clone)]
+pub struct ScopeOpts {
+  /// Modify the local (uncommitted) configuration.
+  #[arg(long="local", conflicts_with="user")]
+  local: bool,
+  /// Modify the user configuration.
+  #[arg(long="user")]
+  user: bool,
+}
+
+impl ScopeOpts {
+  fn scope(&self) -> ConfigScope {
+    if self.local {
+      ConfigScope::Local
+    } else if self.user {
+      ConfigScope::User
+    } else {
+      ConfigScope::Tree
+    }
+  }
+
+  pub(super) async fn open(&self, tree: &WorkTree) -> Result<ConfigFile> {
+    let scope = self.scope();
+    let path = scope.path(tree.root_path()).ok_or_else(|| anyhow!("cannot find {} configuration", scope))?;
+    Ok(ConfigFile::load(path).await?)
+  }
+}
+
+/// Parse a value from the command line.
+///
+/// Booleans and numbers are parsed as such, and `[a, b]` is parsed as an array of
+/// values; anything else is a string, taken literally.  Quotes force a string
+/// (e.g. `'"8"'`), and can protect commas in array elements.
+pub(super) fn parse_value(value: &str) -> Value {
+  let trimmed = value.trim();
+  match trimmed.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+    Some(inner) => Value::Array(split_list(inner).into_iter().map(|e| parse_scalar(e.trim())).collect()),
+    None => parse_scalar(value),
+  }
+}
+
+/// Parse a single (non-array) value from the command line.
+fn parse_scalar(value: &str) -> Value {
+  let trimmed = value.trim();
+  let numeric = trimmed.chars().all(|c| c.is_ascii_digit() || "+-._eE".contains(c));
+  if trimmed == "true" || trimmed == "false" {
+    Value::Boolean(trimmed == "true")
+  } else if let Ok(i) = trimmed.replace('_', "").parse::<i64>() {
+    Value::Integer(i)
+  } else if let (true, Ok(f)) = (numeric, trimmed.replace('_', "").parse::<f64>()) {
+    Value::Float(f)
+  } else if let Some(s) = unquote(trimmed) {
+    Value::String(s.to_owned())
+  } else {
+    Value::String(value.to_owned())
+  }
+}
+
+/// Strip matching quotes from a string.
+fn unquote(value: &str) -> Option<&str> {
+  ['"', '\''].iter().find_map(|q| {
+    value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q))
+  })
+}
+
+/// Split the elements of an array on commas outside quotes.
+fn split_list(inner: &str) -> Vec<&str> {
+  let mut items = Vec::new();
+  let mut quote = None;
+  let mut start = 0;
+  for (i, c) in inner.char_indices() {
+    match (quote, c) {
+      (None, '"' | '\'') => quote = Some(c),
+      (Some(q), c) if c == q => quote = None,
+      (None, ',') => {
+        items.push(&inner[start..i]);
+        start = i + 1;
+      },
+      _ => (),
+    }
+  }
+  items.push(&inner[start..]);
+  // allow a trailing comma, and `[]` for an empty array
+  if items.last().map(|s| s.trim().is_empty()).unwrap_or(false) {
+    items.pop();
+  }
+  items
+}
+
+/// Format a value for display.
+fn format_value(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.clone(),
+    v => v.to_string(),
+  }
+}
+
+/// Print the leaves of a configuration table with dotted keys.
+fn print_table(prefix: &str, table: &Table) {
+  for (k, v) in table {
+    let key = if prefix.is_empty() { k.clone() } else { format!("{}.{}", prefix, k) };
+    match v {
+      Value::Table(t) => print_table(&key, t),
+      v => println!("{} = {}", key, v),
+    }
+  }
+}
+
+/// Check that a modified configuration file still produces valid settings, and
+/// return the resulting settings.
+pub(super) async fn check_modified(tree: &WorkTree, file: &ConfigFile) -> Result<Settings> {
+  let mut merged = Table::new();
+  for scope in ConfigScope::ALL {
+    if let Some(path) = scope.path(tree.root_path()) {
+      let layer = if path == file.path() {
+        file.clone()
+      } else {
+        ConfigFile::load(&path).await?
+      };
+      merge_tables(&mut merged, layer.table());
+    }
+  }
+  Ok(Settings::from_table(merged)?)
+}
+
+impl ConfigCommands {
+  pub async fn run(&self) -> Result<()> {
+    let tree = find_tree()?;
+    match self {
+      ConfigCommands::Get { key } => {
+        let merged = load_merged(tree.root_path()).await?;
+        match lookup(&merged, key) {
+          Some(v) => println!("{}", format_value(v)),
+          None => bail!("{}: not set", key),
+        }
+      },
+      ConfigCommands::Set { scope, key, value } => {
+        let mut file = scope.open(&tree).await?;
+        file.set(key, parse_value(value))?;
+        check_modified(&tree, &file).await?;
+        file.save().await?;
+      },
+      ConfigCommands::Unset { scope, key } => {
+        let mut file = scope.open(&tree).await?;
+        if file.unset(key).is_none() {
+          bail!("{}: not set in {:?}", key, file.path());
+        }
+        check_modified(&tree, &file).await?;
+        file.save().await?;
+      },
+      ConfigCommands::List => {
+        let merged = load_merged(tree.root_path()).await?;
+        print_table("", &merged);
+      },
+    }
+    Ok(())
+  }
+}
+
+#[test]
+fn test_parse_scalars() {
+  assert_eq!(parse_value("8"), Value::Integer(8));
+  assert_eq!(parse_value("-1_000"), Value::Integer(-1000));
+  assert_eq!(parse_value("0.5"), Value::Float(0.5));
+  assert_eq!(parse_value("true"), Value::Boolean(true));
+  assert_eq!(parse_value("copy"), Value::String("copy".into()));
+  assert_eq!(parse_value("inf"), Value::String("inf".into()));
+  assert_eq!(parse_value("\"8\""), Value::String("8".into()));
+  assert_eq!(parse_value("s3://bucket/path"), Value::String("s3://bucket/path".into()));
+}
+
+#[test]
+fn test_parse_arrays() {
+  let expected = Value::Array(vec![Value::String("reflink".into()), Value::String("copy".into())]);
+  assert_eq!(parse_value("[reflink, copy]"), expected);
+  assert_eq!(parse_value("[\"reflink\",\"copy\"]"), expected);
+  assert_eq!(parse_value("[reflink, copy,]"), expected);
+  assert_eq!(parse_value("[]"), Value::Array(vec![]));
+  assert_eq!(parse_value("[1, 'a,b']"), Value::Array(vec![Value::Integer(1), Value::String("a,b".into())]));
+}
+
+#[test]
+fn test_parse_no_injection() {
+  // a value can never add other keys to the configuration file
+  assert_eq!(parse_value("1\nother = 2"), Value::String("1\nother = 2".into()));
+  assert_eq!(parse_value("\"x\"\n[remote.evil]"), Value::String("\"x\"\n[remote.evil]".into()));
+}
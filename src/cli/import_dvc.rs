@@ -0,0 +1,185 @@
+//! The `import-dvc` command.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow, bail};
+use clap::Args;
+use log::*;
+use relative_path::RelativePathBuf;
+use tokio::fs::remove_file;
+use tokio::process::Command;
+
+use super::{open_tree, scan_selected};
+use crate::cache::{Cache, CacheLayout};
+use crate::tree::artifact::Artifact;
+use crate::tree::dvc::{DvcFile, DVC_EXT, DVC_LOCK};
+use crate::tree::ignore::{ensure_ignored, GITIGNORE};
+use crate::tree::import::DvcImporter;
+use crate::tree::pointer::{AFCPointer, AFCPointerFile};
+use crate::util::io::path_exists;
+
+/// Convert DVC pointers to AFC pointers.
+///
+/// Each output of a `.dvc` file or `dvc.lock` file gets an `.afc` pointer, and its
+/// data is moved from the DVC cache into the AFC cache (or copied, with `--copy`).  `.dvc` files whose outputs
+/// are all converted are removed; `dvc.lock` files are left for their pipelines.
+/// In a Git repository, the changes are staged.
+#[derive(Args, Debug, Clone)]
+#[command(name="import-dvc")]
+pub struct ImportDvcCmd {
+  /// Show what would be converted without changing anything.
+  #[arg(short='n', long="dry-run")]
+  dry_run: bool,
+
+  /// Copy data from the DVC cache instead of moving it, leaving the DVC cache intact.
+  #[arg(long="copy")]
+  copy: bool,
+
+  /// The DVC cache directory.
+  #[arg(long="dvc-cache", default_value=".dvc/cache")]
+  dvc_cache: PathBuf,
+
+  /// The DVC pointers (or directories containing them) to convert [default: all].
+  #[arg(name="PATH")]
+  paths: Vec<PathBuf>,
+}
+
+/// The outcome of converting the outputs of one DVC pointer.
+#[derive(Default)]
+struct PointerReport {
+  converted: Vec<RelativePathBuf>,
+  failed: usize,
+}
+
+/// Check whether an artifact comes from a DVC pointer.
+fn is_dvc_artifact(art: &Artifact) -> bool {
+  match art.pointer_path() {
+    Some(p) => p.extension() == Some(DVC_EXT) || p.file_name() == Some(DVC_LOCK),
+    None => false,
+  }
+}
+
+/// Run a Git command in the work tree.
+async fn git(root: &Path, args: &[&str], paths: &[RelativePathBuf]) -> Result<()> {
+  if paths.is_empty() {
+    return Ok(());
+  }
+  let mut cmd = Command::new("git");
+  cmd.current_dir(root).args(args).arg("--");
+  cmd.args(paths.iter().map(|p| p.as_str()));
+  debug!("running {:?}", cmd);
+  let status = cmd.status().await?;
+  if !status.success() {
+    bail!("git {} failed ({})", args[0], status);
+  }
+  Ok(())
+}
+
+impl ImportDvcCmd {
+  pub async fn run(&self) -> Result<()> {
+    let (tree, settings) = open_tree().await?;
+    let tree = tree.with_dvc(true);
+    let root = tree.root_path();
+    if settings.cache.layout != CacheLayout::Afc {
+      bail!("the cache uses the {} layout, cannot import into it", settings.cache.layout);
+    }
+    let afc = settings.open_cache(root);
+    let dvc = Cache::open(root.join(&self.dvc_cache)).with_layout(CacheLayout::Dvc);
+    let mut importer = DvcImporter::new(&tree, &dvc, &afc).with_copy(self.copy);
+
+    let arts = scan_selected(&tree, &self.paths).await?;
+    let mut reports: BTreeMap<RelativePathBuf, PointerReport> = BTreeMap::new();
+    let mut problems = Vec::new();
+    let mut added = Vec::new();
+
+    for art in arts.iter().filter(|a| is_dvc_artifact(a)) {
+      let dvc_ptr = art.pointer_path().expect("DVC artifact without pointer");
+      let report = reports.entry(dvc_ptr.to_owned()).or_default();
+      let path = art.path();
+      let name = path.file_name().ok_or_else(|| anyhow!("{}: invalid output path", path))?;
+      let ppath = path.with_file_name(format!("{}.afc", name));
+      if path_exists(ppath.to_path(root)).await? {
+        problems.push(format!("{}: already tracked by {}", path, ppath));
+        report.failed += 1;
+        continue;
+      }
+
+      if self.dry_run {
+        match importer.check(art).await {
+          Ok(()) => {
+            println!("would convert {} ({}) -> {}", path, dvc_ptr, ppath);
+            report.converted.push(ppath);
+          },
+          Err(e) => {
+            problems.push(format!("{}: {}", path, e));
+            report.failed += 1;
+          }
+        }
+        continue;
+      }
+
+      let meta = match importer.import(art).await {
+        Ok(m) => m,
+        Err(e) => {
+          problems.push(format!("{}: {}", path, e));
+          report.failed += 1;
+          continue;
+        }
+      };
+      let ptr = AFCPointerFile::from(AFCPointer {
+        path: name.into(),
+        remote: None,
+        meta,
+      });
+      ptr.save(ppath.to_path(root)).await?;
+      let ignore = path.with_file_name(GITIGNORE);
+      ensure_ignored(ignore.to_path(root), name).await?;
+      println!("converted {} ({}) -> {}", path, dvc_ptr, ppath);
+      added.push(ppath.clone());
+      added.push(ignore);
+      report.converted.push(ppath);
+    }
+
+    let mut removed = Vec::new();
+    for (dvc_ptr, report) in &reports {
+      if report.failed > 0 {
+        continue;
+      }
+      if dvc_ptr.file_name() == Some(DVC_LOCK) {
+        println!("{}: outputs converted; remove them from the pipeline before disabling core.dvc", dvc_ptr);
+        continue;
+      }
+      // outputs DVC does not cache are not artifacts, and would be lost with the file
+      let file = DvcFile::load(dvc_ptr.to_path(root)).await?;
+      if file.outs.len() != report.converted.len() {
+        problems.push(format!("{}: has uncached outputs, not removing", dvc_ptr));
+        continue;
+      }
+      if self.dry_run {
+        println!("would remove {}", dvc_ptr);
+      } else {
+        remove_file(dvc_ptr.to_path(root)).await?;
+        removed.push(dvc_ptr.clone());
+      }
+    }
+
+    if !self.dry_run && tree.is_git_root() {
+      added.sort();
+      added.dedup();
+      git(root, &["rm", "--cached", "--ignore-unmatch", "--quiet"], &removed).await?;
+      git(root, &["add"], &added).await?;
+    } else if !self.dry_run {
+      info!("not a Git repository, not staging changes");
+    }
+
+    if !problems.is_empty() {
+      eprintln!("could not convert:");
+      for p in &problems {
+        eprintln!("  {}", p);
+      }
+      bail!("{} problems importing DVC outputs", problems.len());
+    }
+    Ok(())
+  }
+}
+
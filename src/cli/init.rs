@@ -0,0 +1,64 @@
+//! The `init` command.
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use log::*;
+use tokio::fs::{create_dir_all, write};
+
+use crate::settings::{Settings, CONFIG_FILE, LOCAL_CONFIG_FILE, DEFAULT_CONFIG};
+use crate::tree::{WorkTree, AFC_DIR};
+use crate::tree::ignore::{ensure_ignored, GITIGNORE};
+use crate::util::io::path_exists;
+
+/// Initialize a work tree for use with AFC.
+#[derive(Args, Debug, Clone)]
+#[command(name="init")]
+pub struct InitCmd {
+  /// The root directory of the work tree [default: current directory].
+  #[arg(name="DIR")]
+  dir: Option<PathBuf>,
+}
+
+impl InitCmd {
+  pub async fn run(&self) -> Result<()> {
+    let root = self.dir.clone().unwrap_or_else(|| ".".into());
+    let root = root.canonicalize()?;
+    let tree = WorkTree::open(&root);
+    if tree.root_path() != root {
+      warn!("{:?} is inside existing work tree {:?}", root, tree.root_path());
+    }
+    let tree = WorkTree::open_root(&root);
+
+    if tree.is_initialized() {
+      info!("{:?} is already initialized, checking setup", root);
+    }
+    create_dir_all(root.join(AFC_DIR)).await?;
+
+    let config = root.join(CONFIG_FILE);
+    if !path_exists(&config).await? {
+      debug!("writing default configuration to {:?}", config);
+      write(&config, DEFAULT_CONFIG).await?;
+    }
+
+    let settings = Settings::load(&root).await?;
+    let cache = settings.open_cache(&root);
+    create_dir_all(cache.root_path()).await?;
+
+    let ignore = root.join(GITIGNORE);
+    if let Ok(rel) = cache.root_path().strip_prefix(&root) {
+      let rel = rel.to_string_lossy().replace('\\', "/");
+      ensure_ignored(&ignore, &format!("/{}/", rel)).await?;
+    }
+    ensure_ignored(&ignore, &format!("/{}", LOCAL_CONFIG_FILE)).await?;
+
+    if tree.is_git_root() {
+      println!("initialized AFC in Git repository {}", root.display());
+    } else {
+      warn!("{:?} is not the root of a Git repository", root);
+      println!("initialized AFC in {}", root.display());
+    }
+
+    Ok(())
+  }
+}
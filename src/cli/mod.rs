@@ -4,11 +4,30 @@
 //! from their own CLIs. The [AFC] struct defines the AFC command-line interface, except for
 //! logging setup.  The AFC binary wraps this with additional options for verbosity, sets up
 //! a log backend, and hands control off to the CLI.
+use std::path::PathBuf;
+
 use clap::{Args, Subcommand};
-use anyhow::Result;
+use anyhow::{Result, bail};
+use futures::TryStreamExt;
+use relative_path::RelativePathBuf;
 use tokio::runtime::Builder;
 
+use crate::settings::Settings;
+use crate::tree::WorkTree;
+use crate::tree::artifact::Artifact;
+
 mod util;
+mod add;
+mod status;
+mod checkout;
+mod config;
+mod remote;
+mod init;
+mod transfer;
+mod push;
+mod pull;
+mod import_dvc;
+mod verify;
 
 /// Manage large data files through attached pointer files committed to VCS.
 #[derive(Args, Debug)]
@@ -20,6 +39,26 @@ pub struct AFC {
 
 #[derive(Subcommand, Debug)]
 enum AFCCommand {
+  Init(init::InitCmd),
+  Add(add::AddCmd),
+  Status(status::StatusCmd),
+  Checkout(checkout::CheckoutCmd),
+  Push(push::PushCmd),
+  Pull(pull::PullCmd),
+  ImportDvc(import_dvc::ImportDvcCmd),
+  Verify(verify::VerifyCmd),
+  /// Get and set configuration.
+  Config {
+    /// The configuration command to run.
+    #[command(subcommand)]
+    ccmd: config::ConfigCommands,
+  },
+  /// Manage named remotes.
+  Remote {
+    /// The remote command to run.
+    #[command(subcommand)]
+    rcmd: remote::RemoteCommands,
+  },
   Util {
     /// The utility command to run.
     #[command(subcommand)]
@@ -43,7 +82,54 @@ impl AFC {
   /// [tokio::runtime::Runtime] and wants to run a task.
   pub async fn invoke_async(&self) -> Result<()> {
     match &self.command {
-      AFCCommand::Util { ucmd } => ucmd.run().await
+      AFCCommand::Init(cmd) => cmd.run().await,
+      AFCCommand::Add(cmd) => cmd.run().await,
+      AFCCommand::Status(cmd) => cmd.run().await,
+      AFCCommand::Checkout(cmd) => cmd.run().await,
+      AFCCommand::Push(cmd) => cmd.run().await,
+      AFCCommand::Pull(cmd) => cmd.run().await,
+      AFCCommand::ImportDvc(cmd) => cmd.run().await,
+      AFCCommand::Verify(cmd) => cmd.run().await,
+      AFCCommand::Config { ccmd } => ccmd.run().await,
+      AFCCommand::Remote { rcmd } => rcmd.run().await,
+      AFCCommand::Util { ucmd } => ucmd.run().await,
     }
   }
 }
+
+/// Find the work tree containing the current directory.
+fn find_tree() -> Result<WorkTree> {
+  match WorkTree::find(".")? {
+    Some(t) => Ok(t),
+    None => bail!("not in an AFC work tree (run `afc init` to create one)"),
+  }
+}
+
+/// Find the work tree containing the current directory and load its settings.
+async fn open_tree() -> Result<(WorkTree, Settings)> {
+  let tree = find_tree()?;
+  let settings = Settings::load(tree.root_path()).await?;
+  let tree = tree.with_dvc(settings.core.dvc);
+  Ok((tree, settings))
+}
+
+/// Check whether an artifact is selected by a list of paths.
+fn is_selected(art: &Artifact, paths: &[RelativePathBuf]) -> bool {
+  if paths.is_empty() {
+    return true;
+  }
+  let apath = art.path().normalize();
+  let ppath = art.pointer_path().map(|p| p.normalize());
+  paths.iter().any(|p| {
+    apath.starts_with(p) || ppath.as_ref().map(|pp| pp.starts_with(p)).unwrap_or(false)
+  })
+}
+
+/// Scan the work tree for artifacts selected by command-line paths.
+///
+/// If `paths` is empty, all artifacts are selected.
+async fn scan_selected(tree: &WorkTree, paths: &[PathBuf]) -> Result<Vec<Artifact>> {
+  let paths = paths.iter().map(|p| tree.tree_path(p)).collect::<Result<Vec<_>, _>>()?;
+  let arts: Vec<Artifact> = tree.scan_artifacts().await.try_collect().await?;
+  Ok(arts.into_iter().filter(|a| is_selected(a, &paths)).collect())
+}
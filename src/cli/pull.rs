@@ -0,0 +1,152 @@
+//! The `pull` command.
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use clap::Args;
+use friendly::bytes;
+use futures::{StreamExt, stream};
+use log::*;
+use tokio::fs::{metadata, remove_file};
+
+use super::{open_tree, scan_selected};
+use super::checkout::checkout_all;
+use super::remote::auth_prompt;
+use super::transfer::{ObjectKind, TransferObject, TransferOpts, TransferSummary, cached_path, collect_objects, progress_bar, route_artifacts};
+use crate::cache::Cache;
+use crate::remote::{Remote, open_remote};
+use crate::tree::artifact::Artifact;
+use crate::tree::checkout::CheckoutOptions;
+use crate::util::io::path_exists;
+
+/// Download artifact data from a remote and check it out.
+#[derive(Args, Debug, Clone)]
+#[command(name="pull")]
+pub struct PullCmd {
+  #[command(flatten)]
+  transfer: TransferOpts,
+
+  /// Only fetch data into the cache; do not check it out.
+  #[arg(long="no-checkout")]
+  no_checkout: bool,
+
+  /// The artifacts (or directories containing artifacts) to pull [default: all].
+  #[arg(name="PATH")]
+  paths: Vec<PathBuf>,
+}
+
+impl PullCmd {
+  pub async fn run(&self) -> Result<()> {
+    let (tree, settings) = open_tree().await?;
+    let cache = settings.open_cache(tree.root_path());
+    let jobs = self.transfer.jobs.unwrap_or(settings.core.jobs).max(1);
+
+    let arts = scan_selected(&tree, &self.paths).await?;
+    let groups = route_artifacts(&settings, &self.transfer, &arts)?;
+    if groups.is_empty() {
+      println!("no artifacts to pull");
+    }
+    let mut failed = 0;
+    for group in groups {
+      let remote = open_remote(tree.root_path(), group.settings, Some(auth_prompt())).await?;
+      failed += pull_objects(&cache, group.name, remote.as_ref(), &group.artifacts, jobs).await?;
+    }
+    if failed > 0 {
+      bail!("{} objects failed to pull, not checking out", failed);
+    }
+
+    if !self.no_checkout {
+      let opts = CheckoutOptions {
+        force: false,
+        links: settings.cache.link.clone(),
+      };
+      checkout_all(&tree, &cache, &arts, &opts).await?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Pull the objects of a group of artifacts from a remote into the cache.
+///
+/// DVC directory manifests are fetched first, so the files they list can be
+/// collected and fetched with the rest.  Returns the number of objects that failed
+/// to pull.
+async fn pull_objects(cache: &Cache, name: &str, remote: &dyn Remote, arts: &[&Artifact], jobs: usize) -> Result<usize> {
+  let mut summary = TransferSummary::default();
+  let mut objects = collect_objects(cache, arts.iter().copied()).await?;
+  let manifests = missing_objects(cache, &objects, ObjectKind::Manifest).await?;
+  if !manifests.is_empty() {
+    debug!("{} manifests to pull from {}", manifests.len(), name);
+    let results: Vec<_> = stream::iter(manifests)
+      .map(|o| async move { (o.key.clone(), fetch_object(cache, remote, o).await) })
+      .buffer_unordered(jobs)
+      .collect().await;
+    for (key, res) in results {
+      summary.record(key, res);
+    }
+    objects = collect_objects(cache, arts.iter().copied()).await?;
+  }
+
+  let todo = missing_objects(cache, &objects, ObjectKind::File).await?;
+  let total: u64 = todo.iter().map(|o| o.size.unwrap_or(0)).sum();
+  info!("{} objects to pull ({}) from {}", todo.len(), bytes(total), name);
+
+  let pb = progress_bar(total, "pulling");
+  let pb_ref = &pb;
+  let results: Vec<_> = stream::iter(todo)
+    .map(|o| async move {
+      let res = fetch_object(cache, remote, o).await;
+      if let Ok(size) = &res {
+        pb_ref.inc(o.size.unwrap_or(*size));
+      }
+      (o.key.clone(), res)
+    })
+    .buffer_unordered(jobs)
+    .collect().await;
+  pb.finish_and_clear();
+
+  for (key, res) in results {
+    summary.record(key, res);
+  }
+  println!("pulled {} objects ({}) from {}", summary.objects, bytes(summary.bytes), name);
+  Ok(summary.report_failures())
+}
+
+/// Select the objects of one kind that are not in the cache.
+async fn missing_objects<'a>(cache: &Cache, objects: &'a [TransferObject], kind: ObjectKind) -> Result<Vec<&'a TransferObject>> {
+  let mut missing = Vec::new();
+  for obj in objects.iter().filter(|o| o.kind == kind) {
+    if !path_exists(&cached_path(cache, obj).await?).await? {
+      missing.push(obj);
+    }
+  }
+  Ok(missing)
+}
+
+/// Download an object into the cache, returning its size.
+///
+/// The temporary download file is removed if the download fails.
+async fn fetch_object(cache: &Cache, remote: &dyn Remote, obj: &TransferObject) -> Result<u64> {
+  let tmp = cache.temp_path().await?;
+  let res = async {
+    match obj.kind {
+      ObjectKind::File => {
+        remote.download(&obj.hash, &tmp).await?;
+        let size = metadata(&tmp).await?.len();
+        cache.install(&obj.hash, &tmp).await?;
+        Ok(size)
+      },
+      ObjectKind::Manifest => {
+        let md5 = obj.manifest_md5()?;
+        remote.download_manifest(md5, &tmp).await?;
+        let size = metadata(&tmp).await?.len();
+        cache.install_manifest(md5, &tmp).await?;
+        Ok(size)
+      },
+    }
+  }.await;
+  if res.is_err() && path_exists(&tmp).await? {
+    remove_file(&tmp).await?;
+  }
+  res
+}
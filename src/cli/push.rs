@@ -0,0 +1,119 @@
+//! The `push` command.
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use clap::Args;
+use friendly::bytes;
+use futures::{StreamExt, stream};
+use log::*;
+use tokio::fs::metadata;
+
+use super::{open_tree, scan_selected};
+use super::remote::auth_prompt;
+use super::transfer::{ObjectKind, TransferObject, TransferOpts, TransferSummary, cached_path, collect_objects, progress_bar, route_artifacts};
+use crate::cache::Cache;
+use crate::remote::{Remote, RemoteError, open_remote};
+use crate::tree::artifact::Artifact;
+use crate::util::io::path_exists;
+
+/// Upload cached artifact data to a remote.
+#[derive(Args, Debug, Clone)]
+#[command(name="push")]
+pub struct PushCmd {
+  #[command(flatten)]
+  transfer: TransferOpts,
+
+  /// The artifacts (or directories containing artifacts) to push [default: all].
+  #[arg(name="PATH")]
+  paths: Vec<PathBuf>,
+}
+
+impl PushCmd {
+  pub async fn run(&self) -> Result<()> {
+    let (tree, settings) = open_tree().await?;
+    let cache = settings.open_cache(tree.root_path());
+    let jobs = self.transfer.jobs.unwrap_or(settings.core.jobs).max(1);
+
+    let arts = scan_selected(&tree, &self.paths).await?;
+    let groups = route_artifacts(&settings, &self.transfer, &arts)?;
+    if groups.is_empty() {
+      println!("no artifacts to push");
+    }
+    let mut failed = 0;
+    for group in groups {
+      let remote = open_remote(tree.root_path(), group.settings, Some(auth_prompt())).await?;
+      failed += push_objects(&cache, group.name, remote.as_ref(), &group.artifacts, jobs).await?;
+    }
+    if failed > 0 {
+      bail!("{} objects failed to push", failed);
+    }
+    Ok(())
+  }
+}
+
+/// Push the objects of a group of artifacts to a remote.
+///
+/// Returns the number of objects that failed to push.
+async fn push_objects(cache: &Cache, name: &str, remote: &dyn Remote, arts: &[&Artifact], jobs: usize) -> Result<usize> {
+  let mut objects = Vec::new();
+  for mut obj in collect_objects(cache, arts.iter().copied()).await? {
+    let path = cached_path(cache, &obj).await?;
+    if path_exists(&path).await? {
+      obj.size = Some(metadata(&path).await?.len());
+      objects.push(obj);
+    } else {
+      warn!("object {} is not in the cache, skipping", obj.key);
+    }
+  }
+
+  let mut summary = TransferSummary::default();
+  let present: Vec<_> = stream::iter(&objects)
+    .map(|o| remote_has(remote, o))
+    .buffered(jobs)
+    .collect().await;
+  let mut todo = Vec::new();
+  let mut n_present = 0;
+  for (o, p) in objects.iter().zip(present) {
+    match p {
+      Ok(true) => n_present += 1,
+      Ok(false) => todo.push(o),
+      Err(e) => summary.record(o.key.clone(), Err(e.into())),
+    }
+  }
+  let total: u64 = todo.iter().map(|o| o.size.unwrap_or(0)).sum();
+  info!("{} objects to push ({}), {} already on {}", todo.len(), bytes(total), n_present, name);
+
+  let pb = progress_bar(total, "pushing");
+  let pb_ref = &pb;
+  let results: Vec<_> = stream::iter(todo)
+    .map(|o| async move {
+      let res = async {
+        let path = cached_path(cache, o).await?;
+        match o.kind {
+          ObjectKind::File => remote.upload(&o.hash, &path).await?,
+          ObjectKind::Manifest => remote.upload_manifest(o.manifest_md5()?, &path).await?,
+        }
+        let size = o.size.unwrap_or(0);
+        pb_ref.inc(size);
+        Ok(size)
+      }.await;
+      (o.key.clone(), res)
+    })
+    .buffer_unordered(jobs)
+    .collect().await;
+  pb.finish_and_clear();
+
+  for (key, res) in results {
+    summary.record(key, res);
+  }
+  println!("pushed {} objects ({}) to {}, {} already present", summary.objects, bytes(summary.bytes), name, n_present);
+  Ok(summary.report_failures())
+}
+
+/// Check whether the remote already has an object.
+async fn remote_has(remote: &dyn Remote, obj: &TransferObject) -> Result<bool, RemoteError> {
+  match obj.kind {
+    ObjectKind::File => remote.exists(&obj.hash).await,
+    ObjectKind::Manifest => remote.manifest_exists(obj.manifest_md5()?).await,
+  }
+}
@@ -0,0 +1,305 @@
+//! The `remote` commands.
+use anyhow::{Result, anyhow, bail};
+use clap::Subcommand;
+use reqwest::Url;
+use toml::Value;
+use toml::value::Table;
+
+use super::find_tree;
+use super::config::{ScopeOpts, check_modified, parse_value};
+use crate::cache::CacheLayout;
+use crate::remote::AuthPrompt;
+use crate::settings::{RemoteSettings, Settings};
+
+/// Manage named remotes.
+#[derive(Subcommand, Debug, Clone)]
+#[command(name="remote")]
+pub enum RemoteCommands {
+  /// Add a remote.
+  ///
+  /// The URL selects the remote type: `s3://BUCKET/PREFIX`, `b2://BUCKET/PREFIX`,
+  /// `gdrive://FOLDER_ID`, `sftp://[USER@]HOST[:PORT]/PATH`, `webdav[s]://HOST/PATH`,
+  /// `http[s]://HOST/PATH` (read-only), or a local directory path.  Other settings
+  /// (e.g. credentials, best added with `--local`) can be given with `-o`.
+  Add {
+    #[command(flatten)]
+    scope: ScopeOpts,
+    /// Make this the default remote.
+    #[arg(short='d', long="default")]
+    default: bool,
+    /// Set another remote setting.
+    #[arg(short='o', long="option", value_name="KEY=VALUE")]
+    options: Vec<String>,
+    /// The remote name.
+    #[arg(name="NAME")]
+    name: String,
+    /// The remote URL.
+    #[arg(name="URL")]
+    url: String,
+  },
+  /// Remove a remote.
+  Remove {
+    #[command(flatten)]
+    scope: ScopeOpts,
+    /// The remote name.
+    #[arg(name="NAME")]
+    name: String,
+  },
+  /// List the configured remotes.
+  List {
+    /// Show each remote's location.
+    #[arg(short='v', long="verbose")]
+    verbose: bool,
+  },
+  /// Show or set the default remote.
+  Default {
+    #[command(flatten)]
+    scope: ScopeOpts,
+    /// The remote to make the default.
+    #[arg(name="NAME")]
+    name: Option<String>,
+  },
+}
+
+/// Split a URL path into a bucket-style prefix.
+fn url_prefix(url: &Url) -> String {
+  url.path().trim_matches('/').to_owned()
+}
+
+/// Build the settings table for a remote from its URL.
+fn remote_table(url: &str) -> Result<Table> {
+  let mut table = Table::new();
+  let mut set = |k: &str, v: Value| {
+    table.insert(k.to_owned(), v);
+  };
+  let parsed = match Url::parse(url) {
+    // single-letter schemes are Windows drive letters
+    Ok(u) if u.scheme().len() > 1 => u,
+    _ => {
+      set("type", "local".into());
+      set("path", url.into());
+      return Ok(table);
+    }
+  };
+  let host = parsed.host_str().unwrap_or_default().to_owned();
+  match parsed.scheme() {
+    "file" => {
+      let path = parsed.to_file_path().map_err(|_| anyhow!("{}: invalid file URL", url))?;
+      set("type", "local".into());
+      set("path", path.to_string_lossy().as_ref().into());
+    },
+    "s3" | "b2" => {
+      if host.is_empty() {
+        bail!("{}: no bucket specified", url);
+      }
+      set("type", parsed.scheme().into());
+      set("bucket", host.into());
+      set("prefix", url_prefix(&parsed).into());
+    },
+    "gdrive" => {
+      if host.is_empty() {
+        bail!("{}: no folder ID specified", url);
+      }
+      set("type", "gdrive".into());
+      set("folder_id", host.into());
+    },
+    "sftp" | "ssh" => {
+      if host.is_empty() {
+        bail!("{}: no host specified", url);
+      }
+      set("type", "sftp".into());
+      set("host", host.into());
+      set("path", parsed.path().into());
+      if !parsed.username().is_empty() {
+        set("user", parsed.username().into());
+      }
+      if let Some(port) = parsed.port() {
+        set("port", Value::Integer(port.into()));
+      }
+    },
+    "webdav" | "webdavs" => {
+      let scheme = if parsed.scheme() == "webdavs" { "https" } else { "http" };
+      let rest = &url[parsed.scheme().len()..];
+      set("type", "webdav".into());
+      set("url", format!("{}{}", scheme, rest).into());
+    },
+    "http" | "https" => {
+      set("type", "http".into());
+      set("url", url.into());
+    },
+    s => bail!("{}: unsupported remote type {}", url, s),
+  }
+  Ok(table)
+}
+
+/// Describe a remote's location.
+fn describe(rs: &RemoteSettings) -> String {
+  match rs {
+    RemoteSettings::Local { path, layout: CacheLayout::Afc } => path.display().to_string(),
+    RemoteSettings::Local { path, layout } => format!("{} ({})", path.display(), layout),
+    RemoteSettings::S3(s3) => format!("s3://{}/{}", s3.bucket, s3.prefix),
+    RemoteSettings::Sftp(sftp) => format!("sftp://{}/{}", sftp.host, sftp.path.display()),
+    RemoteSettings::WebDav(dav) => dav.url.clone(),
+    RemoteSettings::B2(b2) => format!("b2://{}/{}", b2.bucket, b2.prefix),
+    RemoteSettings::GDrive(gd) => format!("gdrive://{}", gd.folder_id),
+    RemoteSettings::Http { url } => url.clone(),
+  }
+}
+
+/// Create a prompt that tells the user on the terminal how to authorize access to a remote.
+pub(super) fn auth_prompt() -> AuthPrompt {
+  AuthPrompt::new(|p| {
+    eprintln!("To give AFC access to Google Drive, visit {} and enter the code {}", p.url, p.code);
+  })
+}
+
+/// Check that a new remote's name can be used as a configuration key.
+fn check_name(name: &str) -> Result<()> {
+  if name.is_empty() || name.contains('.') || name.trim() != name {
+    bail!("invalid remote name {:?} (names cannot be empty or contain dots)", name);
+  }
+  Ok(())
+}
+
+fn check_exists(settings: &Settings, name: &str) -> Result<()> {
+  if !settings.remote.contains_key(name) {
+    bail!("remote {} is not configured", name);
+  }
+  Ok(())
+}
+
+impl RemoteCommands {
+  pub async fn run(&self) -> Result<()> {
+    let tree = find_tree()?;
+    match self {
+      RemoteCommands::Add { scope, default, options, name, url } => {
+        check_name(name)?;
+        let settings = Settings::load(tree.root_path()).await?;
+        if settings.remote.contains_key(name) {
+          bail!("remote {} already exists", name);
+        }
+        let mut table = remote_table(url)?;
+        for opt in options {
+          let (k, v) = opt.split_once('=').ok_or_else(|| anyhow!("{}: options must be KEY=VALUE", opt))?;
+          table.insert(k.trim().to_owned(), parse_value(v.trim()));
+        }
+
+        let mut file = scope.open(&tree).await?;
+        file.set(&format!("remote.{}", name), Value::Table(table))?;
+        if *default {
+          file.set("core.remote", name.as_str().into())?;
+        }
+        check_modified(&tree, &file).await?;
+        file.save().await?;
+        println!("added remote {} ({})", name, url);
+      },
+      RemoteCommands::Remove { scope, name } => {
+        let mut file = scope.open(&tree).await?;
+        if file.unset(&format!("remote.{}", name)).is_none() {
+          bail!("remote {} is not configured in {:?}", name, file.path());
+        }
+        if file.get("core.remote").and_then(|v| v.as_str()) == Some(name.as_str()) {
+          file.unset("core.remote");
+        }
+        let settings = check_modified(&tree, &file).await?;
+        file.save().await?;
+        if settings.core.remote.as_deref() == Some(name.as_str()) && !settings.remote.contains_key(name) {
+          eprintln!("warning: {} is still the default remote in another configuration file", name);
+        }
+        println!("removed remote {}", name);
+      },
+      RemoteCommands::List { verbose } => {
+        let settings = Settings::load(tree.root_path()).await?;
+        for (name, rs) in &settings.remote {
+          let mark = if settings.core.remote.as_deref() == Some(name.as_str()) { "*" } else { " " };
+          if *verbose {
+            println!("{} {}\t{}", mark, name, describe(rs));
+          } else {
+            println!("{} {}", mark, name);
+          }
+        }
+      },
+      RemoteCommands::Default { scope, name: Some(name) } => {
+        let settings = Settings::load(tree.root_path()).await?;
+        check_exists(&settings, name)?;
+        let mut file = scope.open(&tree).await?;
+        file.set("core.remote", name.as_str().into())?;
+        check_modified(&tree, &file).await?;
+        file.save().await?;
+      },
+      RemoteCommands::Default { name: None, .. } => {
+        let settings = Settings::load(tree.root_path()).await?;
+        match &settings.core.remote {
+          Some(name) => println!("{}", name),
+          None => bail!("no default remote configured"),
+        }
+      },
+    }
+    Ok(())
+  }
+}
+
+#[test]
+fn test_check_name() {
+  assert!(check_name("origin").is_ok());
+  assert!(check_name("nas-2").is_ok());
+  assert!(check_name("").is_err());
+  assert!(check_name("my.remote").is_err());
+  assert!(check_name(" origin").is_err());
+}
+
+#[test]
+fn test_remote_table_local() {
+  let table = remote_table("../shared/afc").expect("parse failed");
+  assert_eq!(table["type"].as_str(), Some("local"));
+  assert_eq!(table["path"].as_str(), Some("../shared/afc"));
+}
+
+#[test]
+fn test_remote_table_s3() {
+  let table = remote_table("s3://bucket/data/afc/").expect("parse failed");
+  assert_eq!(table["type"].as_str(), Some("s3"));
+  assert_eq!(table["bucket"].as_str(), Some("bucket"));
+  assert_eq!(table["prefix"].as_str(), Some("data/afc"));
+}
+
+#[test]
+fn test_remote_table_sftp() {
+  let table = remote_table("sftp://alice@example.com:2222/srv/afc").expect("parse failed");
+  assert_eq!(table["type"].as_str(), Some("sftp"));
+  assert_eq!(table["host"].as_str(), Some("example.com"));
+  assert_eq!(table["user"].as_str(), Some("alice"));
+  assert_eq!(table["port"].as_integer(), Some(2222));
+  assert_eq!(table["path"].as_str(), Some("/srv/afc"));
+}
+
+#[test]
+fn test_remote_table_sftp_alias() {
+  // the user and port are left to the SSH configuration for the host
+  let table = remote_table("ssh://backup/data/afc").expect("parse failed");
+  assert_eq!(table["type"].as_str(), Some("sftp"));
+  assert_eq!(table["host"].as_str(), Some("backup"));
+  assert_eq!(table["path"].as_str(), Some("/data/afc"));
+  assert!(!table.contains_key("user"));
+  assert!(!table.contains_key("port"));
+}
+
+#[test]
+fn test_remote_table_webdav() {
+  let table = remote_table("webdavs://dav.example.com/afc").expect("parse failed");
+  assert_eq!(table["type"].as_str(), Some("webdav"));
+  assert_eq!(table["url"].as_str(), Some("https://dav.example.com/afc"));
+}
+
+#[test]
+fn test_remote_table_settings() {
+  // every URL type produces valid settings
+  for url in ["s3://b", "b2://b/p", "gdrive://f", "sftp://h/p", "webdav://h/p", "https://h/p", "/tmp/afc"] {
+    let mut table = remote_table(url).expect("parse failed");
+    if url.starts_with("gdrive") {
+      table.insert("client_id".into(), "c".into());
+    }
+    let rs: RemoteSettings = Value::Table(table).try_into().expect("invalid settings");
+    assert!(!describe(&rs).is_empty());
+  }
+}
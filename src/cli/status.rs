@@ -0,0 +1,68 @@
+//! The `status` command.
+use std::collections::HashSet;
+
+use anyhow::Result;
+use clap::Args;
+use futures::TryStreamExt;
+
+use super::open_tree;
+use crate::tree::artifact::Artifact;
+use crate::tree::status::{artifact_status, find_untracked, StatusEntry, ArtifactStatus, DEFAULT_LARGE_FILE_SIZE};
+
+/// Show the status of artifacts in the work tree.
+#[derive(Args, Debug, Clone)]
+#[command(name="status")]
+pub struct StatusCmd {
+  /// Write status as JSON.
+  #[arg(long="json")]
+  json: bool,
+
+  /// Report untracked files at least this many bytes in size.
+  #[arg(long="large-size", default_value_t=DEFAULT_LARGE_FILE_SIZE)]
+  large_size: u64,
+
+  /// Re-hash every file instead of using the hash cache in `.afc/state`.
+  #[arg(long="no-hash-cache")]
+  no_hash_cache: bool,
+}
+
+impl StatusCmd {
+  pub async fn run(&self) -> Result<()> {
+    let (tree, settings) = open_tree().await?;
+    let tree = if self.no_hash_cache { tree } else { tree.load_hash_cache().await? };
+    let cache = settings.open_cache(tree.root_path());
+
+    let arts: Vec<Artifact> = tree.scan_artifacts().await.try_collect().await?;
+    let mut entries = Vec::with_capacity(arts.len());
+    let mut tracked = HashSet::new();
+    for art in &arts {
+      let status = artifact_status(&tree, &cache, art).await?;
+      tracked.insert(art.path().normalize());
+      entries.push(StatusEntry {
+        path: art.path().to_owned(),
+        pointer: art.pointer_path().map(|p| p.to_owned()),
+        status,
+      });
+    }
+    tree.save_state().await?;
+    entries.sort_by(|e1, e2| e1.path.cmp(&e2.path));
+
+    for path in find_untracked(&tree, &cache, &tracked, self.large_size).await? {
+      entries.push(StatusEntry {
+        path,
+        pointer: None,
+        status: ArtifactStatus::Untracked,
+      });
+    }
+
+    if self.json {
+      println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+      for entry in &entries {
+        println!("{:>12}: {}", entry.status, entry.path);
+      }
+    }
+
+    Ok(())
+  }
+}
@@ -0,0 +1,262 @@
+//! Support code for the transfer commands (`push` and `pull`).
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::*;
+
+use crate::cache::{Cache, CacheError};
+use crate::cache::manifest::DIR_SUFFIX;
+use crate::filehash::{DigestValue, HashAlgo, MultiHash};
+use crate::settings::{RemoteSettings, Settings};
+use crate::tree::artifact::{Artifact, ArtifactMeta, FileMeta};
+use crate::tree::dvc::folder_entries;
+
+/// Options for selecting a remote and controlling transfers.
+#[derive(Args, Debug, Clone)]
+pub struct TransferOpts {
+  /// The remote to use for all artifacts [default: each artifact's remote, or the
+  /// configured default remote].
+  #[arg(short='r', long="remote")]
+  pub remote: Option<String>,
+
+  /// The number of concurrent transfers [default: from settings].
+  #[arg(short='j', long="jobs")]
+  pub jobs: Option<usize>,
+}
+
+/// The kind of an object to transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+  /// A file's contents.
+  File,
+  /// A DVC directory manifest, keyed by its MD5.
+  Manifest,
+}
+
+/// An object to transfer.
+#[derive(Debug, Clone)]
+pub struct TransferObject {
+  /// The object's key in the cache.
+  pub key: String,
+  /// The kind of object.
+  pub kind: ObjectKind,
+  /// The object's hashes.
+  pub hash: MultiHash,
+  /// The object's size, if known.
+  pub size: Option<u64>,
+}
+
+impl TransferObject {
+  /// Get the MD5 that keys a manifest object.
+  pub fn manifest_md5(&self) -> Result<&DigestValue<16>, CacheError> {
+    self.hash.md5.as_ref().ok_or(CacheError::NoKey(HashAlgo::Md5))
+  }
+}
+
+/// The outcome of transferring a set of objects.
+#[derive(Debug, Default)]
+pub struct TransferSummary {
+  /// The number of objects transferred.
+  pub objects: usize,
+  /// The number of bytes transferred.
+  pub bytes: u64,
+  /// The keys of objects that failed to transfer, with their errors.
+  pub failures: Vec<(String, anyhow::Error)>,
+}
+
+impl TransferSummary {
+  /// Record the result of transferring an object.
+  pub fn record(&mut self, key: String, res: Result<u64>) {
+    match res {
+      Ok(size) => {
+        self.objects += 1;
+        self.bytes += size;
+      },
+      Err(e) => self.failures.push((key, e)),
+    }
+  }
+
+  /// Print the objects that failed to transfer, and return how many there were.
+  pub fn report_failures(&self) -> usize {
+    for (key, e) in &self.failures {
+      println!("failed: {}: {:#}", key, e);
+    }
+    self.failures.len()
+  }
+}
+
+/// A group of artifacts transferred with the same remote.
+pub struct RemoteGroup<'a> {
+  /// The remote's name.
+  pub name: &'a str,
+  /// The remote's settings.
+  pub settings: &'a RemoteSettings,
+  /// The artifacts to transfer with the remote.
+  pub artifacts: Vec<&'a Artifact>,
+}
+
+/// Group artifacts by the remote to transfer them with.
+///
+/// A remote named on the command line applies to every artifact; otherwise each
+/// artifact uses the remote named in its pointer, or the default remote.  Every
+/// remote is looked up before returning, so a misconfigured remote is reported
+/// before anything is transferred.
+pub fn route_artifacts<'a>(settings: &'a Settings, opts: &'a TransferOpts, arts: &'a [Artifact]) -> Result<Vec<RemoteGroup<'a>>> {
+  let mut groups: BTreeMap<&str, RemoteGroup> = BTreeMap::new();
+  for art in arts {
+    let (name, rs) = settings.remote_settings(opts.remote.as_deref().or(art.remote()))
+      .with_context(|| format!("{}: cannot select remote", art.path()))?;
+    groups.entry(name)
+      .or_insert_with(|| RemoteGroup { name, settings: rs, artifacts: Vec::new() })
+      .artifacts.push(art);
+  }
+  Ok(groups.into_values().collect())
+}
+
+/// Collect the unique objects referenced by a set of artifacts.
+///
+/// Objects are identified by their keys in the cache.  A DVC directory output
+/// contributes its manifest, followed by the files it lists if the manifest is in
+/// the cache; pulls therefore fetch manifests and then collect objects again.
+pub async fn collect_objects<'a>(cache: &Cache, arts: impl IntoIterator<Item=&'a Artifact>) -> Result<Vec<TransferObject>> {
+  let layout = cache.layout();
+  let mut seen = HashSet::new();
+  let mut objects = Vec::new();
+  let mut add = |art: &Artifact, kind: ObjectKind, fm: &FileMeta| {
+    let key = match kind {
+      ObjectKind::File => layout.object_key(&fm.hashes),
+      ObjectKind::Manifest => layout.object_key(&fm.hashes).map(|k| format!("{}{}", k, DIR_SUFFIX)),
+    };
+    match key {
+      Ok(key) => {
+        if seen.insert(key.clone()) {
+          objects.push(TransferObject {
+            key,
+            kind,
+            hash: fm.hashes.clone(),
+            size: fm.size.map(|s| s as u64),
+          });
+        }
+      },
+      Err(e) => warn!("{}: cannot transfer: {}", art.path(), e),
+    }
+  };
+  for art in arts {
+    match art.meta() {
+      Some(ArtifactMeta::Folder(fm)) if fm.files.is_empty() && fm.nfiles.unwrap_or(0) > 0 => {
+        let manifest = FileMeta {
+          size: None,
+          hashes: MultiHash { md5: fm.hashes.md5.clone(), ..MultiHash::default() },
+        };
+        add(art, ObjectKind::Manifest, &manifest);
+        let entries = folder_entries(cache, fm).await
+          .with_context(|| format!("{}: cannot read manifest", art.path()))?;
+        for entry in entries.iter().flat_map(|e| e.iter()) {
+          add(art, ObjectKind::File, &entry.meta);
+        }
+      },
+      Some(meta) => {
+        for fm in meta.file_metas() {
+          add(art, ObjectKind::File, fm);
+        }
+      },
+      None => (),
+    }
+  }
+  Ok(objects)
+}
+
+/// Get the path of an object in the cache.
+pub async fn cached_path(cache: &Cache, obj: &TransferObject) -> Result<PathBuf, CacheError> {
+  match obj.kind {
+    ObjectKind::File => cache.locate(&obj.hash).await,
+    ObjectKind::Manifest => cache.manifest_path(obj.manifest_md5()?),
+  }
+}
+
+/// Create a progress bar for a transfer of `total` bytes.
+pub fn progress_bar(total: u64, msg: &'static str) -> ProgressBar {
+  let pb = ProgressBar::new(total);
+  let style = ProgressStyle::with_template("{msg} [{bar:40}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, {eta})")
+    .expect("invalid progress template")
+    .progress_chars("=> ");
+  pb.set_style(style);
+  pb.set_message(msg);
+  pb
+}
+
+#[cfg(test)]
+async fn test_artifacts(remote_b: &str) -> (tempfile::TempDir, Vec<Artifact>) {
+  use relative_path::RelativePath;
+  use crate::tree::WorkTree;
+
+  let dir = tempfile::tempdir().expect("tempdir failed");
+  let sha = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+  let ptr = format!(
+    "[[artifacts]]\npath = \"a.txt\"\nsha256 = \"{}\"\n\n[[artifacts]]\npath = \"b.txt\"\nremote = \"{}\"\nsha256 = \"{}\"\n",
+    sha, remote_b, sha,
+  );
+  std::fs::write(dir.path().join("data.afc"), ptr).expect("write failed");
+  let tree = WorkTree::open_root(dir.path());
+  let arts = Artifact::load_afc_pointer(&tree, RelativePath::new("data.afc")).await.expect("load failed");
+  (dir, arts)
+}
+
+#[cfg(test)]
+fn test_settings(default: bool) -> Settings {
+  let mut config = String::new();
+  if default {
+    config.push_str("[core]\nremote = \"origin\"\n");
+  }
+  config.push_str("[remote.origin]\ntype = \"local\"\npath = \"/srv/origin\"\n");
+  config.push_str("[remote.archive]\ntype = \"local\"\npath = \"/srv/archive\"\n");
+  Settings::from_table(toml::from_str(&config).unwrap()).expect("invalid settings")
+}
+
+#[cfg(test)]
+fn group_paths(groups: &[RemoteGroup]) -> Vec<(String, Vec<String>)> {
+  groups.iter().map(|g| {
+    (g.name.to_owned(), g.artifacts.iter().map(|a| a.path().to_string()).collect())
+  }).collect()
+}
+
+#[tokio::test]
+async fn test_route_mixed() {
+  // artifacts use their pointer's remote, or the default
+  let (_dir, arts) = test_artifacts("archive").await;
+  let settings = test_settings(true);
+  let opts = TransferOpts { remote: None, jobs: None };
+  let groups = route_artifacts(&settings, &opts, &arts).expect("routing failed");
+  assert_eq!(group_paths(&groups), vec![
+    ("archive".to_owned(), vec!["b.txt".to_owned()]),
+    ("origin".to_owned(), vec!["a.txt".to_owned()]),
+  ]);
+}
+
+#[tokio::test]
+async fn test_route_named() {
+  // a remote named on the command line overrides pointers and the default
+  let (_dir, arts) = test_artifacts("origin").await;
+  let settings = test_settings(true);
+  let opts = TransferOpts { remote: Some("archive".into()), jobs: None };
+  let groups = route_artifacts(&settings, &opts, &arts).expect("routing failed");
+  assert_eq!(group_paths(&groups), vec![
+    ("archive".to_owned(), vec!["a.txt".to_owned(), "b.txt".to_owned()]),
+  ]);
+}
+
+#[tokio::test]
+async fn test_route_unknown() {
+  let (_dir, arts) = test_artifacts("backup").await;
+  let opts = TransferOpts { remote: None, jobs: None };
+  let err = route_artifacts(&test_settings(true), &opts, &arts).err().expect("routing succeeded");
+  assert!(err.to_string().contains("b.txt"));
+
+  // artifacts without a remote need a default
+  let (_dir, arts) = test_artifacts("archive").await;
+  let err = route_artifacts(&test_settings(false), &opts, &arts).err().expect("routing succeeded");
+  assert!(err.to_string().contains("a.txt"));
+}
@@ -1,19 +1,29 @@
 //! The `hash-file` command.
 use std::path::PathBuf;
 use anyhow::Result;
+use futures::TryStreamExt;
 use log::*;
 
 use clap::Args;
 
-use crate::filehash::{hash_file, DigestValue};
+use crate::filehash::{hash_many, DigestValue, HashAlgo, DEFAULT_ALGORITHMS};
+use crate::settings::DEFAULT_JOBS;
 
-/// Compute hashes for a file.
+/// Compute hashes for files.
 #[derive(Args, Debug, Clone)]
 #[command(name="hash-file")]
 pub struct HashFileCmd {
-  /// The file to hash.
-  #[arg(name="FILE")]
-  file: PathBuf,
+  /// The files to hash.
+  #[arg(name="FILE", required=true)]
+  files: Vec<PathBuf>,
+
+  /// A hash algorithm to compute (may be repeated) [default: md5, sha1, sha256].
+  #[arg(short='a', long="algorithm")]
+  algorithms: Vec<HashAlgo>,
+
+  /// The number of files to hash at once.
+  #[arg(short='j', long="jobs", default_value_t=DEFAULT_JOBS)]
+  jobs: usize,
 }
 
 fn maybe_print_hash<const N: usize>(name: &str, hash: &Option<DigestValue<N>>) -> Result<()> {
@@ -25,11 +35,23 @@ fn maybe_print_hash<const N: usize>(name: &str, hash: &Option<DigestValue<N>>) -
 
 impl HashFileCmd {
   pub async fn run(&self) -> Result<()> {
-    info!("hashing file {:?}", &self.file);
-    let hash = hash_file(&self.file).await?;
-    maybe_print_hash("MD-5", &hash.md5)?;
-    maybe_print_hash("SHA-1", &hash.sha1)?;
-    maybe_print_hash("SHA-256", &hash.sha256)?;
+    info!("hashing {} files", self.files.len());
+    let algos = if self.algorithms.is_empty() {
+      DEFAULT_ALGORITHMS
+    } else {
+      &self.algorithms
+    };
+    let mut hashes = hash_many(&self.files, algos, self.jobs);
+    while let Some((path, hash)) = hashes.try_next().await? {
+      if self.files.len() > 1 {
+        println!("[{}]", path.display());
+      }
+      maybe_print_hash("MD-5", &hash.md5)?;
+      maybe_print_hash("SHA-1", &hash.sha1)?;
+      maybe_print_hash("SHA-256", &hash.sha256)?;
+      maybe_print_hash("SHA-512", &hash.sha512)?;
+      maybe_print_hash("BLAKE3", &hash.blake3)?;
+    }
     Ok(())
   }
 }
@@ -0,0 +1,185 @@
+//! The `verify` command.
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use clap::Args;
+use futures::{StreamExt, stream};
+use log::*;
+
+use super::{open_tree, scan_selected};
+use super::remote::auth_prompt;
+use crate::cache::Cache;
+use crate::filehash::MultiHash;
+use crate::remote::open_remote;
+use crate::tree::artifact::ArtifactMeta;
+use crate::tree::verify::{Verified, TrackedFile, tracked_files, verify_file, verify_cache_object, verify_remote_object};
+
+/// Check cached, work tree and remote data against the recorded hashes.
+///
+/// Every recorded hash of every file is recomputed.  Without `--cache`, `--tree` or
+/// `--remote`, the cache and the work tree are checked.  Unless artifacts are
+/// selected, cache objects no artifact refers to are checked (by their key) too.  Fails if any data is
+/// corrupt or cannot be checked; missing data is reported, but is not a failure.
+#[derive(Args, Debug, Clone)]
+#[command(name="verify")]
+pub struct VerifyCmd {
+  /// Verify the objects in the cache.
+  #[arg(long="cache")]
+  cache: bool,
+
+  /// Verify the files in the work tree.
+  #[arg(long="tree")]
+  tree: bool,
+
+  /// Verify the objects on a remote (downloading them).
+  #[arg(short='r', long="remote", value_name="NAME")]
+  remote: Option<String>,
+
+  /// Move corrupt cache objects to the cache's quarantine directory.
+  #[arg(long="quarantine")]
+  quarantine: bool,
+
+  /// The number of files to verify at once [default: from settings].
+  #[arg(short='j', long="jobs")]
+  jobs: Option<usize>,
+
+  /// The artifacts (or directories containing artifacts) to verify [default: all].
+  #[arg(name="PATH")]
+  paths: Vec<PathBuf>,
+}
+
+/// Counts of verification outcomes.
+#[derive(Default)]
+struct Tally {
+  ok: usize,
+  missing: usize,
+  failed: usize,
+}
+
+impl Tally {
+  /// Record the outcome of verifying something, reporting failures.
+  fn record<E: Display>(&mut self, what: &str, res: Result<Verified, E>) -> Option<Verified> {
+    match res {
+      Ok(Verified::Ok) => self.ok += 1,
+      Ok(Verified::Missing) => {
+        debug!("{}: missing", what);
+        self.missing += 1;
+      },
+      Ok(Verified::Corrupt) => {
+        println!("corrupt: {}", what);
+        self.failed += 1;
+      },
+      Err(e) => {
+        println!("failed: {}: {}", what, e);
+        self.failed += 1;
+        return None;
+      },
+    }
+    res.ok()
+  }
+}
+
+impl VerifyCmd {
+  /// Verify cache objects, quarantining corrupt ones if requested.
+  async fn verify_cache(&self, cache: &Cache, what: &str, objects: &[(String, MultiHash)], jobs: usize, tally: &mut Tally) -> Result<()> {
+    let results: Vec<_> = stream::iter(objects)
+      .map(|(_, h)| verify_cache_object(cache, h))
+      .buffered(jobs)
+      .collect().await;
+    for ((key, hash), res) in objects.iter().zip(results) {
+      if tally.record(&format!("{} {}", what, key), res) == Some(Verified::Corrupt) && self.quarantine {
+        let qpath = cache.quarantine(hash).await?;
+        println!("quarantined {} to {}", key, qpath.display());
+      }
+    }
+    Ok(())
+  }
+
+  pub async fn run(&self) -> Result<()> {
+    let (tree, settings) = open_tree().await?;
+    let root = tree.root_path();
+    let cache = settings.open_cache(root);
+    let jobs = self.jobs.unwrap_or(settings.core.jobs).max(1);
+    let all = !self.cache && !self.tree && self.remote.is_none();
+    let mut tally = Tally::default();
+
+    let arts = scan_selected(&tree, &self.paths).await?;
+    let mut files: Vec<TrackedFile> = Vec::new();
+    for art in &arts {
+      if let Some(ArtifactMeta::Folder(fm)) = art.meta() {
+        if !fm.files.is_empty() && !fm.verify_tree_hash() {
+          println!("corrupt: {} (folder hashes do not match its entries)", art.path());
+          tally.failed += 1;
+        }
+      }
+      match tracked_files(&cache, art).await? {
+        Some(fs) => files.extend(fs),
+        None => warn!("{}: contents unknown, not verifying", art.path()),
+      }
+    }
+
+    // each object only needs checking once
+    let mut seen = HashSet::new();
+    let mut objects: Vec<(String, MultiHash)> = Vec::new();
+    for file in &files {
+      match cache.layout().object_key(&file.meta.hashes) {
+        Ok(key) if seen.insert(key.clone()) => objects.push((key, file.meta.hashes.clone())),
+        Ok(_) => (),
+        Err(e) => warn!("{}: cannot verify object: {}", file.path, e),
+      }
+    }
+
+    if all || self.cache {
+      info!("verifying {} cache objects", objects.len());
+      self.verify_cache(&cache, "cache object", &objects, jobs, &mut tally).await?;
+
+      // with every artifact selected, check the objects nothing refers to as well
+      if self.paths.is_empty() {
+        let mut unreferenced = Vec::new();
+        for hash in cache.list_objects().await? {
+          let key = cache.layout().object_key(&hash)?;
+          if !seen.contains(&key) {
+            unreferenced.push((key, hash));
+          }
+        }
+        info!("verifying {} unreferenced cache objects", unreferenced.len());
+        self.verify_cache(&cache, "unreferenced cache object", &unreferenced, jobs, &mut tally).await?;
+        if !unreferenced.is_empty() {
+          println!("{} cache objects are not referenced by any artifact", unreferenced.len());
+        }
+      }
+    }
+
+    if all || self.tree {
+      info!("verifying {} work tree files", files.len());
+      let results: Vec<_> = stream::iter(&files)
+        .map(|f| verify_file(f.path.to_path(root), &f.meta.hashes))
+        .buffered(jobs)
+        .collect().await;
+      for (file, res) in files.iter().zip(results) {
+        tally.record(file.path.as_str(), res);
+      }
+    }
+
+    if let Some(name) = &self.remote {
+      let (name, rs) = settings.remote_settings(Some(name))?;
+      let remote = open_remote(root, rs, Some(auth_prompt())).await?;
+      info!("verifying {} objects on {}", objects.len(), name);
+      let results: Vec<_> = stream::iter(&objects)
+        .map(|(_, h)| verify_remote_object(remote.as_ref(), &cache, h))
+        .buffered(jobs)
+        .collect().await;
+      for ((key, _), res) in objects.iter().zip(results) {
+        tally.record(&format!("object {} on {}", key, name), res);
+      }
+    }
+
+    println!("{} verified, {} missing, {} failed", tally.ok, tally.missing, tally.failed);
+    if tally.failed > 0 {
+      bail!("{} verification failures", tally.failed);
+    }
+    Ok(())
+  }
+}
@@ -1,28 +1,91 @@
 //! File hashing support.
+//!
+//! Files are hashed on blocking threads with large reads, running each algorithm
+//! on its own thread for large files (see [pipeline]); [hash_many] hashes several
+//! files at once.  [copy_file_hashed] copies a file through the same pipeline (this
+//! is how files enter the cache), while [copy_hashed] hashes async streams such as
+//! downloads as they are copied.
 mod value;
+pub mod pipeline;
+pub mod state;
 
+use std::fmt;
 use std::io;
 use std::path::Path;
+use std::str::FromStr;
+use futures::{Stream, StreamExt, stream};
 use serde::{Serialize, Deserialize};
 use digest::Digest;
 use md5::Md5;
 use sha1::Sha1;
-use sha2::Sha256;
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio::fs::File;
-use friendly::bytes;
-use log::*;
+use sha2::{Sha256, Sha512};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::spawn_blocking;
 
 pub use value::DigestValue;
 
 const MD5_SIZE: usize = 16;
 const SHA1_SIZE: usize = 20;
 const SHA256_SIZE: usize = 32;
-// const SHA512_SIZE: usize = 64;
+const SHA512_SIZE: usize = 64;
+const BLAKE3_SIZE: usize = 32;
 
 
+/// Size of the buffer used when copying data.
+const COPY_BUF_SIZE: usize = 64 * 1024;
+
+/// A hash algorithm supported by AFC.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all="lowercase")]
+pub enum HashAlgo {
+  Md5,
+  Sha1,
+  Sha256,
+  Sha512,
+  Blake3,
+}
+
+/// All supported hash algorithms.
+pub const ALL_ALGORITHMS: &[HashAlgo] = &[
+  HashAlgo::Md5, HashAlgo::Sha1, HashAlgo::Sha256, HashAlgo::Sha512, HashAlgo::Blake3,
+];
+
+/// The hash algorithms computed by default.
+///
+/// SHA-512 and BLAKE3 are opt-in (through `core.hashes`), so existing pointers and
+/// tools see the same hashes they always have.
+pub const DEFAULT_ALGORITHMS: &[HashAlgo] = &[HashAlgo::Md5, HashAlgo::Sha1, HashAlgo::Sha256];
+
+impl fmt::Display for HashAlgo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let s = match self {
+      HashAlgo::Md5 => "md5",
+      HashAlgo::Sha1 => "sha1",
+      HashAlgo::Sha256 => "sha256",
+      HashAlgo::Sha512 => "sha512",
+      HashAlgo::Blake3 => "blake3",
+    };
+    f.write_str(s)
+  }
+}
+
+impl FromStr for HashAlgo {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim().to_lowercase().as_str() {
+      "md5" => Ok(HashAlgo::Md5),
+      "sha1" => Ok(HashAlgo::Sha1),
+      "sha256" => Ok(HashAlgo::Sha256),
+      "sha512" => Ok(HashAlgo::Sha512),
+      "blake3" => Ok(HashAlgo::Blake3),
+      _ => Err(format!("unknown hash algorithm {:?}", s)),
+    }
+  }
+}
+
 /// A set of file hashes.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 pub struct MultiHash {
   #[serde(skip_serializing_if="Option::is_none")]
   pub md5: Option<DigestValue<MD5_SIZE>>,
@@ -30,6 +93,87 @@ pub struct MultiHash {
   pub sha1: Option<DigestValue<SHA1_SIZE>>,
   #[serde(skip_serializing_if="Option::is_none")]
   pub sha256: Option<DigestValue<SHA256_SIZE>>,
+  #[serde(default, skip_serializing_if="Option::is_none")]
+  pub sha512: Option<DigestValue<SHA512_SIZE>>,
+  #[serde(default, skip_serializing_if="Option::is_none")]
+  pub blake3: Option<DigestValue<BLAKE3_SIZE>>,
+}
+
+/// Compare two optional hashes, returning `None` if either is missing.
+fn compare<T: PartialEq>(h1: &Option<T>, h2: &Option<T>) -> Option<bool> {
+  match (h1, h2) {
+    (Some(h1), Some(h2)) => Some(h1 == h2),
+    _ => None,
+  }
+}
+
+impl MultiHash {
+  /// Check whether this hash set is consistent with another.
+  ///
+  /// Two hash sets match if they have at least one hash algorithm in common, and
+  /// every hash present in both sets has the same value.
+  pub fn matches(&self, other: &MultiHash) -> bool {
+    let results = [
+      compare(&self.md5, &other.md5),
+      compare(&self.sha1, &other.sha1),
+      compare(&self.sha256, &other.sha256),
+      compare(&self.sha512, &other.sha512),
+      compare(&self.blake3, &other.blake3),
+    ];
+    let common: Vec<bool> = results.into_iter().flatten().collect();
+    !common.is_empty() && common.into_iter().all(|m| m)
+  }
+
+  /// Get the algorithms with hashes present in this hash set.
+  pub fn algorithms(&self) -> Vec<HashAlgo> {
+    let mut algos = Vec::new();
+    if self.md5.is_some() {
+      algos.push(HashAlgo::Md5);
+    }
+    if self.sha1.is_some() {
+      algos.push(HashAlgo::Sha1);
+    }
+    if self.sha256.is_some() {
+      algos.push(HashAlgo::Sha256);
+    }
+    if self.sha512.is_some() {
+      algos.push(HashAlgo::Sha512);
+    }
+    if self.blake3.is_some() {
+      algos.push(HashAlgo::Blake3);
+    }
+    algos
+  }
+
+  /// Get the hex string of the hash for an algorithm, if present.
+  pub fn hex(&self, algo: HashAlgo) -> Option<String> {
+    match algo {
+      HashAlgo::Md5 => self.md5.as_ref().map(|h| h.to_string()),
+      HashAlgo::Sha1 => self.sha1.as_ref().map(|h| h.to_string()),
+      HashAlgo::Sha256 => self.sha256.as_ref().map(|h| h.to_string()),
+      HashAlgo::Sha512 => self.sha512.as_ref().map(|h| h.to_string()),
+      HashAlgo::Blake3 => self.blake3.as_ref().map(|h| h.to_string()),
+    }
+  }
+
+  /// Add the hashes from another hash set that are missing from this one.
+  pub fn merge(&mut self, other: MultiHash) {
+    if self.md5.is_none() {
+      self.md5 = other.md5;
+    }
+    if self.sha1.is_none() {
+      self.sha1 = other.sha1;
+    }
+    if self.sha256.is_none() {
+      self.sha256 = other.sha256;
+    }
+    if self.sha512.is_none() {
+      self.sha512 = other.sha512;
+    }
+    if self.blake3.is_none() {
+      self.blake3 = other.blake3;
+    }
+  }
 }
 
 /// A set of digests for computing multiple hashes simultaneously.
@@ -37,6 +181,9 @@ pub struct MultiDigest {
   md5: Option<Md5>,
   sha1: Option<Sha1>,
   sha256: Option<Sha256>,
+  sha512: Option<Sha512>,
+  // BLAKE3 does not implement the `digest` traits, so it is updated directly
+  blake3: Option<blake3::Hasher>,
 }
 
 fn maybe_update<D: Digest>(hash: &mut Option<D>, data: &[u8]) {
@@ -46,21 +193,63 @@ fn maybe_update<D: Digest>(hash: &mut Option<D>, data: &[u8]) {
 }
 
 impl MultiDigest {
-  /// Construct a new multi-digest with all hashes enabled.
+  /// Construct a new multi-digest with the default hashes enabled.
   pub fn new() -> MultiDigest {
+    MultiDigest::with_algorithms(DEFAULT_ALGORITHMS)
+  }
+
+  /// Construct a new multi-digest computing only the specified hashes.
+  pub fn with_algorithms(algos: &[HashAlgo]) -> MultiDigest {
     MultiDigest {
-      md5: Some(Md5::new()),
-      sha1: Some(Sha1::new()),
-      sha256: Some(Sha256::new()),
+      md5: algos.contains(&HashAlgo::Md5).then(Md5::new),
+      sha1: algos.contains(&HashAlgo::Sha1).then(Sha1::new),
+      sha256: algos.contains(&HashAlgo::Sha256).then(Sha256::new),
+      sha512: algos.contains(&HashAlgo::Sha512).then(Sha512::new),
+      blake3: algos.contains(&HashAlgo::Blake3).then(blake3::Hasher::new),
     }
   }
 
+  /// Construct a new multi-digest computing the hashes present in a hash set.
+  ///
+  /// This is useful for verifying data against previously-recorded hashes.
+  pub fn for_hash(hash: &MultiHash) -> MultiDigest {
+    MultiDigest::with_algorithms(&hash.algorithms())
+  }
+
+  /// Split this digest into single-algorithm digests, to update them in parallel.
+  ///
+  /// The hashes of the parts can be recombined with [MultiHash::merge].
+  pub fn split(self) -> Vec<MultiDigest> {
+    let none = || MultiDigest::with_algorithms(&[]);
+    let mut parts = Vec::new();
+    if let Some(h) = self.md5 {
+      parts.push(MultiDigest { md5: Some(h), ..none() });
+    }
+    if let Some(h) = self.sha1 {
+      parts.push(MultiDigest { sha1: Some(h), ..none() });
+    }
+    if let Some(h) = self.sha256 {
+      parts.push(MultiDigest { sha256: Some(h), ..none() });
+    }
+    if let Some(h) = self.sha512 {
+      parts.push(MultiDigest { sha512: Some(h), ..none() });
+    }
+    if let Some(h) = self.blake3 {
+      parts.push(MultiDigest { blake3: Some(h), ..none() });
+    }
+    parts
+  }
+
   /// Update the hashes.
   pub fn update(&mut self, data: impl AsRef<[u8]>) {
     let data = data.as_ref();
     maybe_update(&mut self.md5, data);
     maybe_update(&mut self.sha1, data);
     maybe_update(&mut self.sha256, data);
+    maybe_update(&mut self.sha512, data);
+    if let Some(hash) = &mut self.blake3 {
+      hash.update(data);
+    }
   }
 
   /// Finish this digest and return the hashes.
@@ -69,29 +258,76 @@ impl MultiDigest {
       md5: self.md5.map(|h| h.finalize().into()),
       sha1: self.sha1.map(|h| h.finalize().into()),
       sha256: self.sha256.map(|h| h.finalize().into()),
+      sha512: self.sha512.map(|h| h.finalize().into()),
+      blake3: self.blake3.map(|h| (*h.finalize().as_bytes()).into()),
     }
   }
 }
 
+impl Default for MultiDigest {
+  fn default() -> MultiDigest {
+    MultiDigest::new()
+  }
+}
+
+/// Compute the default hashes of a file.
 pub async fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<MultiHash> {
-  let mut digest = MultiDigest::new();
-  let file = File::open(path).await?;
-  let stat = file.metadata().await?;
-  let len = stat.len();
-  debug!("hashing file {:?} ({})", file, bytes(len));
-  let mut read = BufReader::new(file);
-  let mut buf = [0u8; 4096];
+  hash_file_with(path, MultiDigest::new()).await
+}
+
+/// Compute the hashes of a file with a specific digest (e.g. to compute only the
+/// configured algorithms, or those recorded in a pointer).
+pub async fn hash_file_with<P: AsRef<Path>>(path: P, digest: MultiDigest) -> io::Result<MultiHash> {
+  let path = path.as_ref().to_owned();
+  spawn_blocking(move || pipeline::hash_path(&path, digest)).await?
+}
+
+/// Copy a file to a new file, hashing it along the way with the pipeline.
+///
+/// Returns the number of bytes copied and their hashes.  The destination is synced.
+pub async fn copy_file_hashed<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q, digest: MultiDigest) -> io::Result<(u64, MultiHash)> {
+  let src = src.as_ref().to_owned();
+  let dst = dst.as_ref().to_owned();
+  spawn_blocking(move || pipeline::copy_path(&src, &dst, digest)).await?
+}
+
+/// Hash many files concurrently, with at most `jobs` files in flight.
+///
+/// The results are yielded in the same order as the paths.
+pub fn hash_many<I, P>(paths: I, algos: &[HashAlgo], jobs: usize) -> impl Stream<Item=io::Result<(P, MultiHash)>>
+where I: IntoIterator<Item=P>, P: AsRef<Path> {
+  let algos = algos.to_vec();
+  stream::iter(paths).map(move |path| {
+    let digest = MultiDigest::with_algorithms(&algos);
+    async move {
+      let hash = hash_file_with(&path, digest).await?;
+      Ok((path, hash))
+    }
+  }).buffered(jobs.max(1))
+}
+
+/// Copy data from a reader to a writer, hashing it along the way.
+///
+/// Returns the number of bytes copied and their hashes, as computed by `digest`.  The
+/// writer is flushed, but not synced or closed.
+pub async fn copy_hashed<R, W>(src: &mut R, dst: &mut W, mut digest: MultiDigest) -> io::Result<(u64, MultiHash)>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+  let mut buf = vec![0u8; COPY_BUF_SIZE];
+  let mut size = 0;
 
   loop {
-    let n = read.read(&mut buf).await?;
+    let n = src.read(&mut buf).await?;
     if n == 0 {
       break;
     }
 
     digest.update(&buf[..n]);
+    dst.write_all(&buf[..n]).await?;
+    size += n as u64;
   }
 
-  Ok(digest.finish())
+  dst.flush().await?;
+  Ok((size, digest.finish()))
 }
 
 #[tokio::test]
@@ -115,3 +351,91 @@ async fn test_hash_file() {
   let sha1: [u8; SHA1_SIZE] = sha1.finalize().into();
   assert_eq!(hashes.sha1.unwrap().hash, sha1);
 }
+
+#[tokio::test]
+async fn test_hash_many() {
+  use futures::TryStreamExt;
+
+  let paths = vec!["Cargo.toml", "README.md", "src/lib.rs"];
+  let hashes: Vec<_> = hash_many(paths.clone(), &[HashAlgo::Sha256], 2).try_collect().await.expect("hash error");
+  assert_eq!(hashes.len(), paths.len());
+  for (path, (hpath, hash)) in paths.iter().zip(hashes) {
+    assert_eq!(*path, hpath);
+    let mut digest = MultiDigest::with_algorithms(&[HashAlgo::Sha256]);
+    digest.update(std::fs::read(path).expect("read failed"));
+    assert_eq!(hash, digest.finish());
+  }
+
+  let res: io::Result<Vec<_>> = hash_many(vec!["no-such-file"], ALL_ALGORITHMS, 2).try_collect().await;
+  assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn test_copy_hashed() {
+  let data = b"hello, astral filing cabinet".to_vec();
+  let mut src = data.as_slice();
+  let mut dst = Vec::new();
+  let (size, hashes) = copy_hashed(&mut src, &mut dst, MultiDigest::new()).await.expect("copy error");
+  assert_eq!(size, data.len() as u64);
+  assert_eq!(dst, data);
+
+  let mut digest = MultiDigest::new();
+  digest.update(&data);
+  assert_eq!(hashes, digest.finish());
+}
+
+#[test]
+fn test_hash_matches() {
+  let mut d1 = MultiDigest::new();
+  d1.update(b"first");
+  let h1 = d1.finish();
+  let mut d2 = MultiDigest::new();
+  d2.update(b"second");
+  let h2 = d2.finish();
+
+  assert!(h1.matches(&h1));
+  assert!(!h1.matches(&h2));
+
+  let partial = MultiHash { sha256: h1.sha256.clone(), ..MultiHash::default() };
+  assert!(h1.matches(&partial));
+  let empty = MultiHash::default();
+  assert!(!h1.matches(&empty));
+}
+
+#[test]
+fn test_with_algorithms() {
+  let mut digest = MultiDigest::with_algorithms(&[HashAlgo::Sha256]);
+  digest.update(b"hello");
+  let hash = digest.finish();
+  assert!(hash.md5.is_none());
+  assert!(hash.sha1.is_none());
+  assert!(hash.sha256.is_some());
+  assert_eq!(hash.algorithms(), vec![HashAlgo::Sha256]);
+  assert_eq!("SHA256".parse::<HashAlgo>(), Ok(HashAlgo::Sha256));
+}
+
+#[test]
+fn test_sha512_blake3() {
+  let mut digest = MultiDigest::with_algorithms(&[HashAlgo::Sha512, HashAlgo::Blake3]);
+  digest.update(b"hello");
+  let hash = digest.finish();
+  assert!(hash.md5.is_none());
+  assert_eq!(hash.algorithms(), vec![HashAlgo::Sha512, HashAlgo::Blake3]);
+  assert_eq!(hash.hex(HashAlgo::Sha512).unwrap(), concat!(
+    "9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca7",
+    "2323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043",
+  ));
+  assert_eq!(hash.hex(HashAlgo::Blake3).unwrap(), "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f");
+  assert_eq!("blake3".parse::<HashAlgo>(), Ok(HashAlgo::Blake3));
+
+  // pointers with only the older hashes still verify against the shared ones
+  let mut digest = MultiDigest::with_algorithms(ALL_ALGORITHMS);
+  digest.update(b"hello");
+  let full = digest.finish();
+  assert!(full.matches(&hash));
+  let mut old = full.clone();
+  old.sha512 = None;
+  old.blake3 = None;
+  assert!(full.matches(&old));
+  assert!(!old.matches(&hash));
+}
@@ -0,0 +1,202 @@
+//! Pipelined file hashing.
+//!
+//! Hashing a large file with several algorithms is CPU-bound, and the algorithms
+//! run at very different speeds.  The pipeline reads the file in large chunks and
+//! hands each chunk (shared, not copied) over a bounded channel to a thread per
+//! algorithm, so reading and all the digests proceed in parallel and the whole
+//! hash takes about as long as the slowest algorithm.  The channels bound memory
+//! use to a few chunks per algorithm.  Small files, and digests with only one
+//! algorithm, are hashed on the calling thread.
+//!
+//! Copies (e.g. into the cache) go through the same pipeline, writing each chunk
+//! on the reading thread while the algorithms' threads hash it.
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+use friendly::bytes;
+use log::*;
+
+use super::{MultiDigest, MultiHash};
+
+/// Size of the chunks read when hashing.
+pub(super) const READ_BUF_SIZE: usize = 1024 * 1024;
+/// Number of chunks queued for each algorithm's thread.
+const PIPELINE_DEPTH: usize = 4;
+/// Files smaller than this are not worth starting threads for.
+const PIPELINE_MIN_SIZE: u64 = 4 * READ_BUF_SIZE as u64;
+
+/// Read into a buffer, retrying interrupted reads.
+fn read_some<R: Read>(src: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+  loop {
+    match src.read(buf) {
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      r => return r,
+    }
+  }
+}
+
+/// Read a full chunk, unless the data ends first.  Returns an empty chunk at EOF.
+fn read_chunk<R: Read>(src: &mut R) -> io::Result<Vec<u8>> {
+  let mut buf = vec![0u8; READ_BUF_SIZE];
+  let mut n = 0;
+  while n < buf.len() {
+    let k = read_some(src, &mut buf[n..])?;
+    if k == 0 {
+      break;
+    }
+    n += k;
+  }
+  buf.truncate(n);
+  Ok(buf)
+}
+
+/// Hash data from a reader on the current thread.
+pub fn hash_serial<R: Read>(src: &mut R, digest: MultiDigest) -> io::Result<MultiHash> {
+  hash_serial_with(src, digest, |_| Ok(()))
+}
+
+/// Hash data from a reader on the current thread, passing each chunk to `sink`.
+fn hash_serial_with<R, F>(src: &mut R, mut digest: MultiDigest, mut sink: F) -> io::Result<MultiHash>
+where R: Read, F: FnMut(&[u8]) -> io::Result<()> {
+  let mut buf = vec![0u8; READ_BUF_SIZE];
+  loop {
+    let n = read_some(src, &mut buf)?;
+    if n == 0 {
+      break;
+    }
+    digest.update(&buf[..n]);
+    sink(&buf[..n])?;
+  }
+  Ok(digest.finish())
+}
+
+/// Hash data from a reader, running each algorithm on its own thread.
+pub fn hash_pipelined<R: Read>(src: &mut R, digest: MultiDigest) -> io::Result<MultiHash> {
+  hash_pipelined_with(src, digest, |_| Ok(()))
+}
+
+/// Hash data from a reader on a thread per algorithm, passing each chunk to `sink`.
+fn hash_pipelined_with<R, F>(src: &mut R, digest: MultiDigest, mut sink: F) -> io::Result<MultiHash>
+where R: Read, F: FnMut(&[u8]) -> io::Result<()> {
+  let mut parts = digest.split();
+  if parts.len() < 2 {
+    let digest = parts.pop().unwrap_or_else(|| MultiDigest::with_algorithms(&[]));
+    return hash_serial_with(src, digest, sink);
+  }
+
+  thread::scope(|scope| {
+    let mut senders = Vec::with_capacity(parts.len());
+    let mut workers = Vec::with_capacity(parts.len());
+    for mut part in parts {
+      let (tx, rx) = sync_channel::<Arc<Vec<u8>>>(PIPELINE_DEPTH);
+      senders.push(tx);
+      workers.push(scope.spawn(move || {
+        for chunk in rx {
+          part.update(chunk.as_slice());
+        }
+        part.finish()
+      }));
+    }
+
+    let mut read = || -> io::Result<()> {
+      loop {
+        let chunk = read_chunk(src)?;
+        if chunk.is_empty() {
+          return Ok(());
+        }
+        let chunk = Arc::new(chunk);
+        for tx in &senders {
+          // a send only fails if the worker died, which the join below reports
+          if tx.send(chunk.clone()).is_err() {
+            return Ok(());
+          }
+        }
+        sink(chunk.as_slice())?;
+      }
+    };
+    let res = read();
+    // closing the channels tells the workers to finish
+    drop(senders);
+
+    let mut hash = MultiHash::default();
+    for worker in workers {
+      hash.merge(worker.join().expect("hash thread panicked"));
+    }
+    res.map(|_| hash)
+  })
+}
+
+/// Hash a file, pipelining the algorithms if the file is large enough to benefit.
+pub fn hash_path(path: &Path, digest: MultiDigest) -> io::Result<MultiHash> {
+  let mut file = File::open(path)?;
+  let len = file.metadata()?.len();
+  debug!("hashing file {:?} ({})", path, bytes(len));
+  if len >= PIPELINE_MIN_SIZE {
+    hash_pipelined(&mut file, digest)
+  } else {
+    hash_serial(&mut file, digest)
+  }
+}
+
+/// Copy a file to a new file, hashing it along the way.
+///
+/// The destination is synced before returning.  Returns the number of bytes copied
+/// and their hashes.
+pub fn copy_path(src: &Path, dst: &Path, digest: MultiDigest) -> io::Result<(u64, MultiHash)> {
+  let mut input = File::open(src)?;
+  let len = input.metadata()?.len();
+  debug!("copying {:?} to {:?} ({})", src, dst, bytes(len));
+  let mut output = File::create(dst)?;
+  let mut size = 0;
+  let sink = |chunk: &[u8]| {
+    size += chunk.len() as u64;
+    output.write_all(chunk)
+  };
+  let hash = if len >= PIPELINE_MIN_SIZE {
+    hash_pipelined_with(&mut input, digest, sink)?
+  } else {
+    hash_serial_with(&mut input, digest, sink)?
+  };
+  output.sync_all()?;
+  Ok((size, hash))
+}
+
+#[test]
+fn test_pipelined_matches_serial() {
+  use super::{HashAlgo, ALL_ALGORITHMS};
+
+  // several chunks, with a partial one at the end
+  let data: Vec<u8> = (0..(3 * READ_BUF_SIZE + 1234)).map(|i| (i % 251) as u8).collect();
+  let serial = hash_serial(&mut data.as_slice(), MultiDigest::with_algorithms(ALL_ALGORITHMS)).expect("hash failed");
+  let piped = hash_pipelined(&mut data.as_slice(), MultiDigest::with_algorithms(ALL_ALGORITHMS)).expect("hash failed");
+  assert_eq!(piped, serial);
+  assert_eq!(piped.algorithms(), ALL_ALGORITHMS.to_vec());
+
+  let single = hash_pipelined(&mut data.as_slice(), MultiDigest::with_algorithms(&[HashAlgo::Sha1])).expect("hash failed");
+  assert_eq!(single.sha1, serial.sha1);
+  assert!(single.md5.is_none());
+  let empty = hash_pipelined(&mut [].as_slice(), MultiDigest::new()).expect("hash failed");
+  assert_eq!(empty, MultiDigest::new().finish());
+}
+
+#[test]
+fn test_copy_path() {
+  use super::ALL_ALGORITHMS;
+
+  let dir = tempfile::tempdir().expect("tempdir failed");
+  let data: Vec<u8> = (0..(PIPELINE_MIN_SIZE as usize + 1234)).map(|i| (i % 251) as u8).collect();
+  for len in [0, 1234, data.len()] {
+    let src = dir.path().join("src");
+    let dst = dir.path().join("dst");
+    std::fs::write(&src, &data[..len]).expect("write failed");
+    let (size, hash) = copy_path(&src, &dst, MultiDigest::with_algorithms(ALL_ALGORITHMS)).expect("copy failed");
+    assert_eq!(size, len as u64);
+    assert_eq!(std::fs::read(&dst).expect("read failed"), &data[..len]);
+    let expected = hash_serial(&mut &data[..len], MultiDigest::with_algorithms(ALL_ALGORITHMS)).expect("hash failed");
+    assert_eq!(hash, expected);
+  }
+}
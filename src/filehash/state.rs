@@ -0,0 +1,277 @@
+//! Persistent cache of file hashes.
+//!
+//! Re-hashing every tracked file on every `afc status` is prohibitive for large
+//! work trees, so the hashes of work tree files are remembered (in `.afc/state`,
+//! by path relative to the work tree root) with the device, inode, size and
+//! modification time (to the nanosecond) of the file they were computed from.  A
+//! remembered hash is only used while all of these are unchanged, so any change to
+//! the file's metadata causes it to be hashed again.
+//!
+//! Only `afc status` and `afc checkout` consult the hash cache, as they only need
+//! hashes.  `afc add` must read each file to copy it into the cache anyway, and
+//! hashes it during the copy ([Cache::insert_file](crate::cache::Cache::insert_file)).
+//!
+//! File systems record modification times at a coarser granularity than they
+//! claim, so a file modified just after it was hashed can keep its old metadata.
+//! As in Git's index, hashes of files modified too recently to rule that out
+//! (within [RACY_WINDOW]) are not remembered, nor are those of files that change
+//! while they are being hashed.
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::*;
+use relative_path::RelativePath;
+use serde::{Serialize, Deserialize};
+use tokio::fs::{create_dir_all, metadata, read, write};
+
+use crate::util::io::{path_exists, write_file_atomic};
+use super::{DigestValue, HashAlgo, MultiDigest, MultiHash, hash_file_with};
+
+/// The name of the hash cache file in the state directory.
+pub const HASH_STATE_FILE: &str = "hashes.bin";
+/// The version of the hash cache format; caches with other versions are discarded.
+const STATE_VERSION: u32 = 1;
+/// Files modified less than this long before they are hashed are not remembered.
+pub const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// The metadata identifying a version of a file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+  dev: u64,
+  ino: u64,
+  size: u64,
+  mtime_ns: i128,
+}
+
+impl FileStamp {
+  #[cfg(unix)]
+  fn of(meta: &Metadata) -> FileStamp {
+    use std::os::unix::fs::MetadataExt;
+    FileStamp {
+      dev: meta.dev(),
+      ino: meta.ino(),
+      size: meta.size(),
+      mtime_ns: meta.mtime() as i128 * 1_000_000_000 + meta.mtime_nsec() as i128,
+    }
+  }
+
+  #[cfg(not(unix))]
+  fn of(meta: &Metadata) -> FileStamp {
+    let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+    FileStamp {
+      dev: 0,
+      ino: 0,
+      size: meta.len(),
+      mtime_ns: mtime.map(|d| d.as_nanos() as i128).unwrap_or(0),
+    }
+  }
+}
+
+/// Stored form of [MultiHash]; binary formats cannot skip missing fields.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredHash {
+  md5: Option<DigestValue<16>>,
+  sha1: Option<DigestValue<20>>,
+  sha256: Option<DigestValue<32>>,
+  sha512: Option<DigestValue<64>>,
+  blake3: Option<DigestValue<32>>,
+}
+
+impl From<MultiHash> for StoredHash {
+  fn from(h: MultiHash) -> StoredHash {
+    StoredHash { md5: h.md5, sha1: h.sha1, sha256: h.sha256, sha512: h.sha512, blake3: h.blake3 }
+  }
+}
+
+impl From<StoredHash> for MultiHash {
+  fn from(h: StoredHash) -> MultiHash {
+    MultiHash { md5: h.md5, sha1: h.sha1, sha256: h.sha256, sha512: h.sha512, blake3: h.blake3 }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StateEntry {
+  stamp: FileStamp,
+  hashes: StoredHash,
+}
+
+/// A persistent cache of the hashes of files in a directory tree.
+///
+/// The cache is loaded from a state directory with [HashCache::load] and written
+/// back with [HashCache::save]; it can be shared between concurrent hashing tasks.
+#[derive(Debug)]
+pub struct HashCache {
+  root: PathBuf,
+  dir: PathBuf,
+  // keyed by normalized relative path (as strings, for the binary encoding)
+  entries: Mutex<HashMap<String, StateEntry>>,
+  dirty: AtomicBool,
+}
+
+impl HashCache {
+  /// Load the hash cache for files under `root` from a state directory.
+  ///
+  /// A missing cache is empty; an unreadable one is discarded with a warning.
+  pub async fn load<R: AsRef<Path>, P: AsRef<Path>>(root: R, dir: P) -> io::Result<HashCache> {
+    let root = root.as_ref().to_owned();
+    let dir = dir.as_ref().to_owned();
+    let file = dir.join(HASH_STATE_FILE);
+    let entries = if path_exists(&file).await? {
+      let data = read(&file).await?;
+      match bincode::deserialize::<(u32, HashMap<String, StateEntry>)>(&data) {
+        Ok((STATE_VERSION, entries)) => entries,
+        Ok((v, _)) => {
+          info!("{:?}: hash cache version {} is not supported, discarding", file, v);
+          HashMap::new()
+        },
+        Err(e) => {
+          warn!("{:?}: invalid hash cache ({}), discarding", file, e);
+          HashMap::new()
+        }
+      }
+    } else {
+      HashMap::new()
+    };
+    debug!("loaded {} cached hashes from {:?}", entries.len(), file);
+    Ok(HashCache { root, dir, entries: Mutex::new(entries), dirty: AtomicBool::new(false) })
+  }
+
+  /// Get the number of remembered hashes.
+  pub fn len(&self) -> usize {
+    self.entries.lock().expect("poisoned lock").len()
+  }
+
+  /// Check whether the cache is empty.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Compute the hashes of a file, using remembered hashes if the file is unchanged.
+  ///
+  /// The result has at least the hashes for `algos`, and may have others.
+  pub async fn hash_file(&self, path: &RelativePath, algos: &[HashAlgo]) -> io::Result<MultiHash> {
+    let path = path.normalize();
+    let fpath = path.to_path(&self.root);
+    let key = path.as_str();
+    let stamp = match metadata(&fpath).await {
+      Ok(m) => FileStamp::of(&m),
+      Err(e) => {
+        if self.entries.lock().expect("poisoned lock").remove(key).is_some() {
+          self.dirty.store(true, Ordering::Relaxed);
+        }
+        return Err(e);
+      }
+    };
+
+    let known = {
+      let entries = self.entries.lock().expect("poisoned lock");
+      entries.get(key).filter(|e| e.stamp == stamp).map(|e| MultiHash::from(e.hashes.clone()))
+    };
+    let mut missing = algos.to_vec();
+    if let Some(known) = &known {
+      let have = known.algorithms();
+      missing.retain(|a| !have.contains(a));
+      if missing.is_empty() {
+        trace!("{}: using cached hash", path);
+        return Ok(known.clone());
+      }
+    }
+
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let racy = stamp.mtime_ns > started.saturating_sub(RACY_WINDOW).as_nanos() as i128;
+    let mut hash = hash_file_with(&fpath, MultiDigest::with_algorithms(&missing)).await?;
+    if let Some(known) = known {
+      hash.merge(known);
+    }
+
+    // the hash is only good for the version of the file we started with
+    let after = FileStamp::of(&metadata(&fpath).await?);
+    if racy {
+      debug!("{}: modified too recently to cache hash", path);
+    } else if after == stamp {
+      let entry = StateEntry { stamp, hashes: hash.clone().into() };
+      self.entries.lock().expect("poisoned lock").insert(key.to_owned(), entry);
+      self.dirty.store(true, Ordering::Relaxed);
+    } else {
+      debug!("{}: changed while hashing, not caching hash", path);
+    }
+    Ok(hash)
+  }
+
+  /// Write the cache back to its state directory, if it has changed.
+  pub async fn save(&self) -> io::Result<()> {
+    if !self.dirty.swap(false, Ordering::Relaxed) {
+      return Ok(());
+    }
+    let data = {
+      let entries = self.entries.lock().expect("poisoned lock");
+      debug!("saving {} cached hashes", entries.len());
+      bincode::serialize(&(STATE_VERSION, &*entries))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+    };
+    create_dir_all(&self.dir).await?;
+    // state is local to this work tree, and never committed
+    let ignore = self.dir.join(".gitignore");
+    if !path_exists(&ignore).await? {
+      write(&ignore, "*\n").await?;
+    }
+    write_file_atomic(self.dir.join(HASH_STATE_FILE), data).await
+  }
+}
+
+#[tokio::test]
+async fn test_hash_cache() {
+  use std::fs::write;
+
+  let tmp = tempfile::tempdir().expect("tempdir failed");
+  let dir = tmp.path();
+  let file = dir.join("data.txt");
+  let backdate = || {
+    let f = std::fs::File::options().write(true).open(&file).expect("open failed");
+    f.set_modified(SystemTime::now() - Duration::from_secs(3600)).expect("set mtime failed");
+  };
+  write(&file, "hello").expect("write failed");
+  backdate();
+  let state = dir.join("state");
+  let rel = RelativePath::new("data.txt");
+
+  let cache = HashCache::load(dir, &state).await.expect("load failed");
+  let hash = cache.hash_file(rel, &[HashAlgo::Sha256]).await.expect("hash failed");
+  assert_eq!(hash.algorithms(), vec![HashAlgo::Sha256]);
+  assert_eq!(cache.len(), 1);
+  // more algorithms are added to the remembered hash
+  let hash = cache.hash_file(rel, &[HashAlgo::Md5]).await.expect("hash failed");
+  assert_eq!(hash.algorithms(), vec![HashAlgo::Md5, HashAlgo::Sha256]);
+  cache.save().await.expect("save failed");
+
+  // a remembered hash is used while the file is unchanged...
+  let cache = HashCache::load(dir, &state).await.expect("load failed");
+  assert_eq!(cache.len(), 1);
+  {
+    let mut entries = cache.entries.lock().unwrap();
+    let entry = entries.get_mut(rel.as_str()).unwrap();
+    entry.hashes.sha256 = Some([0u8; 32].into());
+  }
+  let hash = cache.hash_file(rel, &[HashAlgo::Sha256]).await.expect("hash failed");
+  assert_eq!(hash.sha256, Some([0u8; 32].into()));
+
+  // ...and replaced once it changes
+  write(&file, "world").expect("write failed");
+  let hash = cache.hash_file(rel, &[HashAlgo::Sha256]).await.expect("hash failed");
+  assert_ne!(hash.sha256, Some([0u8; 32].into()));
+  assert!(hash.md5.is_none());
+  // but not remembered until it is old enough
+  assert!(cache.entries.lock().unwrap()[rel.as_str()].hashes.md5.is_some());
+  backdate();
+  cache.hash_file(rel, &[HashAlgo::Sha256]).await.expect("hash failed");
+  assert!(cache.entries.lock().unwrap()[rel.as_str()].hashes.md5.is_none());
+
+  std::fs::remove_file(&file).expect("remove failed");
+  assert!(cache.hash_file(rel, &[HashAlgo::Sha256]).await.is_err());
+  assert!(cache.is_empty());
+}
@@ -35,7 +35,7 @@ pub enum DigestDecodeError {
 
 /// A digest value with good serialization & I/O support.
 #[repr(transparent)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct DigestValue<const SIZE: usize> {
   pub hash: [u8; SIZE]
 }
@@ -46,12 +46,14 @@ impl <const N: usize> From<[u8; N]> for DigestValue<N> {
   }
 }
 
+#[allow(clippy::from_over_into)]
 impl <const N: usize> Into<[u8; N]> for DigestValue<N> {
   fn into(self) -> [u8; N] {
     self.hash
   }
 }
 
+#[allow(clippy::from_over_into)]
 impl <'a, const N: usize> Into<&'a [u8; N]> for &'a DigestValue<N> {
   fn into(self) -> &'a [u8; N] {
     &self.hash
@@ -99,7 +101,11 @@ impl <const N: usize> Serialize for DigestValue<N> {
 impl <'de, const N: usize> Deserialize<'de> for DigestValue<N> {
   fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
   where D: serde::Deserializer<'de> {
-    deserializer.deserialize_str(HexDecodeVisitor::<N> {})
+    if deserializer.is_human_readable() {
+      deserializer.deserialize_str(HexDecodeVisitor::<N> {})
+    } else {
+      deserializer.deserialize_bytes(HexDecodeVisitor::<N> {})
+    }
   }
 }
 
@@ -147,3 +153,11 @@ fn test_hash_zero_decode() {
   let dv = dv.expect("hash decode failed");
   assert_eq!(dv.hash, [0, 0, 0, 0]);
 }
+
+#[test]
+fn test_hash_binary_roundtrip() {
+  let dv = DigestValue { hash: [1, 2, 3, 255] };
+  let data = bincode::serialize(&dv).expect("serialize failed");
+  let back: DigestValue<4> = bincode::deserialize(&data).expect("deserialize failed");
+  assert_eq!(back, dv);
+}
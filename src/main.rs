@@ -5,6 +5,7 @@ use anyhow::Result;
 use astral_filing_cabinet::cli::AFC;
 
 // Wrapper class that sets up logging.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Parser, Debug)]
 struct AFCCLI {
   #[command(flatten)]
@@ -0,0 +1,541 @@
+//! Remote stored in a Backblaze B2 bucket, using the native B2 API.
+//!
+//! Objects are stored under the configured prefix with the same layout as the
+//! cache.  B2 checks the SHA-1 of everything it receives, so uploads send the
+//! object's SHA-1 (computing it if the hash set lacks one), and downloads check the
+//! SHA-1 the server recorded before fetching the data.  Files larger than the
+//! multipart threshold are uploaded as B2 large files.
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::*;
+use relative_path::RelativePath;
+use reqwest::{Body, Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use tokio::fs::{File, metadata};
+use tokio::io::{AsyncReadExt, sink};
+use tokio::sync::RwLock;
+use tokio_util::io::ReaderStream;
+
+use crate::cache::{object_key, object_relpath, parse_object_relpath};
+use crate::filehash::{HashAlgo, MultiHash, MultiDigest, copy_hashed};
+use crate::settings::B2Settings;
+
+use super::{Remote, RemoteError};
+use super::http::save_verified;
+use super::s3::uri_encode;
+
+/// The number of attempts to make for an upload.
+const MAX_ATTEMPTS: usize = 3;
+/// The maximum number of file names to request at once.
+const LIST_PAGE_SIZE: usize = 1000;
+/// The content type for uploaded objects.
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct AuthorizeResponse {
+  account_id: String,
+  authorization_token: String,
+  api_url: String,
+  download_url: String,
+  absolute_minimum_part_size: u64,
+  allowed: Option<Allowed>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct Allowed {
+  bucket_id: Option<String>,
+  bucket_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct ListBucketsResponse {
+  buckets: Vec<BucketInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct BucketInfo {
+  bucket_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct UploadUrl {
+  upload_url: String,
+  authorization_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct FileVersion {
+  file_id: String,
+  file_name: String,
+  #[serde(default)]
+  action: String,
+  content_sha1: Option<String>,
+  #[serde(default)]
+  file_info: HashMap<String, String>,
+}
+
+impl FileVersion {
+  /// Get the SHA-1 B2 recorded for this file, if any.
+  fn sha1(&self) -> Option<&str> {
+    sha1_value(self.content_sha1.as_deref(), self.file_info.get("large_file_sha1").map(|s| s.as_str()))
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct ListFilesResponse {
+  files: Vec<FileVersion>,
+  next_file_name: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct ListFilesRequest<'a> {
+  bucket_id: &'a str,
+  prefix: &'a str,
+  start_file_name: Option<&'a str>,
+  max_file_count: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct StartLargeFileResponse {
+  file_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+  code: String,
+  message: Option<String>,
+}
+
+/// Pick the recorded SHA-1 of a file from its content SHA-1 or large-file info.
+///
+/// B2 does not know the SHA-1 of large files, so it reports `none` (possibly with
+/// an `unverified:` prefix on other values); we record it in the file info instead.
+fn sha1_value<'a>(content: Option<&'a str>, large: Option<&'a str>) -> Option<&'a str> {
+  match content.map(|s| s.strip_prefix("unverified:").unwrap_or(s)) {
+    Some("none") | None => large,
+    Some(s) => Some(s),
+  }
+}
+
+/// Turn an unsuccessful B2 response into an error.
+async fn check_response(resp: Response) -> Result<Response, RemoteError> {
+  let status = resp.status();
+  if status.is_success() {
+    return Ok(resp);
+  }
+  let body = resp.text().await.unwrap_or_default();
+  let msg = match serde_json::from_str::<ErrorResponse>(&body) {
+    Ok(err) => format!("{}: {}", err.code, err.message.unwrap_or_default()),
+    Err(_) => body,
+  };
+  Err(RemoteError::Server(status.as_u16(), msg))
+}
+
+/// Check whether an upload error is worth retrying with a new upload URL.
+fn is_retryable(err: &RemoteError) -> bool {
+  match err {
+    RemoteError::HTTPError(_) => true,
+    RemoteError::Server(status, _) => *status == 401 || *status == 408 || *status == 429 || *status >= 500,
+    _ => false,
+  }
+}
+
+/// An authorized B2 session.
+#[derive(Debug)]
+struct Session {
+  token: String,
+  api_url: String,
+  download_url: String,
+  bucket_id: String,
+  min_part_size: u64,
+}
+
+/// A remote that stores objects in a Backblaze B2 bucket.
+#[derive(Debug)]
+pub struct B2Remote {
+  client: Client,
+  auth_url: String,
+  bucket: String,
+  prefix: String,
+  key_id: String,
+  key: String,
+  multipart_threshold: u64,
+  part_size: u64,
+  session: RwLock<Option<Arc<Session>>>,
+}
+
+impl B2Remote {
+  /// Create a B2 remote from its settings.
+  ///
+  /// The application key is taken from the settings, or from the
+  /// `B2_APPLICATION_KEY_ID` and `B2_APPLICATION_KEY` environment variables.  The
+  /// account is not authorized until the first request.
+  pub fn new(settings: &B2Settings) -> Result<B2Remote, RemoteError> {
+    let key_id = settings.key_id.clone().or_else(|| env::var("B2_APPLICATION_KEY_ID").ok());
+    let key = settings.key.clone().or_else(|| env::var("B2_APPLICATION_KEY").ok());
+    let (key_id, key) = match (key_id, key) {
+      (Some(id), Some(k)) => (id, k),
+      _ => return Err(RemoteError::Config(format!("no B2 application key found for bucket {}", settings.bucket))),
+    };
+    Ok(B2Remote {
+      client: Client::new(),
+      auth_url: settings.api_url.trim_end_matches('/').to_owned(),
+      bucket: settings.bucket.clone(),
+      prefix: settings.prefix.trim_matches('/').to_owned(),
+      key_id,
+      key,
+      multipart_threshold: settings.multipart_threshold,
+      part_size: settings.part_size,
+      session: RwLock::new(None),
+    })
+  }
+
+  /// Get the B2 file name for an object.
+  fn file_name(&self, hash: &MultiHash) -> Result<String, RemoteError> {
+    let rel = object_relpath(hash)?;
+    if self.prefix.is_empty() {
+      Ok(rel.to_string())
+    } else {
+      Ok(format!("{}/{}", self.prefix, rel))
+    }
+  }
+
+  /// Authorize the account and look up the bucket.
+  async fn authorize(&self) -> Result<Arc<Session>, RemoteError> {
+    debug!("authorizing B2 account for bucket {}", self.bucket);
+    let url = format!("{}/b2api/v2/b2_authorize_account", self.auth_url);
+    let resp = self.client.get(url).basic_auth(&self.key_id, Some(&self.key)).send().await?;
+    let auth: AuthorizeResponse = check_response(resp).await?.json().await?;
+
+    let allowed = auth.allowed.as_ref();
+    let bucket_id = match allowed.and_then(|a| a.bucket_id.as_ref().zip(a.bucket_name.as_ref())) {
+      Some((id, name)) if *name == self.bucket => id.clone(),
+      Some((_, name)) => return Err(RemoteError::Config(format!("B2 key is restricted to bucket {}", name))),
+      None => {
+        let url = format!("{}/b2api/v2/b2_list_buckets", auth.api_url);
+        let body = json!({"accountId": auth.account_id, "bucketName": self.bucket});
+        let resp = self.client.post(url).header("authorization", &auth.authorization_token).json(&body).send().await?;
+        let buckets: ListBucketsResponse = check_response(resp).await?.json().await?;
+        match buckets.buckets.into_iter().next() {
+          Some(b) => b.bucket_id,
+          None => return Err(RemoteError::Config(format!("B2 bucket {} not found", self.bucket))),
+        }
+      }
+    };
+
+    let session = Arc::new(Session {
+      token: auth.authorization_token,
+      api_url: auth.api_url,
+      download_url: auth.download_url,
+      bucket_id,
+      min_part_size: auth.absolute_minimum_part_size,
+    });
+    *self.session.write().await = Some(session.clone());
+    Ok(session)
+  }
+
+  /// Get the current session, authorizing if needed.
+  async fn session(&self) -> Result<Arc<Session>, RemoteError> {
+    if let Some(s) = self.session.read().await.as_ref() {
+      return Ok(s.clone());
+    }
+    self.authorize().await
+  }
+
+  /// Call a B2 API operation, re-authorizing once if the token has expired.
+  async fn call<B: Serialize + Sync, T: DeserializeOwned>(&self, op: &str, body: &B) -> Result<T, RemoteError> {
+    let mut session = self.session().await?;
+    let mut reauthorized = false;
+    loop {
+      let url = format!("{}/b2api/v2/{}", session.api_url, op);
+      trace!("calling {}", op);
+      let resp = self.client.post(url).header("authorization", &session.token).json(body).send().await?;
+      if resp.status() == StatusCode::UNAUTHORIZED && !reauthorized {
+        debug!("{}: authorization rejected, re-authorizing", op);
+        session = self.authorize().await?;
+        reauthorized = true;
+        continue;
+      }
+      return Ok(check_response(resp).await?.json().await?);
+    }
+  }
+
+  /// Look up the current version of a file.
+  async fn find_file(&self, name: &str) -> Result<Option<FileVersion>, RemoteError> {
+    let session = self.session().await?;
+    let req = ListFilesRequest {
+      bucket_id: &session.bucket_id,
+      prefix: name,
+      start_file_name: Some(name),
+      max_file_count: 1,
+    };
+    let files: ListFilesResponse = self.call("b2_list_file_names", &req).await?;
+    Ok(files.files.into_iter().find(|f| f.file_name == name && f.action == "upload"))
+  }
+
+  /// Check the SHA-1 that B2 recorded for an object against its hashes.
+  ///
+  /// This verifies the stored object without downloading it.  Returns `None` if
+  /// either B2 or the hash set has no SHA-1 for the object.
+  pub async fn verify_sha1(&self, hash: &MultiHash) -> Result<Option<bool>, RemoteError> {
+    let name = self.file_name(hash)?;
+    let file = match self.find_file(&name).await? {
+      Some(f) => f,
+      None => return Err(RemoteError::NotFound(object_key(hash)?.to_string())),
+    };
+    match (file.sha1(), &hash.sha1) {
+      (Some(remote), Some(local)) => Ok(Some(remote.eq_ignore_ascii_case(&local.to_string()))),
+      _ => Ok(None),
+    }
+  }
+
+  /// Get the SHA-1 of a file to upload, checking the file against its hashes if
+  /// the SHA-1 has to be computed.
+  async fn upload_sha1(&self, hash: &MultiHash, src: &Path) -> Result<String, RemoteError> {
+    if let Some(sha1) = &hash.sha1 {
+      return Ok(sha1.to_string());
+    }
+    let mut algos = hash.algorithms();
+    algos.push(HashAlgo::Sha1);
+    let mut input = File::open(src).await?;
+    let (_, actual) = copy_hashed(&mut input, &mut sink(), MultiDigest::with_algorithms(&algos)).await?;
+    if !hash.matches(&actual) {
+      return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+    }
+    Ok(actual.sha1.expect("SHA-1 not computed").to_string())
+  }
+
+  /// Upload a file in a single request.
+  async fn upload_single(&self, name: &str, src: &Path, size: u64, sha1: &str) -> Result<(), RemoteError> {
+    let session = self.session().await?;
+    let mut attempt = 1;
+    loop {
+      let target: UploadUrl = self.call("b2_get_upload_url", &json!({"bucketId": session.bucket_id})).await?;
+      let body = Body::wrap_stream(ReaderStream::new(File::open(src).await?));
+      let res = self.client.post(&target.upload_url)
+        .header("authorization", &target.authorization_token)
+        .header("x-bz-file-name", uri_encode(name, false))
+        .header("content-type", CONTENT_TYPE)
+        .header("content-length", size)
+        .header("x-bz-content-sha1", sha1)
+        .body(body)
+        .send().await;
+      let res = match res {
+        Ok(resp) => check_response(resp).await.map(|_| ()),
+        Err(e) => Err(e.into()),
+      };
+      match res {
+        Err(e) if attempt < MAX_ATTEMPTS && is_retryable(&e) => {
+          warn!("{}: upload failed ({}), retrying", name, e);
+          attempt += 1;
+        },
+        r => return r,
+      }
+    }
+  }
+
+  /// Upload a file as a B2 large file.
+  async fn upload_large(&self, hash: &MultiHash, name: &str, src: &Path, sha1: &str) -> Result<(), RemoteError> {
+    let session = self.session().await?;
+    let start: StartLargeFileResponse = self.call("b2_start_large_file", &json!({
+      "bucketId": session.bucket_id,
+      "fileName": name,
+      "contentType": CONTENT_TYPE,
+      "fileInfo": {"large_file_sha1": sha1},
+    })).await?;
+    debug!("{}: started large file {}", name, start.file_id);
+
+    let res = match self.upload_parts(hash, name, src, &start.file_id).await {
+      Ok(shas) => {
+        let finish = json!({"fileId": start.file_id, "partSha1Array": shas});
+        self.call::<_, serde_json::Value>("b2_finish_large_file", &finish).await.map(|_| ())
+      },
+      Err(e) => Err(e),
+    };
+    if res.is_err() {
+      if let Err(e) = self.call::<_, serde_json::Value>("b2_cancel_large_file", &json!({"fileId": start.file_id})).await {
+        warn!("{}: failed to cancel large file {}: {}", name, start.file_id, e);
+      }
+    }
+    res
+  }
+
+  /// Upload the parts of a large file, returning their SHA-1 hashes.
+  async fn upload_parts(&self, hash: &MultiHash, name: &str, src: &Path, file_id: &str) -> Result<Vec<String>, RemoteError> {
+    let session = self.session().await?;
+    let part_size = self.part_size.max(session.min_part_size);
+    let mut target: UploadUrl = self.call("b2_get_upload_part_url", &json!({"fileId": file_id})).await?;
+    let mut file = File::open(src).await?;
+    let mut digest = MultiDigest::for_hash(hash);
+    let mut shas = Vec::new();
+    loop {
+      let mut chunk = Vec::with_capacity(part_size as usize);
+      (&mut file).take(part_size).read_to_end(&mut chunk).await?;
+      if chunk.is_empty() {
+        break;
+      }
+      digest.update(&chunk);
+      let part = shas.len() + 1;
+      let sha = hex::encode(Sha1::digest(&chunk));
+
+      let mut attempt = 1;
+      loop {
+        trace!("{}: uploading part {} ({} bytes)", name, part, chunk.len());
+        let res = self.client.post(&target.upload_url)
+          .header("authorization", &target.authorization_token)
+          .header("x-bz-part-number", part)
+          .header("content-length", chunk.len())
+          .header("x-bz-content-sha1", &sha)
+          .body(chunk.clone())
+          .send().await;
+        let res = match res {
+          Ok(resp) => check_response(resp).await.map(|_| ()),
+          Err(e) => Err(e.into()),
+        };
+        match res {
+          Ok(()) => break,
+          Err(e) if attempt < MAX_ATTEMPTS && is_retryable(&e) => {
+            warn!("{}: part {} failed ({}), retrying", name, part, e);
+            target = self.call("b2_get_upload_part_url", &json!({"fileId": file_id})).await?;
+            attempt += 1;
+          },
+          Err(e) => return Err(e),
+        }
+      }
+      shas.push(sha);
+    }
+
+    if !hash.matches(&digest.finish()) {
+      return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+    }
+    Ok(shas)
+  }
+}
+
+#[async_trait]
+impl Remote for B2Remote {
+  async fn exists(&self, hash: &MultiHash) -> Result<bool, RemoteError> {
+    let name = self.file_name(hash)?;
+    Ok(self.find_file(&name).await?.is_some())
+  }
+
+  async fn upload(&self, hash: &MultiHash, src: &Path) -> Result<(), RemoteError> {
+    let name = self.file_name(hash)?;
+    let size = metadata(src).await?.len();
+    debug!("uploading {:?} to b2://{}/{}", src, self.bucket, name);
+    let sha1 = self.upload_sha1(hash, src).await?;
+    if size > self.multipart_threshold {
+      self.upload_large(hash, &name, src, &sha1).await
+    } else {
+      self.upload_single(&name, src, size, &sha1).await
+    }
+  }
+
+  async fn download(&self, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError> {
+    let name = self.file_name(hash)?;
+    debug!("downloading b2://{}/{} to {:?}", self.bucket, name, dest);
+    let mut session = self.session().await?;
+    let mut reauthorized = false;
+    let resp = loop {
+      let url = format!("{}/file/{}/{}", session.download_url, uri_encode(&self.bucket, true), uri_encode(&name, false));
+      let resp = self.client.get(url).header("authorization", &session.token).send().await?;
+      match resp.status() {
+        StatusCode::UNAUTHORIZED if !reauthorized => {
+          session = self.authorize().await?;
+          reauthorized = true;
+        },
+        StatusCode::NOT_FOUND => return Err(RemoteError::NotFound(object_key(hash)?.to_string())),
+        _ => break check_response(resp).await?,
+      }
+    };
+
+    // check the server's recorded SHA-1 before fetching the data
+    let header = |h: &str| resp.headers().get(h).and_then(|v| v.to_str().ok());
+    let remote_sha1 = sha1_value(header("x-bz-content-sha1"), header("x-bz-info-large_file_sha1"));
+    if let (Some(remote), Some(local)) = (remote_sha1, &hash.sha1) {
+      if !remote.eq_ignore_ascii_case(&local.to_string()) {
+        return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+      }
+    }
+    save_verified(resp, hash, dest).await
+  }
+
+  async fn list(&self) -> Result<Vec<MultiHash>, RemoteError> {
+    let session = self.session().await?;
+    let prefix = if self.prefix.is_empty() {
+      String::new()
+    } else {
+      format!("{}/", self.prefix)
+    };
+    let mut objects = Vec::new();
+    let mut start: Option<String> = None;
+    loop {
+      let req = ListFilesRequest {
+        bucket_id: &session.bucket_id,
+        prefix: &prefix,
+        start_file_name: start.as_deref(),
+        max_file_count: LIST_PAGE_SIZE,
+      };
+      let page: ListFilesResponse = self.call("b2_list_file_names", &req).await?;
+      for file in page.files {
+        let rel = file.file_name.strip_prefix(&prefix).unwrap_or(&file.file_name);
+        match parse_object_relpath(RelativePath::new(rel)) {
+          Some(h) => objects.push(h),
+          None => trace!("{}: not an object, skipping", file.file_name),
+        }
+      }
+      match page.next_file_name {
+        Some(n) => start = Some(n),
+        None => break,
+      }
+    }
+    Ok(objects)
+  }
+
+  async fn delete(&self, hash: &MultiHash) -> Result<(), RemoteError> {
+    let session = self.session().await?;
+    let name = self.file_name(hash)?;
+    let req = ListFilesRequest {
+      bucket_id: &session.bucket_id,
+      prefix: &name,
+      start_file_name: Some(&name),
+      max_file_count: LIST_PAGE_SIZE,
+    };
+    let versions: ListFilesResponse = self.call("b2_list_file_versions", &req).await?;
+    let versions: Vec<_> = versions.files.into_iter().filter(|f| f.file_name == name).collect();
+    if versions.is_empty() {
+      return Err(RemoteError::NotFound(object_key(hash)?.to_string()));
+    }
+    // delete every version, so the object is really gone
+    for v in versions {
+      trace!("{}: deleting version {}", name, v.file_id);
+      let body = json!({"fileName": v.file_name, "fileId": v.file_id});
+      self.call::<_, serde_json::Value>("b2_delete_file_version", &body).await?;
+    }
+    Ok(())
+  }
+}
+
+#[test]
+fn test_sha1_value() {
+  assert_eq!(sha1_value(Some("abc"), None), Some("abc"));
+  assert_eq!(sha1_value(Some("unverified:abc"), None), Some("abc"));
+  assert_eq!(sha1_value(Some("none"), Some("def")), Some("def"));
+  assert_eq!(sha1_value(None, None), None);
+}
@@ -0,0 +1,371 @@
+//! Remote stored in a Google Drive folder.
+//!
+//! Objects are stored directly in the configured folder, named by their keys.
+//! Drive addresses files by ID rather than by path, so finding an object by name
+//! takes a search query; the remote keeps a local cache of object keys to file IDs
+//! (in the user configuration directory, next to the OAuth token) so downloads and
+//! deletions can usually skip the search.  Stale IDs are detected and looked up
+//! again.
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::*;
+use reqwest::{Body, Client, RequestBuilder, Response, StatusCode};
+use reqwest::header::LOCATION;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::fs::{File, create_dir_all, metadata};
+use tokio::sync::Mutex;
+use tokio_util::io::ReaderStream;
+
+use crate::cache::object_key;
+use crate::filehash::{HashAlgo, MultiHash, MultiDigest};
+use crate::settings::{GDriveSettings, user_config_dir};
+use crate::util::io::{read_file_string, write_file_atomic};
+
+use super::{Remote, RemoteError};
+use super::http::{check_status, save_verified};
+
+mod oauth;
+
+use oauth::Authenticator;
+pub use oauth::{AuthPrompt, DevicePrompt};
+
+/// The maximum number of files to request at once.
+const LIST_PAGE_SIZE: &str = "1000";
+/// The content type for uploaded objects.
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct DriveFile {
+  id: String,
+  #[serde(default)]
+  name: String,
+  md5_checksum: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct FileList {
+  files: Vec<DriveFile>,
+  next_page_token: Option<String>,
+}
+
+/// Quote a string for a Drive search query.
+fn quote(s: &str) -> String {
+  format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Make a string safe to use in a file name.
+fn file_name_safe(s: &str) -> String {
+  s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_' { c } else { '_' }).collect()
+}
+
+/// The local cache of object keys to Drive file IDs.
+#[derive(Debug)]
+struct IdCache {
+  path: PathBuf,
+  ids: BTreeMap<String, String>,
+}
+
+impl IdCache {
+  async fn load(path: PathBuf) -> Result<IdCache, RemoteError> {
+    let ids = match read_file_string(&path).await {
+      Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+        warn!("{:?}: invalid file ID cache, ignoring: {}", path, e);
+        BTreeMap::new()
+      }),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+      Err(e) => return Err(e.into()),
+    };
+    Ok(IdCache { path, ids })
+  }
+
+  async fn save(&self) -> Result<(), RemoteError> {
+    let json = serde_json::to_string(&self.ids).expect("ID cache serialization failed");
+    write_file_atomic(&self.path, json).await?;
+    Ok(())
+  }
+}
+
+/// A remote that stores objects in a Google Drive folder.
+#[derive(Debug)]
+pub struct GDriveRemote {
+  client: Client,
+  api_url: String,
+  folder_id: String,
+  auth: Authenticator,
+  // held across saves, so concurrent updates are written in order
+  ids: Mutex<IdCache>,
+}
+
+impl GDriveRemote {
+  /// Open a Google Drive remote, keeping its token and ID cache in the user
+  /// configuration directory.
+  pub async fn open(settings: &GDriveSettings) -> Result<GDriveRemote, RemoteError> {
+    let dir = user_config_dir()
+      .ok_or_else(|| RemoteError::Config("cannot determine the user configuration directory".into()))?;
+    GDriveRemote::with_state_dir(settings, &dir.join("gdrive")).await
+  }
+
+  /// Open a Google Drive remote, keeping its token and ID cache in a directory.
+  pub async fn with_state_dir(settings: &GDriveSettings, dir: &Path) -> Result<GDriveRemote, RemoteError> {
+    create_dir_all(dir).await?;
+    let client = Client::new();
+    let token_file = dir.join(format!("{}.token.json", file_name_safe(&settings.client_id)));
+    let id_file = dir.join(format!("{}.ids.json", file_name_safe(&settings.folder_id)));
+    Ok(GDriveRemote {
+      auth: Authenticator::new(client.clone(), settings, &token_file),
+      client,
+      api_url: settings.api_url.trim_end_matches('/').to_owned(),
+      folder_id: settings.folder_id.clone(),
+      ids: Mutex::new(IdCache::load(id_file).await?),
+    })
+  }
+
+  /// Set the function that shows the user how to authorize access, if needed.
+  pub fn with_prompt(mut self, prompt: AuthPrompt) -> GDriveRemote {
+    self.auth.set_prompt(prompt);
+    self
+  }
+
+  fn files_url(&self) -> String {
+    format!("{}/drive/v3/files", self.api_url)
+  }
+
+  fn file_url(&self, id: &str) -> String {
+    format!("{}/drive/v3/files/{}", self.api_url, id)
+  }
+
+  /// Send an authorized request, obtaining a new token and retrying once if the
+  /// token is rejected.
+  async fn send<F>(&self, build: F) -> Result<Response, RemoteError>
+  where F: Fn(&str) -> RequestBuilder + Sync
+  {
+    let token = self.auth.access_token().await?;
+    let resp = build(&token).send().await?;
+    if resp.status() != StatusCode::UNAUTHORIZED {
+      return Ok(resp);
+    }
+    self.auth.invalidate(&token).await;
+    let token = self.auth.access_token().await?;
+    Ok(build(&token).send().await?)
+  }
+
+  /// Search the folder for an object, updating the ID cache.
+  async fn search(&self, key: &str) -> Result<Option<String>, RemoteError> {
+    let q = format!("name = {} and {} in parents and trashed = false", quote(key), quote(&self.folder_id));
+    let resp = self.send(|t| {
+      self.client.get(self.files_url()).bearer_auth(t).query(&[
+        ("q", q.as_str()),
+        ("fields", "files(id,name)"),
+        ("supportsAllDrives", "true"),
+        ("includeItemsFromAllDrives", "true"),
+      ])
+    }).await?;
+    let list: FileList = check_status(resp).await?.json().await?;
+    let id = list.files.into_iter().next().map(|f| f.id);
+
+    let mut ids = self.ids.lock().await;
+    let changed = match &id {
+      Some(id) => ids.ids.insert(key.to_owned(), id.clone()).as_ref() != Some(id),
+      None => ids.ids.remove(key).is_some(),
+    };
+    if changed {
+      ids.save().await?;
+    }
+    Ok(id)
+  }
+
+  /// Get the file ID of an object, from the cache unless `fresh` is set.
+  async fn file_id(&self, key: &str, fresh: bool) -> Result<Option<String>, RemoteError> {
+    if !fresh {
+      if let Some(id) = self.ids.lock().await.ids.get(key) {
+        return Ok(Some(id.clone()));
+      }
+    }
+    self.search(key).await
+  }
+
+  async fn forget(&self, key: &str) -> Result<(), RemoteError> {
+    let mut ids = self.ids.lock().await;
+    if ids.ids.remove(key).is_some() {
+      ids.save().await?;
+    }
+    Ok(())
+  }
+
+  /// Delete a file by ID.
+  async fn delete_file(&self, id: &str) -> Result<Response, RemoteError> {
+    self.send(|t| {
+      self.client.delete(self.file_url(id)).bearer_auth(t).query(&[("supportsAllDrives", "true")])
+    }).await
+  }
+}
+
+#[async_trait]
+impl Remote for GDriveRemote {
+  async fn exists(&self, hash: &MultiHash) -> Result<bool, RemoteError> {
+    // always search, since a cached ID may have been deleted by someone else
+    let key = object_key(hash)?.to_string();
+    Ok(self.search(&key).await?.is_some())
+  }
+
+  async fn upload(&self, hash: &MultiHash, src: &Path) -> Result<(), RemoteError> {
+    let key = object_key(hash)?.to_string();
+    debug!("uploading {:?} to Drive folder {}", src, self.folder_id);
+    let size = metadata(src).await?.len();
+    let meta = json!({"name": key, "parents": [self.folder_id], "mimeType": CONTENT_TYPE});
+    let resp = self.send(|t| {
+      self.client.post(format!("{}/upload/drive/v3/files", self.api_url)).bearer_auth(t)
+        .query(&[("uploadType", "resumable"), ("fields", "id,name,md5Checksum"), ("supportsAllDrives", "true")])
+        .header("x-upload-content-type", CONTENT_TYPE)
+        .header("x-upload-content-length", size)
+        .json(&meta)
+    }).await?;
+    let resp = check_status(resp).await?;
+    let session = resp.headers().get(LOCATION).and_then(|v| v.to_str().ok())
+      .ok_or_else(|| RemoteError::Protocol("Drive did not return an upload session URL".into()))?
+      .to_owned();
+
+    // hash the data as it is sent, including MD5 to check against Drive's checksum
+    let mut algos = hash.algorithms();
+    if !algos.contains(&HashAlgo::Md5) {
+      algos.push(HashAlgo::Md5);
+    }
+    let digest = Arc::new(SyncMutex::new(Some(MultiDigest::with_algorithms(&algos))));
+    let stream = {
+      let digest = digest.clone();
+      ReaderStream::new(File::open(src).await?).map_ok(move |chunk| {
+        if let Some(d) = digest.lock().expect("poisoned lock").as_mut() {
+          d.update(&chunk);
+        }
+        chunk
+      })
+    };
+    // the file is only created once the session completes, so uploads are atomic
+    let resp = self.client.put(session)
+      .header("content-length", size)
+      .body(Body::wrap_stream(stream))
+      .send().await?;
+    let file: DriveFile = check_status(resp).await?.json().await?;
+
+    let actual = digest.lock().expect("poisoned lock").take().expect("digest missing").finish();
+    let md5 = actual.md5.as_ref().map(|h| h.to_string());
+    let drive_ok = match (&file.md5_checksum, &md5) {
+      (Some(remote), Some(local)) => remote.eq_ignore_ascii_case(local),
+      _ => true,
+    };
+    if !hash.matches(&actual) || !drive_ok {
+      if let Err(e) = self.delete_file(&file.id).await {
+        warn!("{}: failed to delete bad upload {}: {}", key, file.id, e);
+      }
+      return Err(RemoteError::Corrupt(key));
+    }
+
+    let mut ids = self.ids.lock().await;
+    ids.ids.insert(key, file.id);
+    ids.save().await
+  }
+
+  async fn download(&self, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError> {
+    let key = object_key(hash)?.to_string();
+    let mut fresh = false;
+    loop {
+      let id = match self.file_id(&key, fresh).await? {
+        Some(id) => id,
+        None => return Err(RemoteError::NotFound(key)),
+      };
+      debug!("downloading Drive file {} to {:?}", id, dest);
+      let resp = self.send(|t| {
+        self.client.get(self.file_url(&id)).bearer_auth(t).query(&[("alt", "media"), ("supportsAllDrives", "true")])
+      }).await?;
+      if resp.status() == StatusCode::NOT_FOUND {
+        if fresh {
+          self.forget(&key).await?;
+          return Err(RemoteError::NotFound(key));
+        }
+        debug!("{}: file {} is gone, searching again", key, id);
+        fresh = true;
+        continue;
+      }
+      let resp = check_status(resp).await?;
+      return save_verified(resp, hash, dest).await;
+    }
+  }
+
+  async fn list(&self) -> Result<Vec<MultiHash>, RemoteError> {
+    let q = format!("{} in parents and trashed = false", quote(&self.folder_id));
+    let mut objects = Vec::new();
+    let mut found = BTreeMap::new();
+    let mut page: Option<String> = None;
+    loop {
+      let resp = self.send(|t| {
+        let rb = self.client.get(self.files_url()).bearer_auth(t).query(&[
+          ("q", q.as_str()),
+          ("fields", "nextPageToken,files(id,name)"),
+          ("pageSize", LIST_PAGE_SIZE),
+          ("supportsAllDrives", "true"),
+          ("includeItemsFromAllDrives", "true"),
+        ]);
+        match &page {
+          Some(p) => rb.query(&[("pageToken", p)]),
+          None => rb,
+        }
+      }).await?;
+      let list: FileList = check_status(resp).await?.json().await?;
+      for file in list.files {
+        match file.name.parse() {
+          Ok(key) => {
+            objects.push(MultiHash { sha256: Some(key), ..MultiHash::default() });
+            found.insert(file.name, file.id);
+          },
+          Err(_) => trace!("{}: not an object, skipping", file.name),
+        }
+      }
+      match list.next_page_token {
+        Some(p) => page = Some(p),
+        None => break,
+      }
+    }
+
+    // the listing is authoritative, so it replaces the ID cache
+    let mut ids = self.ids.lock().await;
+    ids.ids = found;
+    ids.save().await?;
+    Ok(objects)
+  }
+
+  async fn delete(&self, hash: &MultiHash) -> Result<(), RemoteError> {
+    let key = object_key(hash)?.to_string();
+    let mut fresh = false;
+    loop {
+      let id = match self.file_id(&key, fresh).await? {
+        Some(id) => id,
+        None => return Err(RemoteError::NotFound(key)),
+      };
+      let resp = self.delete_file(&id).await?;
+      if resp.status() == StatusCode::NOT_FOUND && !fresh {
+        fresh = true;
+        continue;
+      }
+      if resp.status() == StatusCode::NOT_FOUND {
+        self.forget(&key).await?;
+        return Err(RemoteError::NotFound(key));
+      }
+      check_status(resp).await?;
+      return self.forget(&key).await;
+    }
+  }
+}
+
+#[test]
+fn test_quote() {
+  assert_eq!(quote("abc"), "'abc'");
+  assert_eq!(quote("it's"), "'it\\'s'");
+}
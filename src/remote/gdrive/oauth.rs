@@ -0,0 +1,287 @@
+//! OAuth 2.0 device authorization for Google APIs.
+//!
+//! AFC runs in terminals (often over SSH), so it uses the device flow: the user
+//! visits a URL on any device and enters a code while AFC polls for the token.  The
+//! token and its refresh token are cached in a file, so this normally only happens
+//! once per client.
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::*;
+use reqwest::{Client, Response};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::settings::GDriveSettings;
+use crate::util::io::{read_file_string, write_file_atomic};
+
+use super::super::RemoteError;
+
+/// The grant type for polling the device flow.
+const DEVICE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// How long before its expiration to stop using an access token, in seconds.
+const EXPIRY_MARGIN: u64 = 60;
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// A cached OAuth token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct Token {
+  pub access_token: String,
+  pub refresh_token: Option<String>,
+  /// The expiration time, in seconds since the Unix epoch.
+  pub expires_at: u64,
+}
+
+impl Token {
+  fn is_fresh(&self) -> bool {
+    now() + EXPIRY_MARGIN < self.expires_at
+  }
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+  access_token: String,
+  expires_in: u64,
+  refresh_token: Option<String>,
+}
+
+impl TokenResponse {
+  fn into_token(self, refresh: Option<&str>) -> Token {
+    Token {
+      access_token: self.access_token,
+      refresh_token: self.refresh_token.or_else(|| refresh.map(|s| s.to_owned())),
+      expires_at: now() + self.expires_in,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug)]
+struct DeviceCodeResponse {
+  device_code: String,
+  user_code: String,
+  #[serde(alias="verification_uri")]
+  verification_url: String,
+  expires_in: u64,
+  interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+  error: String,
+  error_description: Option<String>,
+}
+
+/// Parse an OAuth error response.
+async fn oauth_error(resp: Response) -> (u16, ErrorResponse) {
+  let status = resp.status().as_u16();
+  let body = resp.text().await.unwrap_or_default();
+  let err = serde_json::from_str(&body).unwrap_or(ErrorResponse {
+    error: "unknown_error".into(),
+    error_description: Some(body),
+  });
+  (status, err)
+}
+
+fn error_message(err: &ErrorResponse) -> String {
+  match &err.error_description {
+    Some(d) => format!("{}: {}", err.error, d),
+    None => err.error.clone(),
+  }
+}
+
+/// What the user must do to authorize access in the device flow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DevicePrompt {
+  /// The URL to visit.
+  pub url: String,
+  /// The code to enter there.
+  pub code: String,
+}
+
+/// A function that shows the user a [DevicePrompt].
+#[derive(Clone)]
+pub struct AuthPrompt(Arc<dyn Fn(&DevicePrompt) + Send + Sync>);
+
+impl AuthPrompt {
+  /// Create a prompt from a function.
+  pub fn new<F: Fn(&DevicePrompt) + Send + Sync + 'static>(func: F) -> AuthPrompt {
+    AuthPrompt(Arc::new(func))
+  }
+
+  /// Show a prompt to the user.
+  pub fn show(&self, prompt: &DevicePrompt) {
+    (self.0)(prompt)
+  }
+}
+
+impl fmt::Debug for AuthPrompt {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("AuthPrompt")
+  }
+}
+
+/// Obtains, refreshes, and caches access tokens for a client.
+#[derive(Debug)]
+pub(super) struct Authenticator {
+  client: Client,
+  oauth_url: String,
+  client_id: String,
+  client_secret: Option<String>,
+  scope: String,
+  token_file: PathBuf,
+  prompt: Option<AuthPrompt>,
+  // a tokio mutex, so concurrent requests wait for one authorization
+  token: Mutex<Option<Token>>,
+}
+
+impl Authenticator {
+  pub fn new(client: Client, settings: &GDriveSettings, token_file: &Path) -> Authenticator {
+    Authenticator {
+      client,
+      oauth_url: settings.oauth_url.trim_end_matches('/').to_owned(),
+      client_id: settings.client_id.clone(),
+      client_secret: settings.client_secret.clone(),
+      scope: settings.scope.clone(),
+      token_file: token_file.to_owned(),
+      prompt: None,
+      token: Mutex::new(None),
+    }
+  }
+
+  /// Set the function that shows the user how to authorize access.
+  ///
+  /// Without one, the instructions are only logged.
+  pub fn set_prompt(&mut self, prompt: AuthPrompt) {
+    self.prompt = Some(prompt);
+  }
+
+  /// Get a valid access token, refreshing or authorizing as needed.
+  pub async fn access_token(&self) -> Result<String, RemoteError> {
+    let mut token = self.token.lock().await;
+    if token.is_none() {
+      *token = self.load().await?;
+    }
+    if let Some(t) = token.as_ref().filter(|t| t.is_fresh()) {
+      return Ok(t.access_token.clone());
+    }
+
+    let refresh = token.as_ref().and_then(|t| t.refresh_token.clone());
+    let new = match refresh {
+      Some(rt) => match self.refresh(&rt).await {
+        Ok(t) => t,
+        Err(e) => {
+          warn!("could not refresh Google token ({}), authorizing again", e);
+          self.authorize().await?
+        }
+      },
+      None => self.authorize().await?,
+    };
+    self.save(&new).await?;
+    let access = new.access_token.clone();
+    *token = Some(new);
+    Ok(access)
+  }
+
+  /// Mark an access token as rejected, so the next request obtains a new one.
+  pub async fn invalidate(&self, access: &str) {
+    let mut token = self.token.lock().await;
+    if let Some(t) = token.as_mut().filter(|t| t.access_token == access) {
+      debug!("access token rejected, expiring it");
+      t.expires_at = 0;
+    }
+  }
+
+  async fn load(&self) -> Result<Option<Token>, RemoteError> {
+    match read_file_string(&self.token_file).await {
+      Ok(s) => match serde_json::from_str(&s) {
+        Ok(t) => Ok(Some(t)),
+        Err(e) => {
+          warn!("{:?}: invalid token file: {}", self.token_file, e);
+          Ok(None)
+        }
+      },
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn save(&self, token: &Token) -> Result<(), RemoteError> {
+    debug!("saving token to {:?}", self.token_file);
+    let json = serde_json::to_string_pretty(token).expect("token serialization failed");
+    write_file_atomic(&self.token_file, json).await?;
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      tokio::fs::set_permissions(&self.token_file, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    Ok(())
+  }
+
+  /// Request a token from the token endpoint.
+  async fn token_request(&self, params: &[(&str, &str)]) -> Result<Response, RemoteError> {
+    let mut form = vec![("client_id", self.client_id.as_str())];
+    if let Some(secret) = &self.client_secret {
+      form.push(("client_secret", secret.as_str()));
+    }
+    form.extend_from_slice(params);
+    let url = format!("{}/token", self.oauth_url);
+    Ok(self.client.post(url).form(&form).send().await?)
+  }
+
+  async fn refresh(&self, refresh: &str) -> Result<Token, RemoteError> {
+    debug!("refreshing Google access token");
+    let resp = self.token_request(&[("grant_type", "refresh_token"), ("refresh_token", refresh)]).await?;
+    if !resp.status().is_success() {
+      let (status, err) = oauth_error(resp).await;
+      return Err(RemoteError::Server(status, error_message(&err)));
+    }
+    let tr: TokenResponse = resp.json().await?;
+    Ok(tr.into_token(Some(refresh)))
+  }
+
+  /// Run the device authorization flow.
+  async fn authorize(&self) -> Result<Token, RemoteError> {
+    let url = format!("{}/device/code", self.oauth_url);
+    let form = [("client_id", self.client_id.as_str()), ("scope", self.scope.as_str())];
+    let resp = self.client.post(url).form(&form).send().await?;
+    if !resp.status().is_success() {
+      let (status, err) = oauth_error(resp).await;
+      return Err(RemoteError::Server(status, error_message(&err)));
+    }
+    let dc: DeviceCodeResponse = resp.json().await?;
+
+    let prompt = DevicePrompt { url: dc.verification_url.clone(), code: dc.user_code.clone() };
+    match &self.prompt {
+      Some(p) => p.show(&prompt),
+      None => warn!("to give AFC access to Google Drive, visit {} and enter the code {}", prompt.url, prompt.code),
+    }
+    let deadline = now() + dc.expires_in;
+    let mut interval = dc.interval.unwrap_or(5);
+    loop {
+      if now() > deadline {
+        return Err(RemoteError::Config("Google authorization code expired".into()));
+      }
+      let resp = self.token_request(&[("grant_type", DEVICE_GRANT), ("device_code", &dc.device_code)]).await?;
+      if resp.status().is_success() {
+        let tr: TokenResponse = resp.json().await?;
+        info!("authorized with Google");
+        return Ok(tr.into_token(None));
+      }
+      let (status, err) = oauth_error(resp).await;
+      match err.error.as_str() {
+        "authorization_pending" => (),
+        "slow_down" => interval += 5,
+        _ => return Err(RemoteError::Server(status, error_message(&err))),
+      }
+      trace!("authorization pending, waiting {}s", interval);
+      sleep(Duration::from_secs(interval)).await;
+    }
+  }
+}
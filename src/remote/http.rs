@@ -0,0 +1,137 @@
+//! Read-only remote served over plain HTTP(S).
+//!
+//! This only needs `GET` and `HEAD`, so a directory pushed to any other remote
+//! (e.g. a local or WebDAV remote) can be published from a static file server and
+//! pulled without credentials.
+use std::path::Path;
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::*;
+use reqwest::{Client, Response, StatusCode, Url};
+use tokio::fs::{File, create_dir_all, remove_file, rename};
+use tokio::io::AsyncWriteExt;
+
+use crate::cache::{object_key, object_relpath};
+use crate::filehash::{MultiHash, MultiDigest};
+use crate::util::io::sibling_temp_path;
+
+use super::{Remote, RemoteError};
+
+/// Parse a base URL, making sure it ends with `/` so object paths join under it.
+pub(super) fn parse_base_url(url: &str) -> Result<Url, RemoteError> {
+  let mut url = Url::parse(url).map_err(|e| RemoteError::Config(format!("invalid URL {}: {}", url, e)))?;
+  if !url.path().ends_with('/') {
+    let path = format!("{}/", url.path());
+    url.set_path(&path);
+  }
+  Ok(url)
+}
+
+/// Turn an unsuccessful response into an error.
+pub(super) async fn check_status(resp: Response) -> Result<Response, RemoteError> {
+  let status = resp.status();
+  if status.is_success() {
+    Ok(resp)
+  } else {
+    let msg = resp.text().await.unwrap_or_default();
+    Err(RemoteError::Server(status.as_u16(), msg))
+  }
+}
+
+/// Save the body of a response to a file, verifying it against an object's hashes.
+///
+/// The data is written to a temporary file, which is renamed into place once
+/// verified.
+pub(super) async fn save_verified(resp: Response, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError> {
+  if let Some(dir) = dest.parent() {
+    create_dir_all(dir).await?;
+  }
+  let tmp = sibling_temp_path(dest);
+  let mut output = File::create(&tmp).await?;
+  let mut digest = MultiDigest::for_hash(hash);
+  let mut stream = resp.bytes_stream();
+  let res: Result<(), RemoteError> = async {
+    while let Some(chunk) = stream.try_next().await? {
+      digest.update(&chunk);
+      output.write_all(&chunk).await?;
+    }
+    output.sync_all().await?;
+    Ok(())
+  }.await;
+  drop(output);
+  if let Err(e) = res {
+    let _ = remove_file(&tmp).await;
+    return Err(e);
+  }
+
+  if !hash.matches(&digest.finish()) {
+    remove_file(&tmp).await?;
+    return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+  }
+  rename(&tmp, dest).await?;
+  Ok(())
+}
+
+/// A read-only remote that fetches objects over HTTP(S).
+#[derive(Debug, Clone)]
+pub struct HttpRemote {
+  client: Client,
+  base: Url,
+}
+
+impl HttpRemote {
+  /// Create an HTTP remote with a base URL.
+  pub fn new(url: &str) -> Result<HttpRemote, RemoteError> {
+    Ok(HttpRemote {
+      client: Client::new(),
+      base: parse_base_url(url)?,
+    })
+  }
+
+  /// Get the base URL of this remote.
+  pub fn base_url(&self) -> &Url {
+    &self.base
+  }
+
+  fn object_url(&self, hash: &MultiHash) -> Result<Url, RemoteError> {
+    let rel = object_relpath(hash)?;
+    self.base.join(rel.as_str()).map_err(|e| RemoteError::Config(e.to_string()))
+  }
+}
+
+#[async_trait]
+impl Remote for HttpRemote {
+  async fn exists(&self, hash: &MultiHash) -> Result<bool, RemoteError> {
+    let url = self.object_url(hash)?;
+    let resp = self.client.head(url).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+      return Ok(false);
+    }
+    check_status(resp).await?;
+    Ok(true)
+  }
+
+  async fn upload(&self, _hash: &MultiHash, _src: &Path) -> Result<(), RemoteError> {
+    Err(RemoteError::ReadOnly)
+  }
+
+  async fn download(&self, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError> {
+    let url = self.object_url(hash)?;
+    debug!("downloading {} to {:?}", url, dest);
+    let resp = self.client.get(url).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+      return Err(RemoteError::NotFound(object_key(hash)?.to_string()));
+    }
+    let resp = check_status(resp).await?;
+    save_verified(resp, hash, dest).await
+  }
+
+  async fn list(&self) -> Result<Vec<MultiHash>, RemoteError> {
+    Err(RemoteError::Unsupported("HTTP remotes cannot list objects".into()))
+  }
+
+  async fn delete(&self, _hash: &MultiHash) -> Result<(), RemoteError> {
+    Err(RemoteError::ReadOnly)
+  }
+}
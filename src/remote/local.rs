@@ -0,0 +1,193 @@
+//! Remote stored in a local (or network-mounted) directory.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::*;
+use relative_path::RelativePathBuf;
+use tokio::fs::{File, create_dir_all, remove_file, rename};
+
+use crate::cache::{CacheError, CacheLayout};
+use crate::cache::manifest::DIR_SUFFIX;
+use crate::filehash::{MultiHash, MultiDigest, DigestValue, copy_hashed};
+use crate::util::io::{path_exists, sibling_temp_path};
+use crate::util::walk::walk_directory;
+
+use super::{Remote, RemoteError};
+
+/// A remote that stores objects in a directory, with the same layout as a cache.
+///
+/// With a DVC layout, this can also fetch from (and push to) a DVC cache or a DVC
+/// local remote.
+#[derive(Debug, Clone)]
+pub struct LocalRemote {
+  root: PathBuf,
+  layout: CacheLayout,
+}
+
+impl LocalRemote {
+  /// Create a local remote rooted at a directory.
+  pub fn new<P: AsRef<Path>>(root: P) -> LocalRemote {
+    LocalRemote {
+      root: root.as_ref().to_owned(),
+      layout: CacheLayout::Afc,
+    }
+  }
+
+  /// Set the layout of objects in the remote directory.
+  pub fn with_layout(self, layout: CacheLayout) -> LocalRemote {
+    LocalRemote { layout, ..self }
+  }
+
+  /// Get the root directory of this remote.
+  pub fn root_path(&self) -> &Path {
+    self.root.as_path()
+  }
+
+  fn object_path(&self, hash: &MultiHash) -> Result<PathBuf, RemoteError> {
+    Ok(self.layout.object_relpath(hash)?.to_path(&self.root))
+  }
+
+  /// Find the path of an existing object, if it is present.
+  async fn find_object(&self, hash: &MultiHash) -> Result<Option<PathBuf>, RemoteError> {
+    for rel in self.layout.object_relpaths(hash)? {
+      let path = rel.to_path(&self.root);
+      if path_exists(&path).await? {
+        return Ok(Some(path));
+      }
+    }
+    Ok(None)
+  }
+
+  fn manifest_path(&self, md5: &DigestValue<16>) -> Result<PathBuf, RemoteError> {
+    let rel = self.layout.manifest_relpath(md5).ok_or(CacheError::NoManifests(self.layout))?;
+    Ok(rel.to_path(&self.root))
+  }
+
+  fn not_found(&self, hash: &MultiHash) -> RemoteError {
+    match self.layout.object_key(hash) {
+      Ok(key) => RemoteError::NotFound(key),
+      Err(e) => e.into(),
+    }
+  }
+}
+
+/// Copy a file to a destination through a temporary file, verifying its hashes.
+///
+/// `key` names the object in the error if it is corrupt.
+async fn copy_verified(key: &str, hash: &MultiHash, src: &Path, dest: &Path) -> Result<(), RemoteError> {
+  if let Some(dir) = dest.parent() {
+    create_dir_all(dir).await?;
+  }
+  let tmp = sibling_temp_path(dest);
+  let mut input = File::open(src).await?;
+  let mut output = File::create(&tmp).await?;
+  let res = copy_hashed(&mut input, &mut output, MultiDigest::for_hash(hash)).await;
+  let res = match res {
+    Ok(r) => output.sync_all().await.map(|_| r),
+    Err(e) => Err(e),
+  };
+  drop(output);
+  let actual = match res {
+    Ok((_, h)) => h,
+    Err(e) => {
+      let _ = remove_file(&tmp).await;
+      return Err(e.into());
+    }
+  };
+
+  if !hash.matches(&actual) {
+    remove_file(&tmp).await?;
+    return Err(RemoteError::Corrupt(key.to_owned()));
+  }
+
+  rename(&tmp, dest).await?;
+  Ok(())
+}
+
+/// Get the key of a directory manifest, for error messages.
+fn manifest_key(md5: &DigestValue<16>) -> String {
+  format!("{}{}", md5, DIR_SUFFIX)
+}
+
+/// Get the hashes to verify a directory manifest against (its MD5).
+fn manifest_hash(md5: &DigestValue<16>) -> MultiHash {
+  MultiHash { md5: Some(md5.clone()), ..MultiHash::default() }
+}
+
+#[async_trait]
+impl Remote for LocalRemote {
+  async fn exists(&self, hash: &MultiHash) -> Result<bool, RemoteError> {
+    Ok(self.find_object(hash).await?.is_some())
+  }
+
+  async fn upload(&self, hash: &MultiHash, src: &Path) -> Result<(), RemoteError> {
+    let dest = self.object_path(hash)?;
+    debug!("uploading {:?} to {:?}", src, dest);
+    copy_verified(&self.layout.object_key(hash)?, hash, src, &dest).await
+  }
+
+  async fn download(&self, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError> {
+    let src = match self.find_object(hash).await? {
+      Some(p) => p,
+      None => return Err(self.not_found(hash)),
+    };
+    debug!("downloading {:?} to {:?}", src, dest);
+    copy_verified(&self.layout.object_key(hash)?, hash, &src, dest).await
+  }
+
+  async fn list(&self) -> Result<Vec<MultiHash>, RemoteError> {
+    let mut objects = Vec::new();
+    if !path_exists(&self.root).await? {
+      return Ok(objects);
+    }
+    let mut stream = walk_directory(&self.root);
+    while let Some(de) = stream.try_next().await? {
+      if !de.file_type()?.is_file() {
+        continue;
+      }
+      let path = de.path();
+      let rel = match path.strip_prefix(&self.root).ok().and_then(|p| RelativePathBuf::from_path(p).ok()) {
+        Some(p) => p,
+        None => continue,
+      };
+      match self.layout.parse_object_relpath(&rel) {
+        Some(h) => objects.push(h),
+        None => trace!("{}: not an object, skipping", rel),
+      }
+    }
+    Ok(objects)
+  }
+
+  async fn delete(&self, hash: &MultiHash) -> Result<(), RemoteError> {
+    let path = match self.find_object(hash).await? {
+      Some(p) => p,
+      None => return Err(self.not_found(hash)),
+    };
+    match remove_file(&path).await {
+      Ok(()) => Ok(()),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Err(self.not_found(hash)),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn manifest_exists(&self, md5: &DigestValue<16>) -> Result<bool, RemoteError> {
+    Ok(path_exists(&self.manifest_path(md5)?).await?)
+  }
+
+  async fn upload_manifest(&self, md5: &DigestValue<16>, src: &Path) -> Result<(), RemoteError> {
+    let dest = self.manifest_path(md5)?;
+    debug!("uploading manifest {:?} to {:?}", src, dest);
+    copy_verified(&manifest_key(md5), &manifest_hash(md5), src, &dest).await
+  }
+
+  async fn download_manifest(&self, md5: &DigestValue<16>, dest: &Path) -> Result<(), RemoteError> {
+    let src = self.manifest_path(md5)?;
+    if !path_exists(&src).await? {
+      return Err(RemoteError::NotFound(manifest_key(md5)));
+    }
+    debug!("downloading manifest {:?} to {:?}", src, dest);
+    copy_verified(&manifest_key(md5), &manifest_hash(md5), &src, dest).await
+  }
+}
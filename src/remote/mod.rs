@@ -1 +1,126 @@
 //! Remotes and their operations.
+//!
+//! A remote is a content-addressed object store that AFC can push cached data to
+//! and pull it back from.  Objects are keyed by their hashes (currently their
+//! SHA-256 digest), just like the local cache.
+use std::io;
+use std::path::Path;
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+use crate::cache::CacheError;
+use crate::filehash::{DigestValue, MultiHash};
+use crate::settings::RemoteSettings;
+
+mod local;
+mod http;
+mod webdav;
+mod b2;
+mod gdrive;
+pub mod s3;
+#[cfg(unix)]
+mod sftp;
+
+pub use local::LocalRemote;
+pub use http::HttpRemote;
+pub use webdav::WebDavRemote;
+pub use b2::B2Remote;
+pub use gdrive::{AuthPrompt, DevicePrompt, GDriveRemote};
+pub use s3::S3Remote;
+#[cfg(unix)]
+pub use sftp::SftpRemote;
+
+/// An error that occurred in a remote operation.
+#[derive(Error, Debug)]
+pub enum RemoteError {
+  #[error("IO error occurred: {0}")]
+  IOError(#[from] io::Error),
+  #[error("cache error: {0}")]
+  CacheError(#[from] CacheError),
+  #[error("object {0} is not on the remote")]
+  NotFound(String),
+  #[error("object {0} does not match its recorded hashes")]
+  Corrupt(String),
+  #[error("remote configuration error: {0}")]
+  Config(String),
+  #[error("HTTP error: {0}")]
+  HTTPError(#[from] reqwest::Error),
+  #[error("server returned status {0}: {1}")]
+  Server(u16, String),
+  #[error("protocol error: {0}")]
+  Protocol(String),
+  #[error("remote is read-only")]
+  ReadOnly,
+  #[error("unsupported operation: {0}")]
+  Unsupported(String),
+  #[cfg(unix)]
+  #[error("SFTP error: {0}")]
+  SFTPError(#[from] openssh_sftp_client::Error),
+}
+
+/// Interface for remote object stores.
+///
+/// Implementations must make uploads atomic: an interrupted upload must never leave
+/// a partial object visible under its final name.  Downloads must verify the data
+/// against the requested hashes before placing it at its destination.
+#[async_trait]
+pub trait Remote: Send + Sync {
+  /// Check whether the remote has an object.
+  async fn exists(&self, hash: &MultiHash) -> Result<bool, RemoteError>;
+
+  /// Upload a file to the remote as the object with the specified hashes.
+  async fn upload(&self, hash: &MultiHash, src: &Path) -> Result<(), RemoteError>;
+
+  /// Download an object from the remote to a destination file.
+  async fn download(&self, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError>;
+
+  /// List the objects on the remote.
+  async fn list(&self) -> Result<Vec<MultiHash>, RemoteError>;
+
+  /// Delete an object from the remote.
+  async fn delete(&self, hash: &MultiHash) -> Result<(), RemoteError>;
+
+  /// Check whether the remote has a DVC directory manifest.
+  ///
+  /// Only remotes with a DVC layout store manifests; others return
+  /// [RemoteError::Unsupported].
+  async fn manifest_exists(&self, _md5: &DigestValue<16>) -> Result<bool, RemoteError> {
+    Err(RemoteError::Unsupported("directory manifests".into()))
+  }
+
+  /// Upload a file to the remote as the DVC directory manifest `md5`.
+  async fn upload_manifest(&self, _md5: &DigestValue<16>, _src: &Path) -> Result<(), RemoteError> {
+    Err(RemoteError::Unsupported("directory manifests".into()))
+  }
+
+  /// Download a DVC directory manifest from the remote to a destination file.
+  async fn download_manifest(&self, _md5: &DigestValue<16>, _dest: &Path) -> Result<(), RemoteError> {
+    Err(RemoteError::Unsupported("directory manifests".into()))
+  }
+}
+
+/// Open a remote from its settings.
+///
+/// `root` is the work tree root, for resolving relative paths in the settings.
+/// Remotes that need the user to authorize access show them how with `prompt`.
+pub async fn open_remote(root: &Path, settings: &RemoteSettings, prompt: Option<AuthPrompt>) -> Result<Box<dyn Remote>, RemoteError> {
+  match settings {
+    RemoteSettings::Local { path, layout } => Ok(Box::new(LocalRemote::new(root.join(path)).with_layout(*layout))),
+    RemoteSettings::S3(s3) => Ok(Box::new(S3Remote::open(s3).await?)),
+    #[cfg(unix)]
+    RemoteSettings::Sftp(sftp) => Ok(Box::new(SftpRemote::connect(sftp).await?)),
+    #[cfg(not(unix))]
+    RemoteSettings::Sftp(_) => Err(RemoteError::Config("SFTP remotes are not supported on this platform".into())),
+    RemoteSettings::WebDav(dav) => Ok(Box::new(WebDavRemote::new(dav)?)),
+    RemoteSettings::B2(b2) => Ok(Box::new(B2Remote::new(b2)?)),
+    RemoteSettings::GDrive(gd) => {
+      let remote = GDriveRemote::open(gd).await?;
+      Ok(Box::new(match prompt {
+        Some(p) => remote.with_prompt(p),
+        None => remote,
+      }))
+    },
+    RemoteSettings::Http { url } => Ok(Box::new(HttpRemote::new(url)?)),
+  }
+}
@@ -0,0 +1,113 @@
+//! Credential lookup for S3 remotes.
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+use log::*;
+
+use crate::settings::S3Settings;
+use crate::util::io::read_file_string;
+
+use super::sign::Credentials;
+use super::super::RemoteError;
+
+/// Find the AWS shared credentials file.
+fn shared_credentials_path() -> Option<PathBuf> {
+  if let Some(path) = env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+    return Some(path.into());
+  }
+  let home = if cfg!(windows) {
+    env::var_os("USERPROFILE")
+  } else {
+    env::var_os("HOME")
+  };
+  home.map(|h| PathBuf::from(h).join(".aws").join("credentials"))
+}
+
+/// Parse a profile from the contents of an AWS shared credentials file.
+fn parse_profile(content: &str, profile: &str) -> Option<Credentials> {
+  let mut in_profile = false;
+  let mut key_id = None;
+  let mut secret = None;
+  let mut token = None;
+  for line in content.lines() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+      continue;
+    }
+    if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+      in_profile = section.trim() == profile;
+    } else if in_profile {
+      if let Some((k, v)) = line.split_once('=') {
+        let v = v.trim().to_owned();
+        match k.trim() {
+          "aws_access_key_id" => key_id = Some(v),
+          "aws_secret_access_key" => secret = Some(v),
+          "aws_session_token" => token = Some(v),
+          _ => (),
+        }
+      }
+    }
+  }
+
+  Some(Credentials {
+    access_key_id: key_id?,
+    secret_access_key: secret?,
+    session_token: token,
+  })
+}
+
+/// Look up the credentials for an S3 remote.
+///
+/// Credentials are taken from the remote settings, then the `AWS_ACCESS_KEY_ID` and
+/// `AWS_SECRET_ACCESS_KEY` environment variables, then the AWS shared credentials
+/// file (using the configured profile, `AWS_PROFILE`, or `default`).
+pub async fn find_credentials(settings: &S3Settings) -> Result<Credentials, RemoteError> {
+  if let (Some(id), Some(secret)) = (&settings.access_key_id, &settings.secret_access_key) {
+    debug!("using S3 credentials from settings");
+    return Ok(Credentials {
+      access_key_id: id.clone(),
+      secret_access_key: secret.clone(),
+      session_token: None,
+    });
+  }
+
+  if let (Ok(id), Ok(secret)) = (env::var("AWS_ACCESS_KEY_ID"), env::var("AWS_SECRET_ACCESS_KEY")) {
+    debug!("using S3 credentials from environment");
+    return Ok(Credentials {
+      access_key_id: id,
+      secret_access_key: secret,
+      session_token: env::var("AWS_SESSION_TOKEN").ok(),
+    });
+  }
+
+  let profile = settings.profile.clone()
+    .or_else(|| env::var("AWS_PROFILE").ok())
+    .unwrap_or_else(|| "default".into());
+  if let Some(path) = shared_credentials_path() {
+    match read_file_string(&path).await {
+      Ok(content) => {
+        if let Some(creds) = parse_profile(&content, &profile) {
+          debug!("using S3 credentials from profile {} in {:?}", profile, path);
+          return Ok(creds);
+        }
+      },
+      Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+      Err(e) => return Err(e.into()),
+    }
+  }
+
+  Err(RemoteError::Config(format!("no S3 credentials found for bucket {}", settings.bucket)))
+}
+
+#[test]
+fn test_parse_profile() {
+  let content = "[default]\naws_access_key_id = AKID\naws_secret_access_key = secret\n\n[other]\naws_access_key_id=K2\naws_secret_access_key=S2\naws_session_token=T2\n";
+  let creds = parse_profile(content, "default").expect("no default profile");
+  assert_eq!(creds.access_key_id, "AKID");
+  assert_eq!(creds.secret_access_key, "secret");
+  assert!(creds.session_token.is_none());
+  let creds = parse_profile(content, "other").expect("no other profile");
+  assert_eq!(creds.session_token.as_deref(), Some("T2"));
+  assert!(parse_profile(content, "missing").is_none());
+}
@@ -0,0 +1,480 @@
+//! Remote stored in an S3 (or S3-compatible) bucket.
+//!
+//! This talks to the S3 REST API directly, signing requests with AWS Signature
+//! Version 4, so it works with S3-compatible stores such as MinIO.  Objects are
+//! stored under the configured key prefix with the same layout as the cache.
+//!
+//! Uploads send the object's SHA-256 as the payload hash, so the server verifies
+//! the data it receives; files larger than the multipart threshold are uploaded in
+//! parts.  Downloads are written to a partial file, named by the object's key, in
+//! a `tmp` directory next to the destination, and resumed with ranged requests if
+//! the connection is interrupted, even by a later download to another destination.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::*;
+use relative_path::RelativePath;
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio::fs::{File, OpenOptions, create_dir_all, metadata, remove_file, rename};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, sink};
+use tokio_util::io::ReaderStream;
+
+use crate::cache::{object_key, object_relpath, parse_object_relpath, PARTIAL_DIR};
+use crate::filehash::{MultiHash, MultiDigest, copy_hashed};
+use crate::settings::S3Settings;
+
+use super::{Remote, RemoteError};
+
+mod sign;
+mod credentials;
+
+pub use sign::Credentials;
+pub use credentials::find_credentials;
+pub(super) use sign::uri_encode;
+use sign::{EMPTY_SHA256, SigningRequest, amz_date, authorization, canonical_query};
+
+/// The number of attempts to make for an interrupted download.
+const MAX_ATTEMPTS: usize = 3;
+/// The minimum part size S3 allows for multipart uploads.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Response to a ListObjectsV2 request.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="PascalCase")]
+struct ListBucketResult {
+  #[serde(default)]
+  contents: Vec<ListEntry>,
+  #[serde(default)]
+  is_truncated: bool,
+  next_continuation_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="PascalCase")]
+struct ListEntry {
+  key: String,
+}
+
+/// Response to a CreateMultipartUpload request.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="PascalCase")]
+struct InitiateMultipartUploadResult {
+  upload_id: String,
+}
+
+/// An S3 error response.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all="PascalCase")]
+struct ErrorResponse {
+  code: String,
+  message: Option<String>,
+}
+
+/// A remote that stores objects in an S3 bucket.
+#[derive(Debug, Clone)]
+pub struct S3Remote {
+  client: Client,
+  base: Url,
+  path_style: bool,
+  bucket: String,
+  prefix: String,
+  region: String,
+  creds: Credentials,
+  multipart_threshold: u64,
+  part_size: u64,
+}
+
+/// Get the path of the partial file used while downloading an object to `dest`.
+fn partial_path(hash: &MultiHash, dest: &Path) -> Result<PathBuf, RemoteError> {
+  let dir = dest.parent().unwrap_or_else(|| Path::new("."));
+  Ok(dir.join(PARTIAL_DIR).join(format!("{}.part", object_key(hash)?)))
+}
+
+/// Turn an unsuccessful response into an error.
+async fn check_response(resp: Response) -> Result<Response, RemoteError> {
+  let status = resp.status();
+  if status.is_success() {
+    return Ok(resp);
+  }
+  let body = resp.text().await.unwrap_or_default();
+  let msg = match quick_xml::de::from_str::<ErrorResponse>(&body) {
+    Ok(err) => format!("{}: {}", err.code, err.message.unwrap_or_default()),
+    Err(_) => body,
+  };
+  Err(RemoteError::Server(status.as_u16(), msg))
+}
+
+/// Parse an XML response body.
+fn parse_xml<'de, T: Deserialize<'de>>(body: &'de str) -> Result<T, RemoteError> {
+  quick_xml::de::from_str(body).map_err(|e| RemoteError::Protocol(format!("invalid S3 response: {}", e)))
+}
+
+/// Read up to `size` bytes from a file.
+async fn read_chunk(file: &mut File, size: u64) -> io::Result<Vec<u8>> {
+  let mut buf = Vec::with_capacity(size as usize);
+  file.take(size).read_to_end(&mut buf).await?;
+  Ok(buf)
+}
+
+impl S3Remote {
+  /// Create an S3 remote with its settings and credentials.
+  pub fn new(settings: &S3Settings, creds: Credentials) -> Result<S3Remote, RemoteError> {
+    let endpoint = match &settings.endpoint {
+      Some(e) => e.clone(),
+      None => format!("https://s3.{}.amazonaws.com", settings.region),
+    };
+    let mut base = Url::parse(&endpoint).map_err(|e| RemoteError::Config(format!("invalid S3 endpoint {}: {}", endpoint, e)))?;
+    let path_style = settings.path_style.unwrap_or(settings.endpoint.is_some());
+    let host = match base.host_str() {
+      Some(h) => h.to_owned(),
+      None => return Err(RemoteError::Config(format!("S3 endpoint {} has no host", endpoint))),
+    };
+    if !path_style {
+      // virtual-hosted style addresses the bucket in the host name
+      let vhost = format!("{}.{}", settings.bucket, host);
+      base.set_host(Some(&vhost)).map_err(|e| {
+        RemoteError::Config(format!("bucket {:?} is not a valid host name: {}", settings.bucket, e))
+      })?;
+    }
+    Ok(S3Remote {
+      client: Client::new(),
+      base,
+      path_style,
+      bucket: settings.bucket.clone(),
+      prefix: settings.prefix.trim_matches('/').to_owned(),
+      region: settings.region.clone(),
+      creds,
+      multipart_threshold: settings.multipart_threshold,
+      part_size: settings.part_size.max(MIN_PART_SIZE),
+    })
+  }
+
+  /// Open an S3 remote, looking up its credentials with [find_credentials].
+  pub async fn open(settings: &S3Settings) -> Result<S3Remote, RemoteError> {
+    let creds = find_credentials(settings).await?;
+    S3Remote::new(settings, creds)
+  }
+
+  /// Get the bucket key for an object.
+  fn object_key(&self, hash: &MultiHash) -> Result<String, RemoteError> {
+    let rel = object_relpath(hash)?;
+    if self.prefix.is_empty() {
+      Ok(rel.to_string())
+    } else {
+      Ok(format!("{}/{}", self.prefix, rel))
+    }
+  }
+
+  /// Get the origin (scheme, host and port) and request path for a key in the bucket.
+  fn location(&self, key: Option<&str>) -> (Url, String) {
+    let key = key.map(|k| uri_encode(k, false)).unwrap_or_default();
+    let url = self.base.clone();
+    if self.path_style {
+      let path = if key.is_empty() {
+        format!("/{}", self.bucket)
+      } else {
+        format!("/{}/{}", self.bucket, key)
+      };
+      (url, path)
+    } else {
+      (url, format!("/{}", key))
+    }
+  }
+
+  /// Build a signed request.
+  fn request(&self, method: Method, key: Option<&str>, query: &[(String, String)], payload_hash: &str, extra: &[(String, String)]) -> RequestBuilder {
+    let (origin, path) = self.location(key);
+    let mut host = origin.host_str().unwrap_or_default().to_owned();
+    if let Some(port) = origin.port() {
+      host = format!("{}:{}", host, port);
+    }
+
+    let date = amz_date(OffsetDateTime::now_utc());
+    let mut headers = vec![
+      ("host".to_string(), host),
+      ("x-amz-content-sha256".to_string(), payload_hash.to_string()),
+      ("x-amz-date".to_string(), date.clone()),
+    ];
+    if let Some(tok) = &self.creds.session_token {
+      headers.push(("x-amz-security-token".to_string(), tok.clone()));
+    }
+    headers.extend(extra.iter().cloned());
+
+    let req = SigningRequest {
+      method: method.as_str(),
+      path: &path,
+      query,
+      headers: &headers,
+      payload_hash,
+    };
+    let auth = authorization(&self.creds, &self.region, "s3", &date, &req);
+
+    let qs = canonical_query(query);
+    let mut url = format!("{}{}", origin.origin().ascii_serialization(), path);
+    if !qs.is_empty() {
+      url.push('?');
+      url.push_str(&qs);
+    }
+    trace!("{} {}", method, url);
+
+    let mut rb = self.client.request(method, url).header("authorization", auth);
+    for (k, v) in headers {
+      if k != "host" {
+        rb = rb.header(k, v);
+      }
+    }
+    rb
+  }
+
+  /// Upload a file in a single request.
+  async fn upload_single(&self, hash: &MultiHash, key: &str, src: &Path, size: u64) -> Result<(), RemoteError> {
+    let sha = object_key(hash)?.to_string();
+    let file = File::open(src).await?;
+    let body = Body::wrap_stream(ReaderStream::new(file));
+    let len = [("content-length".to_string(), size.to_string())];
+    let resp = self.request(Method::PUT, Some(key), &[], &sha, &len).body(body).send().await?;
+    check_response(resp).await?;
+    Ok(())
+  }
+
+  /// Upload a file with a multipart upload.
+  async fn upload_multipart(&self, hash: &MultiHash, key: &str, src: &Path) -> Result<(), RemoteError> {
+    let query = [("uploads".to_string(), String::new())];
+    let resp = self.request(Method::POST, Some(key), &query, EMPTY_SHA256, &[]).send().await?;
+    let body = check_response(resp).await?.text().await?;
+    let init: InitiateMultipartUploadResult = parse_xml(&body)?;
+    debug!("{}: started multipart upload {}", key, init.upload_id);
+
+    let res = match self.upload_parts(hash, key, src, &init.upload_id).await {
+      Ok(parts) => self.complete_multipart(key, &init.upload_id, &parts).await,
+      Err(e) => Err(e),
+    };
+    if res.is_err() {
+      let query = [("uploadId".to_string(), init.upload_id.clone())];
+      let abort = self.request(Method::DELETE, Some(key), &query, EMPTY_SHA256, &[]).send().await;
+      if let Err(e) = abort {
+        warn!("{}: failed to abort upload {}: {}", key, init.upload_id, e);
+      }
+    }
+    res
+  }
+
+  /// Upload the parts of a multipart upload, returning their ETags.
+  async fn upload_parts(&self, hash: &MultiHash, key: &str, src: &Path, upload_id: &str) -> Result<Vec<String>, RemoteError> {
+    let mut file = File::open(src).await?;
+    let mut digest = MultiDigest::for_hash(hash);
+    let mut etags = Vec::new();
+    loop {
+      let chunk = read_chunk(&mut file, self.part_size).await?;
+      if chunk.is_empty() && !etags.is_empty() {
+        break;
+      }
+      digest.update(&chunk);
+      let part = etags.len() + 1;
+      trace!("{}: uploading part {} ({} bytes)", key, part, chunk.len());
+      let query = [
+        ("partNumber".to_string(), part.to_string()),
+        ("uploadId".to_string(), upload_id.to_string()),
+      ];
+      let sha = hex::encode(Sha256::digest(&chunk));
+      let last = (chunk.len() as u64) < self.part_size;
+      let resp = self.request(Method::PUT, Some(key), &query, &sha, &[]).body(chunk).send().await?;
+      let resp = check_response(resp).await?;
+      let etag = match resp.headers().get("etag").and_then(|v| v.to_str().ok()) {
+        Some(e) => e.to_owned(),
+        None => return Err(RemoteError::Protocol(format!("no ETag for part {} of {}", part, key))),
+      };
+      etags.push(etag);
+      if last {
+        break;
+      }
+    }
+
+    if !hash.matches(&digest.finish()) {
+      return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+    }
+    Ok(etags)
+  }
+
+  /// Complete a multipart upload.
+  async fn complete_multipart(&self, key: &str, upload_id: &str, etags: &[String]) -> Result<(), RemoteError> {
+    let mut xml = String::from("<CompleteMultipartUpload>");
+    for (i, etag) in etags.iter().enumerate() {
+      xml.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, etag));
+    }
+    xml.push_str("</CompleteMultipartUpload>");
+
+    let query = [("uploadId".to_string(), upload_id.to_string())];
+    let sha = hex::encode(Sha256::digest(xml.as_bytes()));
+    let resp = self.request(Method::POST, Some(key), &query, &sha, &[]).body(xml).send().await?;
+    let status = resp.status();
+    let body = check_response(resp).await?.text().await?;
+    // S3 can report a failure to complete with a 200 status and an error body
+    if body.contains("<Error>") {
+      let err: ErrorResponse = parse_xml(&body)?;
+      return Err(RemoteError::Server(status.as_u16(), format!("{}: {}", err.code, err.message.unwrap_or_default())));
+    }
+    debug!("{}: completed multipart upload with {} parts", key, etags.len());
+    Ok(())
+  }
+
+  /// Fetch an object into a partial file, starting at `offset`.
+  async fn fetch(&self, hash: &MultiHash, key: &str, part: &Path, offset: u64) -> Result<(), RemoteError> {
+    let mut extra = Vec::new();
+    if offset > 0 {
+      debug!("{}: resuming download at byte {}", key, offset);
+      extra.push(("range".to_string(), format!("bytes={}-", offset)));
+    }
+    let resp = self.request(Method::GET, Some(key), &[], EMPTY_SHA256, &extra).send().await?;
+    match resp.status() {
+      StatusCode::NOT_FOUND => return Err(RemoteError::NotFound(object_key(hash)?.to_string())),
+      // the partial file already has all the data
+      StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(()),
+      _ => (),
+    }
+    let resp = check_response(resp).await?;
+
+    let mut file = if resp.status() == StatusCode::PARTIAL_CONTENT {
+      OpenOptions::new().append(true).open(part).await?
+    } else {
+      File::create(part).await?
+    };
+    let mut stream = resp.bytes_stream();
+    while let Some(chunk) = stream.try_next().await? {
+      file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Remote for S3Remote {
+  async fn exists(&self, hash: &MultiHash) -> Result<bool, RemoteError> {
+    let key = self.object_key(hash)?;
+    let resp = self.request(Method::HEAD, Some(&key), &[], EMPTY_SHA256, &[]).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+      return Ok(false);
+    }
+    check_response(resp).await?;
+    Ok(true)
+  }
+
+  async fn upload(&self, hash: &MultiHash, src: &Path) -> Result<(), RemoteError> {
+    let key = self.object_key(hash)?;
+    let size = metadata(src).await?.len();
+    debug!("uploading {:?} to s3://{}/{}", src, self.bucket, key);
+    if size > self.multipart_threshold {
+      self.upload_multipart(hash, &key, src).await
+    } else {
+      self.upload_single(hash, &key, src, size).await
+    }
+  }
+
+  async fn download(&self, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError> {
+    let key = self.object_key(hash)?;
+    debug!("downloading s3://{}/{} to {:?}", self.bucket, key, dest);
+    let part = partial_path(hash, dest)?;
+    if let Some(dir) = part.parent() {
+      create_dir_all(dir).await?;
+    }
+
+    let mut attempt = 1;
+    loop {
+      let offset = match metadata(&part).await {
+        Ok(m) => m.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+      };
+      match self.fetch(hash, &key, &part, offset).await {
+        Ok(()) => break,
+        Err(RemoteError::HTTPError(e)) if attempt < MAX_ATTEMPTS => {
+          warn!("{}: download interrupted ({}), retrying", key, e);
+          attempt += 1;
+        },
+        Err(e) => return Err(e),
+      }
+    }
+
+    let mut input = File::open(&part).await?;
+    let (_, actual) = copy_hashed(&mut input, &mut sink(), MultiDigest::for_hash(hash)).await?;
+    drop(input);
+    if !hash.matches(&actual) {
+      remove_file(&part).await?;
+      return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+    }
+    rename(&part, dest).await?;
+    Ok(())
+  }
+
+  async fn list(&self) -> Result<Vec<MultiHash>, RemoteError> {
+    let prefix = if self.prefix.is_empty() {
+      String::new()
+    } else {
+      format!("{}/", self.prefix)
+    };
+    let mut objects = Vec::new();
+    let mut token: Option<String> = None;
+    loop {
+      let mut query = vec![
+        ("list-type".to_string(), "2".to_string()),
+        ("prefix".to_string(), prefix.clone()),
+      ];
+      if let Some(t) = &token {
+        query.push(("continuation-token".to_string(), t.clone()));
+      }
+      let resp = self.request(Method::GET, None, &query, EMPTY_SHA256, &[]).send().await?;
+      let body = check_response(resp).await?.text().await?;
+      let page: ListBucketResult = parse_xml(&body)?;
+      for entry in page.contents {
+        let rel = entry.key.strip_prefix(&prefix).unwrap_or(&entry.key);
+        match parse_object_relpath(RelativePath::new(rel)) {
+          Some(h) => objects.push(h),
+          None => trace!("{}: not an object, skipping", entry.key),
+        }
+      }
+      match (page.is_truncated, page.next_continuation_token) {
+        (true, Some(t)) => token = Some(t),
+        _ => break,
+      }
+    }
+    Ok(objects)
+  }
+
+  async fn delete(&self, hash: &MultiHash) -> Result<(), RemoteError> {
+    // S3 deletes succeed for missing objects, so check first
+    if !self.exists(hash).await? {
+      return Err(RemoteError::NotFound(object_key(hash)?.to_string()));
+    }
+    let key = self.object_key(hash)?;
+    let resp = self.request(Method::DELETE, Some(&key), &[], EMPTY_SHA256, &[]).send().await?;
+    check_response(resp).await?;
+    Ok(())
+  }
+}
+
+#[test]
+fn test_invalid_bucket_host() {
+  let creds = Credentials {
+    access_key_id: "AKID".into(),
+    secret_access_key: "secret".into(),
+    session_token: None,
+  };
+  let settings: S3Settings = toml::from_str("bucket = \"my bucket\"\npath_style = false\n").expect("invalid settings");
+  match S3Remote::new(&settings, creds.clone()) {
+    Err(RemoteError::Config(_)) => (),
+    Err(e) => panic!("unexpected error: {}", e),
+    Ok(_) => panic!("invalid bucket accepted"),
+  }
+
+  let settings: S3Settings = toml::from_str("bucket = \"my-bucket\"\nregion = \"us-east-1\"\n").expect("invalid settings");
+  let remote = S3Remote::new(&settings, creds).expect("valid bucket rejected");
+  let (url, path) = remote.location(Some("ab/cd"));
+  assert_eq!(url.host_str(), Some("my-bucket.s3.us-east-1.amazonaws.com"));
+  assert_eq!(path, "/ab/cd");
+}
@@ -0,0 +1,433 @@
+//! Remote stored in a directory on a host reachable over SSH.
+//!
+//! Connections go through the system OpenSSH client, so host aliases, keys, and
+//! other options in `~/.ssh/config` apply, and keys are taken from the SSH agent.
+//! Objects are stored under the remote directory with the same layout as the
+//! cache, and are uploaded to a temporary name and renamed into place.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::collections::BTreeSet;
+#[cfg(test)]
+use std::io;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::*;
+use openssh::{KnownHosts, SessionBuilder};
+use openssh_sftp_client::{Sftp, SftpOptions};
+use openssh_sftp_client::error::{Error as SftpError, SftpErrorKind};
+use openssh_sftp_client::file::TokioCompatFile;
+use openssh_sftp_client::fs::Fs;
+use relative_path::RelativePathBuf;
+use tokio::fs::{File, create_dir_all, remove_file, rename};
+use tokio::io::AsyncWriteExt;
+
+use crate::cache::{object_key, object_relpath, parse_object_relpath};
+use crate::filehash::{MultiHash, MultiDigest, copy_hashed};
+use crate::settings::SftpSettings;
+use crate::util::io::sibling_temp_path;
+
+use super::{Remote, RemoteError};
+
+/// Check whether an SFTP error means the file does not exist.
+fn is_not_found(err: &SftpError) -> bool {
+  matches!(err, SftpError::SftpError(SftpErrorKind::NoSuchFile, _))
+}
+
+/// The remote file operations used to put objects in place.
+///
+/// This is implemented for SFTP's [Fs], and lets the upload logic be tested
+/// without a server.
+#[async_trait]
+trait RemoteFs {
+  /// Check whether a path exists.
+  async fn exists(&mut self, path: &Path) -> Result<bool, RemoteError>;
+  /// Create a directory, whose parent must exist.
+  async fn create_dir(&mut self, path: &Path) -> Result<(), RemoteError>;
+  /// Rename a file; like plain SFTP, this may fail if the destination exists.
+  async fn rename(&mut self, from: &Path, to: &Path) -> Result<(), RemoteError>;
+  /// Remove a file.
+  async fn remove_file(&mut self, path: &Path) -> Result<(), RemoteError>;
+}
+
+#[async_trait]
+impl RemoteFs for Fs {
+  async fn exists(&mut self, path: &Path) -> Result<bool, RemoteError> {
+    match self.metadata(path).await {
+      Ok(_) => Ok(true),
+      Err(e) if is_not_found(&e) => Ok(false),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn create_dir(&mut self, path: &Path) -> Result<(), RemoteError> {
+    Ok(Fs::create_dir(self, path).await?)
+  }
+
+  async fn rename(&mut self, from: &Path, to: &Path) -> Result<(), RemoteError> {
+    Ok(Fs::rename(self, from, to).await?)
+  }
+
+  async fn remove_file(&mut self, path: &Path) -> Result<(), RemoteError> {
+    Ok(Fs::remove_file(self, path).await?)
+  }
+}
+
+/// Get the path of an object under a remote's root directory.
+fn object_path(root: &Path, hash: &MultiHash) -> Result<PathBuf, RemoteError> {
+  Ok(object_relpath(hash)?.to_path(root))
+}
+
+/// Create a remote directory and its parents, if they do not exist.
+async fn create_dirs<F: RemoteFs + Send>(fs: &mut F, dir: &Path) -> Result<(), RemoteError> {
+  let mut missing = Vec::new();
+  let mut cur = Some(dir);
+  while let Some(d) = cur {
+    if fs.exists(d).await? {
+      break;
+    }
+    missing.push(d);
+    cur = d.parent().filter(|p| !p.as_os_str().is_empty());
+  }
+  for d in missing.into_iter().rev() {
+    if let Err(e) = fs.create_dir(d).await {
+      // another upload may have created it in the meantime
+      if !fs.exists(d).await.unwrap_or(false) {
+        return Err(e);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Put a file in place on a remote.
+///
+/// The file's directory is created, `send` writes its data to `tmp`, and `tmp` is
+/// renamed to `dest`; if either step fails, `tmp` is removed.  A file that is
+/// already at `dest` (e.g. from a concurrent upload) is left in place.
+async fn put_file<F, S>(fs: &mut F, dest: &Path, tmp: &Path, send: S) -> Result<(), RemoteError>
+where F: RemoteFs + Send, S: Future<Output=Result<(), RemoteError>> + Send
+{
+  if let Some(dir) = dest.parent() {
+    create_dirs(fs, dir).await?;
+  }
+
+  if let Err(e) = send.await {
+    let _ = fs.remove_file(tmp).await;
+    return Err(e);
+  }
+
+  if let Err(e) = fs.rename(tmp, dest).await {
+    // plain SFTP rename fails if the destination exists
+    let _ = fs.remove_file(tmp).await;
+    if fs.exists(dest).await? {
+      debug!("{:?}: already uploaded", dest);
+    } else {
+      return Err(e);
+    }
+  }
+  Ok(())
+}
+
+/// A remote that stores objects in a directory on an SSH host.
+pub struct SftpRemote {
+  host: String,
+  root: PathBuf,
+  sftp: Sftp,
+}
+
+impl SftpRemote {
+  /// Connect to an SFTP remote.
+  ///
+  /// The host key must already be known (e.g. by having connected with `ssh`).
+  pub async fn connect(settings: &SftpSettings) -> Result<SftpRemote, RemoteError> {
+    let mut builder = SessionBuilder::default();
+    builder.known_hosts_check(KnownHosts::Strict);
+    if let Some(user) = &settings.user {
+      builder.user(user.clone());
+    }
+    if let Some(port) = settings.port {
+      builder.port(port);
+    }
+    debug!("connecting to {}", settings.host);
+    let session = builder.connect(&settings.host).await.map_err(SftpError::from)?;
+    let sftp = Sftp::from_session(session, SftpOptions::default()).await?;
+    Ok(SftpRemote {
+      host: settings.host.clone(),
+      root: settings.path.clone(),
+      sftp,
+    })
+  }
+
+  /// Get the root directory of this remote on its host.
+  pub fn root_path(&self) -> &Path {
+    self.root.as_path()
+  }
+
+  /// Close the connection.
+  pub async fn close(self) -> Result<(), RemoteError> {
+    self.sftp.close().await?;
+    Ok(())
+  }
+
+  fn object_path(&self, hash: &MultiHash) -> Result<PathBuf, RemoteError> {
+    object_path(&self.root, hash)
+  }
+
+  /// Write a local file to a remote path, verifying its hashes as it is sent.
+  async fn send(&self, hash: &MultiHash, src: &Path, dest: &Path) -> Result<(), RemoteError> {
+    let mut input = File::open(src).await?;
+    let mut output = Box::pin(TokioCompatFile::new(self.sftp.create(dest).await?));
+    let (_, actual) = copy_hashed(&mut input, &mut output, MultiDigest::for_hash(hash)).await?;
+    // wait for all writes to be acknowledged; dropping the file closes it
+    output.shutdown().await?;
+    drop(output);
+    if !hash.matches(&actual) {
+      return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Remote for SftpRemote {
+  async fn exists(&self, hash: &MultiHash) -> Result<bool, RemoteError> {
+    let path = self.object_path(hash)?;
+    RemoteFs::exists(&mut self.sftp.fs(), &path).await
+  }
+
+  async fn upload(&self, hash: &MultiHash, src: &Path) -> Result<(), RemoteError> {
+    let dest = self.object_path(hash)?;
+    debug!("uploading {:?} to {}:{:?}", src, self.host, dest);
+    let tmp = sibling_temp_path(&dest);
+    put_file(&mut self.sftp.fs(), &dest, &tmp, self.send(hash, src, &tmp)).await
+  }
+
+  async fn download(&self, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError> {
+    let src = self.object_path(hash)?;
+    debug!("downloading {}:{:?} to {:?}", self.host, src, dest);
+    let input = match self.sftp.open(&src).await {
+      Ok(f) => f,
+      Err(e) if is_not_found(&e) => return Err(RemoteError::NotFound(object_key(hash)?.to_string())),
+      Err(e) => return Err(e.into()),
+    };
+    let mut input = Box::pin(TokioCompatFile::new(input));
+
+    if let Some(dir) = dest.parent() {
+      create_dir_all(dir).await?;
+    }
+    let tmp = sibling_temp_path(dest);
+    let mut output = File::create(&tmp).await?;
+    let res = copy_hashed(&mut input, &mut output, MultiDigest::for_hash(hash)).await;
+    let res = match res {
+      Ok(r) => output.sync_all().await.map(|_| r),
+      Err(e) => Err(e),
+    };
+    drop(output);
+    let actual = match res {
+      Ok((_, h)) => h,
+      Err(e) => {
+        let _ = remove_file(&tmp).await;
+        return Err(e.into());
+      }
+    };
+    drop(input);
+
+    if !hash.matches(&actual) {
+      remove_file(&tmp).await?;
+      return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+    }
+    rename(&tmp, dest).await?;
+    Ok(())
+  }
+
+  async fn list(&self) -> Result<Vec<MultiHash>, RemoteError> {
+    let mut fs = self.sftp.fs();
+    let mut objects = Vec::new();
+    let mut queue = vec![RelativePathBuf::new()];
+    while let Some(rel) = queue.pop() {
+      let dir = match fs.open_dir(rel.to_path(&self.root)).await {
+        Ok(d) => d,
+        Err(e) if is_not_found(&e) && rel.as_str().is_empty() => return Ok(objects),
+        Err(e) => return Err(e.into()),
+      };
+      let mut entries = Box::pin(dir.read_dir());
+      while let Some(de) = entries.try_next().await? {
+        let name = de.filename().to_string_lossy().into_owned();
+        if name == "." || name == ".." {
+          continue;
+        }
+        let path = rel.join(&name);
+        match de.file_type() {
+          Some(t) if t.is_dir() => queue.push(path),
+          Some(t) if t.is_file() => match parse_object_relpath(&path) {
+            Some(h) => objects.push(h),
+            None => trace!("{}: not an object, skipping", path),
+          },
+          _ => (),
+        }
+      }
+    }
+    Ok(objects)
+  }
+
+  async fn delete(&self, hash: &MultiHash) -> Result<(), RemoteError> {
+    let path = self.object_path(hash)?;
+    match self.sftp.fs().remove_file(&path).await {
+      Ok(()) => Ok(()),
+      Err(e) if is_not_found(&e) => Err(RemoteError::NotFound(object_key(hash)?.to_string())),
+      Err(e) => Err(e.into()),
+    }
+  }
+}
+
+/// An in-memory [RemoteFs] for testing, holding the set of existing paths.
+#[cfg(test)]
+#[derive(Default, Clone)]
+struct MemFs {
+  paths: Arc<Mutex<BTreeSet<PathBuf>>>,
+}
+
+#[cfg(test)]
+impl MemFs {
+  fn with_dir(dir: &str) -> MemFs {
+    let fs = MemFs::default();
+    fs.add(Path::new(dir));
+    fs
+  }
+
+  fn add(&self, path: &Path) {
+    self.paths.lock().unwrap().insert(path.to_owned());
+  }
+
+  fn has(&self, path: &Path) -> bool {
+    self.paths.lock().unwrap().contains(path)
+  }
+}
+
+#[cfg(test)]
+fn mem_error(kind: io::ErrorKind, path: &Path) -> RemoteError {
+  RemoteError::IOError(io::Error::new(kind, format!("{:?}", path)))
+}
+
+#[cfg(test)]
+#[async_trait]
+impl RemoteFs for MemFs {
+  async fn exists(&mut self, path: &Path) -> Result<bool, RemoteError> {
+    Ok(self.has(path))
+  }
+
+  async fn create_dir(&mut self, path: &Path) -> Result<(), RemoteError> {
+    match path.parent() {
+      _ if self.has(path) => Err(mem_error(io::ErrorKind::AlreadyExists, path)),
+      Some(p) if !self.has(p) => Err(mem_error(io::ErrorKind::NotFound, p)),
+      _ => {
+        self.add(path);
+        Ok(())
+      },
+    }
+  }
+
+  async fn rename(&mut self, from: &Path, to: &Path) -> Result<(), RemoteError> {
+    let mut paths = self.paths.lock().unwrap();
+    if paths.contains(to) {
+      Err(mem_error(io::ErrorKind::AlreadyExists, to))
+    } else if !paths.remove(from) {
+      Err(mem_error(io::ErrorKind::NotFound, from))
+    } else {
+      paths.insert(to.to_owned());
+      Ok(())
+    }
+  }
+
+  async fn remove_file(&mut self, path: &Path) -> Result<(), RemoteError> {
+    match self.paths.lock().unwrap().remove(path) {
+      true => Ok(()),
+      false => Err(mem_error(io::ErrorKind::NotFound, path)),
+    }
+  }
+}
+
+#[cfg(test)]
+fn test_hash() -> MultiHash {
+  MultiHash {
+    sha256: Some("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".parse().unwrap()),
+    ..MultiHash::default()
+  }
+}
+
+#[test]
+fn test_object_path() {
+  let path = object_path(Path::new("/srv/afc"), &test_hash()).expect("no path");
+  assert_eq!(path, Path::new("/srv/afc/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"));
+  // uploads go to a temporary file in the same directory
+  let tmp = sibling_temp_path(&path);
+  assert_eq!(tmp.parent(), path.parent());
+  assert_ne!(tmp, path);
+}
+
+#[tokio::test]
+async fn test_put_file() {
+  let mut fs = MemFs::with_dir("/srv");
+  let dest = object_path(Path::new("/srv/afc"), &test_hash()).unwrap();
+  let tmp = sibling_temp_path(&dest);
+  let sent = fs.clone();
+  put_file(&mut fs, &dest, &tmp, async { sent.add(&tmp); Ok(()) }).await.expect("put failed");
+  assert!(fs.has(Path::new("/srv/afc/9f")));
+  assert!(fs.has(&dest));
+  assert!(!fs.has(&tmp));
+}
+
+#[tokio::test]
+async fn test_put_file_send_fails() {
+  let mut fs = MemFs::with_dir("/srv");
+  let dest = object_path(Path::new("/srv"), &test_hash()).unwrap();
+  let tmp = sibling_temp_path(&dest);
+  let sent = fs.clone();
+  let send = async {
+    sent.add(&tmp);
+    Err(RemoteError::Corrupt("9f86d08".into()))
+  };
+  let res = put_file(&mut fs, &dest, &tmp, send).await;
+  assert!(matches!(res, Err(RemoteError::Corrupt(_))));
+  assert!(!fs.has(&tmp));
+  assert!(!fs.has(&dest));
+}
+
+#[tokio::test]
+async fn test_put_file_exists() {
+  // a concurrent upload got there first
+  let mut fs = MemFs::with_dir("/srv");
+  let dest = object_path(Path::new("/srv"), &test_hash()).unwrap();
+  fs.add(dest.parent().unwrap());
+  fs.add(&dest);
+  let tmp = sibling_temp_path(&dest);
+  let sent = fs.clone();
+  put_file(&mut fs, &dest, &tmp, async { sent.add(&tmp); Ok(()) }).await.expect("put failed");
+  assert!(fs.has(&dest));
+  assert!(!fs.has(&tmp));
+}
+
+#[tokio::test]
+async fn test_put_file_rename_fails() {
+  let mut fs = MemFs::with_dir("/srv");
+  let dest = object_path(Path::new("/srv"), &test_hash()).unwrap();
+  let tmp = sibling_temp_path(&dest);
+  // the temporary file is never written, so it cannot be renamed
+  let res = put_file(&mut fs, &dest, &tmp, async { Ok(()) }).await;
+  assert!(res.is_err());
+  assert!(!fs.has(&dest));
+}
+
+#[test]
+fn test_settings_ssh_alias() {
+  // without a user or port, they come from the SSH configuration for the alias
+  let settings: SftpSettings = toml::from_str("host = \"backup\"\npath = \"afc\"\n").expect("invalid settings");
+  assert_eq!(settings.host, "backup");
+  assert_eq!(settings.path, Path::new("afc"));
+  assert!(settings.user.is_none());
+  assert!(settings.port.is_none());
+}
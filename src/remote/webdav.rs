@@ -0,0 +1,305 @@
+//! Remote stored on a WebDAV server.
+//!
+//! Objects are stored under the base URL with the same layout as the cache.  Only
+//! downloads use plain `GET`, so the same directory can also be published as a
+//! read-only [HttpRemote](super::HttpRemote).
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::*;
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use relative_path::RelativePath;
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use tokio::fs::{File, metadata};
+use tokio_util::io::ReaderStream;
+
+use crate::cache::{object_key, object_relpath, parse_object_relpath};
+use crate::filehash::{MultiHash, MultiDigest};
+use crate::settings::WebDavSettings;
+use crate::util::io::sibling_temp_path;
+
+use super::{Remote, RemoteError};
+use super::http::{check_status, parse_base_url, save_verified};
+
+/// The body of a PROPFIND request for just the resource type.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:"><D:prop><D:resourcetype/></D:prop></D:propfind>"#;
+
+/// An entry in a PROPFIND multistatus response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct DavEntry {
+  href: String,
+  collection: bool,
+}
+
+/// Parse a WebDAV multistatus response.
+fn parse_multistatus(body: &str) -> Result<Vec<DavEntry>, RemoteError> {
+  let mut reader = Reader::from_str(body);
+  reader.trim_text(true);
+  let mut entries = Vec::new();
+  let mut cur: Option<DavEntry> = None;
+  let mut in_href = false;
+  loop {
+    let event = reader.read_event().map_err(|e| RemoteError::Protocol(format!("invalid WebDAV response: {}", e)))?;
+    match event {
+      Event::Start(e) => match e.local_name().as_ref() {
+        b"response" => cur = Some(DavEntry::default()),
+        b"href" => in_href = true,
+        b"collection" => if let Some(c) = cur.as_mut() { c.collection = true },
+        _ => (),
+      },
+      Event::Empty(e) if e.local_name().as_ref() == b"collection" => {
+        if let Some(c) = cur.as_mut() {
+          c.collection = true;
+        }
+      },
+      Event::Text(t) if in_href => {
+        if let Some(c) = cur.as_mut() {
+          let text = t.unescape().map_err(|e| RemoteError::Protocol(format!("invalid WebDAV response: {}", e)))?;
+          c.href.push_str(&text);
+        }
+      },
+      Event::End(e) => match e.local_name().as_ref() {
+        b"response" => if let Some(c) = cur.take() { entries.push(c) },
+        b"href" => in_href = false,
+        _ => (),
+      },
+      Event::Eof => break,
+      _ => (),
+    }
+  }
+  Ok(entries)
+}
+
+fn dav_method(name: &'static str) -> Method {
+  Method::from_bytes(name.as_bytes()).expect("invalid method name")
+}
+
+/// A remote that stores objects on a WebDAV server.
+#[derive(Debug, Clone)]
+pub struct WebDavRemote {
+  client: Client,
+  base: Url,
+  user: Option<String>,
+  password: Option<String>,
+}
+
+impl WebDavRemote {
+  /// Create a WebDAV remote from its settings.
+  pub fn new(settings: &WebDavSettings) -> Result<WebDavRemote, RemoteError> {
+    Ok(WebDavRemote {
+      client: Client::new(),
+      base: parse_base_url(&settings.url)?,
+      user: settings.user.clone(),
+      password: settings.password.clone(),
+    })
+  }
+
+  /// Get the base URL of this remote.
+  pub fn base_url(&self) -> &Url {
+    &self.base
+  }
+
+  fn join(&self, base: &Url, path: &str) -> Result<Url, RemoteError> {
+    base.join(path).map_err(|e| RemoteError::Config(e.to_string()))
+  }
+
+  fn object_url(&self, hash: &MultiHash) -> Result<Url, RemoteError> {
+    self.join(&self.base, object_relpath(hash)?.as_str())
+  }
+
+  /// Start a request, with credentials if configured.
+  fn request(&self, method: Method, url: Url) -> RequestBuilder {
+    let rb = self.client.request(method, url);
+    match &self.user {
+      Some(user) => rb.basic_auth(user, self.password.as_ref()),
+      None => rb,
+    }
+  }
+
+  /// Run a PROPFIND request, returning `None` if the resource does not exist.
+  async fn propfind(&self, url: Url, depth: &str) -> Result<Option<Vec<DavEntry>>, RemoteError> {
+    let resp = self.request(dav_method("PROPFIND"), url)
+      .header("depth", depth)
+      .header("content-type", "application/xml")
+      .body(PROPFIND_BODY)
+      .send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+      return Ok(None);
+    }
+    let body = check_status(resp).await?.text().await?;
+    Ok(Some(parse_multistatus(&body)?))
+  }
+
+  /// Create a collection, returning the failed response if it could not be created.
+  async fn mkcol(&self, url: &Url) -> Result<Option<Response>, RemoteError> {
+    let resp = self.request(dav_method("MKCOL"), url.clone()).send().await?;
+    // 405 means the collection already exists
+    if resp.status().is_success() || resp.status() == StatusCode::METHOD_NOT_ALLOWED {
+      Ok(None)
+    } else {
+      Ok(Some(resp))
+    }
+  }
+
+  /// Make sure a shard collection (and the base collection) exist.
+  async fn ensure_collection(&self, url: &Url) -> Result<(), RemoteError> {
+    let resp = match self.mkcol(url).await? {
+      None => return Ok(()),
+      Some(r) => r,
+    };
+    if resp.status() != StatusCode::CONFLICT {
+      check_status(resp).await?;
+      return Ok(());
+    }
+    // the base collection is missing
+    debug!("creating collection {}", self.base);
+    if let Some(resp) = self.mkcol(&self.base).await? {
+      check_status(resp).await?;
+    }
+    if let Some(resp) = self.mkcol(url).await? {
+      check_status(resp).await?;
+    }
+    Ok(())
+  }
+
+  /// Get the path of a resource relative to the base URL.
+  fn relative_href(&self, href: &str) -> Option<String> {
+    let url = self.base.join(href).ok()?;
+    let rel = url.path().strip_prefix(self.base.path())?;
+    let rel = rel.trim_end_matches('/');
+    if rel.is_empty() {
+      None
+    } else {
+      Some(rel.to_owned())
+    }
+  }
+}
+
+#[async_trait]
+impl Remote for WebDavRemote {
+  async fn exists(&self, hash: &MultiHash) -> Result<bool, RemoteError> {
+    let url = self.object_url(hash)?;
+    Ok(self.propfind(url, "0").await?.is_some())
+  }
+
+  async fn upload(&self, hash: &MultiHash, src: &Path) -> Result<(), RemoteError> {
+    let rel = object_relpath(hash)?;
+    let url = self.object_url(hash)?;
+    debug!("uploading {:?} to {}", src, url);
+    let dir = self.join(&self.base, &format!("{}/", rel.parent().map(|p| p.as_str()).unwrap_or_default()))?;
+    self.ensure_collection(&dir).await?;
+
+    let name = rel.file_name().unwrap_or_default();
+    let tmp_name = sibling_temp_path(Path::new(name));
+    let tmp = self.join(&dir, &tmp_name.to_string_lossy())?;
+
+    // hash the data as it is sent, so we do not publish a file that has changed
+    let size = metadata(src).await?.len();
+    let digest = Arc::new(Mutex::new(Some(MultiDigest::for_hash(hash))));
+    let stream = {
+      let digest = digest.clone();
+      ReaderStream::new(File::open(src).await?).map_ok(move |chunk| {
+        if let Some(d) = digest.lock().expect("poisoned lock").as_mut() {
+          d.update(&chunk);
+        }
+        chunk
+      })
+    };
+    let resp = self.request(Method::PUT, tmp.clone())
+      .header("content-length", size)
+      .body(Body::wrap_stream(stream))
+      .send().await?;
+    check_status(resp).await?;
+
+    let actual = digest.lock().expect("poisoned lock").take().expect("digest missing").finish();
+    if !hash.matches(&actual) {
+      let _ = self.request(Method::DELETE, tmp).send().await;
+      return Err(RemoteError::Corrupt(object_key(hash)?.to_string()));
+    }
+
+    let resp = self.request(dav_method("MOVE"), tmp.clone())
+      .header("destination", url.as_str())
+      .header("overwrite", "T")
+      .send().await?;
+    if let Err(e) = check_status(resp).await {
+      let _ = self.request(Method::DELETE, tmp).send().await;
+      return Err(e);
+    }
+    Ok(())
+  }
+
+  async fn download(&self, hash: &MultiHash, dest: &Path) -> Result<(), RemoteError> {
+    let url = self.object_url(hash)?;
+    debug!("downloading {} to {:?}", url, dest);
+    let resp = self.request(Method::GET, url).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+      return Err(RemoteError::NotFound(object_key(hash)?.to_string()));
+    }
+    let resp = check_status(resp).await?;
+    save_verified(resp, hash, dest).await
+  }
+
+  async fn list(&self) -> Result<Vec<MultiHash>, RemoteError> {
+    let mut objects = Vec::new();
+    let top = match self.propfind(self.base.clone(), "1").await? {
+      Some(es) => es,
+      None => return Ok(objects),
+    };
+    // many servers refuse infinite-depth PROPFIND, so walk the shard collections
+    for shard in top.iter().filter(|e| e.collection) {
+      let shard = match self.relative_href(&shard.href) {
+        Some(s) => s,
+        None => continue,
+      };
+      let url = self.join(&self.base, &format!("{}/", shard))?;
+      for entry in self.propfind(url, "1").await?.unwrap_or_default() {
+        if entry.collection {
+          continue;
+        }
+        let rel = match self.relative_href(&entry.href) {
+          Some(r) => r,
+          None => continue,
+        };
+        match parse_object_relpath(RelativePath::new(&rel)) {
+          Some(h) => objects.push(h),
+          None => trace!("{}: not an object, skipping", rel),
+        }
+      }
+    }
+    Ok(objects)
+  }
+
+  async fn delete(&self, hash: &MultiHash) -> Result<(), RemoteError> {
+    let url = self.object_url(hash)?;
+    let resp = self.request(Method::DELETE, url).send().await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+      return Err(RemoteError::NotFound(object_key(hash)?.to_string()));
+    }
+    check_status(resp).await?;
+    Ok(())
+  }
+}
+
+#[test]
+fn test_parse_multistatus() {
+  let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response>
+    <D:href>/dav/afc/</D:href>
+    <D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>/dav/afc/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08</D:href>
+    <D:propstat><D:prop><D:resourcetype/></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>
+  </D:response>
+</D:multistatus>"#;
+  let entries = parse_multistatus(body).expect("parse failed");
+  assert_eq!(entries, vec![
+    DavEntry { href: "/dav/afc/".into(), collection: true },
+    DavEntry { href: "/dav/afc/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".into(), collection: false },
+  ]);
+}
@@ -1 +1,632 @@
-//! AFC settings (run-time, not stored in tree).
+//! AFC settings.
+//!
+//! Settings are layered from several configuration files, with later files taking
+//! precedence over earlier ones:
+//!
+//! 1. The user's configuration (`~/.config/afc/config.toml`)
+//! 2. The work tree's configuration (`.afc/config.toml`), committed to version control
+//! 3. The work tree's local configuration (`.afc/config.local.toml`), ignored by
+//!    version control
+//!
+//! Tables are merged key-by-key, so a layer only needs to specify the settings it
+//! changes.
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use toml::Value;
+use toml::value::Table;
+
+use crate::cache::{Cache, CacheLayout};
+use crate::cache::link::{LinkStrategy, DEFAULT_LINK_STRATEGIES};
+use crate::filehash::{HashAlgo, DEFAULT_ALGORITHMS};
+use crate::tree::DEFAULT_CACHE_DIR;
+use crate::util::io::{read_file_string, write_file_atomic};
+
+/// The path to the work tree configuration file, relative to the work tree root.
+pub const CONFIG_FILE: &str = ".afc/config.toml";
+/// The path to the local configuration file, relative to the work tree root.
+pub const LOCAL_CONFIG_FILE: &str = ".afc/config.local.toml";
+/// The initial contents of a new work tree's configuration file.
+pub const DEFAULT_CONFIG: &str = r#"# AFC configuration for this work tree.
+# Uncomment and edit settings to change them from their defaults.
+
+[core]
+# remote = "origin"
+# hashes = ["md5", "sha1", "sha256"]  # also: "sha512", "blake3"
+# jobs = 4
+# dvc = false
+
+[cache]
+# dir = ".afc/cache"
+# link = ["reflink", "copy"]
+# layout = "afc"
+"#;
+
+/// The default number of concurrent jobs.
+pub const DEFAULT_JOBS: usize = 4;
+
+/// An error loading or saving settings.
+#[derive(Error, Debug)]
+pub enum SettingsError {
+  #[error("IO error occurred: {0}")]
+  IOError(#[from] io::Error),
+  #[error("invalid configuration in {0:?}: {1}")]
+  ParseError(PathBuf, toml::de::Error),
+  #[error("invalid settings: {0}")]
+  InvalidSettings(toml::de::Error),
+  #[error("failed to write configuration: {0}")]
+  WriteError(#[from] toml::ser::Error),
+  #[error("configuration key {0:?} is not a table")]
+  NotATable(String),
+  #[error("no remote specified and no default remote configured")]
+  NoDefaultRemote,
+  #[error("remote {0:?} is not configured")]
+  UnknownRemote(String),
+}
+
+/// A configuration scope (layer).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigScope {
+  /// The user's configuration.
+  User,
+  /// The work tree's shared configuration.
+  Tree,
+  /// The work tree's local configuration.
+  Local,
+}
+
+impl fmt::Display for ConfigScope {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let s = match self {
+      ConfigScope::User => "user",
+      ConfigScope::Tree => "tree",
+      ConfigScope::Local => "local",
+    };
+    f.write_str(s)
+  }
+}
+
+impl ConfigScope {
+  /// All scopes, in increasing order of precedence.
+  pub const ALL: &'static [ConfigScope] = &[ConfigScope::User, ConfigScope::Tree, ConfigScope::Local];
+
+  /// Get the path of this scope's configuration file.
+  ///
+  /// Returns `None` for the user scope if the user configuration directory cannot
+  /// be determined.
+  pub fn path(&self, root: &Path) -> Option<PathBuf> {
+    match self {
+      ConfigScope::User => user_config_dir().map(|d| d.join("config.toml")),
+      ConfigScope::Tree => Some(root.join(CONFIG_FILE)),
+      ConfigScope::Local => Some(root.join(LOCAL_CONFIG_FILE)),
+    }
+  }
+}
+
+/// Get the user's AFC configuration directory.
+///
+/// This is `$XDG_CONFIG_HOME/afc` if `XDG_CONFIG_HOME` is set, and `~/.config/afc`
+/// otherwise (`%APPDATA%\afc` on Windows).
+pub fn user_config_dir() -> Option<PathBuf> {
+  if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+    return Some(PathBuf::from(dir).join("afc"));
+  }
+  if cfg!(windows) {
+    env::var_os("APPDATA").map(|d| PathBuf::from(d).join("afc"))
+  } else {
+    env::var_os("HOME").map(|d| PathBuf::from(d).join(".config").join("afc"))
+  }
+}
+
+/// A single configuration file.
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+  path: PathBuf,
+  table: Table,
+}
+
+/// Split a dotted configuration key into its components.
+fn split_key(key: &str) -> Vec<&str> {
+  key.split('.').collect()
+}
+
+impl ConfigFile {
+  /// Load a configuration file.  A missing file is treated as empty.
+  pub async fn load<P: AsRef<Path>>(path: P) -> Result<ConfigFile, SettingsError> {
+    let path = path.as_ref().to_owned();
+    let table = match read_file_string(&path).await {
+      Ok(content) => {
+        debug!("reading configuration from {:?}", path);
+        toml::from_str(&content).map_err(|e| SettingsError::ParseError(path.clone(), e))?
+      },
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Table::new(),
+      Err(e) => return Err(e.into()),
+    };
+    Ok(ConfigFile { path, table })
+  }
+
+  /// Get the path of this configuration file.
+  pub fn path(&self) -> &Path {
+    self.path.as_path()
+  }
+
+  /// Get the contents of this configuration file.
+  pub fn table(&self) -> &Table {
+    &self.table
+  }
+
+  /// Look up a dotted key (e.g. `cache.link`) in this file.
+  pub fn get(&self, key: &str) -> Option<&Value> {
+    lookup(&self.table, key)
+  }
+
+  /// Set a dotted key in this file, creating intermediate tables as needed.
+  pub fn set(&mut self, key: &str, value: Value) -> Result<(), SettingsError> {
+    let parts = split_key(key);
+    let (last, parents) = parts.split_last().expect("empty key");
+    let mut table = &mut self.table;
+    for (i, part) in parents.iter().enumerate() {
+      let entry = table.entry(part.to_string()).or_insert_with(|| Value::Table(Table::new()));
+      table = match entry {
+        Value::Table(t) => t,
+        _ => return Err(SettingsError::NotATable(parts[..=i].join("."))),
+      };
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+  }
+
+  /// Remove a dotted key from this file, returning its old value.
+  pub fn unset(&mut self, key: &str) -> Option<Value> {
+    let parts = split_key(key);
+    let (last, parents) = parts.split_last()?;
+    let mut table = &mut self.table;
+    for part in parents {
+      table = match table.get_mut(*part) {
+        Some(Value::Table(t)) => t,
+        _ => return None,
+      };
+    }
+    table.remove(*last)
+  }
+
+  /// Save this configuration file.
+  pub async fn save(&self) -> Result<(), SettingsError> {
+    if let Some(dir) = self.path.parent() {
+      tokio::fs::create_dir_all(dir).await?;
+    }
+    let content = toml::to_string(&self.table)?;
+    write_file_atomic(&self.path, content).await?;
+    Ok(())
+  }
+}
+
+/// Look up a dotted key in a table.
+pub fn lookup<'a>(table: &'a Table, key: &str) -> Option<&'a Value> {
+  let parts = split_key(key);
+  let (last, parents) = parts.split_last()?;
+  let mut table = table;
+  for part in parents {
+    table = match table.get(*part) {
+      Some(Value::Table(t)) => t,
+      _ => return None,
+    };
+  }
+  table.get(*last)
+}
+
+/// Merge a configuration table into another, with `over` taking precedence.
+pub fn merge_tables(base: &mut Table, over: &Table) {
+  for (k, v) in over {
+    match (base.get_mut(k), v) {
+      (Some(Value::Table(bt)), Value::Table(ot)) => merge_tables(bt, ot),
+      _ => {
+        base.insert(k.clone(), v.clone());
+      }
+    }
+  }
+}
+
+/// Load and merge the configuration layers for a work tree.
+pub async fn load_merged<P: AsRef<Path>>(root: P) -> Result<Table, SettingsError> {
+  let root = root.as_ref();
+  let mut merged = Table::new();
+  for scope in ConfigScope::ALL {
+    if let Some(path) = scope.path(root) {
+      let file = ConfigFile::load(&path).await?;
+      merge_tables(&mut merged, file.table());
+    }
+  }
+  Ok(merged)
+}
+
+/// AFC settings for a work tree.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Settings {
+  /// Core settings.
+  #[serde(default)]
+  pub core: CoreSettings,
+  /// Settings for the local cache.
+  #[serde(default)]
+  pub cache: CacheSettings,
+  /// Configured remotes, by name.
+  #[serde(default)]
+  pub remote: BTreeMap<String, RemoteSettings>,
+}
+
+/// Core AFC settings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreSettings {
+  /// The name of the default remote.
+  pub remote: Option<String>,
+  /// The hash algorithms to compute for new artifacts.
+  #[serde(default="default_hashes")]
+  pub hashes: Vec<HashAlgo>,
+  /// The number of concurrent jobs for transfers and hashing.
+  #[serde(default="default_jobs")]
+  pub jobs: usize,
+  /// Whether to read DVC pointers (`.dvc` files and `dvc.lock` outputs) as artifacts.
+  #[serde(default)]
+  pub dvc: bool,
+}
+
+/// Settings for the local cache.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheSettings {
+  /// The cache directory; relative paths are resolved against the work tree root.
+  #[serde(default="default_cache_dir")]
+  pub dir: PathBuf,
+  /// Strategies for placing cached files in the work tree, in order of preference.
+  #[serde(default="default_link")]
+  pub link: Vec<LinkStrategy>,
+  /// The layout of cached objects (`dvc` or `dvc2` to share a DVC cache).
+  #[serde(default)]
+  pub layout: CacheLayout,
+}
+
+/// Configuration for a remote.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag="type", rename_all="lowercase")]
+pub enum RemoteSettings {
+  /// A remote in a local (or network-mounted) directory.
+  Local {
+    /// The remote directory; relative paths are resolved against the work tree root.
+    path: PathBuf,
+    /// The layout of objects in the directory (`dvc` or `dvc2` for a DVC remote or cache).
+    #[serde(default)]
+    layout: CacheLayout,
+  },
+  /// An S3 (or S3-compatible) bucket.
+  S3(S3Settings),
+  /// A directory on a host reachable over SSH.
+  Sftp(SftpSettings),
+  /// A collection on a WebDAV server.
+  WebDav(WebDavSettings),
+  /// A Backblaze B2 bucket, using the native B2 API.
+  B2(B2Settings),
+  /// A folder in Google Drive.
+  GDrive(GDriveSettings),
+  /// A read-only directory published over plain HTTP(S).
+  Http {
+    /// The base URL of the directory.
+    url: String,
+  },
+}
+
+/// Settings for an S3 remote.
+///
+/// Credentials can be given here (preferably in the local configuration, which is
+/// not committed), in the usual `AWS_*` environment variables, or in the AWS shared
+/// credentials file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct S3Settings {
+  /// The bucket name.
+  pub bucket: String,
+  /// A key prefix for objects in the bucket.
+  #[serde(default)]
+  pub prefix: String,
+  /// The bucket's region.
+  #[serde(default="default_s3_region")]
+  pub region: String,
+  /// The service endpoint URL (for S3-compatible stores such as MinIO).
+  pub endpoint: Option<String>,
+  /// Whether to use path-style bucket URLs [default: true with a custom endpoint].
+  pub path_style: Option<bool>,
+  /// The access key ID.
+  pub access_key_id: Option<String>,
+  /// The secret access key.
+  pub secret_access_key: Option<String>,
+  /// The profile to use from the AWS shared credentials file.
+  pub profile: Option<String>,
+  /// Files larger than this many bytes are uploaded in multiple parts.
+  #[serde(default="default_multipart_threshold")]
+  pub multipart_threshold: u64,
+  /// The size of each part in a multipart upload.
+  #[serde(default="default_part_size")]
+  pub part_size: u64,
+}
+
+/// Settings for an SFTP remote.
+///
+/// Connections use the system OpenSSH client, so `~/.ssh/config` and the SSH agent
+/// are honored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SftpSettings {
+  /// The SSH destination (`[user@]host` or a host alias from `~/.ssh/config`).
+  pub host: String,
+  /// The directory on the host; relative paths are resolved against the login directory.
+  pub path: PathBuf,
+  /// The user to log in as [default: from the SSH configuration].
+  pub user: Option<String>,
+  /// The SSH port [default: from the SSH configuration].
+  pub port: Option<u16>,
+}
+
+/// Settings for a WebDAV remote.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebDavSettings {
+  /// The URL of the collection to store objects in.
+  pub url: String,
+  /// The user name for HTTP basic authentication.
+  pub user: Option<String>,
+  /// The password for HTTP basic authentication (best kept in the local configuration).
+  pub password: Option<String>,
+}
+
+/// Settings for a Backblaze B2 remote.
+///
+/// The application key can be given here (preferably in the local configuration)
+/// or in the `B2_APPLICATION_KEY_ID` and `B2_APPLICATION_KEY` environment variables.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct B2Settings {
+  /// The bucket name.
+  pub bucket: String,
+  /// A file name prefix for objects in the bucket.
+  #[serde(default)]
+  pub prefix: String,
+  /// The application key ID.
+  pub key_id: Option<String>,
+  /// The application key.
+  pub key: Option<String>,
+  /// The URL to authorize the account against.
+  #[serde(default="default_b2_api_url")]
+  pub api_url: String,
+  /// Files larger than this many bytes are uploaded as large files, in parts.
+  #[serde(default="default_multipart_threshold")]
+  pub multipart_threshold: u64,
+  /// The size of each part of a large file.
+  #[serde(default="default_part_size")]
+  pub part_size: u64,
+}
+
+/// Settings for a Google Drive remote.
+///
+/// AFC authorizes with the OAuth device flow, so the client must be registered as
+/// a "TVs and Limited Input devices" client.  The token is cached in the user
+/// configuration directory.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GDriveSettings {
+  /// The ID of the folder to store objects in.
+  pub folder_id: String,
+  /// The OAuth client ID.
+  pub client_id: String,
+  /// The OAuth client secret.
+  pub client_secret: Option<String>,
+  /// The OAuth scope to request; the folder must be accessible with it.
+  #[serde(default="default_gdrive_scope")]
+  pub scope: String,
+  /// The base URL of the OAuth endpoints.
+  #[serde(default="default_gdrive_oauth_url")]
+  pub oauth_url: String,
+  /// The base URL of the Drive API.
+  #[serde(default="default_gdrive_api_url")]
+  pub api_url: String,
+}
+
+fn default_hashes() -> Vec<HashAlgo> {
+  DEFAULT_ALGORITHMS.to_vec()
+}
+
+fn default_jobs() -> usize {
+  DEFAULT_JOBS
+}
+
+fn default_s3_region() -> String {
+  "us-east-1".into()
+}
+
+fn default_b2_api_url() -> String {
+  "https://api.backblazeb2.com".into()
+}
+
+fn default_gdrive_scope() -> String {
+  "https://www.googleapis.com/auth/drive.file".into()
+}
+
+fn default_gdrive_oauth_url() -> String {
+  "https://oauth2.googleapis.com".into()
+}
+
+fn default_gdrive_api_url() -> String {
+  "https://www.googleapis.com".into()
+}
+
+fn default_multipart_threshold() -> u64 {
+  64 * 1024 * 1024
+}
+
+fn default_part_size() -> u64 {
+  16 * 1024 * 1024
+}
+
+fn default_cache_dir() -> PathBuf {
+  DEFAULT_CACHE_DIR.into()
+}
+
+fn default_link() -> Vec<LinkStrategy> {
+  DEFAULT_LINK_STRATEGIES.to_vec()
+}
+
+impl Default for CoreSettings {
+  fn default() -> CoreSettings {
+    CoreSettings {
+      remote: None,
+      hashes: default_hashes(),
+      jobs: default_jobs(),
+      dvc: false,
+    }
+  }
+}
+
+impl Default for CacheSettings {
+  fn default() -> CacheSettings {
+    CacheSettings {
+      dir: default_cache_dir(),
+      link: default_link(),
+      layout: CacheLayout::default(),
+    }
+  }
+}
+
+impl Settings {
+  /// Load the settings for a work tree, merging all configuration layers.
+  pub async fn load<P: AsRef<Path>>(root: P) -> Result<Settings, SettingsError> {
+    let merged = load_merged(root).await?;
+    Settings::from_table(merged)
+  }
+
+  /// Interpret a (merged) configuration table as settings.
+  pub fn from_table(table: Table) -> Result<Settings, SettingsError> {
+    Value::Table(table).try_into().map_err(SettingsError::InvalidSettings)
+  }
+
+  /// Look up a remote's settings by name, or the default remote if `name` is `None`.
+  pub fn remote_settings<'a>(&'a self, name: Option<&'a str>) -> Result<(&'a str, &'a RemoteSettings), SettingsError> {
+    let name = match name.or(self.core.remote.as_deref()) {
+      Some(n) => n,
+      None => return Err(SettingsError::NoDefaultRemote),
+    };
+    match self.remote.get(name) {
+      Some(rs) => Ok((name, rs)),
+      None => Err(SettingsError::UnknownRemote(name.to_owned())),
+    }
+  }
+
+  /// Open the cache for a work tree with these settings.
+  pub fn open_cache<P: AsRef<Path>>(&self, root: P) -> Cache {
+    let dir = root.as_ref().join(&self.cache.dir);
+    Cache::open(dir).with_layout(self.cache.layout).with_algorithms(&self.core.hashes)
+  }
+}
+
+#[test]
+fn test_merge_tables() {
+  let mut base: Table = toml::from_str("[cache]\nlink = [\"copy\"]\ndir = \"cache\"\n").unwrap();
+  let over: Table = toml::from_str("[cache]\nlink = [\"hardlink\"]\n[core]\njobs = 8\n").unwrap();
+  merge_tables(&mut base, &over);
+  let settings = Settings::from_table(base).expect("invalid settings");
+  assert_eq!(settings.cache.link, vec![LinkStrategy::Hardlink]);
+  assert_eq!(settings.cache.dir, PathBuf::from("cache"));
+  assert_eq!(settings.core.jobs, 8);
+  assert_eq!(settings.core.hashes, DEFAULT_ALGORITHMS.to_vec());
+}
+
+#[test]
+fn test_set_get_key() {
+  let mut file = ConfigFile { path: "config.toml".into(), table: Table::new() };
+  file.set("core.remote", Value::String("origin".into())).expect("set failed");
+  assert_eq!(file.get("core.remote"), Some(&Value::String("origin".into())));
+  assert!(file.set("core.remote.name", Value::Integer(1)).is_err());
+  assert_eq!(file.unset("core.remote"), Some(Value::String("origin".into())));
+  assert_eq!(file.get("core.remote"), None);
+}
+
+#[test]
+fn test_cache_layout() {
+  let table: Table = toml::from_str("[cache]\ndir = \".dvc/cache\"\nlayout = \"dvc\"\n").unwrap();
+  let settings = Settings::from_table(table).expect("invalid settings");
+  assert_eq!(settings.cache.layout, CacheLayout::Dvc);
+  assert_eq!(settings.open_cache("/srv/tree").layout(), CacheLayout::Dvc);
+  assert_eq!(Settings::from_table(Table::new()).unwrap().cache.layout, CacheLayout::Afc);
+}
+
+#[test]
+fn test_remote_settings() {
+  let table: Table = toml::from_str("[core]\nremote = \"nas\"\n[remote.nas]\ntype = \"local\"\npath = \"/mnt/nas/afc\"\n").unwrap();
+  let settings = Settings::from_table(table).expect("invalid settings");
+  let (name, rs) = settings.remote_settings(None).expect("no remote");
+  assert_eq!(name, "nas");
+  assert!(matches!(rs, RemoteSettings::Local { path, layout: CacheLayout::Afc } if path == Path::new("/mnt/nas/afc")));
+  assert!(matches!(settings.remote_settings(Some("s3")), Err(SettingsError::UnknownRemote(_))));
+}
+
+#[test]
+fn test_s3_settings() {
+  let table: Table = toml::from_str("[remote.s3]\ntype = \"s3\"\nbucket = \"data\"\nendpoint = \"http://localhost:9000\"\n").unwrap();
+  let settings = Settings::from_table(table).expect("invalid settings");
+  let (_, rs) = settings.remote_settings(Some("s3")).expect("no remote");
+  match rs {
+    RemoteSettings::S3(s3) => {
+      assert_eq!(s3.bucket, "data");
+      assert_eq!(s3.region, "us-east-1");
+      assert_eq!(s3.prefix, "");
+      assert_eq!(s3.endpoint.as_deref(), Some("http://localhost:9000"));
+    },
+    _ => panic!("not an S3 remote"),
+  }
+}
+
+#[test]
+fn test_sftp_settings() {
+  let table: Table = toml::from_str("[remote.lab]\ntype = \"sftp\"\nhost = \"cluster\"\npath = \"/data/afc\"\n").unwrap();
+  let settings = Settings::from_table(table).expect("invalid settings");
+  let (_, rs) = settings.remote_settings(Some("lab")).expect("no remote");
+  assert!(matches!(rs, RemoteSettings::Sftp(s) if s.host == "cluster" && s.port.is_none()));
+}
+
+#[test]
+fn test_http_settings() {
+  let table: Table = toml::from_str("[remote.dav]\ntype = \"webdav\"\nurl = \"https://dav.example.com/afc\"\n[remote.public]\ntype = \"http\"\nurl = \"https://example.com/afc\"\n").unwrap();
+  let settings = Settings::from_table(table).expect("invalid settings");
+  let (_, rs) = settings.remote_settings(Some("dav")).expect("no remote");
+  assert!(matches!(rs, RemoteSettings::WebDav(d) if d.user.is_none()));
+  let (_, rs) = settings.remote_settings(Some("public")).expect("no remote");
+  assert!(matches!(rs, RemoteSettings::Http { url } if url == "https://example.com/afc"));
+}
+
+#[test]
+fn test_b2_settings() {
+  let table: Table = toml::from_str("[remote.b2]\ntype = \"b2\"\nbucket = \"data\"\nprefix = \"afc\"\n").unwrap();
+  let settings = Settings::from_table(table).expect("invalid settings");
+  let (_, rs) = settings.remote_settings(Some("b2")).expect("no remote");
+  match rs {
+    RemoteSettings::B2(b2) => {
+      assert_eq!(b2.bucket, "data");
+      assert_eq!(b2.prefix, "afc");
+      assert_eq!(b2.api_url, "https://api.backblazeb2.com");
+      assert!(b2.key_id.is_none());
+    },
+    _ => panic!("wrong remote type"),
+  }
+}
+
+#[test]
+fn test_gdrive_settings() {
+  let table: Table = toml::from_str("[remote.drive]\ntype = \"gdrive\"\nfolder_id = \"f00\"\nclient_id = \"c1.apps.googleusercontent.com\"\n").unwrap();
+  let settings = Settings::from_table(table).expect("invalid settings");
+  let (_, rs) = settings.remote_settings(Some("drive")).expect("no remote");
+  match rs {
+    RemoteSettings::GDrive(gd) => {
+      assert_eq!(gd.folder_id, "f00");
+      assert!(gd.client_secret.is_none());
+      assert_eq!(gd.api_url, "https://www.googleapis.com");
+    },
+    _ => panic!("wrong remote type"),
+  }
+}
@@ -4,9 +4,11 @@ use std::io;
 use relative_path::{RelativePathBuf, RelativePath};
 use serde::{Serialize, Deserialize};
 
-use crate::filehash::MultiHash;
+use crate::cache::manifest::listing_json;
+use crate::filehash::{MultiHash, MultiDigest, ALL_ALGORITHMS};
 
 use super::{pointer::{AFCPointerFile}, WorkTree};
+use super::dvc::{DvcFile, DvcLock, DvcOut};
 
 /// An artifact in the work tree.
 pub struct Artifact {
@@ -16,29 +18,52 @@ pub struct Artifact {
   pointer_path: Option<RelativePathBuf>,
   /// The saved file metadata, if available.
   meta: Option<ArtifactMeta>,
+  /// The remote named by the pointer file, if any.
+  remote: Option<String>,
 }
 
 /// Metadata for an artifact.
+///
+/// Folders are listed first so deserialization only picks [FileMeta] (whose fields
+/// are all optional) when there is no `files` list.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum ArtifactMeta {
-  /// The artifact is a single file.
-  File(FileMeta),
   /// The artifact is a folder.
   Folder(FolderMeta),
+  /// The artifact is a single file.
+  File(FileMeta),
+}
+
+impl ArtifactMeta {
+  /// Get the metadata for each file in this artifact.
+  ///
+  /// Folders read from DVC pointers have no file entries of their own; use
+  /// [folder_entries](super::dvc::folder_entries) to read them from the manifest.
+  pub fn file_metas(&self) -> Vec<&FileMeta> {
+    match self {
+      ArtifactMeta::File(fm) => vec![fm],
+      ArtifactMeta::Folder(fm) => fm.files.iter().map(|e| &e.meta).collect(),
+    }
+  }
 }
 
 /// Metadata for a single file.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMeta {
+  #[serde(skip_serializing_if="Option::is_none")]
   pub size: Option<usize>,
   #[serde(flatten)]
   pub hashes: MultiHash,
 }
 
 /// Metadata for a folder.
+///
+/// The folder's hashes are tree hashes over its sorted entries (see
+/// [FolderMeta::tree_hash]), so a folder pointer identifies the folder's contents.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FolderMeta {
+  #[serde(skip_serializing_if="Option::is_none")]
   pub nfiles: Option<usize>,
   #[serde(flatten)]
   pub hashes: MultiHash,
@@ -52,19 +77,79 @@ pub struct FolderEntry {
   pub meta: FileMeta,
 }
 
+impl FolderMeta {
+  /// Create folder metadata from its entries, sorting them by path.
+  pub fn from_entries(mut files: Vec<FolderEntry>) -> FolderMeta {
+    files.sort_by(|a, b| a.relpath.as_str().cmp(b.relpath.as_str()));
+    FolderMeta {
+      nfiles: Some(files.len()),
+      hashes: FolderMeta::tree_hash(&files),
+      files,
+    }
+  }
+
+  /// Compute the tree hashes of a list of folder entries.
+  ///
+  /// For each algorithm that every entry has a hash for, the tree hash is the hash
+  /// of the entries' hashes and paths, listed as in a DVC directory manifest.  The
+  /// MD5 tree hash is thus the hash DVC records for the same directory.
+  pub fn tree_hash(files: &[FolderEntry]) -> MultiHash {
+    let mut hash = MultiHash::default();
+    for algo in ALL_ALGORITHMS {
+      let listing: Option<Vec<_>> = files.iter()
+        .map(|e| e.meta.hashes.hex(*algo).map(|h| (h, &e.relpath)))
+        .collect();
+      if let Some(listing) = listing {
+        let mut digest = MultiDigest::with_algorithms(&[*algo]);
+        digest.update(listing_json(*algo, &listing));
+        hash.merge(digest.finish());
+      }
+    }
+    hash
+  }
+
+  /// Check that the folder's recorded hashes match its entries.
+  pub fn verify_tree_hash(&self) -> bool {
+    self.nfiles.map(|n| n == self.files.len()).unwrap_or(true)
+      && self.hashes.matches(&FolderMeta::tree_hash(&self.files))
+  }
+}
+
 impl Artifact {
-  pub async fn load_afc_pointer(tree: &WorkTree, path: &RelativePath) -> io::Result<Artifact> {
+  /// Load the artifacts tracked by an `.afc` pointer file.
+  pub async fn load_afc_pointer(tree: &WorkTree, path: &RelativePath) -> io::Result<Vec<Artifact>> {
     let fp = path.to_path(tree.root_path());
-    let ptr = AFCPointerFile::load(&fp).await?;
-    let ptr = ptr.artifact;
+    let file = AFCPointerFile::load(&fp).await?;
     let dir = path.parent().map(RelativePath::to_owned);
     let dir = dir.unwrap_or_else(|| ".".into());
-    let apath = dir.join(ptr.path());
-    Ok(Artifact {
-      tree_path: apath,
+    Ok(file.into_pointers().into_iter().map(|ptr| Artifact {
+      tree_path: dir.join(ptr.path()),
       pointer_path: Some(path.to_owned()),
       meta: Some(ptr.meta),
-    })
+      remote: ptr.remote,
+    }).collect())
+  }
+
+  /// Load the artifacts for the outputs in a `.dvc` file.
+  pub async fn load_dvc_file(tree: &WorkTree, path: &RelativePath) -> io::Result<Vec<Artifact>> {
+    let dvc = DvcFile::load(path.to_path(tree.root_path())).await?;
+    Ok(Artifact::from_dvc_outs(path, dvc.outs.iter()))
+  }
+
+  /// Load the artifacts for the outputs recorded in a `dvc.lock` file.
+  pub async fn load_dvc_lock(tree: &WorkTree, path: &RelativePath) -> io::Result<Vec<Artifact>> {
+    let lock = DvcLock::load(path.to_path(tree.root_path())).await?;
+    Ok(Artifact::from_dvc_outs(path, lock.outs()))
+  }
+
+  fn from_dvc_outs<'a>(path: &RelativePath, outs: impl Iterator<Item=&'a DvcOut>) -> Vec<Artifact> {
+    let dir = path.parent().map(RelativePath::to_owned).unwrap_or_default();
+    outs.filter(|o| o.cache).map(|out| Artifact {
+      tree_path: dir.join_normalized(&out.path),
+      pointer_path: Some(path.to_owned()),
+      meta: out.meta(),
+      remote: None,
+    }).collect()
   }
 
   /// Get the path of this artifact, relative to the pointer file.
@@ -81,4 +166,40 @@ impl Artifact {
   pub fn meta(&self) -> Option<&ArtifactMeta> {
     self.meta.as_ref()
   }
+
+  /// Get the name of the remote this artifact is transferred with, if its pointer
+  /// overrides the default remote.
+  pub fn remote(&self) -> Option<&str> {
+    self.remote.as_deref()
+  }
+}
+
+#[test]
+fn test_folder_tree_hash() {
+  let entry = |path: &str, data: &str| {
+    let mut digest = MultiDigest::new();
+    digest.update(data);
+    FolderEntry {
+      relpath: path.into(),
+      meta: FileMeta { size: Some(data.len()), hashes: digest.finish() },
+    }
+  };
+  let fm = FolderMeta::from_entries(vec![entry("sub/b.txt", "world"), entry("hello.txt", "hello")]);
+  assert_eq!(fm.nfiles, Some(2));
+  assert_eq!(fm.files[0].relpath.as_str(), "hello.txt");
+  assert!(fm.hashes.sha1.is_some() && fm.hashes.sha256.is_some());
+  assert!(fm.verify_tree_hash());
+
+  // entry order does not matter, contents do
+  let other = FolderMeta::from_entries(vec![entry("hello.txt", "hello"), entry("sub/b.txt", "world")]);
+  assert_eq!(other.hashes, fm.hashes);
+  let changed = FolderMeta::from_entries(vec![entry("hello.txt", "hello"), entry("sub/b.txt", "World")]);
+  assert!(!changed.hashes.matches(&fm.hashes));
+  let mut tampered = fm.clone();
+  tampered.files[1].relpath = "sub/c.txt".into();
+  assert!(!tampered.verify_tree_hash());
+
+  // the MD5 tree hash is DVC's directory hash
+  let dvc = FolderMeta::from_entries(vec![entry("hello.txt", "hello")]);
+  assert_eq!(dvc.hashes.md5.unwrap().to_string(), "0273ec1121a1a261f4da03e0dc056f27");
 }
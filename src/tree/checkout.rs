@@ -0,0 +1,102 @@
+//! Materialize artifacts from the cache into the work tree.
+use log::*;
+use relative_path::RelativePath;
+
+use crate::cache::{Cache, CacheError};
+use crate::cache::link::{LinkStrategy, DEFAULT_LINK_STRATEGIES};
+
+use super::WorkTree;
+use super::artifact::{Artifact, ArtifactMeta, FileMeta};
+use super::dvc::folder_entries;
+use super::status::check_file;
+
+/// Options controlling checkout.
+#[derive(Debug, Clone)]
+pub struct CheckoutOptions {
+  /// Overwrite locally-modified files.
+  pub force: bool,
+  /// Strategies for placing files, in order of preference.
+  pub links: Vec<LinkStrategy>,
+}
+
+impl Default for CheckoutOptions {
+  fn default() -> CheckoutOptions {
+    CheckoutOptions {
+      force: false,
+      links: DEFAULT_LINK_STRATEGIES.to_vec(),
+    }
+  }
+}
+
+/// The result of checking out an artifact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckoutOutcome {
+  /// The work tree already matched the pointer.
+  Unchanged,
+  /// The artifact was (fully or partially) restored from the cache.
+  Restored,
+  /// The work tree has local modifications that were not overwritten.
+  Modified,
+}
+
+impl CheckoutOutcome {
+  /// Combine the outcomes of two files in the same artifact.
+  fn combine(self, other: CheckoutOutcome) -> CheckoutOutcome {
+    use CheckoutOutcome::*;
+    match (self, other) {
+      (Modified, _) | (_, Modified) => Modified,
+      (Restored, _) | (_, Restored) => Restored,
+      _ => Unchanged,
+    }
+  }
+}
+
+/// Check out a single file.
+async fn checkout_file(tree: &WorkTree, cache: &Cache, path: &RelativePath, meta: &FileMeta, opts: &CheckoutOptions) -> Result<CheckoutOutcome, CacheError> {
+  match check_file(tree, path, meta).await? {
+    Some(true) => {
+      debug!("{}: already up to date", path);
+      return Ok(CheckoutOutcome::Unchanged);
+    },
+    Some(false) if !opts.force => {
+      debug!("{}: locally modified, not overwriting", path);
+      return Ok(CheckoutOutcome::Modified);
+    },
+    _ => (),
+  }
+
+  debug!("{}: restoring from cache", path);
+  cache.link(&meta.hashes, path.to_path(tree.root_path()), &opts.links).await?;
+  Ok(CheckoutOutcome::Restored)
+}
+
+/// Check out an artifact from the cache into the work tree.
+///
+/// Files that already match their recorded hashes are left alone.  Files that exist
+/// but do not match are only replaced if [CheckoutOptions::force] is set.
+pub async fn checkout_artifact(tree: &WorkTree, cache: &Cache, art: &Artifact, opts: &CheckoutOptions) -> Result<CheckoutOutcome, CacheError> {
+  let path = art.path();
+  match art.meta() {
+    None => {
+      warn!("{}: no metadata recorded, cannot check out", path);
+      Ok(CheckoutOutcome::Unchanged)
+    },
+    Some(ArtifactMeta::File(fm)) => checkout_file(tree, cache, path, fm, opts).await,
+    Some(ArtifactMeta::Folder(fm)) => {
+      let files = match folder_entries(cache, fm).await? {
+        Some(f) => f,
+        None => {
+          warn!("{}: folder contents unknown, cannot check out", path);
+          return Ok(CheckoutOutcome::Unchanged);
+        }
+      };
+      let mut outcome = CheckoutOutcome::Unchanged;
+      for entry in files.iter() {
+        let epath = path.join(&entry.relpath);
+        let res = checkout_file(tree, cache, &epath, &entry.meta, opts).await?;
+        outcome = outcome.combine(res);
+      }
+      Ok(outcome)
+    },
+  }
+}
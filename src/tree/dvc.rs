@@ -0,0 +1,222 @@
+//! DVC pointers (`.dvc` files and `dvc.lock` outputs).
+//!
+//! DVC records outputs with their MD5 hashes, which map onto [ArtifactMeta] with only
+//! the `md5` hash set.  A directory output is recorded by the MD5 of its `.dir`
+//! manifest (written as `<md5>.dir`); the manifest itself lives in the DVC cache, so
+//! folder artifacts read from DVC pointers have no file entries until their manifest
+//! is read with [folder_entries].
+//!
+//! DVC 2 (pointers without a `hash: md5` field) normalized line endings in text files
+//! before hashing them, so such files with CRLF line endings do not match their
+//! recorded hashes.
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use log::*;
+use relative_path::RelativePathBuf;
+use serde::{Serialize, Deserialize};
+
+use crate::cache::{Cache, CacheError};
+use crate::filehash::MultiHash;
+use crate::util::io::read_file_string;
+
+use super::artifact::{ArtifactMeta, FileMeta, FolderMeta, FolderEntry};
+
+pub use crate::cache::manifest::DIR_SUFFIX;
+
+/// The name of DVC's metadata directory.
+pub const DVC_DIR: &str = ".dvc";
+/// The extension of DVC pointer files.
+pub const DVC_EXT: &str = "dvc";
+/// The name of DVC's pipeline lock file.
+pub const DVC_LOCK: &str = "dvc.lock";
+
+fn default_true() -> bool {
+  true
+}
+
+/// Parse a YAML document into an I/O result.
+fn parse_yaml<T: for<'de> Deserialize<'de>>(content: &str) -> io::Result<T> {
+  serde_yaml::from_str(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// An output recorded in a DVC pointer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DvcOut {
+  /// The output path, relative to the pointer's directory.
+  pub path: RelativePathBuf,
+  /// The MD5 hash (with a `.dir` suffix for directories).
+  pub md5: Option<String>,
+  /// The size in bytes (total size for directories).
+  pub size: Option<u64>,
+  /// The number of files in a directory.
+  pub nfiles: Option<usize>,
+  /// The hash algorithm (`md5`); only present in DVC 3 pointers.
+  pub hash: Option<String>,
+  /// Whether DVC caches this output.
+  #[serde(default="default_true")]
+  pub cache: bool,
+}
+
+impl DvcOut {
+  /// Check whether this output is a directory.
+  pub fn is_dir(&self) -> bool {
+    self.md5.as_ref().map(|h| h.ends_with(DIR_SUFFIX)).unwrap_or(false)
+  }
+
+  /// Check whether this output was recorded by DVC 2 or earlier.
+  pub fn is_legacy(&self) -> bool {
+    self.hash.is_none()
+  }
+
+  /// Get the artifact metadata for this output.
+  ///
+  /// Returns `None` if no (valid) hash is recorded.
+  pub fn meta(&self) -> Option<ArtifactMeta> {
+    let md5 = self.md5.as_ref()?;
+    let hex = md5.strip_suffix(DIR_SUFFIX).unwrap_or(md5);
+    let hashes = match hex.parse() {
+      Ok(h) => MultiHash { md5: Some(h), ..MultiHash::default() },
+      Err(e) => {
+        warn!("{}: invalid DVC hash {}: {}", self.path, md5, e);
+        return None;
+      }
+    };
+    Some(if self.is_dir() {
+      ArtifactMeta::Folder(FolderMeta { nfiles: self.nfiles, hashes, files: Vec::new() })
+    } else {
+      ArtifactMeta::File(FileMeta { size: self.size.map(|s| s as usize), hashes })
+    })
+  }
+}
+
+/// A `.dvc` pointer file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DvcFile {
+  #[serde(default)]
+  pub outs: Vec<DvcOut>,
+}
+
+impl DvcFile {
+  /// Load a `.dvc` file.
+  pub async fn load<P: AsRef<Path>>(path: P) -> io::Result<DvcFile> {
+    debug!("reading DVC file {:?}", path.as_ref());
+    parse_yaml(&read_file_string(path).await?)
+  }
+}
+
+/// A stage in a `dvc.lock` file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DvcStage {
+  #[serde(default)]
+  pub outs: Vec<DvcOut>,
+}
+
+/// A `dvc.lock` file (schema 2.0, used since DVC 2).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DvcLock {
+  pub schema: Option<String>,
+  #[serde(default)]
+  pub stages: BTreeMap<String, DvcStage>,
+}
+
+impl DvcLock {
+  /// Load a `dvc.lock` file.
+  pub async fn load<P: AsRef<Path>>(path: P) -> io::Result<DvcLock> {
+    let path = path.as_ref();
+    debug!("reading DVC lock file {:?}", path);
+    let lock: DvcLock = parse_yaml(&read_file_string(path).await?)?;
+    if lock.schema.is_none() {
+      warn!("{:?}: unsupported lock file version (from DVC 1?), ignoring", path);
+      return Ok(DvcLock { schema: None, stages: BTreeMap::new() });
+    }
+    Ok(lock)
+  }
+
+  /// Iterate over the outputs of all stages.
+  pub fn outs(&self) -> impl Iterator<Item=&DvcOut> {
+    self.stages.values().flat_map(|s| s.outs.iter())
+  }
+}
+
+/// Get the file entries of a folder artifact.
+///
+/// Folders from DVC pointers have no entries of their own; their entries are read
+/// from the manifest in the cache.  Returns `None` if the manifest is unavailable.
+pub async fn folder_entries<'a>(cache: &Cache, meta: &'a FolderMeta) -> Result<Option<Cow<'a, [FolderEntry]>>, CacheError> {
+  if !meta.files.is_empty() || meta.nfiles.unwrap_or(0) == 0 {
+    return Ok(Some(Cow::Borrowed(&meta.files)));
+  }
+  let md5 = match &meta.hashes.md5 {
+    Some(h) => h,
+    None => return Ok(None),
+  };
+  match cache.read_manifest(md5).await {
+    Ok(entries) => Ok(Some(Cow::Owned(entries.into_iter().map(|e| FolderEntry {
+      relpath: e.relpath,
+      meta: FileMeta {
+        size: None,
+        hashes: MultiHash { md5: Some(e.md5), ..MultiHash::default() },
+      },
+    }).collect()))),
+    Err(CacheError::NotFound(_) | CacheError::NoManifests(_)) => Ok(None),
+    Err(e) => Err(e),
+  }
+}
+
+#[test]
+fn test_parse_dvc_file() {
+  let dvc: DvcFile = parse_yaml(r#"
+outs:
+- md5: d8e8fca2dc0f896fd7cb4cb0031ba249
+  size: 5
+  hash: md5
+  path: data.txt
+- md5: b6923e1e4ad16ea1a7e2a328842d56a2.dir
+  size: 9
+  nfiles: 2
+  path: images
+"#).expect("parse failed");
+  assert_eq!(dvc.outs.len(), 2);
+  assert!(!dvc.outs[0].is_legacy());
+  match dvc.outs[0].meta() {
+    Some(ArtifactMeta::File(fm)) => {
+      assert_eq!(fm.size, Some(5));
+      assert_eq!(fm.hashes.md5.unwrap().to_string(), "d8e8fca2dc0f896fd7cb4cb0031ba249");
+    },
+    m => panic!("unexpected metadata {:?}", m),
+  }
+  assert!(dvc.outs[1].is_dir());
+  match dvc.outs[1].meta() {
+    Some(ArtifactMeta::Folder(fm)) => {
+      assert_eq!(fm.nfiles, Some(2));
+      assert_eq!(fm.hashes.md5.unwrap().to_string(), "b6923e1e4ad16ea1a7e2a328842d56a2");
+      assert!(fm.files.is_empty());
+    },
+    m => panic!("unexpected metadata {:?}", m),
+  }
+}
+
+#[test]
+fn test_parse_dvc_lock() {
+  let lock: DvcLock = parse_yaml(r#"
+schema: '2.0'
+stages:
+  train:
+    cmd: python train.py
+    deps:
+    - path: train.py
+      md5: 0c8d5dc1d3b4a7bc5c5b41e8ab0f1b10
+      size: 100
+    outs:
+    - path: model.pkl
+      md5: 2b0f7e0a73ac9a8a2e7d6b2e1b6a2f14
+      size: 1234
+"#).expect("parse failed");
+  let outs: Vec<_> = lock.outs().collect();
+  assert_eq!(outs.len(), 1);
+  assert_eq!(outs[0].path.as_str(), "model.pkl");
+  assert!(outs[0].is_legacy());
+}
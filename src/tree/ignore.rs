@@ -0,0 +1,74 @@
+//! Support for maintaining VCS ignore files.
+use std::io;
+use std::path::Path;
+
+use log::*;
+use tokio::fs::{OpenOptions, read_to_string};
+use tokio::io::AsyncWriteExt;
+
+/// The name of Git's ignore file.
+pub const GITIGNORE: &str = ".gitignore";
+
+/// Ensure that an ignore file contains an entry, appending it if needed.
+///
+/// Entries are anchored to the ignore file's directory (`/name`), so that they do
+/// not match files of the same name in subdirectories; an existing unanchored
+/// entry also counts.  Returns `true` if the entry was added, and `false` if it
+/// was already present.
+pub async fn ensure_ignored<P: AsRef<Path>>(file: P, entry: &str) -> io::Result<bool> {
+  let file = file.as_ref();
+  let content = match read_to_string(file).await {
+    Ok(s) => s,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+    Err(e) => return Err(e),
+  };
+
+  let bare = entry.trim_start_matches('/');
+  if content.lines().map(str::trim).any(|l| l.trim_start_matches('/') == bare) {
+    return Ok(false);
+  }
+  let entry = format!("/{}", bare);
+
+  debug!("{:?}: adding {}", file, entry);
+  let mut out = OpenOptions::new().create(true).append(true).open(file).await?;
+  if !content.is_empty() && !content.ends_with('\n') {
+    out.write_all(b"\n").await?;
+  }
+  out.write_all(entry.as_bytes()).await?;
+  out.write_all(b"\n").await?;
+  out.flush().await?;
+  Ok(true)
+}
+
+#[tokio::test]
+async fn test_ensure_ignored_append() {
+  let dir = tempfile::tempdir().expect("tempdir failed");
+  let file = dir.path().join(GITIGNORE);
+  assert!(ensure_ignored(&file, "data.csv").await.expect("ignore failed"));
+  assert!(ensure_ignored(&file, "/model.bin").await.expect("ignore failed"));
+  let content = read_to_string(&file).await.expect("read failed");
+  assert_eq!(content, "/data.csv\n/model.bin\n");
+}
+
+#[tokio::test]
+async fn test_ensure_ignored_dedup() {
+  let dir = tempfile::tempdir().expect("tempdir failed");
+  let file = dir.path().join(GITIGNORE);
+  tokio::fs::write(&file, "data.csv\n/model.bin\n").await.expect("write failed");
+  // anchored and unanchored entries both count
+  assert!(!ensure_ignored(&file, "data.csv").await.expect("ignore failed"));
+  assert!(!ensure_ignored(&file, "model.bin").await.expect("ignore failed"));
+  assert!(!ensure_ignored(&file, "/data.csv").await.expect("ignore failed"));
+  let content = read_to_string(&file).await.expect("read failed");
+  assert_eq!(content, "data.csv\n/model.bin\n");
+}
+
+#[tokio::test]
+async fn test_ensure_ignored_no_newline() {
+  let dir = tempfile::tempdir().expect("tempdir failed");
+  let file = dir.path().join(GITIGNORE);
+  tokio::fs::write(&file, "*.log").await.expect("write failed");
+  assert!(ensure_ignored(&file, "data.csv").await.expect("ignore failed"));
+  let content = read_to_string(&file).await.expect("read failed");
+  assert_eq!(content, "*.log\n/data.csv\n");
+}
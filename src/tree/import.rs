@@ -0,0 +1,200 @@
+//! Import DVC outputs as AFC artifacts.
+//!
+//! DVC only records MD5 hashes, so importing an output computes the configured hashes
+//! from the data in the DVC cache (or, failing that, the work tree) and moves the
+//! data into the AFC cache.  Objects are moved by renaming where possible, so the
+//! data is not duplicated; importers can instead copy objects, leaving the DVC
+//! cache intact.
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+use log::*;
+use relative_path::RelativePath;
+use thiserror::Error;
+use tokio::fs::{metadata, remove_file};
+
+use crate::cache::{Cache, CacheError};
+use crate::filehash::{MultiHash, MultiDigest, HashAlgo, DigestValue, copy_file_hashed, hash_file_with};
+use crate::util::io::path_exists;
+
+use super::WorkTree;
+use super::artifact::{Artifact, ArtifactMeta, FileMeta, FolderMeta, FolderEntry};
+
+/// An error importing a DVC output.
+#[derive(Error, Debug)]
+pub enum ImportError {
+  #[error("IO error occurred: {0}")]
+  IOError(#[from] io::Error),
+  #[error("cache error: {0}")]
+  CacheError(#[from] CacheError),
+  #[error("no MD5 hash recorded")]
+  NoHash,
+  #[error("data for {0} is not in the DVC cache or the work tree")]
+  NoData(String),
+  #[error("data for {0} does not match its MD5 (modified, or normalized by DVC 2?)")]
+  Mismatch(String),
+  #[error("directory manifest {0} is not in the DVC cache")]
+  NoManifest(String),
+}
+
+/// Where the data for a DVC output can be found.
+enum Source {
+  /// The data has already been imported.
+  Imported(FileMeta),
+  /// The data is in the DVC cache.
+  Cache(PathBuf),
+  /// The data is in the work tree.
+  Tree(PathBuf),
+}
+
+/// Imports DVC outputs into an AFC cache.
+pub struct DvcImporter<'a> {
+  tree: &'a WorkTree,
+  dvc: &'a Cache,
+  afc: &'a Cache,
+  copy: bool,
+  imported: HashMap<DigestValue<16>, FileMeta>,
+}
+
+fn md5_hash(md5: &DigestValue<16>) -> MultiHash {
+  MultiHash { md5: Some(md5.clone()), ..MultiHash::default() }
+}
+
+impl<'a> DvcImporter<'a> {
+  /// Create an importer moving data from a DVC-layout cache into an AFC cache.
+  pub fn new(tree: &'a WorkTree, dvc: &'a Cache, afc: &'a Cache) -> DvcImporter<'a> {
+    DvcImporter { tree, dvc, afc, copy: false, imported: HashMap::new() }
+  }
+
+  /// Set whether to copy objects from the DVC cache instead of moving them.
+  pub fn with_copy(self, copy: bool) -> DvcImporter<'a> {
+    DvcImporter { copy, ..self }
+  }
+
+  /// Find the data for a file.
+  async fn find_file(&self, path: &RelativePath, md5: &DigestValue<16>) -> Result<Source, ImportError> {
+    if let Some(fm) = self.imported.get(md5) {
+      return Ok(Source::Imported(fm.clone()));
+    }
+    let hash = md5_hash(md5);
+    if self.dvc.contains(&hash).await? {
+      return Ok(Source::Cache(self.dvc.locate(&hash).await?));
+    }
+    let wpath = path.to_path(self.tree.root_path());
+    if path_exists(&wpath).await? {
+      return Ok(Source::Tree(wpath));
+    }
+    Err(ImportError::NoData(path.to_string()))
+  }
+
+  /// Read the manifest of a directory output.
+  async fn manifest(&self, fm: &FolderMeta) -> Result<Vec<FolderEntry>, ImportError> {
+    let md5 = fm.hashes.md5.as_ref().ok_or(ImportError::NoHash)?;
+    let entries = match self.dvc.read_manifest(md5).await {
+      Ok(es) => es,
+      Err(CacheError::NotFound(k)) => return Err(ImportError::NoManifest(k)),
+      Err(e) => return Err(e.into()),
+    };
+    Ok(entries.into_iter().map(|e| FolderEntry {
+      relpath: e.relpath,
+      meta: FileMeta { size: None, hashes: md5_hash(&e.md5) },
+    }).collect())
+  }
+
+  /// Check that an output can be imported, without importing it.
+  ///
+  /// This only checks that the data is available; it does not verify it.
+  pub async fn check(&self, art: &Artifact) -> Result<(), ImportError> {
+    let path = art.path();
+    match art.meta() {
+      Some(ArtifactMeta::File(fm)) => {
+        let md5 = fm.hashes.md5.as_ref().ok_or(ImportError::NoHash)?;
+        self.find_file(path, md5).await?;
+      },
+      Some(ArtifactMeta::Folder(fm)) => {
+        for entry in self.manifest(fm).await? {
+          let md5 = entry.meta.hashes.md5.as_ref().ok_or(ImportError::NoHash)?;
+          self.find_file(&path.join(&entry.relpath), md5).await?;
+        }
+      },
+      None => return Err(ImportError::NoHash),
+    }
+    Ok(())
+  }
+
+  /// Import a single file, returning its full metadata.
+  async fn import_file(&mut self, path: &RelativePath, md5: &DigestValue<16>) -> Result<FileMeta, ImportError> {
+    let (src, in_cache) = match self.find_file(path, md5).await? {
+      Source::Imported(fm) => return Ok(fm),
+      Source::Cache(p) => (p, true),
+      Source::Tree(p) => (p, false),
+    };
+
+    // compute the AFC cache's hashes, plus the MD5 to check the data against
+    let mut algos = self.afc.algorithms().to_vec();
+    algos.push(HashAlgo::Md5);
+    let digest = MultiDigest::with_algorithms(&algos);
+
+    let (size, hashes) = if in_cache && !self.copy {
+      let hashes = hash_file_with(&src, digest).await?;
+      if hashes.md5.as_ref() != Some(md5) {
+        return Err(ImportError::Mismatch(path.to_string()));
+      }
+      let size = metadata(&src).await?.len();
+      debug!("{}: moving {} from DVC cache", path, md5);
+      if let Err(e) = self.afc.install(&hashes, &src).await {
+        // e.g. the caches are on different file systems
+        debug!("{}: cannot move object ({}), copying", path, e);
+        self.afc.insert_file(&src).await?;
+      }
+      (size, hashes)
+    } else {
+      debug!("{}: copying {} from {}", path, md5, if in_cache { "DVC cache" } else { "work tree" });
+      let tmp = self.afc.temp_path().await?;
+      let (size, hashes) = match copy_file_hashed(&src, &tmp, digest).await {
+        Ok((size, hashes)) if hashes.md5.as_ref() == Some(md5) => (size, hashes),
+        Ok(_) => {
+          remove_file(&tmp).await?;
+          return Err(ImportError::Mismatch(path.to_string()));
+        },
+        Err(e) => {
+          let _ = remove_file(&tmp).await;
+          return Err(e.into());
+        },
+      };
+      self.afc.install(&hashes, &tmp).await?;
+      (size, hashes)
+    };
+
+    let fm = FileMeta { size: Some(size as usize), hashes };
+    self.imported.insert(md5.clone(), fm.clone());
+    Ok(fm)
+  }
+
+  /// Import an output, returning its metadata for an AFC pointer.
+  pub async fn import(&mut self, art: &Artifact) -> Result<ArtifactMeta, ImportError> {
+    let path = art.path();
+    match art.meta() {
+      Some(ArtifactMeta::File(fm)) => {
+        let md5 = fm.hashes.md5.as_ref().ok_or(ImportError::NoHash)?;
+        Ok(ArtifactMeta::File(self.import_file(path, md5).await?))
+      },
+      Some(ArtifactMeta::Folder(fm)) => {
+        let mut files = Vec::new();
+        for entry in self.manifest(fm).await? {
+          let md5 = entry.meta.hashes.md5.as_ref().ok_or(ImportError::NoHash)?;
+          let meta = self.import_file(&path.join(&entry.relpath), md5).await?;
+          files.push(FolderEntry { relpath: entry.relpath, meta });
+        }
+        let meta = FolderMeta::from_entries(files);
+        if meta.hashes.md5 != fm.hashes.md5 {
+          // DVC's manifest was not encoded the way we would encode it
+          debug!("{}: tree hash differs from DVC manifest hash", path);
+        }
+        Ok(ArtifactMeta::Folder(meta))
+      },
+      None => Err(ImportError::NoHash),
+    }
+  }
+}
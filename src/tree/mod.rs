@@ -1,18 +1,37 @@
 //! File tree operations.
 use std::io;
-use std::path::{PathBuf, Path};
+use std::path::{PathBuf, Path, StripPrefixError};
 
-use futures::{TryStream, TryStreamExt};
+use futures::{TryStream, TryStreamExt, stream};
 use thiserror::Error;
 use log::*;
 
 pub mod pointer;
 pub mod artifact;
+pub mod ignore;
+pub mod status;
+pub mod checkout;
+pub mod dvc;
+pub mod import;
+pub mod verify;
 
 use artifact::Artifact;
-use relative_path::{RelativePathBuf, FromPathError};
+use relative_path::{RelativePath, RelativePathBuf, FromPathError};
 
 use crate::util::walk::walk_directory;
+use crate::cache::Cache;
+use crate::filehash::{HashAlgo, MultiDigest, MultiHash, hash_file_with};
+use crate::filehash::state::HashCache;
+use dvc::{DVC_DIR, DVC_EXT, DVC_LOCK};
+
+/// The name of the AFC metadata directory at the root of a work tree.
+pub const AFC_DIR: &str = ".afc";
+/// The name of Git's metadata directory.
+pub const GIT_DIR: &str = ".git";
+/// The default location of the cache, relative to the root of a work tree.
+pub const DEFAULT_CACHE_DIR: &str = ".afc/cache";
+/// The location of local state (e.g. the hash cache), relative to the root of a work tree.
+pub const STATE_DIR: &str = ".afc/state";
 
 /// An error that occured scanning the work tree.
 #[derive(Error, Debug)]
@@ -21,18 +40,111 @@ pub enum ScanError {
   IOError(#[from] io::Error),
   #[error("failed to relativize path: {0}")]
   PathError(#[from] FromPathError),
+  #[error("path is outside the work tree: {0}")]
+  OutsideTree(#[from] StripPrefixError),
 }
 
 /// Representation of a working tree.
 pub struct WorkTree {
   path: PathBuf,
+  dvc: bool,
+  hashes: Option<HashCache>,
 }
 
 impl WorkTree {
-  /// Open a WorkTree at the specified location.
+  /// Open the WorkTree containing the specified location.
+  ///
+  /// This finds the root of the initialized work tree (the nearest directory with
+  /// an `.afc` directory) containing `path` with [WorkTree::find]; if there is none,
+  /// `path` itself is used as the root.  Either way, the root path is canonicalized
+  /// (if it exists), so it may differ from `path` even when `path` is the root.
   pub fn open<P: AsRef<Path>>(path: P) -> WorkTree {
-    let path = path.as_ref().to_owned();
-    WorkTree { path }
+    let path = path.as_ref();
+    match WorkTree::find(path) {
+      Ok(Some(tree)) => tree,
+      Ok(None) => WorkTree::open_root(path.canonicalize().unwrap_or_else(|_| path.to_owned())),
+      Err(e) => {
+        warn!("{:?}: error finding work tree: {}", path, e);
+        WorkTree::open_root(path)
+      }
+    }
+  }
+
+  /// Open a WorkTree rooted at exactly the specified location, without searching.
+  ///
+  /// The path is used as given; callers should canonicalize it if needed.
+  pub fn open_root<P: AsRef<Path>>(path: P) -> WorkTree {
+    WorkTree { path: path.as_ref().to_owned(), dvc: false, hashes: None }
+  }
+
+  /// Find the root of the initialized work tree containing a path.
+  ///
+  /// This walks up from `path` to the nearest directory containing an `.afc`
+  /// directory.  The search stops at the root of a Git repository, so a work tree
+  /// never extends outside the repository it is in.  Returns `None` if no work
+  /// tree is found.
+  pub fn find<P: AsRef<Path>>(path: P) -> io::Result<Option<WorkTree>> {
+    let start = path.as_ref().canonicalize()?;
+    let mut cur = Some(start.as_path());
+    while let Some(dir) = cur {
+      trace!("looking for work tree in {:?}", dir);
+      if dir.join(AFC_DIR).is_dir() {
+        debug!("found work tree at {:?}", dir);
+        return Ok(Some(WorkTree::open_root(dir)));
+      }
+      if dir.join(GIT_DIR).exists() {
+        debug!("reached Git repository root {:?}", dir);
+        break;
+      }
+      cur = dir.parent();
+    }
+    Ok(None)
+  }
+
+  /// Set whether to read DVC pointers when scanning for artifacts.
+  pub fn with_dvc(mut self, dvc: bool) -> WorkTree {
+    self.dvc = dvc;
+    self
+  }
+
+  /// Remember the hashes of work tree files in a persistent hash cache.
+  pub fn with_hash_cache(mut self, cache: HashCache) -> WorkTree {
+    self.hashes = Some(cache);
+    self
+  }
+
+  /// Load the hash cache from the work tree's state directory and use it.
+  pub async fn load_hash_cache(self) -> io::Result<WorkTree> {
+    let cache = HashCache::load(&self.path, self.path.join(STATE_DIR)).await?;
+    Ok(self.with_hash_cache(cache))
+  }
+
+  /// Compute the hashes of a file in the work tree, using the hash cache if enabled.
+  ///
+  /// The result has at least the hashes for `algos`, and may have others.
+  pub async fn hash_file(&self, path: &RelativePath, algos: &[HashAlgo]) -> io::Result<MultiHash> {
+    match &self.hashes {
+      Some(cache) => cache.hash_file(path, algos).await,
+      None => hash_file_with(path.to_path(&self.path), MultiDigest::with_algorithms(algos)).await,
+    }
+  }
+
+  /// Save the work tree's local state (the hash cache, if enabled).
+  pub async fn save_state(&self) -> io::Result<()> {
+    if let Some(cache) = &self.hashes {
+      cache.save().await?;
+    }
+    Ok(())
+  }
+
+  /// Check whether this work tree has been initialized (has an `.afc` directory).
+  pub fn is_initialized(&self) -> bool {
+    self.path.join(AFC_DIR).is_dir()
+  }
+
+  /// Check whether this work tree is the root of a Git repository.
+  pub fn is_git_root(&self) -> bool {
+    self.path.join(GIT_DIR).exists()
   }
 
   /// Get the root path of this work tree.
@@ -40,23 +152,77 @@ impl WorkTree {
     self.path.as_path()
   }
 
+  /// Resolve a file system path to a path within this work tree.
+  ///
+  /// Relative paths are resolved against the current directory.  The path itself
+  /// does not need to exist, but its parent directory does.
+  pub fn tree_path<P: AsRef<Path>>(&self, path: P) -> io::Result<RelativePathBuf> {
+    let path = path.as_ref();
+    let root = self.path.canonicalize()?;
+    let full = match path.canonicalize() {
+      Ok(p) => p,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        let name = path.file_name().ok_or(e)?;
+        let parent = match path.parent() {
+          Some(p) if !p.as_os_str().is_empty() => p,
+          _ => Path::new("."),
+        };
+        parent.canonicalize()?.join(name)
+      },
+      Err(e) => return Err(e),
+    };
+    let rel = full.strip_prefix(&root).map_err(|_| {
+      io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is outside the work tree", path))
+    })?;
+    RelativePathBuf::from_path(rel).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+  }
+
+  /// Get the local cache for this work tree.
+  pub fn cache(&self) -> Cache {
+    Cache::open(self.path.join(DEFAULT_CACHE_DIR))
+  }
+
+  /// Get the path of a file found by walking this tree, relative to the tree root.
+  pub fn relative_path(&self, path: &Path) -> Result<RelativePathBuf, ScanError> {
+    let rel = path.strip_prefix(&self.path)?;
+    Ok(RelativePathBuf::from_path(rel)?)
+  }
+
+  /// Scan the work tree for artifacts.
+  ///
+  /// This finds `.afc` pointer files and, if DVC support is enabled, `.dvc` files
+  /// and `dvc.lock` files (which may each describe several artifacts).
   pub async fn scan_artifacts<'a>(&'a self) -> impl TryStream<Ok=Artifact, Error=ScanError> + 'a {
     let stream = walk_directory(self.root_path());
     let stream = stream.map_err(ScanError::IOError);
     stream.try_filter_map(move |de| async move {
       let fpath = de.path();
       trace!("scanning path {:?}", fpath);
-      match fpath.extension() {
+      let arts = match fpath.extension() {
         Some(ext) if ext == "afc" => {
-          let root = RelativePathBuf::from_path(self.root_path())?;
-          let rp = RelativePathBuf::from_path(&fpath)?;
-          let path = root.relative(rp);
-          let art = Artifact::load_afc_pointer(self, &path).await?;
-          Ok(Some(art))
+          let path = self.relative_path(&fpath)?;
+          Artifact::load_afc_pointer(self, &path).await?
         },
-        _ => Ok(None)
-      }
-    })
-    // futures::stream::iter(Vec::new())
+        Some(ext) if ext == DVC_EXT && self.is_dvc_pointer(&fpath)? => {
+          let path = self.relative_path(&fpath)?;
+          Artifact::load_dvc_file(self, &path).await?
+        },
+        _ if fpath.file_name() == Some(DVC_LOCK.as_ref()) && self.is_dvc_pointer(&fpath)? => {
+          let path = self.relative_path(&fpath)?;
+          Artifact::load_dvc_lock(self, &path).await?
+        },
+        _ => return Ok(None)
+      };
+      Ok(Some(stream::iter(arts.into_iter().map(Ok))))
+    }).try_flatten()
+  }
+
+  /// Check whether a file found while scanning should be read as a DVC pointer.
+  fn is_dvc_pointer(&self, path: &Path) -> Result<bool, ScanError> {
+    if !self.dvc || !path.is_file() {
+      return Ok(false);
+    }
+    let rel = self.relative_path(path)?;
+    Ok(!rel.components().any(|c| c.as_str() == DVC_DIR || c.as_str() == GIT_DIR))
   }
 }
@@ -6,7 +6,7 @@ use log::*;
 use relative_path::{RelativePath, RelativePathBuf};
 use serde::{Serialize, Deserialize};
 
-use crate::util::io::read_file_string;
+use crate::util::io::{read_file_string, write_file_atomic};
 
 use super::artifact::ArtifactMeta;
 
@@ -17,35 +17,101 @@ use super::artifact::ArtifactMeta;
 /// ```toml
 /// [artifact]
 /// path = "big-file.parquet"
+/// remote = "public"  # optional
+/// size = 1048576
 /// md5 = "<...>"
 /// sha1 = "<...>"
 /// sha256 = "<...>"
-/// sha512 = "<...>"
+/// sha512 = "<...>"  # optional
+/// blake3 = "<...>"  # optional
 /// ```
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// A pointer file can instead track several related artifacts (e.g. the shards of a
+/// dataset) with an array of `[[artifacts]]` tables, each with the same fields as
+/// `[artifact]`.  Artifact paths must be unique within a pointer file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AFCPointerFile {
-  pub artifact: AFCPointer
+  #[serde(default, skip_serializing_if="Option::is_none")]
+  pub artifact: Option<AFCPointer>,
+  #[serde(default, skip_serializing_if="Vec::is_empty")]
+  pub artifacts: Vec<AFCPointer>,
 }
 
 impl AFCPointerFile {
-  /// Load an artifact from a pointer file.
+  /// Create a pointer file tracking several artifacts, sorted by path.
+  pub fn from_pointers(mut artifacts: Vec<AFCPointer>) -> AFCPointerFile {
+    artifacts.sort_by(|a, b| a.path.as_str().cmp(b.path.as_str()));
+    AFCPointerFile { artifact: None, artifacts }
+  }
+
+  /// Load the artifacts from a pointer file.
   pub async fn load<P: AsRef<Path>>(path: P) -> io::Result<AFCPointerFile> {
     debug!("reading pointer file {:?}", path.as_ref());
     let content = read_file_string(path).await?;
-    let obj = toml::from_str(&content)?;
+    let obj: AFCPointerFile = toml::from_str(&content)?;
+    obj.validate()?;
     Ok(obj)
   }
-}
 
-impl From<AFCPointer> for AFCPointerFile {
-  fn from(artifact: AFCPointer) -> Self {
-    AFCPointerFile { artifact }
+  /// Check that this pointer file tracks at least one artifact, and no path twice.
+  fn validate(&self) -> io::Result<()> {
+    let mut paths: Vec<_> = self.pointers().map(|p| p.path().normalize()).collect();
+    if paths.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "pointer file has no artifacts"));
+    }
+    paths.sort();
+    if let Some(w) = paths.windows(2).find(|w| w[0] == w[1]) {
+      let msg = format!("pointer file tracks {} more than once", w[0]);
+      return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok(())
+  }
+
+  /// Iterate over the artifact pointers in this file.
+  pub fn pointers(&self) -> impl Iterator<Item=&AFCPointer> {
+    self.artifact.iter().chain(self.artifacts.iter())
+  }
+
+  /// Get the artifact pointers in this file.
+  pub fn into_pointers(self) -> Vec<AFCPointer> {
+    self.artifact.into_iter().chain(self.artifacts).collect()
+  }
+
+  /// Add an artifact pointer, converting a single-artifact file to `[[artifacts]]`.
+  ///
+  /// Fails if the file already tracks an artifact with the same path.
+  pub fn push(&mut self, ptr: AFCPointer) -> io::Result<()> {
+    if self.pointers().any(|p| p.path().normalize() == ptr.path().normalize()) {
+      let msg = format!("pointer file already tracks {}", ptr.path);
+      return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+    }
+    let mut ptrs = std::mem::take(self).into_pointers();
+    ptrs.push(ptr);
+    *self = AFCPointerFile::from_pointers(ptrs);
+    Ok(())
+  }
+
+  /// Render this pointer as TOML.
+  ///
+  /// Keys are written in a fixed order and `[[artifacts]]` are sorted by path, so
+  /// pointer files produce minimal diffs in version control.
+  pub fn to_toml(&self) -> io::Result<String> {
+    toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+  }
+
+  /// Save this pointer to a file.
+  ///
+  /// The file is written atomically, so readers never see a partially-written pointer.
+  pub async fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    debug!("writing pointer file {:?}", path.as_ref());
+    let content = self.to_toml()?;
+    write_file_atomic(path, content).await
   }
 }
 
-impl Into<AFCPointer> for AFCPointerFile {
-  fn into(self) -> AFCPointer {
-    self.artifact
+impl From<AFCPointer> for AFCPointerFile {
+  fn from(artifact: AFCPointer) -> Self {
+    AFCPointerFile { artifact: Some(artifact), artifacts: Vec::new() }
   }
 }
 
@@ -53,6 +119,9 @@ impl Into<AFCPointer> for AFCPointerFile {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AFCPointer {
   pub path: RelativePathBuf,
+  /// The name of the remote to transfer this artifact with, instead of the default.
+  #[serde(default, skip_serializing_if="Option::is_none")]
+  pub remote: Option<String>,
   #[serde(flatten)]
   pub meta: ArtifactMeta,
 }
@@ -62,4 +131,172 @@ impl AFCPointer {
   pub fn path(&self) -> &RelativePath {
     self.path.as_relative_path()
   }
+
+  /// Get the name of the remote for this pointer, if it overrides the default.
+  pub fn remote(&self) -> Option<&str> {
+    self.remote.as_deref()
+  }
+}
+
+#[cfg(test)]
+fn test_hashes(data: &[u8]) -> crate::filehash::MultiHash {
+  let mut digest = crate::filehash::MultiDigest::new();
+  digest.update(data);
+  digest.finish()
+}
+
+#[test]
+fn test_file_pointer_format() {
+  use super::artifact::FileMeta;
+
+  let hashes = test_hashes(b"hello");
+  let ptr = AFCPointerFile::from(AFCPointer {
+    path: "hello.txt".into(),
+    remote: None,
+    meta: ArtifactMeta::File(FileMeta { size: Some(5), hashes: hashes.clone() }),
+  });
+  let text = ptr.to_toml().expect("serialize failed");
+  let expected = format!(
+    "[artifact]\npath = \"hello.txt\"\nsize = 5\nmd5 = \"{}\"\nsha1 = \"{}\"\nsha256 = \"{}\"\n",
+    hashes.md5.as_ref().unwrap(), hashes.sha1.as_ref().unwrap(), hashes.sha256.as_ref().unwrap(),
+  );
+  assert_eq!(text, expected);
+
+  let back: AFCPointerFile = toml::from_str(&text).expect("parse failed");
+  match &back.artifact.as_ref().expect("no artifact").meta {
+    ArtifactMeta::File(fm) => {
+      assert_eq!(fm.size, Some(5));
+      assert_eq!(fm.hashes, hashes);
+    },
+    m => panic!("unexpected metadata {:?}", m),
+  }
+  assert_eq!(back.to_toml().expect("serialize failed"), text);
+}
+
+#[test]
+fn test_folder_pointer_roundtrip() {
+  use super::artifact::{FileMeta, FolderMeta, FolderEntry};
+
+  let ptr = AFCPointerFile::from(AFCPointer {
+    path: "data".into(),
+    remote: None,
+    meta: ArtifactMeta::Folder(FolderMeta {
+      nfiles: Some(2),
+      hashes: test_hashes(b"manifest"),
+      files: vec![
+        FolderEntry {
+          relpath: "a.txt".into(),
+          meta: FileMeta { size: Some(1), hashes: test_hashes(b"a") },
+        },
+        FolderEntry {
+          relpath: "b/c.txt".into(),
+          meta: FileMeta { size: Some(1), hashes: test_hashes(b"c") },
+        },
+      ],
+    }),
+  });
+  let text = ptr.to_toml().expect("serialize failed");
+  let keys: Vec<_> = text.lines().filter_map(|l| l.split(" = ").next()).collect();
+  assert_eq!(&keys[..6], &["[artifact]", "path", "nfiles", "md5", "sha1", "sha256"]);
+
+  let back: AFCPointerFile = toml::from_str(&text).expect("parse failed");
+  match &back.artifact.as_ref().expect("no artifact").meta {
+    ArtifactMeta::Folder(fm) => {
+      assert_eq!(fm.nfiles, Some(2));
+      assert_eq!(fm.files.len(), 2);
+      assert_eq!(fm.files[1].relpath.as_str(), "b/c.txt");
+    },
+    m => panic!("unexpected metadata {:?}", m),
+  }
+  assert_eq!(back.to_toml().expect("serialize failed"), text);
+}
+
+#[test]
+fn test_pointer_all_hashes() {
+  use super::artifact::FileMeta;
+  use crate::filehash::{MultiDigest, ALL_ALGORITHMS};
+
+  let mut digest = MultiDigest::with_algorithms(ALL_ALGORITHMS);
+  digest.update(b"hello");
+  let hashes = digest.finish();
+  let ptr = AFCPointerFile::from(AFCPointer {
+    path: "hello.txt".into(),
+    remote: None,
+    meta: ArtifactMeta::File(FileMeta { size: Some(5), hashes: hashes.clone() }),
+  });
+  let text = ptr.to_toml().expect("serialize failed");
+  let keys: Vec<_> = text.lines().filter_map(|l| l.split(" = ").next()).collect();
+  assert_eq!(keys, vec!["[artifact]", "path", "size", "md5", "sha1", "sha256", "sha512", "blake3"]);
+
+  let back: AFCPointerFile = toml::from_str(&text).expect("parse failed");
+  match &back.artifact.as_ref().expect("no artifact").meta {
+    ArtifactMeta::File(fm) => {
+      assert!(fm.hashes.sha512.is_some());
+      assert!(fm.hashes.blake3.is_some());
+      assert_eq!(fm.hashes, hashes);
+    },
+    m => panic!("unexpected metadata {:?}", m),
+  }
+  assert_eq!(back.to_toml().expect("serialize failed"), text);
+
+  // pointers written before SHA-512 and BLAKE3 still load
+  let old = format!(
+    "[artifact]\npath = \"hello.txt\"\nsize = 5\nmd5 = \"{}\"\nsha1 = \"{}\"\nsha256 = \"{}\"\n",
+    hashes.md5.as_ref().unwrap(), hashes.sha1.as_ref().unwrap(), hashes.sha256.as_ref().unwrap(),
+  );
+  let back: AFCPointerFile = toml::from_str(&old).expect("parse failed");
+  match &back.artifact.as_ref().expect("no artifact").meta {
+    ArtifactMeta::File(fm) => {
+      assert_eq!(fm.hashes.sha256, hashes.sha256);
+      assert!(fm.hashes.sha512.is_none());
+      assert!(fm.hashes.blake3.is_none());
+    },
+    m => panic!("unexpected metadata {:?}", m),
+  }
+}
+
+#[test]
+fn test_pointer_remote() {
+  use super::artifact::FileMeta;
+
+  let ptr = AFCPointerFile::from(AFCPointer {
+    path: "hello.txt".into(),
+    remote: Some("public".into()),
+    meta: ArtifactMeta::File(FileMeta { size: Some(5), hashes: test_hashes(b"hello") }),
+  });
+  let text = ptr.to_toml().expect("serialize failed");
+  assert!(text.starts_with("[artifact]\npath = \"hello.txt\"\nremote = \"public\"\nsize = 5\n"));
+
+  let back: AFCPointerFile = toml::from_str(&text).expect("parse failed");
+  let art = back.artifact.expect("no artifact");
+  assert_eq!(art.remote(), Some("public"));
+  assert!(matches!(art.meta, ArtifactMeta::File(_)));
+}
+
+#[test]
+fn test_multi_pointer() {
+  use super::artifact::FileMeta;
+
+  let ptr = |path: &str| AFCPointer {
+    path: path.into(),
+    remote: None,
+    meta: ArtifactMeta::File(FileMeta { size: Some(4), hashes: test_hashes(path.as_bytes()) }),
+  };
+  let mut file = AFCPointerFile::from(ptr("part-1.parquet"));
+  file.push(ptr("part-0.parquet")).expect("push failed");
+  assert!(file.push(ptr("./part-1.parquet")).is_err());
+  assert!(file.artifact.is_none());
+
+  let text = file.to_toml().expect("serialize failed");
+  assert!(text.starts_with("[[artifacts]]\npath = \"part-0.parquet\"\n"));
+  let back: AFCPointerFile = toml::from_str(&text).expect("parse failed");
+  back.validate().expect("invalid pointer file");
+  let paths: Vec<_> = back.pointers().map(|p| p.path().as_str()).collect();
+  assert_eq!(paths, vec!["part-0.parquet", "part-1.parquet"]);
+  assert_eq!(back.to_toml().expect("serialize failed"), text);
+
+  let empty: AFCPointerFile = toml::from_str("").expect("parse failed");
+  assert!(empty.validate().is_err());
+  let dup: AFCPointerFile = toml::from_str("[artifact]\npath = \"a\"\n[[artifacts]]\npath = \"a\"\n").expect("parse failed");
+  assert!(dup.validate().is_err());
 }
@@ -0,0 +1,229 @@
+//! Compare the work tree against artifact pointers.
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use futures::TryStreamExt;
+use log::*;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::Serialize;
+use tokio::fs::metadata;
+
+use crate::cache::{Cache, CacheError};
+use crate::filehash::MultiHash;
+use crate::util::walk::walk_directory;
+
+use super::artifact::{Artifact, ArtifactMeta, FileMeta, FolderEntry};
+use super::dvc::{folder_entries, DVC_DIR};
+use super::{WorkTree, ScanError, AFC_DIR, GIT_DIR};
+
+/// The default minimum size for reporting untracked files (10 MiB).
+pub const DEFAULT_LARGE_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The status of an artifact (or untracked file) in the work tree.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all="kebab-case")]
+pub enum ArtifactStatus {
+  /// The work tree matches the pointer and the data is cached.
+  UpToDate,
+  /// The work tree data does not match the pointer.
+  Modified,
+  /// The artifact is missing from the work tree.
+  Missing,
+  /// The work tree matches the pointer, but the data is not in the cache.
+  NotCached,
+  /// A large file that is not tracked by any pointer.
+  Untracked,
+}
+
+impl fmt::Display for ArtifactStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let s = match self {
+      ArtifactStatus::UpToDate => "up to date",
+      ArtifactStatus::Modified => "modified",
+      ArtifactStatus::Missing => "missing",
+      ArtifactStatus::NotCached => "not cached",
+      ArtifactStatus::Untracked => "untracked",
+    };
+    f.write_str(s)
+  }
+}
+
+/// A status report entry for a single path.
+#[derive(Serialize, Debug, Clone)]
+pub struct StatusEntry {
+  /// The path to the artifact or file.
+  pub path: RelativePathBuf,
+  /// The path to the artifact's pointer, if it is tracked.
+  pub pointer: Option<RelativePathBuf>,
+  /// The artifact's status.
+  pub status: ArtifactStatus,
+}
+
+/// Check whether a file matches its recorded metadata.
+///
+/// Returns `None` if the file does not exist.
+pub(crate) async fn check_file(tree: &WorkTree, path: &RelativePath, meta: &FileMeta) -> io::Result<Option<bool>> {
+  let fpath = path.to_path(tree.root_path());
+  let stat = match metadata(&fpath).await {
+    Ok(s) => s,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e),
+  };
+  if !stat.is_file() {
+    return Ok(Some(false));
+  }
+  if let Some(size) = meta.size {
+    if stat.len() != size as u64 {
+      debug!("{}: size mismatch", path);
+      return Ok(Some(false));
+    }
+  }
+  // only the recorded hashes can be compared, so skip computing the others
+  let hash = tree.hash_file(path, &meta.hashes.algorithms()).await?;
+  Ok(Some(meta.hashes.matches(&hash)))
+}
+
+/// Check whether an object is in the cache.
+///
+/// Objects without a cache key (e.g. from DVC pointers, which only record MD5) are
+/// never in the cache.
+async fn is_cached(cache: &Cache, hash: &MultiHash) -> Result<bool, CacheError> {
+  match cache.contains(hash).await {
+    Err(CacheError::NoKey(_)) => Ok(false),
+    r => r,
+  }
+}
+
+/// Check whether a folder contains files that are not among its entries.
+///
+/// Files that `afc add` does not track in folders (in `.git` or `.afc`, or not
+/// regular files) are ignored.
+async fn has_unlisted_files(dir: &Path, files: &[FolderEntry]) -> io::Result<bool> {
+  let listed: HashSet<&str> = files.iter().map(|e| e.relpath.as_str()).collect();
+  let mut stream = walk_directory(dir);
+  while let Some(de) = stream.try_next().await? {
+    if !de.file_type()?.is_file() {
+      continue;
+    }
+    let path = de.path();
+    let rel = match path.strip_prefix(dir).ok().and_then(|p| RelativePathBuf::from_path(p).ok()) {
+      Some(p) => p,
+      None => continue,
+    };
+    if rel.components().any(|c| c.as_str() == GIT_DIR || c.as_str() == AFC_DIR) {
+      continue;
+    }
+    if !listed.contains(rel.as_str()) {
+      trace!("{:?}: {} is not listed", dir, rel);
+      return Ok(true);
+    }
+  }
+  Ok(false)
+}
+
+/// Compute the status of an artifact.
+pub async fn artifact_status(tree: &WorkTree, cache: &Cache, art: &Artifact) -> Result<ArtifactStatus, CacheError> {
+  let path = art.path();
+  let meta = match art.meta() {
+    Some(m) => m,
+    None => {
+      warn!("{}: no metadata recorded", path);
+      return Ok(ArtifactStatus::Modified);
+    }
+  };
+
+  let (present, cached) = match meta {
+    ArtifactMeta::File(fm) => {
+      let present = check_file(tree, path, fm).await?;
+      (present, is_cached(cache, &fm.hashes).await?)
+    },
+    ArtifactMeta::Folder(fm) => {
+      let dir = path.to_path(tree.root_path());
+      if !dir.is_dir() {
+        (None, false)
+      } else if let Some(files) = folder_entries(cache, fm).await? {
+        let mut matched = true;
+        let mut cached = true;
+        for entry in files.iter() {
+          let epath = path.join(&entry.relpath);
+          if check_file(tree, &epath, &entry.meta).await? != Some(true) {
+            matched = false;
+          }
+          if !is_cached(cache, &entry.meta.hashes).await? {
+            cached = false;
+          }
+        }
+        if matched && has_unlisted_files(&dir, &files).await? {
+          debug!("{}: folder has new files", path);
+          matched = false;
+        }
+        (Some(matched), cached)
+      } else {
+        // the file list is in a manifest we do not have (e.g. a DVC directory)
+        debug!("{}: folder contents unknown", path);
+        return Ok(ArtifactStatus::NotCached);
+      }
+    },
+  };
+
+  Ok(match present {
+    None => ArtifactStatus::Missing,
+    Some(false) => ArtifactStatus::Modified,
+    Some(true) if !cached => ArtifactStatus::NotCached,
+    Some(true) => ArtifactStatus::UpToDate,
+  })
+}
+
+/// Check whether a path, or one of its parent directories, is tracked.
+fn is_tracked(path: &RelativePath, tracked: &HashSet<RelativePathBuf>) -> bool {
+  let mut cur = Some(path);
+  while let Some(p) = cur {
+    if tracked.contains(p) {
+      return true;
+    }
+    cur = p.parent();
+  }
+  false
+}
+
+/// Find large files in the work tree that are not tracked by any artifact.
+///
+/// `tracked` is the set of artifact paths; files inside tracked folders are also
+/// considered to be tracked.  Files in `.git`, `.afc`, `.dvc` and the cache are
+/// never reported.
+pub async fn find_untracked(tree: &WorkTree, cache: &Cache, tracked: &HashSet<RelativePathBuf>, min_size: u64) -> Result<Vec<RelativePathBuf>, ScanError> {
+  // the cache may be configured to live in the work tree (e.g. DVC's cache)
+  let cache_dir = cache.root_path().strip_prefix(tree.root_path()).ok()
+    .and_then(|p| RelativePathBuf::from_path(p).ok())
+    .map(|p| p.normalize());
+  let mut found = Vec::new();
+  let mut stream = walk_directory(tree.root_path());
+  while let Some(de) = stream.try_next().await? {
+    let ft = de.file_type()?;
+    if !ft.is_file() {
+      continue;
+    }
+    let path = tree.relative_path(&de.path())?;
+    if path.extension() == Some("afc") {
+      continue;
+    }
+    if path.components().any(|c| c.as_str() == GIT_DIR || c.as_str() == AFC_DIR || c.as_str() == DVC_DIR) {
+      continue;
+    }
+    if cache_dir.as_ref().is_some_and(|d| path.starts_with(d)) {
+      continue;
+    }
+    if is_tracked(&path, tracked) {
+      continue;
+    }
+    let size = de.metadata()?.len();
+    if size >= min_size {
+      trace!("{}: untracked large file", path);
+      found.push(path);
+    }
+  }
+  found.sort();
+  Ok(found)
+}
@@ -0,0 +1,101 @@
+//! Verify the integrity of cached, work tree and remote data.
+//!
+//! Verification recomputes every hash recorded for a file, not just the one its
+//! object is keyed by, so that corruption is caught even if (say) the SHA-256 in a
+//! pointer was itself computed from corrupt data.  Work tree files are always
+//! re-read, bypassing the hash cache.
+use std::borrow::Cow;
+use std::path::Path;
+
+use log::*;
+use relative_path::RelativePathBuf;
+use tokio::fs::remove_file;
+
+use crate::cache::{Cache, CacheError};
+use crate::filehash::{MultiHash, MultiDigest, hash_file_with};
+use crate::remote::{Remote, RemoteError};
+use crate::util::io::path_exists;
+
+use super::artifact::{Artifact, ArtifactMeta, FileMeta, FolderEntry};
+use super::dvc::folder_entries;
+
+/// The outcome of verifying a piece of data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verified {
+  /// The data matches every recorded hash.
+  Ok,
+  /// The data does not match its recorded hashes.
+  Corrupt,
+  /// The data is not present.
+  Missing,
+}
+
+/// A file tracked by an artifact.
+#[derive(Debug, Clone)]
+pub struct TrackedFile {
+  /// The file's path in the work tree.
+  pub path: RelativePathBuf,
+  /// The file's recorded metadata.
+  pub meta: FileMeta,
+}
+
+/// List the files tracked by an artifact.
+///
+/// The files of DVC folders are read from their manifests in the cache; returns
+/// `None` if a folder's contents are unknown.
+pub async fn tracked_files(cache: &Cache, art: &Artifact) -> Result<Option<Vec<TrackedFile>>, CacheError> {
+  let path = art.path();
+  match art.meta() {
+    Some(ArtifactMeta::File(fm)) => Ok(Some(vec![TrackedFile { path: path.to_owned(), meta: fm.clone() }])),
+    Some(ArtifactMeta::Folder(fm)) => {
+      let entries: Option<Cow<[FolderEntry]>> = folder_entries(cache, fm).await?;
+      Ok(entries.map(|es| es.iter().map(|e| TrackedFile {
+        path: path.join(&e.relpath),
+        meta: e.meta.clone(),
+      }).collect()))
+    },
+    None => Ok(None),
+  }
+}
+
+/// Check a file against every hash recorded for it.
+pub async fn verify_file<P: AsRef<Path>>(path: P, hash: &MultiHash) -> Result<Verified, CacheError> {
+  let path = path.as_ref();
+  if !path_exists(path).await? {
+    return Ok(Verified::Missing);
+  }
+  let actual = hash_file_with(path, MultiDigest::for_hash(hash)).await?;
+  if hash.matches(&actual) {
+    Ok(Verified::Ok)
+  } else {
+    debug!("{:?}: expected {:?}, found {:?}", path, hash, actual);
+    Ok(Verified::Corrupt)
+  }
+}
+
+/// Check a cached object against every hash recorded for it.
+pub async fn verify_cache_object(cache: &Cache, hash: &MultiHash) -> Result<Verified, CacheError> {
+  let path = cache.locate(hash).await?;
+  verify_file(&path, hash).await
+}
+
+/// Check an object on a remote against every hash recorded for it.
+///
+/// The object is downloaded to a temporary file in the cache, which is removed
+/// afterwards.
+pub async fn verify_remote_object(remote: &dyn Remote, cache: &Cache, hash: &MultiHash) -> Result<Verified, RemoteError> {
+  if !remote.exists(hash).await? {
+    return Ok(Verified::Missing);
+  }
+  let tmp = cache.temp_path().await?;
+  let res = match remote.download(hash, &tmp).await {
+    // remotes check some hashes while downloading
+    Err(RemoteError::Corrupt(_)) => Ok(Verified::Corrupt),
+    Err(e) => Err(e),
+    Ok(()) => verify_file(&tmp, hash).await.map_err(RemoteError::from),
+  };
+  if path_exists(&tmp).await? {
+    remove_file(&tmp).await?;
+  }
+  res
+}
@@ -1,9 +1,13 @@
 //! I/O utilities.
-use std::io::Result;
-use std::path::Path;
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::fs::{File, metadata, rename, remove_file};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 /// Read a file into a string.
 pub async fn read_file_string<P: AsRef<Path>>(path: P) -> Result<String> {
@@ -12,3 +16,49 @@ pub async fn read_file_string<P: AsRef<Path>>(path: P) -> Result<String> {
   file.read_to_string(&mut content).await?;
   Ok(content)
 }
+
+/// Check whether a path exists.
+///
+/// Unlike [Path::exists], this reports I/O errors other than the path not existing.
+pub async fn path_exists<P: AsRef<Path>>(path: P) -> Result<bool> {
+  match metadata(path).await {
+    Ok(_) => Ok(true),
+    Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+    Err(e) => Err(e),
+  }
+}
+
+/// Get a temporary path in a directory for writing a file to be renamed into place.
+///
+/// The file name is hidden and unique to this process, so concurrent writers do not
+/// collide with each other.
+pub fn temp_path<P: AsRef<Path>>(dir: P, base: &str) -> PathBuf {
+  let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+  let name = format!(".{}.{}-{}.tmp", base, process::id(), n);
+  dir.as_ref().join(name)
+}
+
+/// Get a temporary path alongside a file, for atomically replacing it.
+pub fn sibling_temp_path<P: AsRef<Path>>(path: P) -> PathBuf {
+  let path = path.as_ref();
+  let dir = path.parent().unwrap_or_else(|| Path::new("."));
+  let base = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+  temp_path(dir, &base)
+}
+
+/// Atomically write a file by writing to a temporary file and renaming it into place.
+pub async fn write_file_atomic<P: AsRef<Path>>(path: P, content: impl AsRef<[u8]>) -> Result<()> {
+  let path = path.as_ref();
+  let tmp = sibling_temp_path(path);
+  let res = async {
+    let mut file = File::create(&tmp).await?;
+    file.write_all(content.as_ref()).await?;
+    file.sync_all().await?;
+    Ok(())
+  }.await;
+  if let Err(e) = res {
+    let _ = remove_file(&tmp).await;
+    return Err(e);
+  }
+  rename(&tmp, path).await
+}
@@ -0,0 +1,113 @@
+//! Tests for the `afc add` command.
+use std::fs::{create_dir_all, read_dir, read_to_string, write};
+
+use astral_filing_cabinet::cache::Cache;
+use astral_filing_cabinet::cache::manifest::{ManifestEntry, manifest_json, manifest_md5};
+use astral_filing_cabinet::tree::artifact::{ArtifactMeta, FolderMeta};
+use astral_filing_cabinet::tree::pointer::AFCPointerFile;
+
+mod common;
+use common::TestDir;
+use common::cli::{afc, afc_ok};
+
+const HELLO_MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+const WORLD_MD5: &str = "7d793037a0760186574b0282f2f435e7";
+
+#[tokio::test]
+async fn test_add_folder() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  afc_ok(root, &["init"]);
+  create_dir_all(root.join("data/sub")).expect("mkdir failed");
+  write(root.join("data/sub/b.txt"), "world").expect("write failed");
+  write(root.join("data/a.txt"), "hello").expect("write failed");
+
+  let out = afc_ok(root, &["add", "data"]);
+  assert!(out.contains("added data (2 files, "), "unexpected output: {}", out);
+  assert!(read_to_string(root.join(".gitignore")).expect("read failed").lines().any(|l| l == "/data"));
+
+  let ptr = AFCPointerFile::load(root.join("data.afc")).await.expect("load failed");
+  let fm = match &ptr.artifact.as_ref().expect("no artifact").meta {
+    ArtifactMeta::Folder(fm) => fm.clone(),
+    m => panic!("unexpected metadata {:?}", m),
+  };
+  assert_eq!(fm.nfiles, Some(2));
+  let paths: Vec<_> = fm.files.iter().map(|e| e.relpath.as_str()).collect();
+  assert_eq!(paths, vec!["a.txt", "sub/b.txt"]);
+  assert_eq!(fm.hashes, FolderMeta::tree_hash(&fm.files));
+  // the MD5 tree hash is the hash of the equivalent DVC manifest
+  let manifest = manifest_json(&[
+    ManifestEntry { md5: HELLO_MD5.parse().unwrap(), relpath: "a.txt".into() },
+    ManifestEntry { md5: WORLD_MD5.parse().unwrap(), relpath: "sub/b.txt".into() },
+  ]);
+  assert_eq!(fm.hashes.md5, Some(manifest_md5(manifest.as_bytes())));
+
+  let cache = Cache::open(root.join(".afc/cache"));
+  for entry in &fm.files {
+    assert!(cache.contains(&entry.meta.hashes).await.expect("contains failed"), "{} not cached", entry.relpath);
+  }
+
+  let out = afc_ok(root, &["status"]);
+  assert_eq!(out.lines().map(str::trim).collect::<Vec<_>>(), vec!["up to date: data"]);
+  write(root.join("data/sub/b.txt"), "there").expect("write failed");
+  let out = afc_ok(root, &["status"]);
+  assert_eq!(out.lines().map(str::trim).collect::<Vec<_>>(), vec!["modified: data"]);
+}
+
+#[test]
+fn test_add_pointer_invalid() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  afc_ok(root, &["init"]);
+  create_dir_all(root.join("sub")).expect("mkdir failed");
+  write(root.join("a.txt"), "hello").expect("write failed");
+  write(root.join("sub/c.txt"), "world").expect("write failed");
+
+  // the bad path is found before anything is cached or ignored
+  let out = afc(root, &["add", "-p", "data.afc", "a.txt", "sub/c.txt"]);
+  assert!(!out.status.success());
+  let stderr = String::from_utf8_lossy(&out.stderr);
+  assert!(stderr.contains("sub/c.txt: not in the same directory as data.afc"), "unexpected errors: {}", stderr);
+  assert!(!root.join("data.afc").exists());
+  assert_eq!(read_dir(root.join(".afc/cache")).expect("read failed").count(), 0);
+  assert!(!read_to_string(root.join(".gitignore")).expect("read failed").contains("/a.txt"));
+}
+
+#[test]
+fn test_add_tracked_elsewhere() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  afc_ok(root, &["init"]);
+  write(root.join("a.txt"), "hello").expect("write failed");
+  write(root.join("b.txt"), "world").expect("write failed");
+  afc_ok(root, &["add", "-p", "shards.afc", "a.txt", "b.txt"]);
+
+  let out = afc(root, &["add", "a.txt"]);
+  assert!(!out.status.success());
+  let stderr = String::from_utf8_lossy(&out.stderr);
+  assert!(stderr.contains("a.txt: already tracked by shards.afc"), "unexpected errors: {}", stderr);
+  assert!(!root.join("a.txt.afc").exists());
+
+  let out = afc(root, &["add", "-p", "other.afc", "b.txt"]);
+  assert!(!out.status.success());
+  let stderr = String::from_utf8_lossy(&out.stderr);
+  assert!(stderr.contains("b.txt: already tracked by shards.afc"), "unexpected errors: {}", stderr);
+  assert!(!root.join("other.afc").exists());
+}
+
+#[test]
+fn test_add_inside_folder() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  afc_ok(root, &["init"]);
+  create_dir_all(root.join("data/images")).expect("mkdir failed");
+  write(root.join("data/images/x.png"), "pixels").expect("write failed");
+  afc_ok(root, &["add", "data/images"]);
+
+  // the file is already tracked, by a pointer in a parent directory
+  let out = afc(root, &["add", "data/images/x.png"]);
+  assert!(!out.status.success());
+  let stderr = String::from_utf8_lossy(&out.stderr);
+  assert!(stderr.contains("data/images/x.png: already tracked by data/images.afc"), "unexpected errors: {}", stderr);
+  assert!(!root.join("data/images/x.png.afc").exists());
+}
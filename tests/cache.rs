@@ -0,0 +1,131 @@
+use std::fs::{read, write};
+
+use astral_filing_cabinet::cache::{Cache, CacheError};
+use astral_filing_cabinet::cache::link::LinkStrategy;
+
+mod common;
+use common::TestDir;
+
+#[tokio::test]
+async fn test_insert_get() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"some important data").expect("write failed");
+
+  let (_, hash) = cache.insert_file(&src).await.expect("insert failed");
+  assert!(cache.contains(&hash).await.expect("contains failed"));
+
+  let opath = cache.object_path(&hash).expect("no key");
+  let key = hash.sha256.as_ref().unwrap().to_string();
+  assert!(opath.ends_with(format!("{}/{}", &key[..2], &key[2..])));
+
+  let dst = dir.path().join("copy.txt");
+  cache.get(&hash, &dst).await.expect("get failed");
+  assert_eq!(read(&dst).expect("read failed"), b"some important data");
+}
+
+#[tokio::test]
+async fn test_insert_twice() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"some important data").expect("write failed");
+
+  let (_, h1) = cache.insert_file(&src).await.expect("insert failed");
+  let (_, h2) = cache.insert_file(&src).await.expect("insert failed");
+  assert_eq!(h1, h2);
+}
+
+#[tokio::test]
+async fn test_get_missing() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"some important data").expect("write failed");
+  let (_, hash) = cache.insert_file(&src).await.expect("insert failed");
+
+  let other = Cache::open(dir.path().join("other"));
+  assert!(!other.contains(&hash).await.expect("contains failed"));
+  let res = other.get(&hash, dir.path().join("out.txt")).await;
+  assert!(matches!(res, Err(CacheError::NotFound(_))));
+}
+
+#[tokio::test]
+async fn test_get_corrupt() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"some important data").expect("write failed");
+  let (_, hash) = cache.insert_file(&src).await.expect("insert failed");
+
+  let opath = cache.object_path(&hash).expect("no key");
+  write(&opath, b"some corrupted data").expect("write failed");
+  assert!(!cache.verify(&hash).await.expect("verify failed"));
+
+  let dst = dir.path().join("out.txt");
+  let res = cache.get(&hash, &dst).await;
+  assert!(matches!(res, Err(CacheError::Corrupt(_))));
+  assert!(!dst.exists());
+}
+
+// inode numbers and symlinks are only available on Unix
+#[cfg(unix)]
+#[tokio::test]
+async fn test_link_hardlink() {
+  use std::os::unix::fs::MetadataExt;
+
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"some important data").expect("write failed");
+  let (_, hash) = cache.insert_file(&src).await.expect("insert failed");
+
+  let dst = dir.path().join("linked.txt");
+  let used = cache.link(&hash, &dst, &[LinkStrategy::Hardlink, LinkStrategy::Copy]).await.expect("link failed");
+  assert_eq!(used, LinkStrategy::Hardlink);
+  let meta = std::fs::metadata(&dst).expect("stat failed");
+  let ometa = std::fs::metadata(cache.object_path(&hash).unwrap()).expect("stat failed");
+  assert_eq!(meta.ino(), ometa.ino());
+  assert!(meta.permissions().readonly());
+
+  // linking again over the existing link leaves no temporary file behind
+  let used = cache.link(&hash, &dst, &[LinkStrategy::Hardlink]).await.expect("link failed");
+  assert_eq!(used, LinkStrategy::Hardlink);
+  let names: Vec<_> = std::fs::read_dir(dir.path()).expect("read failed")
+    .map(|e| e.expect("read failed").file_name().to_string_lossy().into_owned())
+    .filter(|n| n.starts_with('.'))
+    .collect();
+  assert!(names.is_empty(), "leftover files {:?}", names);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_link_symlink() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"some important data").expect("write failed");
+  let (_, hash) = cache.insert_file(&src).await.expect("insert failed");
+
+  let dst = dir.path().join("linked.txt");
+  let used = cache.link(&hash, &dst, &[LinkStrategy::Symlink]).await.expect("link failed");
+  assert_eq!(used, LinkStrategy::Symlink);
+  assert!(std::fs::symlink_metadata(&dst).expect("stat failed").file_type().is_symlink());
+  assert_eq!(read(&dst).expect("read failed"), b"some important data");
+}
+
+#[tokio::test]
+async fn test_link_fallback_copy() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"some important data").expect("write failed");
+  let (_, hash) = cache.insert_file(&src).await.expect("insert failed");
+
+  let dst = dir.path().join("copied.txt");
+  let used = cache.link(&hash, &dst, &[LinkStrategy::Reflink, LinkStrategy::Copy]).await.expect("link failed");
+  assert!(used == LinkStrategy::Reflink || used == LinkStrategy::Copy);
+  assert_eq!(read(&dst).expect("read failed"), b"some important data");
+  assert!(!std::fs::metadata(&dst).expect("stat failed").permissions().readonly());
+}
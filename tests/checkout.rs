@@ -0,0 +1,61 @@
+use std::fs::{read, write, remove_file};
+
+use astral_filing_cabinet::cache::CacheError;
+use astral_filing_cabinet::tree::WorkTree;
+use astral_filing_cabinet::tree::checkout::{checkout_artifact, CheckoutOptions, CheckoutOutcome};
+use futures::TryStreamExt;
+
+mod common;
+use common::TestDir;
+
+#[tokio::test]
+async fn test_checkout_restore() {
+  let dir = TestDir::tarball("single-artifact-in-subdir");
+  let tree = WorkTree::open(dir.path());
+  let cache = tree.cache();
+  let apath = dir.path().join("data/artifact.dat");
+  let content = read(&apath).expect("read failed");
+  cache.insert_file(&apath).await.expect("insert failed");
+  remove_file(&apath).expect("remove failed");
+
+  let arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  let res = checkout_artifact(&tree, &cache, &arts[0], &CheckoutOptions::default()).await.expect("checkout failed");
+  assert_eq!(res, CheckoutOutcome::Restored);
+  assert_eq!(read(&apath).expect("read failed"), content);
+
+  let res = checkout_artifact(&tree, &cache, &arts[0], &CheckoutOptions::default()).await.expect("checkout failed");
+  assert_eq!(res, CheckoutOutcome::Unchanged);
+}
+
+#[tokio::test]
+async fn test_checkout_modified() {
+  let dir = TestDir::tarball("single-artifact");
+  let tree = WorkTree::open(dir.path());
+  let cache = tree.cache();
+  let apath = dir.path().join("artifact.dat");
+  let content = read(&apath).expect("read failed");
+  cache.insert_file(&apath).await.expect("insert failed");
+  write(&apath, b"local changes").expect("write failed");
+
+  let arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  let res = checkout_artifact(&tree, &cache, &arts[0], &CheckoutOptions::default()).await.expect("checkout failed");
+  assert_eq!(res, CheckoutOutcome::Modified);
+  assert_eq!(read(&apath).expect("read failed"), b"local changes");
+
+  let force = CheckoutOptions { force: true, ..Default::default() };
+  let res = checkout_artifact(&tree, &cache, &arts[0], &force).await.expect("checkout failed");
+  assert_eq!(res, CheckoutOutcome::Restored);
+  assert_eq!(read(&apath).expect("read failed"), content);
+}
+
+#[tokio::test]
+async fn test_checkout_not_cached() {
+  let dir = TestDir::tarball("single-artifact");
+  let tree = WorkTree::open(dir.path());
+  let cache = tree.cache();
+  remove_file(dir.path().join("artifact.dat")).expect("remove failed");
+
+  let arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  let res = checkout_artifact(&tree, &cache, &arts[0], &CheckoutOptions::default()).await;
+  assert!(matches!(res, Err(CacheError::NotFound(_))));
+}
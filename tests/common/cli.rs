@@ -0,0 +1,21 @@
+//! Support code for running the `afc` program.
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Run `afc` in a directory, isolated from the user's configuration.
+pub fn afc<P: AsRef<Path>>(dir: P, args: &[&str]) -> Output {
+  let dir = dir.as_ref().canonicalize().expect("canonicalize failed");
+  Command::new(env!("CARGO_BIN_EXE_afc"))
+    .args(args)
+    .current_dir(&dir)
+    .env("XDG_CONFIG_HOME", dir.join(".config"))
+    .output()
+    .expect("failed to run afc")
+}
+
+/// Run `afc` in a directory, expecting it to succeed, and return its output.
+pub fn afc_ok<P: AsRef<Path>>(dir: P, args: &[&str]) -> String {
+  let out = afc(dir, args);
+  assert!(out.status.success(), "afc {:?} failed: {}", args, String::from_utf8_lossy(&out.stderr));
+  String::from_utf8(out.stdout).expect("invalid output")
+}
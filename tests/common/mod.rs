@@ -1,3 +1,6 @@
+#![allow(dead_code)]
+
+pub mod cli;
 pub mod testdir;
 
 pub use testdir::TestDir;
@@ -0,0 +1,60 @@
+//! Tests for the `afc config` commands.
+use std::fs::read_to_string;
+
+use toml::Value;
+use toml::value::Table;
+
+mod common;
+use common::TestDir;
+use common::cli::{afc, afc_ok};
+
+fn read_table(dir: &TestDir, file: &str) -> Table {
+  let content = read_to_string(dir.path().join(file)).expect("read failed");
+  toml::from_str(&content).expect("invalid config")
+}
+
+#[test]
+fn test_config_set_get() {
+  let dir = TestDir::empty();
+  afc_ok(dir.path(), &["init"]);
+  assert!(!afc(dir.path(), &["config", "get", "core.jobs"]).status.success());
+
+  afc_ok(dir.path(), &["config", "set", "core.jobs", "8"]);
+  assert_eq!(afc_ok(dir.path(), &["config", "get", "core.jobs"]), "8\n");
+  afc_ok(dir.path(), &["config", "set", "cache.link", "[hardlink, copy]"]);
+  let table = read_table(&dir, ".afc/config.toml");
+  assert_eq!(table["core"]["jobs"], Value::Integer(8));
+  let link = Value::Array(vec![Value::String("hardlink".into()), Value::String("copy".into())]);
+  assert_eq!(table["cache"]["link"], link);
+
+  // values that do not produce valid settings are not saved
+  assert!(!afc(dir.path(), &["config", "set", "core.jobs", "many"]).status.success());
+  assert!(!afc(dir.path(), &["config", "set", "cache.link", "[teleport]"]).status.success());
+  assert_eq!(read_table(&dir, ".afc/config.toml"), table);
+}
+
+#[test]
+fn test_config_local_unset() {
+  let dir = TestDir::empty();
+  afc_ok(dir.path(), &["init"]);
+  afc_ok(dir.path(), &["config", "set", "core.jobs", "8"]);
+  afc_ok(dir.path(), &["config", "set", "--local", "core.jobs", "2"]);
+  assert_eq!(afc_ok(dir.path(), &["config", "get", "core.jobs"]), "2\n");
+  assert_eq!(read_table(&dir, ".afc/config.local.toml")["core"]["jobs"], Value::Integer(2));
+
+  afc_ok(dir.path(), &["config", "unset", "--local", "core.jobs"]);
+  assert_eq!(afc_ok(dir.path(), &["config", "get", "core.jobs"]), "8\n");
+  assert!(!afc(dir.path(), &["config", "unset", "--local", "core.jobs"]).status.success());
+  afc_ok(dir.path(), &["config", "unset", "core.jobs"]);
+  assert!(!afc(dir.path(), &["config", "get", "core.jobs"]).status.success());
+}
+
+#[test]
+fn test_config_set_no_injection() {
+  let dir = TestDir::empty();
+  afc_ok(dir.path(), &["init"]);
+  afc_ok(dir.path(), &["config", "set", "core.remote", "origin\n[remote.evil]\ntype = \"http\""]);
+  let table = read_table(&dir, ".afc/config.toml");
+  assert!(table.get("remote").is_none());
+  assert_eq!(table["core"]["remote"], Value::String("origin\n[remote.evil]\ntype = \"http\"".into()));
+}
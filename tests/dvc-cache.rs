@@ -0,0 +1,186 @@
+//! Tests for DVC-layout caches and remotes.
+use std::fs::{create_dir_all, read, read_to_string, remove_dir_all, remove_file, write};
+
+use astral_filing_cabinet::cache::{Cache, CacheLayout, CacheError};
+use astral_filing_cabinet::cache::manifest::ManifestEntry;
+use astral_filing_cabinet::filehash::MultiHash;
+use astral_filing_cabinet::remote::{LocalRemote, Remote};
+use astral_filing_cabinet::tree::WorkTree;
+use astral_filing_cabinet::tree::checkout::{checkout_artifact, CheckoutOptions, CheckoutOutcome};
+use astral_filing_cabinet::tree::status::{artifact_status, ArtifactStatus};
+use futures::TryStreamExt;
+
+mod common;
+use common::TestDir;
+use common::cli::afc_ok;
+
+const HELLO_MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+const WORLD_MD5: &str = "7d793037a0760186574b0282f2f435e7";
+
+fn md5_hash(md5: &str) -> MultiHash {
+  MultiHash { md5: Some(md5.parse().unwrap()), ..MultiHash::default() }
+}
+
+#[tokio::test]
+async fn test_dvc3_insert_get() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  let src = dir.path().join("hello.txt");
+  write(&src, "hello").expect("write failed");
+
+  let (_, hash) = cache.insert_file(&src).await.expect("insert failed");
+  assert_eq!(hash.md5.as_ref().unwrap().to_string(), HELLO_MD5);
+  let opath = dir.path().join(".dvc/cache/files/md5/5d/41402abc4b2a76b9719d911017c592");
+  assert_eq!(read(&opath).expect("read failed"), b"hello");
+
+  // pointers from DVC only have MD5 hashes
+  let hash = md5_hash(HELLO_MD5);
+  assert!(cache.contains(&hash).await.expect("contains failed"));
+  let dst = dir.path().join("copy.txt");
+  cache.get(&hash, &dst).await.expect("get failed");
+  assert_eq!(read(&dst).expect("read failed"), b"hello");
+
+  let afc = Cache::open(dir.path().join(".afc/cache"));
+  assert!(matches!(afc.contains(&hash).await, Err(CacheError::NoKey(_))));
+}
+
+#[tokio::test]
+async fn test_dvc3_legacy_objects() {
+  let dir = TestDir::empty();
+  let root = dir.path().join(".dvc/cache");
+  create_dir_all(root.join("5d")).expect("mkdir failed");
+  write(root.join("5d/41402abc4b2a76b9719d911017c592"), "hello").expect("write failed");
+
+  let hash = md5_hash(HELLO_MD5);
+  let dvc3 = Cache::open(&root).with_layout(CacheLayout::Dvc);
+  assert!(dvc3.contains(&hash).await.expect("contains failed"));
+  assert!(dvc3.verify(&hash).await.expect("verify failed"));
+  let dvc2 = Cache::open(&root).with_layout(CacheLayout::Dvc2);
+  assert!(dvc2.contains(&hash).await.expect("contains failed"));
+
+  let bad = md5_hash(WORLD_MD5);
+  let res = dvc3.get(&bad, dir.path().join("out.txt")).await;
+  assert!(matches!(res, Err(CacheError::NotFound(k)) if k == WORLD_MD5));
+}
+
+#[tokio::test]
+async fn test_manifest_roundtrip() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  let entries = vec![
+    ManifestEntry { md5: WORLD_MD5.parse().unwrap(), relpath: "b/world.txt".into() },
+    ManifestEntry { md5: HELLO_MD5.parse().unwrap(), relpath: "hello.txt".into() },
+  ];
+  let md5 = cache.write_manifest(&entries).await.expect("write failed");
+  let mpath = dir.path().join(format!(".dvc/cache/files/md5/{}/{}.dir", &md5.to_string()[..2], &md5.to_string()[2..]));
+  assert!(read_to_string(&mpath).expect("read failed").starts_with("[{\"md5\": "));
+
+  let read_back = cache.read_manifest(&md5).await.expect("read failed");
+  assert_eq!(read_back, entries);
+
+  let afc = Cache::open(dir.path().join(".afc/cache"));
+  assert!(matches!(afc.read_manifest(&md5).await, Err(CacheError::NoManifests(CacheLayout::Afc))));
+}
+
+#[tokio::test]
+async fn test_dvc_dir_checkout() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  let cache = Cache::open(root.join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+
+  create_dir_all(root.join("data/b")).expect("mkdir failed");
+  write(root.join("data/hello.txt"), "hello").expect("write failed");
+  write(root.join("data/b/world.txt"), "world").expect("write failed");
+  cache.insert_file(root.join("data/hello.txt")).await.expect("insert failed");
+  cache.insert_file(root.join("data/b/world.txt")).await.expect("insert failed");
+  let md5 = cache.write_manifest(&[
+    ManifestEntry { md5: HELLO_MD5.parse().unwrap(), relpath: "hello.txt".into() },
+    ManifestEntry { md5: WORLD_MD5.parse().unwrap(), relpath: "b/world.txt".into() },
+  ]).await.expect("write failed");
+  write(root.join("data.dvc"), format!("outs:\n- md5: {}.dir\n  size: 10\n  nfiles: 2\n  hash: md5\n  path: data\n", md5)).expect("write failed");
+
+  let tree = WorkTree::open_root(root).with_dvc(true);
+  let arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  assert_eq!(arts.len(), 1);
+  let art = &arts[0];
+  let status = artifact_status(&tree, &cache, art).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::UpToDate);
+
+  remove_file(root.join("data/b/world.txt")).expect("remove failed");
+  let status = artifact_status(&tree, &cache, art).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::Modified);
+
+  let res = checkout_artifact(&tree, &cache, art, &CheckoutOptions::default()).await.expect("checkout failed");
+  assert_eq!(res, CheckoutOutcome::Restored);
+  assert_eq!(read_to_string(root.join("data/b/world.txt")).expect("read failed"), "world");
+
+  // without the manifest, the folder contents are unknown
+  let empty = Cache::open(root.join("empty")).with_layout(CacheLayout::Dvc);
+  let status = artifact_status(&tree, &empty, art).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::NotCached);
+}
+
+#[tokio::test]
+async fn test_pull_from_dvc_remote() {
+  let dir = TestDir::empty();
+  let remote_root = dir.path().join("dvc-remote");
+  create_dir_all(remote_root.join("files/md5/5d")).expect("mkdir failed");
+  write(remote_root.join("files/md5/5d/41402abc4b2a76b9719d911017c592"), "hello").expect("write failed");
+  write(remote_root.join("files/md5/5d/41402abc4b2a76b9719d911017c592.dir"), "[]").expect("write failed");
+  let remote = LocalRemote::new(&remote_root).with_layout(CacheLayout::Dvc);
+
+  let listed = remote.list().await.expect("list failed");
+  assert_eq!(listed, vec![md5_hash(HELLO_MD5)]);
+
+  let hash = md5_hash(HELLO_MD5);
+  assert!(remote.exists(&hash).await.expect("exists failed"));
+  let cache = Cache::open(dir.path().join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  let tmp = cache.temp_path().await.expect("temp failed");
+  remote.download(&hash, &tmp).await.expect("download failed");
+  cache.install(&hash, &tmp).await.expect("install failed");
+  assert!(cache.contains(&hash).await.expect("contains failed"));
+
+  // and push it to an AFC-layout remote, which needs a SHA-256
+  let afc = LocalRemote::new(dir.path().join("afc-remote"));
+  assert!(afc.upload(&hash, &cache.locate(&hash).await.unwrap()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_push_pull_dvc_folder() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  afc_ok(root, &["init"]);
+  afc_ok(root, &["config", "set", "core.dvc", "true"]);
+  afc_ok(root, &["config", "set", "cache.dir", ".dvc/cache"]);
+  afc_ok(root, &["config", "set", "cache.layout", "dvc"]);
+  afc_ok(root, &["remote", "add", "-d", "origin", "dvc-remote"]);
+  afc_ok(root, &["config", "set", "remote.origin.layout", "dvc"]);
+
+  let cache = Cache::open(root.join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  create_dir_all(root.join("data/b")).expect("mkdir failed");
+  write(root.join("data/hello.txt"), "hello").expect("write failed");
+  write(root.join("data/b/world.txt"), "world").expect("write failed");
+  cache.insert_file(root.join("data/hello.txt")).await.expect("insert failed");
+  cache.insert_file(root.join("data/b/world.txt")).await.expect("insert failed");
+  let md5 = cache.write_manifest(&[
+    ManifestEntry { md5: HELLO_MD5.parse().unwrap(), relpath: "hello.txt".into() },
+    ManifestEntry { md5: WORLD_MD5.parse().unwrap(), relpath: "b/world.txt".into() },
+  ]).await.expect("write failed");
+  write(root.join("data.dvc"), format!("outs:\n- md5: {}.dir\n  size: 10\n  nfiles: 2\n  hash: md5\n  path: data\n", md5)).expect("write failed");
+
+  // the manifest is pushed along with the files it lists
+  let out = afc_ok(root, &["push"]);
+  assert!(out.contains("pushed 3 objects"), "unexpected output: {}", out);
+  let remote = LocalRemote::new(root.join("dvc-remote")).with_layout(CacheLayout::Dvc);
+  assert!(remote.manifest_exists(&md5).await.expect("exists failed"));
+  assert!(remote.exists(&md5_hash(HELLO_MD5)).await.expect("exists failed"));
+  assert!(remote.exists(&md5_hash(WORLD_MD5)).await.expect("exists failed"));
+
+  // a fresh clone fetches the manifest, then the files
+  remove_dir_all(root.join(".dvc/cache")).expect("rmdir failed");
+  remove_dir_all(root.join("data")).expect("rmdir failed");
+  let out = afc_ok(root, &["pull"]);
+  assert!(out.contains("pulled 3 objects"), "unexpected output: {}", out);
+  assert_eq!(read_to_string(root.join("data/hello.txt")).expect("read failed"), "hello");
+  assert_eq!(read_to_string(root.join("data/b/world.txt")).expect("read failed"), "world");
+}
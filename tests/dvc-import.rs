@@ -0,0 +1,209 @@
+//! Tests for importing DVC outputs into the AFC cache.
+use std::fs::{create_dir_all, read_dir, read_to_string, write};
+use std::path::Path;
+use std::process::Command;
+
+use astral_filing_cabinet::cache::{Cache, CacheLayout};
+use astral_filing_cabinet::cache::manifest::ManifestEntry;
+use astral_filing_cabinet::filehash::MultiHash;
+use astral_filing_cabinet::tree::WorkTree;
+use astral_filing_cabinet::tree::artifact::{Artifact, ArtifactMeta};
+use astral_filing_cabinet::tree::import::{DvcImporter, ImportError};
+use astral_filing_cabinet::tree::pointer::AFCPointerFile;
+use futures::TryStreamExt;
+
+mod common;
+use common::TestDir;
+use common::cli::{afc, afc_ok};
+
+const HELLO_MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+const WORLD_MD5: &str = "7d793037a0760186574b0282f2f435e7";
+
+fn md5_hash(md5: &str) -> MultiHash {
+  MultiHash { md5: Some(md5.parse().unwrap()), ..MultiHash::default() }
+}
+
+fn dvc_file(root: &Path, name: &str, md5: &str) {
+  write(root.join(format!("{}.dvc", name)), format!("outs:\n- md5: {}\n  hash: md5\n  path: {}\n", md5, name)).expect("write failed");
+}
+
+/// Run a Git command in a directory, returning its output.
+fn git(dir: &Path, args: &[&str]) -> String {
+  let out = Command::new("git")
+    .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+    .args(args)
+    .current_dir(dir)
+    .output()
+    .expect("failed to run git");
+  assert!(out.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&out.stderr));
+  String::from_utf8(out.stdout).expect("invalid output")
+}
+
+/// Set up a work tree with a DVC output whose data is in the DVC cache.
+async fn setup_cli(root: &Path) {
+  afc_ok(root, &["init"]);
+  let dvc = Cache::open(root.join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  write(root.join("src.txt"), "hello").expect("write failed");
+  dvc.insert_file(root.join("src.txt")).await.expect("insert failed");
+  dvc_file(root, "cached.txt", HELLO_MD5);
+}
+
+async fn scan(tree: &WorkTree) -> Vec<Artifact> {
+  let mut arts: Vec<Artifact> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  arts.sort_by(|a, b| a.path().cmp(b.path()));
+  arts
+}
+
+#[tokio::test]
+async fn test_import_outputs() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  let dvc = Cache::open(root.join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  let afc = Cache::open(root.join(".afc/cache"));
+
+  // a file only in the DVC cache
+  write(root.join("src.txt"), "hello").expect("write failed");
+  dvc.insert_file(root.join("src.txt")).await.expect("insert failed");
+  dvc_file(root, "cached.txt", HELLO_MD5);
+  // a file only in the work tree
+  write(root.join("local.txt"), "world").expect("write failed");
+  dvc_file(root, "local.txt", WORLD_MD5);
+  // a directory sharing an object with the first file
+  create_dir_all(root.join("data")).expect("mkdir failed");
+  write(root.join("data/b.txt"), "world").expect("write failed");
+  let md5 = dvc.write_manifest(&[
+    ManifestEntry { md5: HELLO_MD5.parse().unwrap(), relpath: "a.txt".into() },
+    ManifestEntry { md5: WORLD_MD5.parse().unwrap(), relpath: "b.txt".into() },
+  ]).await.expect("write failed");
+  write(root.join("data.dvc"), format!("outs:\n- md5: {}.dir\n  nfiles: 2\n  path: data\n", md5)).expect("write failed");
+
+  let tree = WorkTree::open_root(root).with_dvc(true);
+  let arts = scan(&tree).await;
+  let mut importer = DvcImporter::new(&tree, &dvc, &afc);
+  for art in &arts {
+    importer.check(art).await.expect("check failed");
+  }
+
+  let hello = match importer.import(&arts[0]).await.expect("import failed") {
+    ArtifactMeta::File(fm) => fm,
+    m => panic!("unexpected metadata {:?}", m),
+  };
+  assert_eq!(arts[0].path().as_str(), "cached.txt");
+  assert_eq!(hello.size, Some(5));
+  assert!(hello.hashes.sha256.is_some());
+  assert!(afc.contains(&hello.hashes).await.expect("contains failed"));
+  // the object was moved out of the DVC cache
+  assert!(!dvc.contains(&hello.hashes).await.expect("contains failed"));
+
+  match importer.import(&arts[1]).await.expect("import failed") {
+    ArtifactMeta::Folder(fm) => {
+      assert_eq!(fm.nfiles, Some(2));
+      assert_eq!(fm.hashes.md5, Some(md5));
+      assert_eq!(fm.files[0].relpath.as_str(), "a.txt");
+      assert_eq!(fm.files[0].meta.hashes, hello.hashes);
+      assert_eq!(fm.files[1].meta.size, Some(5));
+      assert!(afc.contains(&fm.files[1].meta.hashes).await.expect("contains failed"));
+    },
+    m => panic!("unexpected metadata {:?}", m),
+  }
+
+  match importer.import(&arts[2]).await.expect("import failed") {
+    ArtifactMeta::File(fm) => assert!(afc.contains(&fm.hashes).await.expect("contains failed")),
+    m => panic!("unexpected metadata {:?}", m),
+  }
+}
+
+#[tokio::test]
+async fn test_import_failures() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  let dvc = Cache::open(root.join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  let afc = Cache::open(root.join(".afc/cache"));
+
+  dvc_file(root, "gone.txt", HELLO_MD5);
+  write(root.join("changed.txt"), "hellO").expect("write failed");
+  dvc_file(root, "changed.txt", HELLO_MD5);
+  write(root.join("dir.dvc"), "outs:\n- md5: 0c8d5dc1d3b4a7bc5c5b41e8ab0f1b10.dir\n  nfiles: 3\n  path: dir\n").expect("write failed");
+
+  let tree = WorkTree::open_root(root).with_dvc(true);
+  let arts = scan(&tree).await;
+  let mut importer = DvcImporter::new(&tree, &dvc, &afc);
+
+  let res = importer.import(&arts[0]).await;
+  assert!(matches!(res, Err(ImportError::Mismatch(p)) if p == "changed.txt"));
+  let res = importer.check(&arts[1]).await;
+  assert!(matches!(res, Err(ImportError::NoManifest(_))));
+  let res = importer.check(&arts[2]).await;
+  assert!(matches!(res, Err(ImportError::NoData(p)) if p == "gone.txt"));
+}
+
+#[tokio::test]
+async fn test_import_dry_run() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  setup_cli(root).await;
+
+  let out = afc_ok(root, &["import-dvc", "--dry-run"]);
+  assert!(out.contains("would convert cached.txt (cached.txt.dvc) -> cached.txt.afc"), "unexpected output: {}", out);
+  assert!(out.contains("would remove cached.txt.dvc"), "unexpected output: {}", out);
+  assert!(root.join("cached.txt.dvc").exists());
+  assert!(!root.join("cached.txt.afc").exists());
+  assert!(!read_to_string(root.join(".gitignore")).expect("read failed").contains("/cached.txt"));
+  assert_eq!(read_dir(root.join(".afc/cache")).expect("read failed").count(), 0);
+}
+
+#[tokio::test]
+async fn test_import_git() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  git(root, &["init", "-q"]);
+  setup_cli(root).await;
+  git(root, &["add", "-A"]);
+  git(root, &["commit", "-q", "-m", "DVC outputs"]);
+
+  let out = afc_ok(root, &["import-dvc"]);
+  assert!(out.contains("converted cached.txt (cached.txt.dvc) -> cached.txt.afc"), "unexpected output: {}", out);
+  assert!(!root.join("cached.txt.dvc").exists());
+  assert!(root.join("cached.txt.afc").exists());
+  assert!(read_to_string(root.join(".gitignore")).expect("read failed").lines().any(|l| l == "/cached.txt"));
+  // the object was moved out of the DVC cache
+  let dvc = Cache::open(root.join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  assert!(!dvc.contains(&md5_hash(HELLO_MD5)).await.expect("contains failed"));
+
+  let staged = git(root, &["diff", "--cached", "--name-status"]);
+  let mut staged: Vec<_> = staged.lines().collect();
+  staged.sort();
+  assert_eq!(staged, vec!["A\tcached.txt.afc", "D\tcached.txt.dvc", "M\t.gitignore"]);
+}
+
+#[tokio::test]
+async fn test_import_report() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  setup_cli(root).await;
+  dvc_file(root, "gone.txt", WORLD_MD5);
+
+  let out = afc(root, &["import-dvc", "--copy"]);
+  assert!(!out.status.success());
+  let stderr = String::from_utf8_lossy(&out.stderr);
+  assert!(stderr.contains("could not convert:\n  gone.txt: data for gone.txt is not in the DVC cache or the work tree\n"), "unexpected errors: {}", stderr);
+  assert!(stderr.contains("1 problems importing DVC outputs"), "unexpected errors: {}", stderr);
+
+  // the other output is still converted
+  assert!(!root.join("cached.txt.dvc").exists());
+  assert!(root.join("cached.txt.afc").exists());
+  assert!(root.join("gone.txt.dvc").exists());
+  assert!(!root.join("gone.txt.afc").exists());
+  // and its data copied, leaving the DVC cache intact
+  let dvc = Cache::open(root.join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  assert!(dvc.contains(&md5_hash(HELLO_MD5)).await.expect("contains failed"));
+  let afc = Cache::open(root.join(".afc/cache"));
+  let meta = match AFCPointerFile::load(root.join("cached.txt.afc")).await.expect("load failed").artifact {
+    Some(ptr) => ptr.meta,
+    None => panic!("no artifact"),
+  };
+  match meta {
+    ArtifactMeta::File(fm) => assert!(afc.contains(&fm.hashes).await.expect("contains failed")),
+    m => panic!("unexpected metadata {:?}", m),
+  }
+}
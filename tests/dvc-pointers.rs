@@ -0,0 +1,95 @@
+//! Tests for reading DVC pointers in a work tree.
+use std::fs::{create_dir_all, write};
+use std::path::Path;
+
+use astral_filing_cabinet::tree::WorkTree;
+use astral_filing_cabinet::tree::artifact::{Artifact, ArtifactMeta};
+use astral_filing_cabinet::tree::status::{artifact_status, ArtifactStatus};
+use futures::TryStreamExt;
+
+mod common;
+use common::TestDir;
+
+const HELLO_MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+
+/// Set up a small DVC repository.
+fn dvc_repo(root: &Path) {
+  create_dir_all(root.join(".dvc").join("tmp")).expect("mkdir failed");
+  write(root.join(".dvc").join("config"), "").expect("write failed");
+  write(root.join(".dvc").join("tmp").join("stray.dvc"), "outs: [{path: x, md5: abc}]\n").expect("write failed");
+
+  write(root.join("hello.txt"), "hello").expect("write failed");
+  write(root.join("hello.txt.dvc"), format!("outs:\n- md5: {}\n  size: 5\n  hash: md5\n  path: hello.txt\n", HELLO_MD5)).expect("write failed");
+
+  let pipe = root.join("pipeline");
+  create_dir_all(pipe.join("images")).expect("mkdir failed");
+  write(pipe.join("model.pkl"), "hellO").expect("write failed");
+  write(pipe.join("dvc.lock"), format!(r#"schema: '2.0'
+stages:
+  train:
+    cmd: python train.py
+    outs:
+    - path: model.pkl
+      md5: {}
+      size: 5
+    - path: images
+      md5: b6923e1e4ad16ea1a7e2a328842d56a2.dir
+      size: 9
+      nfiles: 2
+    - path: metrics.json
+      md5: 0c8d5dc1d3b4a7bc5c5b41e8ab0f1b10
+      size: 20
+      cache: false
+"#, HELLO_MD5)).expect("write failed");
+}
+
+async fn scan(tree: &WorkTree) -> Vec<Artifact> {
+  let mut arts: Vec<Artifact> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  arts.sort_by(|a, b| a.path().cmp(b.path()));
+  arts
+}
+
+#[tokio::test]
+async fn test_dvc_disabled() {
+  let dir = TestDir::empty();
+  dvc_repo(dir.path());
+  let tree = WorkTree::open_root(dir.path());
+  assert!(scan(&tree).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_scan_dvc() {
+  let dir = TestDir::empty();
+  dvc_repo(dir.path());
+  let tree = WorkTree::open_root(dir.path()).with_dvc(true);
+  let arts = scan(&tree).await;
+  let paths: Vec<_> = arts.iter().map(|a| a.path().as_str()).collect();
+  assert_eq!(paths, vec!["hello.txt", "pipeline/images", "pipeline/model.pkl"]);
+  assert_eq!(arts[0].pointer_path().unwrap().as_str(), "hello.txt.dvc");
+  assert_eq!(arts[1].pointer_path().unwrap().as_str(), "pipeline/dvc.lock");
+  match arts[0].meta() {
+    Some(ArtifactMeta::File(fm)) => {
+      assert_eq!(fm.size, Some(5));
+      assert_eq!(fm.hashes.md5.as_ref().unwrap().to_string(), HELLO_MD5);
+      assert!(fm.hashes.sha256.is_none());
+    },
+    m => panic!("unexpected metadata {:?}", m),
+  }
+  assert!(matches!(arts[1].meta(), Some(ArtifactMeta::Folder(fm)) if fm.nfiles == Some(2)));
+}
+
+#[tokio::test]
+async fn test_dvc_status() {
+  let dir = TestDir::empty();
+  dvc_repo(dir.path());
+  let tree = WorkTree::open_root(dir.path()).with_dvc(true);
+  let cache = tree.cache();
+  let arts = scan(&tree).await;
+
+  let status = artifact_status(&tree, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::NotCached);
+  let status = artifact_status(&tree, &cache, &arts[1]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::NotCached);
+  let status = artifact_status(&tree, &cache, &arts[2]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::Modified);
+}
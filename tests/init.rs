@@ -0,0 +1,54 @@
+//! Tests for the `afc init` command.
+use std::fs::{create_dir_all, read_to_string, write};
+
+use astral_filing_cabinet::tree::WorkTree;
+
+mod common;
+use common::TestDir;
+use common::cli::afc_ok;
+
+#[test]
+fn test_init_twice() {
+  let dir = TestDir::tarball("empty-git");
+  afc_ok(dir.path(), &["init"]);
+  assert!(dir.path().join(".afc/cache").is_dir());
+  let ignore = read_to_string(dir.path().join(".gitignore")).expect("read failed");
+  assert_eq!(ignore, "/.afc/cache/\n/.afc/config.local.toml\n");
+
+  // re-initializing keeps the configuration and does not repeat ignore entries
+  write(dir.path().join(".afc/config.toml"), "[core]\njobs = 2\n").expect("write failed");
+  afc_ok(dir.path(), &["init"]);
+  let config = read_to_string(dir.path().join(".afc/config.toml")).expect("read failed");
+  assert_eq!(config, "[core]\njobs = 2\n");
+  assert_eq!(read_to_string(dir.path().join(".gitignore")).expect("read failed"), ignore);
+}
+
+#[test]
+fn test_init_nonempty() {
+  let dir = TestDir::tarball("empty-git");
+  write(dir.path().join("data.csv"), "a,b\n1,2\n").expect("write failed");
+  write(dir.path().join(".gitignore"), "*.log").expect("write failed");
+  afc_ok(dir.path(), &["init"]);
+
+  assert_eq!(read_to_string(dir.path().join("data.csv")).expect("read failed"), "a,b\n1,2\n");
+  let ignore = read_to_string(dir.path().join(".gitignore")).expect("read failed");
+  assert_eq!(ignore, "*.log\n/.afc/cache/\n/.afc/config.local.toml\n");
+}
+
+#[test]
+fn test_init_nested() {
+  let dir = TestDir::tarball("empty-git");
+  afc_ok(dir.path(), &["init"]);
+  let sub = dir.path().join("models");
+  create_dir_all(sub.join("v1")).expect("mkdir failed");
+  afc_ok(dir.path(), &["init", "models"]);
+
+  // the inner work tree is separate from the outer one
+  assert!(sub.join(".afc/config.toml").is_file());
+  let ignore = read_to_string(sub.join(".gitignore")).expect("read failed");
+  assert_eq!(ignore, "/.afc/cache/\n/.afc/config.local.toml\n");
+  let tree = WorkTree::open(sub.join("v1"));
+  assert_eq!(tree.root_path(), sub.canonicalize().unwrap());
+  let tree = WorkTree::open(dir.path());
+  assert_eq!(tree.root_path(), dir.path().canonicalize().unwrap());
+}
@@ -0,0 +1,76 @@
+//! Tests for the `afc push` and `afc pull` commands with a local remote.
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, write};
+use std::path::Path;
+
+use astral_filing_cabinet::cache::{Cache, object_relpath};
+use astral_filing_cabinet::tree::artifact::ArtifactMeta;
+use astral_filing_cabinet::tree::pointer::AFCPointerFile;
+
+mod common;
+use common::TestDir;
+use common::cli::{afc, afc_ok};
+
+/// Set up a work tree with two added files and a local default remote.
+fn setup(dir: &Path) {
+  afc_ok(dir, &["init"]);
+  create_dir_all(dir.join("data")).expect("mkdir failed");
+  write(dir.join("data/a.txt"), "hello").expect("write failed");
+  write(dir.join("data/b.txt"), "world").expect("write failed");
+  afc_ok(dir, &["add", "data/a.txt", "data/b.txt"]);
+  afc_ok(dir, &["remote", "add", "-d", "origin", "remote"]);
+}
+
+/// Remove the cache and the work tree copies of the files, as in a fresh clone.
+fn forget(dir: &Path) {
+  remove_dir_all(dir.join(".afc/cache")).expect("rmdir failed");
+  remove_file(dir.join("data/a.txt")).expect("remove failed");
+  remove_file(dir.join("data/b.txt")).expect("remove failed");
+}
+
+#[test]
+fn test_push_pull() {
+  let dir = TestDir::empty();
+  setup(dir.path());
+  let out = afc_ok(dir.path(), &["push"]);
+  assert!(out.contains("pushed 2 objects"), "unexpected output: {}", out);
+  let out = afc_ok(dir.path(), &["push"]);
+  assert!(out.contains("pushed 0 objects"), "unexpected output: {}", out);
+  assert!(out.contains("2 already present"), "unexpected output: {}", out);
+
+  forget(dir.path());
+  let out = afc_ok(dir.path(), &["pull"]);
+  assert!(out.contains("pulled 2 objects"), "unexpected output: {}", out);
+  assert_eq!(read_to_string(dir.path().join("data/a.txt")).expect("read failed"), "hello");
+  assert_eq!(read_to_string(dir.path().join("data/b.txt")).expect("read failed"), "world");
+}
+
+#[tokio::test]
+async fn test_pull_missing_object() {
+  let dir = TestDir::empty();
+  setup(dir.path());
+  afc_ok(dir.path(), &["push"]);
+  let ptr = AFCPointerFile::load(dir.path().join("data/b.txt.afc")).await.expect("load failed");
+  let hash = match &ptr.artifact.as_ref().expect("no artifact").meta {
+    ArtifactMeta::File(fm) => fm.hashes.clone(),
+    m => panic!("unexpected metadata {:?}", m),
+  };
+  remove_file(object_relpath(&hash).unwrap().to_path(dir.path().join("remote"))).expect("remove failed");
+
+  // the other object is still pulled, and the failure reported at the end
+  forget(dir.path());
+  let out = afc(dir.path(), &["pull"]);
+  assert!(!out.status.success());
+  let stdout = String::from_utf8_lossy(&out.stdout);
+  assert!(stdout.contains("pulled 1 objects"), "unexpected output: {}", stdout);
+  assert!(stdout.contains("failed: "), "unexpected output: {}", stdout);
+  assert!(String::from_utf8_lossy(&out.stderr).contains("1 objects failed to pull"));
+  assert!(!dir.path().join("data/a.txt").exists());
+
+  let cache = Cache::open(dir.path().join(".afc/cache"));
+  assert!(!cache.contains(&hash).await.expect("contains failed"));
+  // no partial downloads are left behind
+  for entry in read_dir(dir.path().join(".afc/cache")).expect("readdir failed") {
+    let name = entry.expect("readdir failed").file_name();
+    assert!(!name.to_string_lossy().ends_with(".tmp"), "leftover temporary file {:?}", name);
+  }
+}
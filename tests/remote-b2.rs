@@ -0,0 +1,339 @@
+//! Tests for the Backblaze B2 remote, against a mock server emulating the B2 API.
+use std::fs::{read, write};
+
+use serde_json::json;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use wiremock::matchers::{body_partial_json, header, method, path};
+
+use astral_filing_cabinet::filehash::{MultiHash, hash_file};
+use astral_filing_cabinet::remote::{B2Remote, Remote, RemoteError};
+use astral_filing_cabinet::settings::B2Settings;
+
+mod common;
+use common::TestDir;
+
+const TEST_NAME: &str = "afc/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+const TEST_SHA1: &str = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3";
+
+fn settings(server: &MockServer) -> B2Settings {
+  B2Settings {
+    bucket: "bucket".into(),
+    prefix: "afc".into(),
+    key_id: Some("keyid".into()),
+    key: Some("key".into()),
+    api_url: server.uri(),
+    multipart_threshold: 8,
+    part_size: 3,
+  }
+}
+
+/// Start a mock B2 server that accepts authorization.
+async fn b2_server(auth_calls: u64) -> MockServer {
+  let server = MockServer::start().await;
+  Mock::given(method("GET"))
+    .and(path("/b2api/v2/b2_authorize_account"))
+    // keyid:key
+    .and(header("authorization", "Basic a2V5aWQ6a2V5"))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+      "accountId": "acct",
+      "authorizationToken": "token",
+      "apiUrl": server.uri(),
+      "downloadUrl": server.uri(),
+      "absoluteMinimumPartSize": 1,
+      "allowed": {"bucketId": "bkt1", "bucketName": "bucket"},
+    })))
+    .expect(auth_calls)
+    .mount(&server).await;
+  server
+}
+
+/// Make a test file containing `test`.
+async fn test_file(dir: &TestDir) -> (std::path::PathBuf, MultiHash) {
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+  (src, hash)
+}
+
+fn file_names(files: serde_json::Value, next: Option<&str>) -> ResponseTemplate {
+  ResponseTemplate::new(200).set_body_json(json!({"files": files, "nextFileName": next}))
+}
+
+#[tokio::test]
+async fn test_upload() {
+  let server = b2_server(1).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_get_upload_url"))
+    .and(header("authorization", "token"))
+    .and(body_partial_json(json!({"bucketId": "bkt1"})))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+      "bucketId": "bkt1",
+      "uploadUrl": format!("{}/upload/bkt1", server.uri()),
+      "authorizationToken": "uptoken",
+    })))
+    .expect(2)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/upload/bkt1"))
+    .and(header("authorization", "uptoken"))
+    .and(header("x-bz-file-name", TEST_NAME))
+    .and(header("x-bz-content-sha1", TEST_SHA1))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({"fileId": "f1", "fileName": TEST_NAME})))
+    .expect(2)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let (src, hash) = test_file(&dir).await;
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  remote.upload(&hash, &src).await.expect("upload failed");
+
+  // without a recorded SHA-1, it is computed for the upload
+  let mut partial = hash.clone();
+  partial.sha1 = None;
+  remote.upload(&partial, &src).await.expect("upload failed");
+}
+
+#[tokio::test]
+async fn test_upload_retry() {
+  let server = b2_server(1).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_get_upload_url"))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+      "bucketId": "bkt1",
+      "uploadUrl": format!("{}/upload/bkt1", server.uri()),
+      "authorizationToken": "uptoken",
+    })))
+    .expect(2)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/upload/bkt1"))
+    .respond_with(ResponseTemplate::new(503).set_body_json(json!({
+      "status": 503, "code": "service_unavailable", "message": "busy",
+    })))
+    .up_to_n_times(1)
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/upload/bkt1"))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({"fileId": "f1"})))
+    .expect(1)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let (src, hash) = test_file(&dir).await;
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  remote.upload(&hash, &src).await.expect("upload failed");
+}
+
+#[tokio::test]
+async fn test_upload_large() {
+  let server = b2_server(1).await;
+  let dir = TestDir::empty();
+  let src = dir.path().join("big.bin");
+  write(&src, b"0123456789").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+  let sha1 = hash.sha1.as_ref().unwrap().to_string();
+
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_start_large_file"))
+    .and(body_partial_json(json!({"bucketId": "bkt1", "fileInfo": {"large_file_sha1": sha1}})))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({"fileId": "large1"})))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_get_upload_part_url"))
+    .and(body_partial_json(json!({"fileId": "large1"})))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+      "fileId": "large1",
+      "uploadUrl": format!("{}/upload_part/large1", server.uri()),
+      "authorizationToken": "parttoken",
+    })))
+    .expect(1)
+    .mount(&server).await;
+  // parts of 3 bytes: 012, 345, 678, 9
+  let part_shas = [
+    "c4a2d99bc28d236098a095277b7eb0718d6be068",
+    "35139ef894b28b73bea022755166a23933c7d9cb",
+    "b2029ba5ea1042d78c96d3888897571eea8c27fa",
+    "0ade7c2cf97f75d009975f4d720d1fa6c19f4897",
+  ];
+  for (n, sha) in part_shas.iter().enumerate() {
+    Mock::given(method("POST"))
+      .and(path("/upload_part/large1"))
+      .and(header("authorization", "parttoken"))
+      .and(header("x-bz-part-number", (n + 1).to_string().as_str()))
+      .and(header("x-bz-content-sha1", *sha))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!({"fileId": "large1", "partNumber": n + 1})))
+      .expect(1)
+      .mount(&server).await;
+  }
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_finish_large_file"))
+    .and(body_partial_json(json!({"fileId": "large1", "partSha1Array": part_shas})))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({"fileId": "large1"})))
+    .expect(1)
+    .mount(&server).await;
+
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  remote.upload(&hash, &src).await.expect("upload failed");
+}
+
+#[tokio::test]
+async fn test_exists_and_verify() {
+  let server = b2_server(1).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_list_file_names"))
+    .and(body_partial_json(json!({"bucketId": "bkt1", "startFileName": TEST_NAME})))
+    .respond_with(file_names(json!([
+      {"fileId": "f1", "fileName": TEST_NAME, "action": "upload", "contentSha1": TEST_SHA1, "fileInfo": {}},
+    ]), None))
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_list_file_names"))
+    .respond_with(file_names(json!([]), None))
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let (_, hash) = test_file(&dir).await;
+  let other = dir.path().join("other.txt");
+  write(&other, b"other").expect("write failed");
+  let other = hash_file(&other).await.expect("hash failed");
+
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  assert!(remote.exists(&hash).await.expect("exists failed"));
+  assert!(!remote.exists(&other).await.expect("exists failed"));
+  assert_eq!(remote.verify_sha1(&hash).await.expect("verify failed"), Some(true));
+  let mut partial = hash.clone();
+  partial.sha1 = None;
+  assert_eq!(remote.verify_sha1(&partial).await.expect("verify failed"), None);
+  assert!(matches!(remote.verify_sha1(&other).await, Err(RemoteError::NotFound(_))));
+}
+
+#[tokio::test]
+async fn test_verify_mismatch() {
+  let server = b2_server(1).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_list_file_names"))
+    .respond_with(file_names(json!([
+      {"fileId": "f1", "fileName": TEST_NAME, "action": "upload", "contentSha1": "none",
+       "fileInfo": {"large_file_sha1": "0000000000000000000000000000000000000000"}},
+    ]), None))
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let (_, hash) = test_file(&dir).await;
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  assert_eq!(remote.verify_sha1(&hash).await.expect("verify failed"), Some(false));
+}
+
+#[tokio::test]
+async fn test_reauthorize() {
+  let server = b2_server(2).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_list_file_names"))
+    .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+      "status": 401, "code": "expired_auth_token", "message": "expired",
+    })))
+    .up_to_n_times(1)
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_list_file_names"))
+    .respond_with(file_names(json!([]), None))
+    .expect(1)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let (_, hash) = test_file(&dir).await;
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  assert!(!remote.exists(&hash).await.expect("exists failed"));
+}
+
+#[tokio::test]
+async fn test_download() {
+  let server = b2_server(1).await;
+  Mock::given(method("GET"))
+    .and(path(format!("/file/bucket/{}", TEST_NAME)))
+    .and(header("authorization", "token"))
+    .respond_with(ResponseTemplate::new(200)
+      .insert_header("x-bz-content-sha1", TEST_SHA1)
+      .set_body_string("test"))
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let (_, hash) = test_file(&dir).await;
+  let dst = dir.path().join("out").join("test.txt");
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  remote.download(&hash, &dst).await.expect("download failed");
+  assert_eq!(read(&dst).expect("read failed"), b"test");
+}
+
+#[tokio::test]
+async fn test_download_bad_sha1() {
+  let server = b2_server(1).await;
+  Mock::given(method("GET"))
+    .and(path(format!("/file/bucket/{}", TEST_NAME)))
+    .respond_with(ResponseTemplate::new(200)
+      .insert_header("x-bz-content-sha1", "0000000000000000000000000000000000000000")
+      .set_body_string("test"))
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let (_, hash) = test_file(&dir).await;
+  let dst = dir.path().join("out.txt");
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  let res = remote.download(&hash, &dst).await;
+  assert!(matches!(res, Err(RemoteError::Corrupt(_))));
+  assert!(!dst.exists());
+}
+
+#[tokio::test]
+async fn test_list_pages() {
+  let server = b2_server(1).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_list_file_names"))
+    .and(body_partial_json(json!({"startFileName": "afc/2c"})))
+    .respond_with(file_names(json!([
+      {"fileId": "f2", "fileName": "afc/2c/26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae", "action": "upload"},
+    ]), None))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_list_file_names"))
+    .and(body_partial_json(json!({"prefix": "afc/"})))
+    .respond_with(file_names(json!([
+      {"fileId": "f1", "fileName": TEST_NAME, "action": "upload"},
+      {"fileId": "f3", "fileName": "afc/README", "action": "upload"},
+    ]), Some("afc/2c")))
+    .expect(1)
+    .mount(&server).await;
+
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  let objects = remote.list().await.expect("list failed");
+  assert_eq!(objects.len(), 2);
+  assert_eq!(objects[0].sha256.as_ref().unwrap().to_string(), "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08");
+  assert_eq!(objects[1].sha256.as_ref().unwrap().to_string(), "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae");
+}
+
+#[tokio::test]
+async fn test_delete() {
+  let server = b2_server(1).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_list_file_versions"))
+    .respond_with(file_names(json!([
+      {"fileId": "v2", "fileName": TEST_NAME, "action": "upload"},
+      {"fileId": "v1", "fileName": TEST_NAME, "action": "upload"},
+    ]), None))
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/b2api/v2/b2_delete_file_version"))
+    .and(body_partial_json(json!({"fileName": TEST_NAME})))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+    .expect(2)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let (_, hash) = test_file(&dir).await;
+  let remote = B2Remote::new(&settings(&server)).expect("remote failed");
+  remote.delete(&hash).await.expect("delete failed");
+}
@@ -0,0 +1,310 @@
+//! Tests for the Google Drive remote, against a mock server emulating the Drive API.
+use std::fs::{read, read_to_string, write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde_json::{Value, json};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use wiremock::matchers::{body_partial_json, body_string_contains, header, method, path, query_param};
+
+use astral_filing_cabinet::filehash::hash_file;
+use astral_filing_cabinet::remote::{AuthPrompt, DevicePrompt, GDriveRemote, Remote, RemoteError};
+use astral_filing_cabinet::settings::GDriveSettings;
+
+mod common;
+use common::TestDir;
+
+const TEST_KEY: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+const TEST_MD5: &str = "098f6bcd4621d373cade4e832627b4f6";
+
+fn settings(server: &MockServer) -> GDriveSettings {
+  GDriveSettings {
+    folder_id: "folder1".into(),
+    client_id: "client.apps.googleusercontent.com".into(),
+    client_secret: Some("secret".into()),
+    scope: "https://www.googleapis.com/auth/drive.file".into(),
+    oauth_url: server.uri(),
+    api_url: server.uri(),
+  }
+}
+
+/// Write a cached token into a state directory.
+fn write_token(state: &Path, access: &str, expires_at: u64) {
+  let token = json!({"access_token": access, "refresh_token": "refresh1", "expires_at": expires_at});
+  write(state.join("client.apps.googleusercontent.com.token.json"), token.to_string()).expect("write failed");
+}
+
+fn read_ids(state: &Path) -> Value {
+  let ids = read_to_string(state.join("folder1.ids.json")).expect("read failed");
+  serde_json::from_str(&ids).expect("invalid ID cache")
+}
+
+fn file_list(files: Value, next: Option<&str>) -> ResponseTemplate {
+  ResponseTemplate::new(200).set_body_json(json!({"files": files, "nextPageToken": next}))
+}
+
+/// Set up a test directory with a state directory holding a valid token.
+fn setup() -> (TestDir, std::path::PathBuf) {
+  let dir = TestDir::empty();
+  let state = dir.path().join("state");
+  std::fs::create_dir_all(&state).expect("mkdir failed");
+  write_token(&state, "token1", u64::MAX / 2);
+  (dir, state)
+}
+
+#[tokio::test]
+async fn test_device_flow() {
+  let server = MockServer::start().await;
+  Mock::given(method("POST"))
+    .and(path("/device/code"))
+    .and(body_string_contains("client_id=client.apps.googleusercontent.com"))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+      "device_code": "dev1",
+      "user_code": "ABCD-EFGH",
+      "verification_url": "https://www.google.com/device",
+      "expires_in": 1800,
+      "interval": 0,
+    })))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/token"))
+    .and(body_string_contains("device_code=dev1"))
+    .respond_with(ResponseTemplate::new(428).set_body_json(json!({"error": "authorization_pending"})))
+    .up_to_n_times(1)
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(path("/token"))
+    .and(body_string_contains("device_code=dev1"))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+      "access_token": "token1", "expires_in": 3600, "refresh_token": "refresh1", "token_type": "Bearer",
+    })))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files"))
+    .and(header("authorization", "Bearer token1"))
+    .respond_with(file_list(json!([]), None))
+    .expect(1)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let state = dir.path().join("state");
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let shown = Arc::new(Mutex::new(Vec::new()));
+  let prompts = shown.clone();
+  let remote = GDriveRemote::with_state_dir(&settings(&server), &state).await.expect("remote failed")
+    .with_prompt(AuthPrompt::new(move |p| prompts.lock().unwrap().push(p.clone())));
+  assert!(!remote.exists(&hash).await.expect("exists failed"));
+  let expected = DevicePrompt { url: "https://www.google.com/device".into(), code: "ABCD-EFGH".into() };
+  assert_eq!(*shown.lock().unwrap(), vec![expected]);
+
+  let token = read_to_string(state.join("client.apps.googleusercontent.com.token.json")).expect("no token");
+  let token: Value = serde_json::from_str(&token).expect("invalid token");
+  assert_eq!(token["refresh_token"], "refresh1");
+}
+
+#[tokio::test]
+async fn test_refresh() {
+  let server = MockServer::start().await;
+  Mock::given(method("POST"))
+    .and(path("/token"))
+    .and(body_string_contains("grant_type=refresh_token"))
+    .and(body_string_contains("refresh_token=refresh1"))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({"access_token": "token2", "expires_in": 3600})))
+    .expect(2)
+    .mount(&server).await;
+  // the refreshed token is rejected once, as if revoked
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files"))
+    .and(header("authorization", "Bearer token2"))
+    .respond_with(ResponseTemplate::new(401))
+    .up_to_n_times(1)
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files"))
+    .and(header("authorization", "Bearer token2"))
+    .and(query_param("q", format!("name = '{}' and 'folder1' in parents and trashed = false", TEST_KEY).as_str()))
+    .respond_with(file_list(json!([{"id": "file1", "name": TEST_KEY}]), None))
+    .expect(1)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let state = dir.path().join("state");
+  std::fs::create_dir_all(&state).expect("mkdir failed");
+  write_token(&state, "expired", 0);
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let remote = GDriveRemote::with_state_dir(&settings(&server), &state).await.expect("remote failed");
+  assert!(remote.exists(&hash).await.expect("exists failed"));
+  assert_eq!(read_ids(&state)[TEST_KEY], "file1");
+}
+
+#[tokio::test]
+async fn test_upload_and_download() {
+  let server = MockServer::start().await;
+  Mock::given(method("POST"))
+    .and(path("/upload/drive/v3/files"))
+    .and(query_param("uploadType", "resumable"))
+    .and(header("authorization", "Bearer token1"))
+    .and(body_partial_json(json!({"name": TEST_KEY, "parents": ["folder1"]})))
+    .respond_with(ResponseTemplate::new(200).insert_header("location", format!("{}/upload/session1", server.uri()).as_str()))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("PUT"))
+    .and(path("/upload/session1"))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "file1", "name": TEST_KEY, "md5Checksum": TEST_MD5})))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files/file1"))
+    .and(query_param("alt", "media"))
+    .respond_with(ResponseTemplate::new(200).set_body_string("test"))
+    .expect(1)
+    .mount(&server).await;
+  // the download uses the cached ID, so there is no search
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files"))
+    .respond_with(file_list(json!([]), None))
+    .expect(0)
+    .mount(&server).await;
+
+  let (dir, state) = setup();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let remote = GDriveRemote::with_state_dir(&settings(&server), &state).await.expect("remote failed");
+  remote.upload(&hash, &src).await.expect("upload failed");
+  assert_eq!(read_ids(&state)[TEST_KEY], "file1");
+
+  let dst = dir.path().join("out").join("test.txt");
+  remote.download(&hash, &dst).await.expect("download failed");
+  assert_eq!(read(&dst).expect("read failed"), b"test");
+}
+
+#[tokio::test]
+async fn test_upload_bad_checksum() {
+  let server = MockServer::start().await;
+  Mock::given(method("POST"))
+    .and(path("/upload/drive/v3/files"))
+    .respond_with(ResponseTemplate::new(200).insert_header("location", format!("{}/upload/session1", server.uri()).as_str()))
+    .mount(&server).await;
+  Mock::given(method("PUT"))
+    .and(path("/upload/session1"))
+    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+      "id": "file1", "name": TEST_KEY, "md5Checksum": "00000000000000000000000000000000",
+    })))
+    .mount(&server).await;
+  Mock::given(method("DELETE"))
+    .and(path("/drive/v3/files/file1"))
+    .respond_with(ResponseTemplate::new(204))
+    .expect(1)
+    .mount(&server).await;
+
+  let (dir, state) = setup();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let remote = GDriveRemote::with_state_dir(&settings(&server), &state).await.expect("remote failed");
+  let res = remote.upload(&hash, &src).await;
+  assert!(matches!(res, Err(RemoteError::Corrupt(_))));
+  assert!(!state.join("folder1.ids.json").exists());
+}
+
+#[tokio::test]
+async fn test_download_stale_id() {
+  let server = MockServer::start().await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files/old"))
+    .respond_with(ResponseTemplate::new(404))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files"))
+    .respond_with(file_list(json!([{"id": "new", "name": TEST_KEY}]), None))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files/new"))
+    .respond_with(ResponseTemplate::new(200).set_body_string("test"))
+    .expect(1)
+    .mount(&server).await;
+
+  let (dir, state) = setup();
+  write(state.join("folder1.ids.json"), json!({TEST_KEY: "old"}).to_string()).expect("write failed");
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let remote = GDriveRemote::with_state_dir(&settings(&server), &state).await.expect("remote failed");
+  let dst = dir.path().join("out.txt");
+  remote.download(&hash, &dst).await.expect("download failed");
+  assert_eq!(read(&dst).expect("read failed"), b"test");
+  assert_eq!(read_ids(&state)[TEST_KEY], "new");
+}
+
+#[tokio::test]
+async fn test_list_pages() {
+  let server = MockServer::start().await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files"))
+    .and(query_param("pageToken", "page2"))
+    .respond_with(file_list(json!([
+      {"id": "file2", "name": "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"},
+    ]), None))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files"))
+    .and(query_param("q", "'folder1' in parents and trashed = false"))
+    .respond_with(file_list(json!([
+      {"id": "file1", "name": TEST_KEY},
+      {"id": "readme", "name": "README"},
+    ]), Some("page2")))
+    .expect(1)
+    .mount(&server).await;
+
+  let (_dir, state) = setup();
+  write(state.join("folder1.ids.json"), json!({"stale": "gone"}).to_string()).expect("write failed");
+  let remote = GDriveRemote::with_state_dir(&settings(&server), &state).await.expect("remote failed");
+  let objects = remote.list().await.expect("list failed");
+  assert_eq!(objects.len(), 2);
+  assert_eq!(objects[0].sha256.as_ref().unwrap().to_string(), TEST_KEY);
+  assert_eq!(read_ids(&state), json!({
+    TEST_KEY: "file1",
+    "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae": "file2",
+  }));
+}
+
+#[tokio::test]
+async fn test_delete() {
+  let server = MockServer::start().await;
+  Mock::given(method("GET"))
+    .and(path("/drive/v3/files"))
+    .respond_with(file_list(json!([{"id": "file1", "name": TEST_KEY}]), None))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("DELETE"))
+    .and(path("/drive/v3/files/file1"))
+    .respond_with(ResponseTemplate::new(204))
+    .expect(1)
+    .mount(&server).await;
+
+  let (dir, state) = setup();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let remote = GDriveRemote::with_state_dir(&settings(&server), &state).await.expect("remote failed");
+  remote.delete(&hash).await.expect("delete failed");
+  assert_eq!(read_ids(&state), json!({}));
+}
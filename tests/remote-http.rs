@@ -0,0 +1,165 @@
+//! Tests for the WebDAV and read-only HTTP remotes, against a mock server.
+use std::fs::{read, write};
+
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use wiremock::matchers::{header, method, path, path_regex};
+
+use astral_filing_cabinet::filehash::hash_file;
+use astral_filing_cabinet::remote::{HttpRemote, Remote, RemoteError, WebDavRemote};
+use astral_filing_cabinet::settings::WebDavSettings;
+
+mod common;
+use common::TestDir;
+
+const TEST_PATH: &str = "/afc/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+fn dav_settings(server: &MockServer) -> WebDavSettings {
+  WebDavSettings {
+    url: format!("{}/afc", server.uri()),
+    user: Some("user".into()),
+    password: Some("pass".into()),
+  }
+}
+
+#[tokio::test]
+async fn test_http_download() {
+  let server = MockServer::start().await;
+  Mock::given(method("GET"))
+    .and(path(TEST_PATH))
+    .respond_with(ResponseTemplate::new(200).set_body_string("test"))
+    .mount(&server).await;
+  Mock::given(method("HEAD"))
+    .and(path(TEST_PATH))
+    .respond_with(ResponseTemplate::new(200))
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let remote = HttpRemote::new(&format!("{}/afc", server.uri())).expect("remote failed");
+  assert!(remote.exists(&hash).await.expect("exists failed"));
+  let dst = dir.path().join("out/test.txt");
+  remote.download(&hash, &dst).await.expect("download failed");
+  assert_eq!(read(&dst).expect("read failed"), b"test");
+
+  assert!(matches!(remote.upload(&hash, &src).await, Err(RemoteError::ReadOnly)));
+  assert!(matches!(remote.delete(&hash).await, Err(RemoteError::ReadOnly)));
+}
+
+#[tokio::test]
+async fn test_http_missing_and_corrupt() {
+  let server = MockServer::start().await;
+  Mock::given(method("GET"))
+    .and(path(TEST_PATH))
+    .respond_with(ResponseTemplate::new(200).set_body_string("tampered"))
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .respond_with(ResponseTemplate::new(404))
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+  let other = dir.path().join("other.txt");
+  write(&other, b"other").expect("write failed");
+  let other = hash_file(&other).await.expect("hash failed");
+
+  let remote = HttpRemote::new(&format!("{}/afc/", server.uri())).expect("remote failed");
+  let dst = dir.path().join("test.txt.out");
+  assert!(matches!(remote.download(&hash, &dst).await, Err(RemoteError::Corrupt(_))));
+  assert!(!dst.exists());
+  assert!(matches!(remote.download(&other, &dst).await, Err(RemoteError::NotFound(_))));
+}
+
+#[tokio::test]
+async fn test_webdav_upload() {
+  let server = MockServer::start().await;
+  // the base collection does not exist yet
+  Mock::given(method("MKCOL"))
+    .and(path("/afc/9f/"))
+    .respond_with(ResponseTemplate::new(409))
+    .up_to_n_times(1)
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("MKCOL"))
+    .and(path("/afc/"))
+    .respond_with(ResponseTemplate::new(201))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("MKCOL"))
+    .and(path("/afc/9f/"))
+    .respond_with(ResponseTemplate::new(201))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("PUT"))
+    .and(path_regex(r"^/afc/9f/\.86d0.*\.tmp$"))
+    .and(header("authorization", "Basic dXNlcjpwYXNz"))
+    .respond_with(ResponseTemplate::new(201))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("MOVE"))
+    .and(path_regex(r"^/afc/9f/\.86d0.*\.tmp$"))
+    .and(header("destination", format!("{}{}", server.uri(), TEST_PATH).as_str()))
+    .respond_with(ResponseTemplate::new(201))
+    .expect(1)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let remote = WebDavRemote::new(&dav_settings(&server)).expect("remote failed");
+  remote.upload(&hash, &src).await.expect("upload failed");
+}
+
+#[tokio::test]
+async fn test_webdav_exists_list() {
+  let server = MockServer::start().await;
+  let top = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response><d:href>/afc/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat></d:response>
+  <d:response><d:href>/afc/9f/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat></d:response>
+</d:multistatus>"#;
+  let shard = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response><d:href>/afc/9f/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat></d:response>
+  <d:response><d:href>/afc/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08</d:href><d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat></d:response>
+</d:multistatus>"#;
+  Mock::given(method("PROPFIND"))
+    .and(path("/afc/"))
+    .and(header("depth", "1"))
+    .respond_with(ResponseTemplate::new(207).set_body_string(top))
+    .mount(&server).await;
+  Mock::given(method("PROPFIND"))
+    .and(path("/afc/9f/"))
+    .and(header("depth", "1"))
+    .respond_with(ResponseTemplate::new(207).set_body_string(shard))
+    .mount(&server).await;
+  Mock::given(method("PROPFIND"))
+    .and(path(TEST_PATH))
+    .and(header("depth", "0"))
+    .respond_with(ResponseTemplate::new(207).set_body_string(shard))
+    .mount(&server).await;
+  Mock::given(method("PROPFIND"))
+    .respond_with(ResponseTemplate::new(404))
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+  let other = dir.path().join("other.txt");
+  write(&other, b"other").expect("write failed");
+  let other = hash_file(&other).await.expect("hash failed");
+
+  let remote = WebDavRemote::new(&dav_settings(&server)).expect("remote failed");
+  assert!(remote.exists(&hash).await.expect("exists failed"));
+  assert!(!remote.exists(&other).await.expect("exists failed"));
+  let listed = remote.list().await.expect("list failed");
+  assert_eq!(listed.len(), 1);
+  assert_eq!(listed[0].sha256, hash.sha256);
+}
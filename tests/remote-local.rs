@@ -0,0 +1,62 @@
+use std::fs::{read, write};
+
+use astral_filing_cabinet::filehash::hash_file;
+use astral_filing_cabinet::remote::{Remote, RemoteError, LocalRemote};
+
+mod common;
+use common::TestDir;
+
+#[tokio::test]
+async fn test_upload_download() {
+  let dir = TestDir::empty();
+  let remote = LocalRemote::new(dir.path().join("remote"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"shared data").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  assert!(!remote.exists(&hash).await.expect("exists failed"));
+  remote.upload(&hash, &src).await.expect("upload failed");
+  assert!(remote.exists(&hash).await.expect("exists failed"));
+
+  let listed = remote.list().await.expect("list failed");
+  assert_eq!(listed.len(), 1);
+  assert_eq!(listed[0].sha256, hash.sha256);
+
+  let dst = dir.path().join("out/data.txt");
+  remote.download(&hash, &dst).await.expect("download failed");
+  assert_eq!(read(&dst).expect("read failed"), b"shared data");
+
+  remote.delete(&hash).await.expect("delete failed");
+  assert!(!remote.exists(&hash).await.expect("exists failed"));
+  assert!(remote.list().await.expect("list failed").is_empty());
+}
+
+#[tokio::test]
+async fn test_download_missing() {
+  let dir = TestDir::empty();
+  let remote = LocalRemote::new(dir.path().join("remote"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"shared data").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let res = remote.download(&hash, &dir.path().join("out.txt")).await;
+  assert!(matches!(res, Err(RemoteError::NotFound(_))));
+  let res = remote.delete(&hash).await;
+  assert!(matches!(res, Err(RemoteError::NotFound(_))));
+}
+
+#[tokio::test]
+async fn test_upload_wrong_hash() {
+  let dir = TestDir::empty();
+  let remote = LocalRemote::new(dir.path().join("remote"));
+  let src = dir.path().join("data.txt");
+  write(&src, b"shared data").expect("write failed");
+  let other = dir.path().join("other.txt");
+  write(&other, b"other data").expect("write failed");
+  let hash = hash_file(&other).await.expect("hash failed");
+
+  let res = remote.upload(&hash, &src).await;
+  assert!(matches!(res, Err(RemoteError::Corrupt(_))));
+  assert!(!remote.exists(&hash).await.expect("exists failed"));
+  assert!(remote.list().await.expect("list failed").is_empty());
+}
@@ -0,0 +1,282 @@
+//! Tests for the S3 remote.
+//!
+//! Most of these run against a mock server.  To test against a real S3-compatible
+//! store, such as a local MinIO, set `AFC_TEST_S3_ENDPOINT` (and optionally
+//! `AFC_TEST_S3_BUCKET`, default `afc-test`) and the usual `AWS_ACCESS_KEY_ID` and
+//! `AWS_SECRET_ACCESS_KEY` variables, and run with `cargo test -- --ignored`; the
+//! bucket must already exist.
+use std::env;
+use std::fs::{create_dir_all, read, write};
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use wiremock::matchers::{header, header_exists, method, path, query_param};
+
+use astral_filing_cabinet::filehash::hash_file;
+use astral_filing_cabinet::remote::{Remote, S3Remote};
+use astral_filing_cabinet::remote::s3::Credentials;
+use astral_filing_cabinet::settings::S3Settings;
+
+mod common;
+use common::TestDir;
+
+const MIB: u64 = 1024 * 1024;
+
+fn settings(endpoint: &str, bucket: &str, prefix: &str) -> S3Settings {
+  S3Settings {
+    bucket: bucket.into(),
+    prefix: prefix.into(),
+    region: "us-east-1".into(),
+    endpoint: Some(endpoint.into()),
+    path_style: None,
+    access_key_id: None,
+    secret_access_key: None,
+    profile: None,
+    multipart_threshold: 8 * MIB,
+    part_size: 5 * MIB,
+  }
+}
+
+fn test_creds() -> Credentials {
+  Credentials {
+    access_key_id: "AKIDTEST".into(),
+    secret_access_key: "secret".into(),
+    session_token: None,
+  }
+}
+
+/// Start a proxy that answers the first connection with `response` and then closes
+/// it, and forwards later connections to `upstream`.
+async fn interrupting_proxy(upstream: SocketAddr, response: &'static [u8]) -> String {
+  let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind failed");
+  let addr = listener.local_addr().expect("no address");
+  tokio::spawn(async move {
+    let mut first = true;
+    while let Ok((mut conn, _)) = listener.accept().await {
+      if first {
+        first = false;
+        let mut req = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+          match conn.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => req.extend_from_slice(&buf[..n]),
+          }
+        }
+        let _ = conn.write_all(response).await;
+      } else {
+        tokio::spawn(async move {
+          if let Ok(mut up) = TcpStream::connect(upstream).await {
+            let _ = copy_bidirectional(&mut conn, &mut up).await;
+          }
+        });
+      }
+    }
+  });
+  format!("http://{}", addr)
+}
+
+#[tokio::test]
+async fn test_mock_exists() {
+  let server = MockServer::start().await;
+  Mock::given(method("HEAD"))
+    .and(path("/bucket/data/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"))
+    .and(header_exists("authorization"))
+    .and(header_exists("x-amz-date"))
+    .respond_with(ResponseTemplate::new(200))
+    .mount(&server).await;
+  Mock::given(method("HEAD"))
+    .respond_with(ResponseTemplate::new(404))
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+  let other = dir.path().join("other.txt");
+  write(&other, b"other").expect("write failed");
+  let other = hash_file(&other).await.expect("hash failed");
+
+  let remote = S3Remote::new(&settings(&server.uri(), "bucket", "data/"), test_creds()).expect("remote failed");
+  assert!(remote.exists(&hash).await.expect("exists failed"));
+  assert!(!remote.exists(&other).await.expect("exists failed"));
+}
+
+#[tokio::test]
+async fn test_mock_list_pages() {
+  let server = MockServer::start().await;
+  let page1 = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult>
+  <Name>bucket</Name><Prefix>data/</Prefix><IsTruncated>true</IsTruncated>
+  <NextContinuationToken>page2</NextContinuationToken>
+  <Contents><Key>data/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08</Key><Size>4</Size></Contents>
+  <Contents><Key>data/README</Key><Size>10</Size></Contents>
+</ListBucketResult>"#;
+  let page2 = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult>
+  <Name>bucket</Name><Prefix>data/</Prefix><IsTruncated>false</IsTruncated>
+  <Contents><Key>data/2c/26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae</Key><Size>3</Size></Contents>
+</ListBucketResult>"#;
+  Mock::given(method("GET"))
+    .and(path("/bucket"))
+    .and(query_param("list-type", "2"))
+    .and(query_param("continuation-token", "page2"))
+    .respond_with(ResponseTemplate::new(200).set_body_string(page2))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .and(path("/bucket"))
+    .and(query_param("prefix", "data/"))
+    .respond_with(ResponseTemplate::new(200).set_body_string(page1))
+    .expect(1)
+    .mount(&server).await;
+
+  let remote = S3Remote::new(&settings(&server.uri(), "bucket", "data"), test_creds()).expect("remote failed");
+  let objects = remote.list().await.expect("list failed");
+  assert_eq!(objects.len(), 2);
+  assert_eq!(objects[0].sha256.as_ref().unwrap().to_string(), "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08");
+  assert_eq!(objects[1].sha256.as_ref().unwrap().to_string(), "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae");
+}
+
+#[tokio::test]
+async fn test_mock_multipart_upload() {
+  let server = MockServer::start().await;
+  Mock::given(method("POST"))
+    .and(query_param("uploads", ""))
+    .respond_with(ResponseTemplate::new(200).set_body_string(
+      "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>k</Key><UploadId>up1</UploadId></InitiateMultipartUploadResult>"
+    ))
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("PUT"))
+    .and(query_param("uploadId", "up1"))
+    .respond_with(ResponseTemplate::new(200).insert_header("ETag", "\"etag\""))
+    .expect(2)
+    .mount(&server).await;
+  Mock::given(method("POST"))
+    .and(query_param("uploadId", "up1"))
+    .respond_with(ResponseTemplate::new(200).set_body_string(
+      "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>k</Key><ETag>\"x-2\"</ETag></CompleteMultipartUploadResult>"
+    ))
+    .expect(1)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let src = dir.path().join("big.bin");
+  let data: Vec<u8> = (0..(9 * MIB)).map(|i| (i % 251) as u8).collect();
+  write(&src, &data).expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  let mut s3 = settings(&server.uri(), "bucket", "");
+  s3.part_size = 6 * MIB;
+  let remote = S3Remote::new(&s3, test_creds()).expect("remote failed");
+  remote.upload(&hash, &src).await.expect("upload failed");
+}
+
+const TEST_KEY: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+#[tokio::test]
+async fn test_mock_download_resume() {
+  let server = MockServer::start().await;
+  Mock::given(method("GET"))
+    .and(path("/bucket/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"))
+    .and(header("range", "bytes=2-"))
+    .respond_with(ResponseTemplate::new(206).set_body_string("st"))
+    .expect(1)
+    .mount(&server).await;
+
+  let dir = TestDir::empty();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  // simulate an interrupted download
+  let part = dir.path().join("tmp").join(format!("{}.part", TEST_KEY));
+  create_dir_all(part.parent().unwrap()).expect("mkdir failed");
+  write(&part, b"te").expect("write failed");
+
+  let dst = dir.path().join("out.txt");
+  let remote = S3Remote::new(&settings(&server.uri(), "bucket", ""), test_creds()).expect("remote failed");
+  remote.download(&hash, &dst).await.expect("download failed");
+  assert_eq!(read(&dst).expect("read failed"), b"test");
+  assert!(!part.exists());
+}
+
+#[tokio::test]
+async fn test_mock_download_interrupted() {
+  let server = MockServer::start().await;
+  let opath = "/bucket/9f/86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+  // the first resumed request fails, and the next succeeds
+  Mock::given(method("GET"))
+    .and(path(opath))
+    .and(header("range", "bytes=2-"))
+    .respond_with(ResponseTemplate::new(503))
+    .up_to_n_times(1)
+    .expect(1)
+    .mount(&server).await;
+  Mock::given(method("GET"))
+    .and(path(opath))
+    .and(header("range", "bytes=2-"))
+    .respond_with(ResponseTemplate::new(206).set_body_string("st"))
+    .expect(1)
+    .mount(&server).await;
+  // the connection drops after the first two bytes of the full object
+  let endpoint = interrupting_proxy(*server.address(), b"HTTP/1.1 200 OK\r\ncontent-length: 4\r\n\r\nte").await;
+
+  let dir = TestDir::empty();
+  let src = dir.path().join("test.txt");
+  write(&src, b"test").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+  let part = dir.path().join("tmp").join(format!("{}.part", TEST_KEY));
+
+  let remote = S3Remote::new(&settings(&endpoint, "bucket", ""), test_creds()).expect("remote failed");
+  let first = dir.path().join("first.tmp");
+  assert!(remote.download(&hash, &first).await.is_err());
+  assert!(!first.exists());
+  assert_eq!(read(&part).expect("read failed"), b"te");
+
+  // a later download of the same object to a new file picks up where it left off
+  let second = dir.path().join("second.tmp");
+  remote.download(&hash, &second).await.expect("download failed");
+  assert_eq!(read(&second).expect("read failed"), b"test");
+  assert!(!part.exists());
+}
+
+#[tokio::test]
+#[ignore = "needs an S3-compatible store (AFC_TEST_S3_ENDPOINT)"]
+async fn test_s3_roundtrip() {
+  let endpoint = env::var("AFC_TEST_S3_ENDPOINT").expect("AFC_TEST_S3_ENDPOINT not set");
+  let bucket = env::var("AFC_TEST_S3_BUCKET").unwrap_or_else(|_| "afc-test".into());
+  let prefix = format!("test-{}", Uuid::new_v4());
+  let remote = S3Remote::open(&settings(&endpoint, &bucket, &prefix)).await.expect("remote failed");
+
+  let dir = TestDir::empty();
+  let small = dir.path().join("small.txt");
+  write(&small, b"shared data").expect("write failed");
+  let big = dir.path().join("big.bin");
+  let data: Vec<u8> = (0..(9 * MIB)).map(|i| (i % 251) as u8).collect();
+  write(&big, &data).expect("write failed");
+  let mut files = Vec::new();
+  for src in [small, big] {
+    let hash = hash_file(&src).await.expect("hash failed");
+    files.push((src, hash));
+  }
+
+  for (src, hash) in &files {
+    assert!(!remote.exists(hash).await.expect("exists failed"));
+    remote.upload(hash, src).await.expect("upload failed");
+    assert!(remote.exists(hash).await.expect("exists failed"));
+  }
+  assert_eq!(remote.list().await.expect("list failed").len(), 2);
+
+  for (src, hash) in &files {
+    let dst = dir.path().join("out").join(src.file_name().unwrap());
+    remote.download(hash, &dst).await.expect("download failed");
+    assert_eq!(read(&dst).expect("read failed"), read(src).expect("read failed"));
+    remote.delete(hash).await.expect("delete failed");
+  }
+  assert!(remote.list().await.expect("list failed").is_empty());
+}
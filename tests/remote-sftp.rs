@@ -0,0 +1,61 @@
+//! Tests for the SFTP remote.
+//!
+//! These need an SSH server that accepts a key from the SSH agent (or from
+//! `~/.ssh/config`) without prompting, such as a local `sshd` on loopback or in a
+//! container.  Set `AFC_TEST_SFTP_HOST` to its destination (e.g. `user@localhost`)
+//! and optionally `AFC_TEST_SFTP_PORT` and `AFC_TEST_SFTP_PATH` (the base directory
+//! for test remotes, default `afc-test`), and run with `cargo test -- --ignored`.
+#![cfg(unix)]
+use std::env;
+use std::fs::{read, write};
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use astral_filing_cabinet::filehash::hash_file;
+use astral_filing_cabinet::remote::{Remote, RemoteError, SftpRemote};
+use astral_filing_cabinet::settings::SftpSettings;
+
+mod common;
+use common::TestDir;
+
+async fn connect() -> SftpRemote {
+  let host = env::var("AFC_TEST_SFTP_HOST").expect("AFC_TEST_SFTP_HOST not set");
+  let base = env::var("AFC_TEST_SFTP_PATH").unwrap_or_else(|_| "afc-test".into());
+  let settings = SftpSettings {
+    host,
+    path: PathBuf::from(base).join(Uuid::new_v4().to_string()),
+    user: None,
+    port: env::var("AFC_TEST_SFTP_PORT").ok().map(|p| p.parse().expect("invalid port")),
+  };
+  SftpRemote::connect(&settings).await.expect("connect failed")
+}
+
+#[tokio::test]
+#[ignore = "needs an SSH server (AFC_TEST_SFTP_HOST)"]
+async fn test_sftp_roundtrip() {
+  let remote = connect().await;
+  let dir = TestDir::empty();
+  let src = dir.path().join("data.txt");
+  write(&src, b"shared data").expect("write failed");
+  let hash = hash_file(&src).await.expect("hash failed");
+
+  assert!(remote.list().await.expect("list failed").is_empty());
+  assert!(!remote.exists(&hash).await.expect("exists failed"));
+  remote.upload(&hash, &src).await.expect("upload failed");
+  assert!(remote.exists(&hash).await.expect("exists failed"));
+  // uploading again is harmless
+  remote.upload(&hash, &src).await.expect("second upload failed");
+
+  let listed = remote.list().await.expect("list failed");
+  assert_eq!(listed.len(), 1);
+  assert_eq!(listed[0].sha256, hash.sha256);
+
+  let dst = dir.path().join("out/data.txt");
+  remote.download(&hash, &dst).await.expect("download failed");
+  assert_eq!(read(&dst).expect("read failed"), b"shared data");
+
+  remote.delete(&hash).await.expect("delete failed");
+  assert!(matches!(remote.download(&hash, &dst).await, Err(RemoteError::NotFound(_))));
+  remote.close().await.expect("close failed");
+}
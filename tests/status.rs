@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, metadata, read, write, File};
+use std::time::Duration;
+
+use astral_filing_cabinet::cache::{Cache, CacheLayout};
+use astral_filing_cabinet::tree::WorkTree;
+use astral_filing_cabinet::tree::status::{artifact_status, find_untracked, ArtifactStatus};
+use futures::TryStreamExt;
+
+mod common;
+use common::TestDir;
+use common::cli::afc_ok;
+
+#[tokio::test]
+async fn test_status_not_cached() {
+  let dir = TestDir::tarball("single-artifact");
+  let tree = WorkTree::open(dir.path());
+  let cache = tree.cache();
+  let arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  let status = artifact_status(&tree, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::NotCached);
+
+  cache.insert_file(dir.path().join("artifact.dat")).await.expect("insert failed");
+  let status = artifact_status(&tree, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::UpToDate);
+}
+
+#[tokio::test]
+async fn test_status_modified() {
+  let dir = TestDir::tarball("single-artifact");
+  let tree = WorkTree::open(dir.path());
+  let cache = tree.cache();
+  let arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  write(dir.path().join("artifact.dat"), b"new contents").expect("write failed");
+  let status = artifact_status(&tree, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::Modified);
+}
+
+#[tokio::test]
+async fn test_status_hash_cache() {
+  let dir = TestDir::tarball("single-artifact");
+  let path = dir.path().join("artifact.dat");
+  let tree = WorkTree::open(dir.path()).load_hash_cache().await.expect("load failed");
+  let cache = tree.cache();
+  cache.insert_file(&path).await.expect("insert failed");
+  let arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  let status = artifact_status(&tree, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::UpToDate);
+  tree.save_state().await.expect("save failed");
+  assert!(dir.path().join(".afc/state/hashes.bin").exists());
+
+  // rewrite the file behind the cache's back, keeping its size and mtime
+  let mtime = metadata(&path).expect("stat failed").modified().expect("no mtime");
+  let mut data = read(&path).expect("read failed");
+  data[0] ^= 0xff;
+  write(&path, &data).expect("write failed");
+  File::options().write(true).open(&path).expect("open failed").set_modified(mtime).expect("set mtime failed");
+
+  // the remembered hash is used, unless the cache is disabled
+  let tree = WorkTree::open(dir.path()).load_hash_cache().await.expect("load failed");
+  let status = artifact_status(&tree, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::UpToDate);
+  let uncached = WorkTree::open(dir.path());
+  let status = artifact_status(&uncached, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::Modified);
+
+  // any change to the metadata invalidates it
+  File::options().write(true).open(&path).expect("open failed")
+    .set_modified(mtime + Duration::from_secs(1)).expect("set mtime failed");
+  let status = artifact_status(&tree, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::Modified);
+}
+
+#[tokio::test]
+async fn test_status_missing() {
+  let dir = TestDir::tarball("single-artifact-in-subdir");
+  let tree = WorkTree::open(dir.path());
+  let cache = tree.cache();
+  let arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  std::fs::remove_file(dir.path().join("data/artifact.dat")).expect("remove failed");
+  let status = artifact_status(&tree, &cache, &arts[0]).await.expect("status failed");
+  assert_eq!(status, ArtifactStatus::Missing);
+}
+
+#[tokio::test]
+async fn test_untracked() {
+  let dir = TestDir::tarball("single-artifact");
+  let tree = WorkTree::open(dir.path());
+  let cache = tree.cache();
+  write(dir.path().join("big.dat"), vec![0u8; 4096]).expect("write failed");
+  write(dir.path().join("small.dat"), b"small").expect("write failed");
+
+  let mut tracked = HashSet::new();
+  tracked.insert("artifact.dat".into());
+  let found = find_untracked(&tree, &cache, &tracked, 1024).await.expect("scan failed");
+  let found: Vec<_> = found.iter().map(|p| p.as_str()).collect();
+  assert_eq!(found, vec!["big.dat"]);
+}
+
+#[tokio::test]
+async fn test_untracked_skips_caches() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  afc_ok(root, &["init"]);
+  afc_ok(root, &["config", "set", "core.dvc", "true"]);
+
+  // a DVC output, with its object in DVC's cache
+  let dvc = Cache::open(root.join(".dvc/cache")).with_layout(CacheLayout::Dvc);
+  write(root.join("dvc.dat"), vec![1u8; 4096]).expect("write failed");
+  let (_, hash) = dvc.insert_file(root.join("dvc.dat")).await.expect("insert failed");
+  write(root.join("dvc.dat.dvc"), format!("outs:\n- md5: {}\n  hash: md5\n  path: dvc.dat\n", hash.md5.unwrap())).expect("write failed");
+  create_dir_all(root.join(".dvc/tmp")).expect("mkdir failed");
+  write(root.join(".dvc/tmp/big.bin"), vec![2u8; 4096]).expect("write failed");
+
+  // an AFC artifact, cached outside .afc
+  afc_ok(root, &["config", "set", "cache.dir", "./store"]);
+  write(root.join("afc.dat"), vec![3u8; 4096]).expect("write failed");
+  afc_ok(root, &["add", "afc.dat"]);
+  write(root.join("big.dat"), vec![4u8; 4096]).expect("write failed");
+
+  let out = afc_ok(root, &["status", "--large-size", "1024"]);
+  let untracked: Vec<_> = out.lines().map(str::trim).filter(|l| l.starts_with("untracked")).collect();
+  assert_eq!(untracked, vec!["untracked: big.dat"], "unexpected output: {}", out);
+}
+
+#[test]
+fn test_status_folder_new_file() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  afc_ok(root, &["init"]);
+  create_dir_all(root.join("data/sub")).expect("mkdir failed");
+  write(root.join("data/a.txt"), "hello").expect("write failed");
+  afc_ok(root, &["add", "data"]);
+  let out = afc_ok(root, &["status"]);
+  assert_eq!(out.lines().map(str::trim).collect::<Vec<_>>(), vec!["up to date: data"]);
+
+  // a file added to the folder changes its contents, even if no entry changed
+  write(root.join("data/sub/b.txt"), "world").expect("write failed");
+  let out = afc_ok(root, &["status"]);
+  assert_eq!(out.lines().map(str::trim).collect::<Vec<_>>(), vec!["modified: data"]);
+}
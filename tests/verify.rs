@@ -0,0 +1,110 @@
+//! Tests for verifying data against recorded hashes.
+use std::fs::{create_dir_all, write};
+
+use astral_filing_cabinet::cache::{Cache, object_relpath};
+use astral_filing_cabinet::filehash::MultiHash;
+use astral_filing_cabinet::remote::{LocalRemote, Remote};
+use astral_filing_cabinet::tree::verify::{Verified, verify_file, verify_cache_object, verify_remote_object};
+
+mod common;
+use common::TestDir;
+use common::cli::{afc, afc_ok};
+
+#[tokio::test]
+async fn test_verify_cache() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  write(dir.path().join("a.txt"), "hello").expect("write failed");
+  write(dir.path().join("b.txt"), "world").expect("write failed");
+  let (_, good) = cache.insert_file(dir.path().join("a.txt")).await.expect("insert failed");
+  let (_, bad) = cache.insert_file(dir.path().join("b.txt")).await.expect("insert failed");
+  write(cache.locate(&bad).await.unwrap(), "w0rld").expect("write failed");
+
+  assert_eq!(verify_cache_object(&cache, &good).await.expect("verify failed"), Verified::Ok);
+  assert_eq!(verify_cache_object(&cache, &bad).await.expect("verify failed"), Verified::Corrupt);
+
+  let qpath = cache.quarantine(&bad).await.expect("quarantine failed");
+  assert!(qpath.starts_with(dir.path().join("cache/quarantine")));
+  assert!(qpath.exists());
+  assert!(!cache.contains(&bad).await.expect("contains failed"));
+  assert_eq!(verify_cache_object(&cache, &bad).await.expect("verify failed"), Verified::Missing);
+}
+
+#[tokio::test]
+async fn test_verify_every_hash() {
+  let dir = TestDir::empty();
+  let path = dir.path().join("a.txt");
+  write(&path, "hello").expect("write failed");
+  let cache = Cache::open(dir.path().join("cache"));
+  let (_, hash) = cache.insert_file(&path).await.expect("insert failed");
+  assert_eq!(verify_file(&path, &hash).await.expect("verify failed"), Verified::Ok);
+
+  // a wrong MD5 is caught even though the SHA-256 matches
+  let wrong = MultiHash { md5: Some("7d793037a0760186574b0282f2f435e7".parse().unwrap()), ..hash.clone() };
+  assert_eq!(verify_file(&path, &wrong).await.expect("verify failed"), Verified::Corrupt);
+  assert_eq!(verify_file(dir.path().join("none.txt"), &hash).await.expect("verify failed"), Verified::Missing);
+}
+
+#[tokio::test]
+async fn test_verify_remote() {
+  let dir = TestDir::empty();
+  let cache = Cache::open(dir.path().join("cache"));
+  let remote = LocalRemote::new(dir.path().join("remote"));
+  write(dir.path().join("a.txt"), "hello").expect("write failed");
+  write(dir.path().join("b.txt"), "world").expect("write failed");
+  let (_, good) = cache.insert_file(dir.path().join("a.txt")).await.expect("insert failed");
+  let (_, bad) = cache.insert_file(dir.path().join("b.txt")).await.expect("insert failed");
+  assert_eq!(verify_remote_object(&remote, &cache, &good).await.expect("verify failed"), Verified::Missing);
+
+  remote.upload(&good, &cache.locate(&good).await.unwrap()).await.expect("upload failed");
+  remote.upload(&bad, &cache.locate(&bad).await.unwrap()).await.expect("upload failed");
+  let rpath = object_relpath(&bad).unwrap().to_path(dir.path().join("remote"));
+  write(rpath, "w0rld").expect("write failed");
+
+  assert_eq!(verify_remote_object(&remote, &cache, &good).await.expect("verify failed"), Verified::Ok);
+  assert_eq!(verify_remote_object(&remote, &cache, &bad).await.expect("verify failed"), Verified::Corrupt);
+  // downloads are cleaned up
+  let leftovers: Vec<_> = std::fs::read_dir(dir.path().join("cache")).unwrap()
+    .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+    .filter(|n| n.starts_with('.'))
+    .collect();
+  assert!(leftovers.is_empty(), "leftover files {:?}", leftovers);
+}
+
+#[tokio::test]
+async fn test_verify_unreferenced() {
+  let dir = TestDir::empty();
+  let root = dir.path();
+  afc_ok(root, &["init"]);
+  write(root.join("a.txt"), "hello").expect("write failed");
+  afc_ok(root, &["add", "a.txt"]);
+
+  // an object no pointer refers to, corrupted
+  let cache = Cache::open(root.join(".afc/cache"));
+  write(root.join("orphan.txt"), "orphan").expect("write failed");
+  let (_, orphan) = cache.insert_file(root.join("orphan.txt")).await.expect("insert failed");
+  let key = orphan.sha256.as_ref().unwrap().to_string();
+  write(cache.locate(&orphan).await.unwrap(), "0rphan").expect("write failed");
+  // quarantined objects and partial downloads are not checked
+  for sub in ["quarantine", "tmp"] {
+    create_dir_all(root.join(".afc/cache").join(sub)).expect("mkdir failed");
+    write(root.join(".afc/cache").join(sub).join(&key), "junk").expect("write failed");
+  }
+  assert_eq!(cache.list_objects().await.expect("list failed").len(), 2);
+
+  let out = afc(root, &["verify", "--cache"]);
+  assert!(!out.status.success());
+  let stdout = String::from_utf8_lossy(&out.stdout);
+  assert!(stdout.contains(&format!("corrupt: unreferenced cache object {}\n", key)), "unexpected output: {}", stdout);
+  assert!(stdout.contains("1 cache objects are not referenced by any artifact"), "unexpected output: {}", stdout);
+  assert!(stdout.contains("1 verified, 0 missing, 1 failed"), "unexpected output: {}", stdout);
+
+  // selecting artifacts only checks their objects
+  afc_ok(root, &["verify", "--cache", "a.txt"]);
+
+  let out = afc(root, &["verify", "--cache", "--quarantine"]);
+  assert!(!out.status.success());
+  assert!(!cache.contains(&orphan).await.expect("contains failed"));
+  let out = afc_ok(root, &["verify", "--cache"]);
+  assert!(out.contains("1 verified, 0 missing, 0 failed"), "unexpected output: {}", out);
+}
@@ -0,0 +1,44 @@
+use std::fs::create_dir_all;
+
+use astral_filing_cabinet::tree::WorkTree;
+
+mod common;
+use common::TestDir;
+
+#[test]
+fn test_find_uninitialized() {
+  let dir = TestDir::tarball("empty-git");
+  let found = WorkTree::find(dir.path()).expect("find failed");
+  assert!(found.is_none());
+
+  let tree = WorkTree::open(dir.path());
+  assert_eq!(tree.root_path(), dir.path().canonicalize().unwrap());
+  assert!(!tree.is_initialized());
+  assert!(tree.is_git_root());
+}
+
+#[test]
+fn test_find_from_subdir() {
+  let dir = TestDir::tarball("empty-git");
+  create_dir_all(dir.path().join(".afc")).expect("mkdir failed");
+  let sub = dir.path().join("data/raw");
+  create_dir_all(&sub).expect("mkdir failed");
+
+  let tree = WorkTree::find(&sub).expect("find failed").expect("no work tree");
+  assert_eq!(tree.root_path(), dir.path().canonicalize().unwrap());
+  assert!(tree.is_initialized());
+
+  let tree = WorkTree::open(&sub);
+  assert_eq!(tree.root_path(), dir.path().canonicalize().unwrap());
+}
+
+#[test]
+fn test_find_stops_at_git_root() {
+  let dir = TestDir::empty();
+  create_dir_all(dir.path().join(".afc")).expect("mkdir failed");
+  let repo = dir.path().join("repo");
+  create_dir_all(repo.join(".git")).expect("mkdir failed");
+
+  let found = WorkTree::find(&repo).expect("find failed");
+  assert!(found.is_none());
+}
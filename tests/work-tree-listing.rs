@@ -45,3 +45,21 @@ async fn test_single_artifact_subdir() {
   assert_eq!(art.path().as_str(), "data/artifact.dat");
   assert_eq!(art.pointer_path().unwrap().as_str(), "data/artifact.dat.afc");
 }
+
+#[tokio::test]
+async fn test_multi_artifact_pointer() {
+  let dir = TestDir::empty();
+  std::fs::create_dir_all(dir.path().join("shards")).expect("mkdir failed");
+  std::fs::write(dir.path().join("shards/shards.afc"), concat!(
+    "[[artifacts]]\npath = \"part-0.parquet\"\nsize = 5\nmd5 = \"5d41402abc4b2a76b9719d911017c592\"\n\n",
+    "[[artifacts]]\npath = \"part-1.parquet\"\nremote = \"public\"\nsize = 5\nmd5 = \"7d793037a0760186574b0282f2f435e7\"\n",
+  )).expect("write failed");
+  let tree = WorkTree::open(dir.path());
+  let mut arts: Vec<_> = tree.scan_artifacts().await.try_collect().await.expect("scan failed");
+  arts.sort_by(|a, b| a.path().cmp(b.path()));
+  assert_eq!(arts.len(), 2);
+  assert_eq!(arts[0].path().as_str(), "shards/part-0.parquet");
+  assert_eq!(arts[1].path().as_str(), "shards/part-1.parquet");
+  assert_eq!(arts[1].remote(), Some("public"));
+  assert!(arts.iter().all(|a| a.pointer_path().unwrap().as_str() == "shards/shards.afc"));
+}